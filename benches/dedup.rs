@@ -0,0 +1,66 @@
+// Duplicate detection benchmark: feeds a stream of distinct payloads (plus a repeat of each tenth
+// one) through the DuplicateFilter, and through the Vec scan it replaced, at a range of window
+// sizes, and reports the throughput of each. The filter should be about as fast with thousands of
+// payloads remembered as with a few; the scan slows down in proportion. Run with `cargo bench`.
+
+extern crate rand;
+extern crate teamech;
+
+use std::time::{Duration,Instant};
+
+use teamech::dedup::DuplicateFilter;
+
+static MESSAGES:usize = 100_000;
+static PAYLOAD_SIZE:usize = 64;
+static WINDOWS:[usize;5] = [32,256,1_024,4_096,16_384];
+
+// Random payloads, with every tenth one followed by a copy of itself.
+fn payloads() -> Vec<Vec<u8>> {
+	let mut payloads:Vec<Vec<u8>> = Vec::with_capacity(MESSAGES+MESSAGES/10);
+	for n in 0..MESSAGES {
+		let payload:Vec<u8> = (0..PAYLOAD_SIZE).map(|_| rand::random::<u8>()).collect();
+		if n%10 == 0 {
+			payloads.push(payload.clone());
+		}
+		payloads.push(payload);
+	}
+	return payloads;
+}
+
+// Runs every payload through `admit`, and reports the throughput and how many were dropped.
+fn bench<F:FnMut(&[u8]) -> bool>(name:&str,payloads:&[Vec<u8>],mut admit:F) {
+	let start:Instant = Instant::now();
+	let mut dropped:usize = 0;
+	for payload in payloads.iter() {
+		if !admit(payload) {
+			dropped += 1;
+		}
+	}
+	let elapsed:Duration = start.elapsed();
+	let persecond:f64 = payloads.len() as f64/elapsed.as_secs_f64();
+	println!("{:>24}: {:>12.1} messages/s {:>8} duplicates dropped",name,persecond,dropped);
+}
+
+fn main() {
+	let payloads:Vec<Vec<u8>> = payloads();
+	println!("Checking {} payloads of {} bytes for duplicates:",payloads.len(),PAYLOAD_SIZE);
+	for window in WINDOWS.iter().cloned() {
+		println!("Window of {}:",window);
+		let mut filter:DuplicateFilter = DuplicateFilter::new(window);
+		bench("ring buffer and set",&payloads,|payload| filter.admit(payload));
+		// What the client used to do, for comparison. The largest windows take a while.
+		let mut lastmsgs:Vec<Vec<u8>> = Vec::new();
+		bench("vec scan",&payloads,|payload| {
+			if lastmsgs.contains(&payload.to_vec()) {
+				return false;
+			}
+			lastmsgs.push(payload.to_vec());
+			if lastmsgs.len() > window {
+				lastmsgs.reverse();
+				let _ = lastmsgs.pop();
+				lastmsgs.reverse();
+			}
+			return true;
+		});
+	}
+}
@@ -0,0 +1,50 @@
+// Pad access benchmark: encrypts the same 400-byte message repeatedly using each of the ways a Pad
+// can serve key bytes, plus the old behaviour of opening the pad file anew for every message, and
+// reports the throughput of each. Run with `cargo bench`.
+
+extern crate rand;
+extern crate teamech;
+
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path,PathBuf};
+use std::time::{Duration,Instant};
+
+use teamech::teacrypt::{Pad,encrypt,Error};
+
+static MESSAGES:u32 = 2_000;
+static MESSAGE_SIZE:usize = 400;
+static PAD_SIZE:usize = 4*1024*1024;
+
+// Times how long it takes to encrypt MESSAGES messages, getting the pad for each one from `getpad`.
+fn bench<F:FnMut() -> Result<Pad,Error>>(name:&str,mut getpad:F) {
+	let message:Vec<u8> = vec![0x55;MESSAGE_SIZE];
+	let start:Instant = Instant::now();
+	for _ in 0..MESSAGES {
+		let pad:Pad = getpad().expect("could not open pad");
+		encrypt(&message,&pad).expect("could not encrypt");
+	}
+	let elapsed:Duration = start.elapsed();
+	let persecond:f64 = MESSAGES as f64/elapsed.as_secs_f64();
+	println!("{:>24}: {:>10.1} messages/s {:>10.1} KiB/s",name,persecond,persecond*MESSAGE_SIZE as f64/1024.0);
+}
+
+fn main() {
+	let padpath:PathBuf = env::temp_dir().join(format!("teamech-bench-{}.pad",rand::random::<u32>()));
+	{
+		let mut padfile:fs::File = fs::File::create(&padpath).expect("could not create pad");
+		let padbytes:Vec<u8> = (0..PAD_SIZE).map(|_| rand::random::<u8>()).collect();
+		padfile.write_all(&padbytes).expect("could not write pad");
+	}
+	let path:&Path = &padpath;
+	println!("Encrypting {} messages of {} bytes with a {} KiB pad:",MESSAGES,MESSAGE_SIZE,PAD_SIZE/1024);
+	bench("reopened per message",|| Pad::open(path));
+	let opened:Pad = Pad::open(path).expect("could not open pad");
+	bench("opened once",|| Ok(opened.clone()));
+	let loaded:Pad = Pad::load(path).expect("could not load pad");
+	bench("loaded into memory",|| Ok(loaded.clone()));
+	let mapped:Pad = Pad::map(path).expect("could not map pad");
+	bench("memory-mapped",|| Ok(mapped.clone()));
+	let _ = fs::remove_file(&padpath);
+}
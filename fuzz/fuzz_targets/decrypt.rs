@@ -0,0 +1,14 @@
+// Feeds arbitrary bytes to teacrypt::decrypt. Any input may fail to decrypt, but none may panic.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate teamech;
+
+mod pad;
+
+use teamech::teacrypt::decrypt;
+
+fuzz_target!(|data:&[u8]| {
+	let _ = decrypt(data,pad::pad());
+});
@@ -0,0 +1,43 @@
+// Exercises fragment reassembly: arbitrary bytes are cut into pieces and fed to a reassembler as
+// fragments, and also split into fragments and put back together, in reverse order. Nothing here
+// may panic, and the round trip must be exact.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate teamech;
+
+use std::time::Duration;
+
+use teamech::fragment::{self,Reassembler,ReassemblyLimits};
+
+fuzz_target!(|data:&[u8]| {
+	let limits:ReassemblyLimits = ReassemblyLimits {
+		max_message_size:1 << 16,
+		timeout:Duration::from_secs(60),
+		max_partial:4,
+	};
+	// As fragments from a (possibly hostile) server: the first byte says how long each one is.
+	let mut reassembler:Reassembler = Reassembler::new(limits);
+	if let Some((&length,rest)) = data.split_first() {
+		for piece in rest.chunks(length.max(1) as usize) {
+			let _ = reassembler.accept(piece);
+		}
+	}
+	// As a message to send: it must come back exactly as it was.
+	let mut reassembler:Reassembler = Reassembler::new(ReassemblyLimits {
+		max_message_size:data.len().max(1),
+		..limits
+	});
+	let pieces:Vec<Vec<u8>> = fragment::split(data).expect("could not split message");
+	if pieces.len() == 1 && !fragment::is_fragment(&pieces[0]) {
+		assert_eq!(pieces[0],data);
+		return;
+	}
+	let mut message:Option<Vec<u8>> = None;
+	for piece in pieces.iter().rev() {
+		assert!(message.is_none());
+		message = reassembler.accept(piece).expect("could not reassemble own fragments");
+	}
+	assert_eq!(message.as_deref(),Some(data));
+});
@@ -0,0 +1,20 @@
+// Shared fuzzing pad. Every fuzz target uses the same deterministic pad, written to a temporary
+// file the first time it's needed, so that crashes found by one run reproduce in the next.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use teamech::teacrypt::Pad;
+
+static PAD:OnceLock<Pad> = OnceLock::new();
+
+pub fn pad() -> &'static Pad {
+	return PAD.get_or_init(|| {
+		let padpath:PathBuf = env::temp_dir().join("teamech-fuzz.pad");
+		let padbytes:Vec<u8> = (0..65_536u32).map(|n| (n.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+		fs::write(&padpath,&padbytes).expect("could not write fuzzing pad");
+		return Pad::load(&padpath).expect("could not load fuzzing pad");
+	});
+}
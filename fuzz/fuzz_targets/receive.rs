@@ -0,0 +1,46 @@
+// Exercises the client's receive path: arbitrary bytes are treated as a datagram from the server,
+// and also as the content of a validly-encrypted message, which is decoded and dispatched the same
+// way the client does it. Nothing here may panic.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate teamech;
+
+mod pad;
+
+use std::net::SocketAddr;
+
+use teamech::handler::{Message,Router};
+use teamech::packet::Packet;
+
+// Does what the client does with a packet once it has been decoded.
+fn receive(packet:&Packet) {
+	if packet.status().is_some() {
+		return;
+	}
+	let text:String = String::from_utf8_lossy(&packet.content).to_string();
+	let server:SocketAddr = "127.0.0.1:6666".parse().unwrap();
+	let message:Message = Message {
+		text:&text,
+		bytes:&packet.content,
+		timestamp:packet.timestamp,
+		server,
+	};
+	let mut router:Router = Router::new();
+	router.command("echo",|message:&Message| Some(message.arguments().as_bytes().to_vec()));
+	router.fallback(|message:&Message| message.command().map(|c| c.as_bytes().to_vec()));
+	let _ = router.dispatch(&message);
+}
+
+fuzz_target!(|data:&[u8]| {
+	// As a raw datagram: this will essentially never validate, but must never crash either.
+	if let Ok(packet) = Packet::decode(data,pad::pad()) {
+		receive(&packet);
+	}
+	// As message content: this must always survive the round trip intact.
+	let payload:Vec<u8> = Packet::encode(data,u64::MAX,pad::pad()).expect("could not encrypt");
+	let packet:Packet = Packet::decode(&payload,pad::pad()).expect("could not decrypt own payload");
+	assert_eq!(packet.content,data);
+	receive(&packet);
+});
@@ -0,0 +1,81 @@
+// Waiting between attempts to subscribe.
+//
+// After a power cut, every device on a network boots at about the same time and asks the server
+// for a subscription at about the same time. If the server can't keep up, and every device retried
+// on the same fixed schedule, they would keep asking in lockstep, and the server would never catch
+// up. So the wait between attempts grows each time one fails (up to a limit), and is shortened by
+// a random amount, which spreads the devices out. Once a subscription is granted, the wait starts
+// over. Optionally, the client gives up after a number of failures in a row.
+
+use std::time::Duration;
+
+use rand;
+
+/// How long to wait between attempts, and how many to make.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct BackoffPolicy {
+	/// The wait after the first failure.
+	pub initial:Duration,
+	/// The most the wait can grow to.
+	pub max:Duration,
+	/// What the wait is multiplied by after each failure.
+	pub multiplier:u32,
+	/// How much of each wait can be randomly cut off, from 0 (none of it) to 1 (all of it).
+	pub jitter:f64,
+	/// How many failures in a row to put up with, or None to keep trying forever.
+	pub budget:Option<u32>,
+}
+
+impl Default for BackoffPolicy {
+	fn default() -> BackoffPolicy {
+		return BackoffPolicy {
+			initial:Duration::from_millis(1_000),
+			max:Duration::from_millis(60_000),
+			multiplier:2,
+			jitter:0.5,
+			budget:None,
+		};
+	}
+}
+
+/// Failures since the last success, and how long to wait after the next one.
+pub struct Backoff {
+	policy:BackoffPolicy,
+	failures:u32,
+	// The wait after the next failure, before jitter.
+	delay:Duration,
+}
+
+impl Backoff {
+	pub fn new(policy:BackoffPolicy) -> Backoff {
+		return Backoff {
+			policy,
+			failures:0,
+			delay:policy.initial.min(policy.max),
+		};
+	}
+
+	/// Failures since the last success.
+	pub fn failures(&self) -> u32 {
+		return self.failures;
+	}
+
+	/// Counts a failure, and returns how long to wait before trying again, or None if that was one
+	/// failure too many.
+	pub fn fail(&mut self) -> Option<Duration> {
+		self.failures = self.failures.saturating_add(1);
+		if self.policy.budget.is_some_and(|budget| self.failures > budget) {
+			return None;
+		}
+		let delay:Duration = self.delay;
+		self.delay = self.delay.saturating_mul(self.policy.multiplier.max(1)).min(self.policy.max);
+		let jitter:f64 = self.policy.jitter.clamp(0.0,1.0)*rand::random::<f64>();
+		return Some(delay.mul_f64(1.0 - jitter));
+	}
+
+	/// Records a success, so that the next failure waits only the initial delay again.
+	pub fn reset(&mut self) {
+		self.failures = 0;
+		self.delay = self.policy.initial.min(self.policy.max);
+	}
+}
@@ -0,0 +1,398 @@
+// Command-line parsing for the embedded client.
+//
+// The client is run as `teamech-embedded-template [COMMAND] [OPTIONS] [ARGS]`. Without a
+// command, `run` is assumed, so the original `[host:remoteport] [localport] [keyfile]` form still
+// works.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use teamech::config::{Config,PadModeConfig};
+use teamech::logging::{LogFilter,LogFormat};
+
+pub static VERSION:&str = env!("CARGO_PKG_VERSION");
+pub static NAME:&str = env!("CARGO_PKG_NAME");
+
+/// Settings given on the command line, which override the configuration file and environment.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Options {
+	pub config:Option<PathBuf>,
+	pub server:Option<String>,
+	pub backups:Vec<String>,
+	pub bind:Option<IpAddr>,
+	pub port:Option<u16>,
+	pub pad:Option<PathBuf>,
+	pub padmode:Option<PadModeConfig>,
+	pub padoffset:Option<u64>,
+	pub padsize:Option<u64>,
+	pub dualstack:bool,
+	pub showhex:bool,
+	pub loglevel:Option<LogFilter>,
+	pub logformat:Option<LogFormat>,
+	pub quiet:bool,
+}
+
+impl Options {
+	/// Overrides configuration settings with the ones given on the command line.
+	pub fn apply(&self,config:&mut Config) {
+		if let Some(ref server) = self.server {
+			config.server = Some(server.clone());
+		}
+		if !self.backups.is_empty() {
+			config.backup_servers = self.backups.clone();
+		}
+		if let Some(bind) = self.bind {
+			config.network.bind = Some(bind);
+		}
+		if let Some(port) = self.port {
+			config.network.port = port;
+		}
+		if let Some(ref pad) = self.pad {
+			config.pad.path = Some(pad.clone());
+		}
+		if let Some(mode) = self.padmode {
+			config.pad.mode = mode;
+		}
+		if let Some(offset) = self.padoffset {
+			config.pad.offset = offset;
+		}
+		if let Some(size) = self.padsize {
+			config.pad.size = Some(size);
+		}
+		if self.dualstack {
+			config.network.dual_stack = true;
+		}
+		if self.showhex {
+			config.logging.show_hex = true;
+		}
+		if let Some(ref level) = self.loglevel {
+			config.logging.level = level.clone();
+		}
+		if let Some(format) = self.logformat {
+			config.logging.format = format;
+		}
+		if self.quiet {
+			config.logging.quiet = true;
+		}
+	}
+}
+
+/// What the user asked the program to do.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Command {
+	/// Connect to the server and run the client until killed.
+	Run(Options),
+	/// Connect to the server, send one message, and exit.
+	Send(Options,String),
+	/// Generate a new random pad file of the given size.
+	GenPad {
+		path:PathBuf,
+		size:u64,
+		force:bool,
+	},
+	/// Check that a pad is readable and suitable for use.
+	CheckPad(Options),
+	/// Decrypt and display a captured payload.
+	Decode(Options,Vec<u8>),
+	/// Show help, either in general or for one command.
+	Help(Option<String>),
+	/// Show the version number.
+	Version,
+}
+
+/// A problem with the command line.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct CliError(pub String);
+
+impl fmt::Display for CliError {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		return write!(f,"{}",self.0);
+	}
+}
+
+static COMMANDS:[&str;5] = ["run","send","genpad","check-pad","decode"];
+
+static OPTIONS_HELP:&str = "\
+Options:
+  -c, --config PATH      read settings from a TOML configuration file
+  -s, --server HOST:PORT address of the Teamech server
+      --backup HOST:PORT a server to fail over to (may be given more than once)
+  -b, --bind ADDRESS     local address to bind to (default: any, matching the server)
+      --dual-stack       reach both IPv4 and IPv6 servers from one IPv6 socket
+  -p, --port PORT        local port to bind to (0 to let the OS choose)
+  -k, --pad PATH         pad file or block device
+      --pad-mode MODE    how to access the pad: file, memory or mapped
+      --load-pad         same as --pad-mode memory
+      --map-pad          same as --pad-mode mapped
+      --pad-offset BYTES where the pad starts in the pad file
+      --pad-size BYTES   how much of the pad file to use (K, M and G suffixes allowed)
+  -x, --showhex          also log messages as hex bytes
+      --log-level FILTER log level, optionally per target (e.g. info,messages=off)
+      --log-format FORMAT
+                         how to write log records: text or json
+  -q, --quiet            only log warnings and errors
+  -h, --help             show this help
+  -V, --version          show the version number
+";
+
+/// Help text, either for the program as a whole or for one command.
+pub fn help(topic:Option<&str>) -> String {
+	let usage:String = match topic {
+		Some("run") => format!("\
+Usage: {0} run [OPTIONS] [HOST:PORT [LOCALPORT] PAD]
+
+Connects to the server and runs the client until it is stopped. The server, local port and pad
+may be given as arguments (as in earlier versions), as options, or in the configuration file.
+
+On SIGINT or SIGTERM, the client finishes delivering reliable messages, tells the server it is
+leaving, and exits; a second signal stops it straight away. Exit status:
+  0        shut down cleanly
+  1        a fatal error (e.g. the pad could not be opened)
+  2        invalid options or configuration
+  3        shut down, but some reliable messages were not delivered
+  4        gave up after retry.retry_budget failures in a row
+  128+N    stopped by a second signal N
+",NAME),
+		Some("send") => format!("\
+Usage: {0} send [OPTIONS] MESSAGE...
+
+Connects to the server, sends one message, waits for it to be acknowledged, and exits. The
+words of the message are joined with spaces.
+",NAME),
+		Some("genpad") => format!("\
+Usage: {0} genpad [--size BYTES] [--force] PATH
+
+Generates a new pad file filled with bytes from the operating system's secure random number
+generator. The size defaults to 10M; K, M and G suffixes are allowed. Existing files are only
+overwritten if --force is given.
+",NAME),
+		Some("check-pad") => format!("\
+Usage: {0} check-pad [OPTIONS] [PAD]
+
+Reads the whole pad, reports its size and how evenly its byte values are distributed, and
+exits with an error if it can't be used. Only the configuration, pad and logging options
+apply.
+",NAME),
+		Some("decode") => format!("\
+Usage: {0} decode [OPTIONS] HEX...
+
+Decrypts a captured payload (given as hex bytes, e.g. as printed by --showhex) with the pad,
+and shows its content and timestamp. Only the configuration, pad and logging options apply.
+",NAME),
+		_ => format!("\
+Usage: {0} [COMMAND] [OPTIONS] [ARGS]
+
+Commands:
+  run        connect to the server and run the client (the default)
+  send       send one message to the server and exit
+  genpad     generate a new random pad file
+  check-pad  check that a pad is readable and suitable for use
+  decode     decrypt and display a captured payload
+
+Use '{0} help COMMAND' or '{0} COMMAND --help' for more about a command.
+",NAME),
+	};
+	return format!("{}\n{}",usage,OPTIONS_HELP);
+}
+
+// Parses a byte count, allowing K, M and G (binary) suffixes.
+fn parsesize(text:&str) -> Result<u64,CliError> {
+	let (number,multiplier):(&str,u64) = match text.chars().last() {
+		Some('K') | Some('k') => (&text[..text.len()-1],1 << 10),
+		Some('M') | Some('m') => (&text[..text.len()-1],1 << 20),
+		Some('G') | Some('g') => (&text[..text.len()-1],1 << 30),
+		_ => (text,1),
+	};
+	match number.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)) {
+		Some(size) => return Ok(size),
+		None => return Err(CliError(format!("'{}' is not a valid number of bytes",text))),
+	};
+}
+
+// Parses hex bytes, ignoring whitespace and any separators between bytes.
+fn parsehex(words:&[String]) -> Result<Vec<u8>,CliError> {
+	let digits:Vec<u8> = words.iter()
+		.flat_map(|w| w.bytes())
+		.filter(|b| b.is_ascii_hexdigit())
+		.collect();
+	let invalid:Option<&String> = words.iter().find(|w| w.chars().any(|c| !c.is_ascii_hexdigit() && !c.is_whitespace() && !":-,".contains(c)));
+	if let Some(word) = invalid {
+		return Err(CliError(format!("'{}' is not hexadecimal",word)));
+	}
+	if !digits.len().is_multiple_of(2) {
+		return Err(CliError(String::from("hex payload has an odd number of digits")));
+	}
+	let mut bytes:Vec<u8> = Vec::with_capacity(digits.len()/2);
+	for pair in digits.chunks(2) {
+		let text:&str = std::str::from_utf8(pair).unwrap_or("");
+		match u8::from_str_radix(text,16) {
+			Ok(byte) => bytes.push(byte),
+			Err(_) => return Err(CliError(format!("'{}' is not a hex byte",text))),
+		};
+	}
+	return Ok(bytes);
+}
+
+// Walks through the arguments, handing out options and their values.
+struct ArgReader {
+	args:Vec<String>,
+	position:usize,
+	// The value attached to the current option with '=', if any.
+	attached:Option<String>,
+}
+
+impl ArgReader {
+	fn value(&mut self,option:&str) -> Result<String,CliError> {
+		if let Some(value) = self.attached.take() {
+			return Ok(value);
+		}
+		if self.position < self.args.len() {
+			self.position += 1;
+			return Ok(self.args[self.position-1].clone());
+		}
+		return Err(CliError(format!("option {} needs a value",option)));
+	}
+
+	fn parsed<T:std::str::FromStr>(&mut self,option:&str,what:&str) -> Result<T,CliError> {
+		let value:String = self.value(option)?;
+		match value.parse::<T>() {
+			Ok(parsed) => return Ok(parsed),
+			Err(_) => return Err(CliError(format!("'{}' is not a valid {} for {}",value,what,option))),
+		};
+	}
+}
+
+/// Parses the command line (without the program name).
+pub fn parse<I:IntoIterator<Item=String>>(args:I) -> Result<Command,CliError> {
+	let mut reader:ArgReader = ArgReader {
+		args:args.into_iter().collect(),
+		position:0,
+		attached:None,
+	};
+	let mut command:String = String::from("run");
+	let mut explicit:bool = false;
+	if let Some(first) = reader.args.first() {
+		if COMMANDS.contains(&first.as_str()) || first == "help" {
+			command = first.clone();
+			explicit = true;
+			reader.position = 1;
+		}
+	}
+	let mut options:Options = Options::default();
+	let mut positional:Vec<String> = Vec::new();
+	let mut genpadsize:u64 = 10 << 20;
+	let mut force:bool = false;
+	let mut optionsdone:bool = false;
+	// These commands don't talk to a server, so options that only change how the client does are
+	// mistakes there.
+	let offline:bool = command == "genpad" || command == "check-pad" || command == "decode";
+	while reader.position < reader.args.len() {
+		let arg:String = reader.args[reader.position].clone();
+		reader.position += 1;
+		if optionsdone || !arg.starts_with('-') || arg == "-" {
+			positional.push(arg);
+			continue;
+		}
+		if arg == "--" {
+			optionsdone = true;
+			continue;
+		}
+		// Split --option=value into the option and its attached value.
+		let option:String = match arg.find('=') {
+			Some(n) if arg.starts_with("--") => {
+				reader.attached = Some(arg[n+1..].to_owned());
+				arg[..n].to_owned()
+			},
+			_ => arg.clone(),
+		};
+		match option.as_str() {
+			"-h" | "--help" if explicit => return Ok(Command::Help(Some(command))),
+			"-h" | "--help" => return Ok(Command::Help(None)),
+			"-V" | "--version" => return Ok(Command::Version),
+			"-s" | "--server" | "--backup" | "-b" | "--bind" | "--dual-stack" | "-p" | "--port" | "-x" | "--showhex" if offline => {
+				return Err(CliError(format!("option {} only applies to run and send, not {}",option,command)));
+			},
+			"-c" | "--config" => options.config = Some(PathBuf::from(reader.value(&option)?)),
+			"-s" | "--server" => options.server = Some(reader.value(&option)?),
+			"--backup" => options.backups.push(reader.value(&option)?),
+			"-b" | "--bind" => options.bind = Some(reader.parsed(&option,"IP address")?),
+			"--dual-stack" => options.dualstack = true,
+			"-p" | "--port" => options.port = Some(reader.parsed(&option,"port number")?),
+			"-k" | "--pad" => options.pad = Some(PathBuf::from(reader.value(&option)?)),
+			"--pad-mode" => options.padmode = Some(reader.parsed(&option,"pad mode (file, memory or mapped)")?),
+			"--load-pad" => options.padmode = Some(PadModeConfig::Memory),
+			"--map-pad" => options.padmode = Some(PadModeConfig::Mapped),
+			"--pad-offset" => options.padoffset = Some(parsesize(&reader.value(&option)?)?),
+			"--pad-size" => options.padsize = Some(parsesize(&reader.value(&option)?)?),
+			"--size" if command == "genpad" => genpadsize = parsesize(&reader.value(&option)?)?,
+			"--force" if command == "genpad" => force = true,
+			"-x" | "--showhex" => options.showhex = true,
+			"--log-level" => options.loglevel = Some(reader.parsed(&option,"log filter (e.g. info,messages=off)")?),
+			"--log-format" => options.logformat = Some(reader.parsed(&option,"log format (text or json)")?),
+			"-q" | "--quiet" => options.quiet = true,
+			_ => return Err(CliError(format!("unknown option '{}' for {}",option,command))),
+		};
+		if let Some(value) = reader.attached.take() {
+			return Err(CliError(format!("option {} does not take a value (got '{}')",option,value)));
+		}
+	}
+	match command.as_str() {
+		"help" => return Ok(Command::Help(positional.first().cloned())),
+		"run" => {
+			// The original positional form: [host:remoteport] [localport] [keyfile].
+			match positional.len() {
+				0 => (),
+				1 => options.server = Some(positional[0].clone()),
+				2 => {
+					options.server = Some(positional[0].clone());
+					options.pad = Some(PathBuf::from(&positional[1]));
+				},
+				3 => {
+					options.server = Some(positional[0].clone());
+					match positional[1].parse::<u16>() {
+						Ok(port) => options.port = Some(port),
+						Err(_) => return Err(CliError(format!("'{}' is not a valid local port number",positional[1]))),
+					};
+					options.pad = Some(PathBuf::from(&positional[2]));
+				},
+				_ => return Err(CliError(String::from("too many arguments for run"))),
+			};
+			return Ok(Command::Run(options));
+		},
+		"send" => {
+			if positional.is_empty() {
+				return Err(CliError(String::from("send needs a message to send")));
+			}
+			return Ok(Command::Send(options,positional.join(" ")));
+		},
+		"genpad" => {
+			if positional.len() != 1 {
+				return Err(CliError(String::from("genpad needs exactly one path to write the pad to")));
+			}
+			if genpadsize == 0 {
+				return Err(CliError(String::from("pad size must be greater than zero")));
+			}
+			return Ok(Command::GenPad {
+				path:PathBuf::from(&positional[0]),
+				size:genpadsize,
+				force,
+			});
+		},
+		"check-pad" => {
+			match positional.len() {
+				0 => (),
+				1 => options.pad = Some(PathBuf::from(&positional[0])),
+				_ => return Err(CliError(String::from("check-pad takes at most one pad path"))),
+			};
+			return Ok(Command::CheckPad(options));
+		},
+		"decode" => {
+			if positional.is_empty() {
+				return Err(CliError(String::from("decode needs a payload, in hex")));
+			}
+			return Ok(Command::Decode(options,parsehex(&positional)?));
+		},
+		_ => return Err(CliError(format!("unknown command '{}'",command))),
+	};
+}
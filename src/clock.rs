@@ -0,0 +1,214 @@
+// Checking message timestamps when clocks disagree.
+//
+// Every message carries the time it was sent, and messages too far from the present are ignored,
+// so that old messages can't be replayed. That only works if the client and the server roughly
+// agree on what time it is, which isn't a given on devices without a real-time clock (e.g. a
+// Raspberry Pi that has just booted and hasn't reached an NTP server yet, whose clock may even be
+// before the Unix epoch, where systime() reports 0).
+//
+// So the client can estimate how far the server's clock is from its own, from the timestamp on
+// the server's answer to a subscription request, and use the corrected time both to check
+// incoming timestamps and to stamp outgoing messages. How much to trust the local clock is set by
+// a ClockPolicy. The estimate is kept in a Clock, which belongs to the TimestampValidator that
+// made it.
+//
+// A local clock before the epoch can't give a time since the epoch at all, so a Clock counts the
+// time since it was made instead. That's no use on its own, but it keeps moving, so once the
+// offset has been estimated from it, the corrected time is as good as any.
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Instant,SystemTime,UNIX_EPOCH};
+
+use super::systime;
+
+/// 2018-09-01 00:00 UTC, in milliseconds. A clock that says it's earlier than this hasn't been set.
+pub static EARLIEST_PLAUSIBLE_TIME:u64 = 1_535_760_000_000;
+
+/// The local clock, and how far the server's clock is estimated to be ahead of it.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Clock {
+	// In milliseconds, as last estimated.
+	offset:i64,
+	// What a local clock before the epoch counts from.
+	started:Instant,
+}
+
+impl Clock {
+	/// A clock with no offset, which only goes by the local clock.
+	pub fn new() -> Clock {
+		return Clock {
+			offset:0,
+			started:Instant::now(),
+		};
+	}
+
+	/// How far the server's clock is estimated to be ahead of the local one, in milliseconds
+	/// (negative if it's behind). Zero until a ClockPolicy::Estimate validator has been
+	/// calibrated.
+	pub fn offset(&self) -> i64 {
+		return self.offset;
+	}
+
+	/// What the local clock read at `time`, in milliseconds since the Unix epoch, or since this
+	/// Clock was made if `time` is before the epoch.
+	pub fn reading(&self,time:SystemTime) -> u64 {
+		match time.duration_since(UNIX_EPOCH) {
+			Ok(since) => return u64::try_from(since.as_millis()).unwrap_or(u64::MAX),
+			Err(_before) => return u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX),
+		};
+	}
+
+	/// What the local clock reads now (see reading()).
+	pub fn local(&self) -> u64 {
+		return self.reading(SystemTime::now());
+	}
+
+	/// The current time as the server would see it. Outgoing messages are stamped with this.
+	pub fn now(&self) -> u64 {
+		return shift(self.local(),self.offset);
+	}
+}
+
+impl Default for Clock {
+	fn default() -> Clock {
+		return Clock::new();
+	}
+}
+
+/// Whether the local clock looks like it has been set.
+pub fn is_set() -> bool {
+	return systime() >= EARLIEST_PLAUSIBLE_TIME;
+}
+
+// Adds a signed offset to a time, without overflowing either way.
+fn shift(time:u64,offset:i64) -> u64 {
+	if offset < 0 {
+		return time.saturating_sub(offset.unsigned_abs());
+	}
+	return time.saturating_add(offset as u64);
+}
+
+/// How message timestamps are checked.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockPolicy {
+	/// Against the local clock, which is assumed to be right.
+	Strict,
+	/// Against the local clock, corrected by the estimated offset of the server's clock.
+	Estimate,
+	/// Not at all. Replayed messages can only be caught by duplicate detection.
+	Ignore,
+}
+
+impl FromStr for ClockPolicy {
+	type Err = ();
+	fn from_str(text:&str) -> Result<ClockPolicy,()> {
+		match text {
+			"strict" => return Ok(ClockPolicy::Strict),
+			"estimate" => return Ok(ClockPolicy::Estimate),
+			"ignore" => return Ok(ClockPolicy::Ignore),
+			_ => return Err(()),
+		};
+	}
+}
+
+/// A message whose timestamp is too far from the present.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct StaleTimestamp {
+	pub timestamp:u64,
+	/// How far the timestamp is ahead of the expected time (negative if it's behind), in
+	/// milliseconds.
+	pub skew:i64,
+	pub tolerance:u64,
+}
+
+impl fmt::Display for StaleTimestamp {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		let direction:&str = match self.skew < 0 {
+			true => "in the past",
+			false => "in the future",
+		};
+		return write!(f,"timestamp {} is {} ms {} (tolerance is {} ms)",self.timestamp,self.skew.unsigned_abs(),direction,self.tolerance);
+	}
+}
+
+impl error::Error for StaleTimestamp {}
+
+/// Decides whether message timestamps are close enough to the present, and keeps the clock they're
+/// checked against.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct TimestampValidator {
+	tolerance:u64,
+	policy:ClockPolicy,
+	clock:Clock,
+}
+
+impl TimestampValidator {
+	pub fn new(tolerance:u64,policy:ClockPolicy) -> TimestampValidator {
+		return TimestampValidator {
+			tolerance,
+			policy,
+			clock:Clock::new(),
+		};
+	}
+
+	/// The clock corrected by the last calibration, for stamping outgoing messages.
+	pub fn clock(&self) -> &Clock {
+		return &self.clock;
+	}
+
+	pub fn policy(&self) -> ClockPolicy {
+		return self.policy;
+	}
+
+	pub fn tolerance(&self) -> u64 {
+		return self.tolerance;
+	}
+
+	/// Estimates the server's clock offset from a message it sent in response to one of ours:
+	/// `sent` and `received` are the local clock's readings (see Clock::local()) when our message
+	/// went out and the response came in, and `servertime` is the response's timestamp. The server
+	/// is assumed to have answered halfway in between. Only has an effect with
+	/// ClockPolicy::Estimate, and returns the new offset.
+	pub fn calibrate(&mut self,sent:u64,received:u64,servertime:u64) -> i64 {
+		if self.policy != ClockPolicy::Estimate {
+			return self.clock.offset;
+		}
+		let midpoint:i128 = (sent as i128 + received.max(sent) as i128)/2;
+		self.clock.offset = narrow(servertime as i128 - midpoint);
+		return self.clock.offset;
+	}
+
+	/// How far a timestamp is ahead of the expected time right now (negative if it's behind).
+	pub fn skew(&self,timestamp:u64) -> i64 {
+		let expected:u64 = match self.policy {
+			ClockPolicy::Strict => self.clock.local(),
+			ClockPolicy::Estimate | ClockPolicy::Ignore => self.clock.now(),
+		};
+		return narrow(timestamp as i128 - expected as i128);
+	}
+
+	/// Checks that a timestamp is within the tolerance of the present.
+	pub fn check(&self,timestamp:u64) -> Result<(),StaleTimestamp> {
+		if self.policy == ClockPolicy::Ignore {
+			return Ok(());
+		}
+		let skew:i64 = self.skew(timestamp);
+		if skew.unsigned_abs() > self.tolerance {
+			return Err(StaleTimestamp {
+				timestamp,
+				skew,
+				tolerance:self.tolerance,
+			});
+		}
+		return Ok(());
+	}
+}
+
+// Narrows a difference of two times, which can't overflow an i128, to an i64.
+fn narrow(difference:i128) -> i64 {
+	return difference.clamp(i64::MIN as i128,i64::MAX as i128) as i64;
+}
@@ -0,0 +1,808 @@
+// Configuration for the embedded client.
+//
+// Settings come from (in increasing order of precedence) the defaults below, a TOML configuration
+// file, TEAMECH_* environment variables, and the command line. Everything is validated once at
+// startup, so that a bad setting is reported clearly instead of causing trouble later.
+//
+// An example configuration file, with every setting at its default value:
+//
+//     server = "teamech.example.com:3840"   # required, unless given on the command line
+//     backup_servers = []                   # servers to fail over to, most preferred first
+//
+//     [network]
+//     # bind = "0.0.0.0"                    # local address to bind to (default: the unspecified
+//                                           # address of the server's family, or :: if dual-stack)
+//     dual_stack = false                    # reach IPv4 and IPv6 servers from one IPv6 socket
+//     dns_ttl_ms = 300000                   # how often to look up server names again (0 only to
+//                                           # do so when a server stops answering)
+//     port = 0                              # local port (0 to let the OS choose)
+//
+//     [pad]
+//     path = "/etc/teamech/network.pad"     # required, unless given on the command line
+//     mode = "file"                         # "file", "memory" or "mapped"
+//     offset = 0                            # where the pad starts in the file, in bytes
+//     # size = 10485760                     # how much of the file to use (default: all of it)
+//
+//     [protocol]
+//     timestamp_tolerance_ms = 10000        # how far a message's timestamp may be off
+//     clock_skew = "estimate"               # "strict" (trust the local clock), "estimate" (correct
+//                                           # it using the server's clock) or "ignore" (don't check)
+//     dedup_window = 32                     # how many recent payloads to remember, to drop repeats
+//     # replay_cache = "/var/lib/teamech/replay"  # where to keep seen nonces across restarts
+//     replay_capacity = 65536               # most nonces to remember, however recent
+//     replay_save_interval_ms = 1000        # how often to save the replay cache, at most
+//     max_message_size = 1048576            # largest fragmented message to reassemble, in bytes
+//     reassembly_timeout_ms = 10000         # how long to wait for the rest of a fragmented message
+//     max_partial_messages = 8              # how many fragmented messages to collect at once
+//
+//     [retry]
+//     response_timeout_ms = 1000            # how long to wait for the server to respond
+//     error_delay_ms = 1000                 # how long to wait before asking the server for a
+//                                           # subscription again, after the first failure
+//     max_backoff_ms = 60000                # the most that wait can grow to
+//     backoff_multiplier = 2                # how much it grows by after each failure
+//     backoff_jitter = 0.5                  # how much of each wait can be randomly cut off (0-1)
+//     retry_budget = 0                      # how many failures in a row to try again after,
+//                                           # before giving up (0 for no limit)
+//     validation_delay_ms = 2000            # how long to wait after a message fails to validate
+//     max_retransmit_interval_ms = 8000     # longest wait between resends of a reliable message
+//     delivery_deadline_ms = 30000          # when to give up on delivering a reliable message
+//     failover_attempts = 3                 # unanswered subscription requests before trying the
+//                                           # next server
+//     failback_interval_ms = 60000          # how often to check whether a more preferred server
+//                                           # is back (0 never to fail back)
+//
+//     [keepalive]
+//     interval_ms = 30000                   # how often to send a heartbeat, which renews the
+//                                           # subscription and keeps NAT mappings open (0 for never)
+//     timeout_ms = 90000                    # how long the server can be silent before the client
+//                                           # subscribes again (only with heartbeats)
+//
+//     [shutdown]
+//     drain_timeout_ms = 5000               # how long to wait for reliable messages to be
+//                                           # delivered when shutting down
+//     goodbye = true                        # tell the server when shutting down
+//
+//     [systemd]
+//     notify = true                         # tell systemd when the client is ready, and how it's
+//                                           # doing (only when run with NOTIFY_SOCKET set)
+//     watchdog = true                       # ping systemd's watchdog (only with WatchdogSec=)
+//
+//     [logging]
+//     level = "info"                        # "error", "warn", "info", "debug", "trace" or "off",
+//                                           # optionally followed by levels for particular targets,
+//                                           # e.g. "info,teamech::delivery=debug,messages=off"
+//     quiet = false                         # only log warnings and errors
+//     format = "text"                       # "text" or "json" (one object per line)
+//     output = "stdout"                     # "stdout", "stderr", "syslog" or "journald"
+//     # socket = "/dev/log"                 # where syslog or the journal listens (default: the
+//                                           # usual place)
+//     timestamps = true                     # start each line with the time (not for syslog or
+//                                           # the journal, which add their own)
+//     show_hex = false                      # also log messages as hex bytes
+//
+//     [[handlers]]                          # canned replies to messages from the server
+//     match = "exact"                       # "exact", "prefix", "pattern" or "command"
+//     text = "Hello world!"
+//     reply = "Hello world!"
+
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr,SocketAddr};
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::LevelFilter;
+use regex::Regex;
+use toml;
+
+use clock::{ClockPolicy,TimestampValidator};
+use backoff::BackoffPolicy;
+use delivery::RetryPolicy;
+use failover::FailoverPolicy;
+use keepalive::KeepalivePolicy;
+use fragment::ReassemblyLimits;
+use replay::ReplayCache;
+use handler::{Router,Message};
+use logging::{LogFilter,LogFormat,LogOutput,Logger};
+use teacrypt::{PadMode,PadWindow};
+
+/// A problem with the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+	/// The configuration file could not be read.
+	Io(PathBuf,io::Error),
+	/// The configuration file is not valid TOML, or contains unknown or mistyped settings.
+	Parse(PathBuf,toml::de::Error),
+	/// An environment variable could not be parsed.
+	Env {
+		var:String,
+		value:String,
+	},
+	/// A setting has an invalid value.
+	Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ConfigError::Io(path,why) => write!(f,"could not read configuration file {}: {}",path.display(),why),
+			ConfigError::Parse(path,why) => write!(f,"error in configuration file {}: {}",path.display(),why),
+			ConfigError::Env { var, value } => write!(f,"could not parse environment variable {}='{}'",var,value),
+			ConfigError::Invalid(why) => write!(f,"invalid configuration: {}",why),
+		}
+	}
+}
+
+impl error::Error for ConfigError {}
+
+/// How the pad file is accessed, as written in the configuration file.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PadModeConfig {
+	File,
+	Memory,
+	Mapped,
+}
+
+impl FromStr for PadModeConfig {
+	type Err = ();
+	fn from_str(text:&str) -> Result<PadModeConfig,()> {
+		match text {
+			"file" => return Ok(PadModeConfig::File),
+			"memory" => return Ok(PadModeConfig::Memory),
+			"mapped" => return Ok(PadModeConfig::Mapped),
+			_ => return Err(()),
+		};
+	}
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct NetworkConfig {
+	pub bind:Option<IpAddr>,
+	pub port:u16,
+	pub dual_stack:bool,
+	pub dns_ttl_ms:u64,
+}
+
+impl Default for NetworkConfig {
+	fn default() -> NetworkConfig {
+		return NetworkConfig {
+			bind:None,
+			port:0,
+			dual_stack:false,
+			dns_ttl_ms:300_000,
+		};
+	}
+}
+
+impl NetworkConfig {
+	/// The local address to bind to for talking to a server.
+	pub fn local_address(&self,server:&SocketAddr) -> SocketAddr {
+		let ip:IpAddr = match self.bind {
+			Some(ip) => ip,
+			None if self.dual_stack || server.is_ipv6() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+			None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+		};
+		return SocketAddr::new(ip,self.port);
+	}
+
+	/// How long a lookup of the server names is good for, or None if they should only be looked up
+	/// again when a server stops answering.
+	pub fn dns_ttl(&self) -> Option<Duration> {
+		match self.dns_ttl_ms {
+			0 => return None,
+			ttl => return Some(Duration::from_millis(ttl)),
+		};
+	}
+
+	/// Whether a server can be reached from the local address: always, unless a bind address of
+	/// the other family (or an IPv6 one without dual-stack, for an IPv4 server) was given.
+	pub fn reaches(&self,server:&SocketAddr) -> bool {
+		match self.bind {
+			None => return true,
+			Some(IpAddr::V4(_)) => return server.is_ipv4(),
+			Some(IpAddr::V6(_)) => return server.is_ipv6() || self.dual_stack,
+		};
+	}
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct PadConfig {
+	pub path:Option<PathBuf>,
+	pub mode:PadModeConfig,
+	pub offset:u64,
+	pub size:Option<u64>,
+}
+
+impl Default for PadConfig {
+	fn default() -> PadConfig {
+		return PadConfig {
+			path:None,
+			mode:PadModeConfig::File,
+			offset:0,
+			size:None,
+		};
+	}
+}
+
+impl PadConfig {
+	pub fn mode(&self) -> PadMode {
+		match self.mode {
+			PadModeConfig::File => return PadMode::File,
+			PadModeConfig::Memory => return PadMode::Memory,
+			PadModeConfig::Mapped => return PadMode::Mapped,
+		};
+	}
+
+	pub fn window(&self) -> PadWindow {
+		return PadWindow {
+			offset:self.offset,
+			size:self.size,
+		};
+	}
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct ProtocolConfig {
+	pub timestamp_tolerance_ms:u64,
+	pub clock_skew:ClockPolicy,
+	pub dedup_window:usize,
+	pub replay_cache:Option<PathBuf>,
+	pub replay_capacity:usize,
+	pub replay_save_interval_ms:u64,
+	pub max_message_size:usize,
+	pub reassembly_timeout_ms:u64,
+	pub max_partial_messages:usize,
+}
+
+impl Default for ProtocolConfig {
+	fn default() -> ProtocolConfig {
+		return ProtocolConfig {
+			timestamp_tolerance_ms:10_000,
+			clock_skew:ClockPolicy::Estimate,
+			dedup_window:32,
+			replay_cache:None,
+			replay_capacity:65_536,
+			replay_save_interval_ms:1_000,
+			max_message_size:1 << 20,
+			reassembly_timeout_ms:10_000,
+			max_partial_messages:8,
+		};
+	}
+}
+
+impl ProtocolConfig {
+	pub fn timestamp_validator(&self) -> TimestampValidator {
+		return TimestampValidator::new(self.timestamp_tolerance_ms,self.clock_skew);
+	}
+
+	/// An empty replay cache. Nonces are remembered for twice the timestamp tolerance, to allow
+	/// for the estimate of the server's clock changing, or until the capacity is reached if
+	/// timestamps aren't checked at all.
+	pub fn replay_cache(&self) -> ReplayCache {
+		let window:Option<u64> = match self.clock_skew {
+			ClockPolicy::Ignore => None,
+			ClockPolicy::Strict | ClockPolicy::Estimate => Some(self.timestamp_tolerance_ms.saturating_mul(2)),
+		};
+		return ReplayCache::new(window,self.replay_capacity,self.replay_cache.clone());
+	}
+
+	pub fn reassembly_limits(&self) -> ReassemblyLimits {
+		return ReassemblyLimits {
+			max_message_size:self.max_message_size,
+			timeout:Duration::from_millis(self.reassembly_timeout_ms),
+			max_partial:self.max_partial_messages,
+		};
+	}
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct RetryConfig {
+	pub response_timeout_ms:u64,
+	pub error_delay_ms:u64,
+	pub max_backoff_ms:u64,
+	pub backoff_multiplier:u32,
+	pub backoff_jitter:f64,
+	pub retry_budget:u32,
+	pub validation_delay_ms:u64,
+	pub max_retransmit_interval_ms:u64,
+	pub delivery_deadline_ms:u64,
+	pub failover_attempts:u32,
+	pub failback_interval_ms:u64,
+}
+
+impl Default for RetryConfig {
+	fn default() -> RetryConfig {
+		return RetryConfig {
+			response_timeout_ms:1_000,
+			error_delay_ms:1_000,
+			max_backoff_ms:60_000,
+			backoff_multiplier:2,
+			backoff_jitter:0.5,
+			retry_budget:0,
+			validation_delay_ms:2_000,
+			max_retransmit_interval_ms:8_000,
+			delivery_deadline_ms:30_000,
+			failover_attempts:3,
+			failback_interval_ms:60_000,
+		};
+	}
+}
+
+impl RetryConfig {
+	/// How to resend reliable messages: starting at the response timeout, and doubling from there.
+	pub fn delivery_policy(&self) -> RetryPolicy {
+		return RetryPolicy {
+			initial_interval:Duration::from_millis(self.response_timeout_ms),
+			max_interval:Duration::from_millis(self.max_retransmit_interval_ms.max(self.response_timeout_ms)),
+			multiplier:2,
+			deadline:Duration::from_millis(self.delivery_deadline_ms),
+		};
+	}
+
+	/// How to wait between subscription attempts: starting at the error delay, and growing from
+	/// there.
+	pub fn backoff_policy(&self) -> BackoffPolicy {
+		let budget:Option<u32> = match self.retry_budget {
+			0 => None,
+			budget => Some(budget),
+		};
+		return BackoffPolicy {
+			initial:Duration::from_millis(self.error_delay_ms),
+			max:Duration::from_millis(self.max_backoff_ms.max(self.error_delay_ms)),
+			multiplier:self.backoff_multiplier,
+			jitter:self.backoff_jitter,
+			budget,
+		};
+	}
+
+	pub fn failover_policy(&self) -> FailoverPolicy {
+		let failback:Option<Duration> = match self.failback_interval_ms {
+			0 => None,
+			interval => Some(Duration::from_millis(interval)),
+		};
+		return FailoverPolicy {
+			attempts:self.failover_attempts,
+			failback_interval:failback,
+		};
+	}
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct KeepaliveConfig {
+	pub interval_ms:u64,
+	pub timeout_ms:u64,
+}
+
+impl Default for KeepaliveConfig {
+	fn default() -> KeepaliveConfig {
+		return KeepaliveConfig {
+			interval_ms:30_000,
+			timeout_ms:90_000,
+		};
+	}
+}
+
+impl KeepaliveConfig {
+	pub fn policy(&self) -> KeepalivePolicy {
+		let interval:Option<Duration> = match self.interval_ms {
+			0 => None,
+			interval => Some(Duration::from_millis(interval)),
+		};
+		return KeepalivePolicy {
+			interval,
+			timeout:Duration::from_millis(self.timeout_ms),
+		};
+	}
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct ShutdownConfig {
+	pub drain_timeout_ms:u64,
+	pub goodbye:bool,
+}
+
+impl Default for ShutdownConfig {
+	fn default() -> ShutdownConfig {
+		return ShutdownConfig {
+			drain_timeout_ms:5_000,
+			goodbye:true,
+		};
+	}
+}
+
+impl ShutdownConfig {
+	pub fn drain_timeout(&self) -> Duration {
+		return Duration::from_millis(self.drain_timeout_ms);
+	}
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct SystemdConfig {
+	pub notify:bool,
+	pub watchdog:bool,
+}
+
+impl Default for SystemdConfig {
+	fn default() -> SystemdConfig {
+		return SystemdConfig {
+			notify:true,
+			watchdog:true,
+		};
+	}
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct LoggingConfig {
+	pub level:LogFilter,
+	pub quiet:bool,
+	pub format:LogFormat,
+	pub output:LogOutput,
+	pub socket:Option<PathBuf>,
+	pub timestamps:bool,
+	pub show_hex:bool,
+}
+
+impl Default for LoggingConfig {
+	fn default() -> LoggingConfig {
+		return LoggingConfig {
+			level:LogFilter::default(),
+			quiet:false,
+			format:LogFormat::Text,
+			output:LogOutput::Stdout,
+			socket:None,
+			timestamps:true,
+			show_hex:false,
+		};
+	}
+}
+
+impl LoggingConfig {
+	/// A logger with these settings. Fails if syslog or the journal can't be reached.
+	pub fn logger(&self) -> Result<Logger,io::Error> {
+		let mut filter:LogFilter = self.level.clone();
+		if self.quiet {
+			filter.cap(LevelFilter::Warn);
+		}
+		return Logger::new(filter,self.format).timestamps(self.timestamps).output(self.output,self.socket.as_deref());
+	}
+}
+
+/// How a configured handler decides which messages it replies to.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+	Exact,
+	Prefix,
+	Pattern,
+	Command,
+}
+
+/// A canned reply to messages from the server, configured without writing any code.
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HandlerConfig {
+	#[serde(rename = "match")]
+	pub kind:MatchKind,
+	pub text:String,
+	pub reply:String,
+}
+
+/// The complete client configuration.
+#[derive(Debug,Clone,Default,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct Config {
+	pub server:Option<String>,
+	/// Servers to use when `server` doesn't answer, most preferred first.
+	pub backup_servers:Vec<String>,
+	pub network:NetworkConfig,
+	pub pad:PadConfig,
+	pub protocol:ProtocolConfig,
+	pub retry:RetryConfig,
+	pub keepalive:KeepaliveConfig,
+	pub shutdown:ShutdownConfig,
+	pub systemd:SystemdConfig,
+	pub logging:LoggingConfig,
+	pub handlers:Vec<HandlerConfig>,
+}
+
+// Reads an environment variable and parses it, if it's set.
+fn envvar<T:FromStr>(var:&str) -> Result<Option<T>,ConfigError> {
+	match env::var(var) {
+		Ok(value) => match value.parse::<T>() {
+			Ok(parsed) => return Ok(Some(parsed)),
+			Err(_) => {
+				return Err(ConfigError::Env {
+					var:var.to_owned(),
+					value,
+				});
+			},
+		},
+		Err(_) => return Ok(None),
+	};
+}
+
+impl Config {
+	/// Reads a configuration file. Settings it doesn't mention keep their default values.
+	pub fn load(path:&Path) -> Result<Config,ConfigError> {
+		let text:String = match fs::read_to_string(path) {
+			Ok(text) => text,
+			Err(why) => return Err(ConfigError::Io(path.to_path_buf(),why)),
+		};
+		match toml::from_str::<Config>(&text) {
+			Ok(config) => return Ok(config),
+			Err(why) => return Err(ConfigError::Parse(path.to_path_buf(),why)),
+		};
+	}
+
+	/// Applies overrides from TEAMECH_* environment variables: TEAMECH_SERVER,
+	/// TEAMECH_BACKUP_SERVERS (separated by commas), TEAMECH_BIND, TEAMECH_PORT,
+	/// TEAMECH_DUAL_STACK, TEAMECH_DNS_TTL_MS, TEAMECH_PAD, TEAMECH_PAD_MODE, TEAMECH_PAD_OFFSET,
+	/// TEAMECH_PAD_SIZE, TEAMECH_TIMESTAMP_TOLERANCE_MS, TEAMECH_CLOCK_SKEW, TEAMECH_DEDUP_WINDOW,
+	/// TEAMECH_REPLAY_CACHE, TEAMECH_REPLAY_CAPACITY, TEAMECH_REPLAY_SAVE_INTERVAL_MS,
+	/// TEAMECH_MAX_MESSAGE_SIZE, TEAMECH_REASSEMBLY_TIMEOUT_MS, TEAMECH_MAX_PARTIAL_MESSAGES,
+	/// TEAMECH_RESPONSE_TIMEOUT_MS, TEAMECH_ERROR_DELAY_MS, TEAMECH_MAX_BACKOFF_MS,
+	/// TEAMECH_BACKOFF_MULTIPLIER, TEAMECH_BACKOFF_JITTER, TEAMECH_RETRY_BUDGET,
+	/// TEAMECH_VALIDATION_DELAY_MS, TEAMECH_MAX_RETRANSMIT_INTERVAL_MS,
+	/// TEAMECH_DELIVERY_DEADLINE_MS, TEAMECH_FAILOVER_ATTEMPTS, TEAMECH_FAILBACK_INTERVAL_MS,
+	/// TEAMECH_KEEPALIVE_INTERVAL_MS, TEAMECH_KEEPALIVE_TIMEOUT_MS, TEAMECH_DRAIN_TIMEOUT_MS,
+	/// TEAMECH_GOODBYE, TEAMECH_SD_NOTIFY, TEAMECH_SD_WATCHDOG, TEAMECH_LOG_LEVEL, TEAMECH_QUIET,
+	/// TEAMECH_LOG_FORMAT, TEAMECH_LOG_OUTPUT, TEAMECH_LOG_SOCKET, TEAMECH_LOG_TIMESTAMPS and
+	/// TEAMECH_SHOW_HEX.
+	pub fn apply_env(&mut self) -> Result<(),ConfigError> {
+		if let Some(server) = envvar::<String>("TEAMECH_SERVER")? {
+			self.server = Some(server);
+		}
+		if let Some(servers) = envvar::<String>("TEAMECH_BACKUP_SERVERS")? {
+			self.backup_servers = servers.split(',')
+				.map(|server| server.trim().to_owned())
+				.filter(|server| !server.is_empty())
+				.collect();
+		}
+		if let Some(bind) = envvar::<IpAddr>("TEAMECH_BIND")? {
+			self.network.bind = Some(bind);
+		}
+		if let Some(port) = envvar::<u16>("TEAMECH_PORT")? {
+			self.network.port = port;
+		}
+		if let Some(dualstack) = envvar::<bool>("TEAMECH_DUAL_STACK")? {
+			self.network.dual_stack = dualstack;
+		}
+		if let Some(ttl) = envvar::<u64>("TEAMECH_DNS_TTL_MS")? {
+			self.network.dns_ttl_ms = ttl;
+		}
+		if let Some(path) = envvar::<PathBuf>("TEAMECH_PAD")? {
+			self.pad.path = Some(path);
+		}
+		if let Some(mode) = envvar::<PadModeConfig>("TEAMECH_PAD_MODE")? {
+			self.pad.mode = mode;
+		}
+		if let Some(offset) = envvar::<u64>("TEAMECH_PAD_OFFSET")? {
+			self.pad.offset = offset;
+		}
+		if let Some(size) = envvar::<u64>("TEAMECH_PAD_SIZE")? {
+			self.pad.size = Some(size);
+		}
+		if let Some(tolerance) = envvar::<u64>("TEAMECH_TIMESTAMP_TOLERANCE_MS")? {
+			self.protocol.timestamp_tolerance_ms = tolerance;
+		}
+		if let Some(policy) = envvar::<ClockPolicy>("TEAMECH_CLOCK_SKEW")? {
+			self.protocol.clock_skew = policy;
+		}
+		if let Some(window) = envvar::<usize>("TEAMECH_DEDUP_WINDOW")? {
+			self.protocol.dedup_window = window;
+		}
+		if let Some(path) = envvar::<PathBuf>("TEAMECH_REPLAY_CACHE")? {
+			self.protocol.replay_cache = Some(path);
+		}
+		if let Some(capacity) = envvar::<usize>("TEAMECH_REPLAY_CAPACITY")? {
+			self.protocol.replay_capacity = capacity;
+		}
+		if let Some(interval) = envvar::<u64>("TEAMECH_REPLAY_SAVE_INTERVAL_MS")? {
+			self.protocol.replay_save_interval_ms = interval;
+		}
+		if let Some(size) = envvar::<usize>("TEAMECH_MAX_MESSAGE_SIZE")? {
+			self.protocol.max_message_size = size;
+		}
+		if let Some(timeout) = envvar::<u64>("TEAMECH_REASSEMBLY_TIMEOUT_MS")? {
+			self.protocol.reassembly_timeout_ms = timeout;
+		}
+		if let Some(count) = envvar::<usize>("TEAMECH_MAX_PARTIAL_MESSAGES")? {
+			self.protocol.max_partial_messages = count;
+		}
+		if let Some(timeout) = envvar::<u64>("TEAMECH_RESPONSE_TIMEOUT_MS")? {
+			self.retry.response_timeout_ms = timeout;
+		}
+		if let Some(delay) = envvar::<u64>("TEAMECH_ERROR_DELAY_MS")? {
+			self.retry.error_delay_ms = delay;
+		}
+		if let Some(delay) = envvar::<u64>("TEAMECH_MAX_BACKOFF_MS")? {
+			self.retry.max_backoff_ms = delay;
+		}
+		if let Some(multiplier) = envvar::<u32>("TEAMECH_BACKOFF_MULTIPLIER")? {
+			self.retry.backoff_multiplier = multiplier;
+		}
+		if let Some(jitter) = envvar::<f64>("TEAMECH_BACKOFF_JITTER")? {
+			self.retry.backoff_jitter = jitter;
+		}
+		if let Some(budget) = envvar::<u32>("TEAMECH_RETRY_BUDGET")? {
+			self.retry.retry_budget = budget;
+		}
+		if let Some(delay) = envvar::<u64>("TEAMECH_VALIDATION_DELAY_MS")? {
+			self.retry.validation_delay_ms = delay;
+		}
+		if let Some(interval) = envvar::<u64>("TEAMECH_MAX_RETRANSMIT_INTERVAL_MS")? {
+			self.retry.max_retransmit_interval_ms = interval;
+		}
+		if let Some(deadline) = envvar::<u64>("TEAMECH_DELIVERY_DEADLINE_MS")? {
+			self.retry.delivery_deadline_ms = deadline;
+		}
+		if let Some(attempts) = envvar::<u32>("TEAMECH_FAILOVER_ATTEMPTS")? {
+			self.retry.failover_attempts = attempts;
+		}
+		if let Some(interval) = envvar::<u64>("TEAMECH_FAILBACK_INTERVAL_MS")? {
+			self.retry.failback_interval_ms = interval;
+		}
+		if let Some(interval) = envvar::<u64>("TEAMECH_KEEPALIVE_INTERVAL_MS")? {
+			self.keepalive.interval_ms = interval;
+		}
+		if let Some(timeout) = envvar::<u64>("TEAMECH_KEEPALIVE_TIMEOUT_MS")? {
+			self.keepalive.timeout_ms = timeout;
+		}
+		if let Some(timeout) = envvar::<u64>("TEAMECH_DRAIN_TIMEOUT_MS")? {
+			self.shutdown.drain_timeout_ms = timeout;
+		}
+		if let Some(goodbye) = envvar::<bool>("TEAMECH_GOODBYE")? {
+			self.shutdown.goodbye = goodbye;
+		}
+		if let Some(notify) = envvar::<bool>("TEAMECH_SD_NOTIFY")? {
+			self.systemd.notify = notify;
+		}
+		if let Some(watchdog) = envvar::<bool>("TEAMECH_SD_WATCHDOG")? {
+			self.systemd.watchdog = watchdog;
+		}
+		if let Some(level) = envvar::<LogFilter>("TEAMECH_LOG_LEVEL")? {
+			self.logging.level = level;
+		}
+		if let Some(quiet) = envvar::<bool>("TEAMECH_QUIET")? {
+			self.logging.quiet = quiet;
+		}
+		if let Some(format) = envvar::<LogFormat>("TEAMECH_LOG_FORMAT")? {
+			self.logging.format = format;
+		}
+		if let Some(output) = envvar::<LogOutput>("TEAMECH_LOG_OUTPUT")? {
+			self.logging.output = output;
+		}
+		if let Some(socket) = envvar::<PathBuf>("TEAMECH_LOG_SOCKET")? {
+			self.logging.socket = Some(socket);
+		}
+		if let Some(timestamps) = envvar::<bool>("TEAMECH_LOG_TIMESTAMPS")? {
+			self.logging.timestamps = timestamps;
+		}
+		if let Some(showhex) = envvar::<bool>("TEAMECH_SHOW_HEX")? {
+			self.logging.show_hex = showhex;
+		}
+		return Ok(());
+	}
+
+	/// Checks that the configuration is complete and makes sense.
+	pub fn validate(&self) -> Result<(),ConfigError> {
+		match self.server {
+			None => return Err(ConfigError::Invalid(String::from("no server address was given"))),
+			Some(ref server) if server.trim().is_empty() => {
+				return Err(ConfigError::Invalid(String::from("server address is empty")));
+			},
+			_ => (),
+		};
+		self.validate_pad()?;
+		if self.network.dual_stack && self.network.bind.is_some_and(|ip| ip.is_ipv4()) {
+			return Err(ConfigError::Invalid(String::from("network.dual_stack needs an IPv6 bind address")));
+		}
+		if self.protocol.timestamp_tolerance_ms == 0 {
+			return Err(ConfigError::Invalid(String::from("protocol.timestamp_tolerance_ms must be greater than zero")));
+		}
+		if self.protocol.dedup_window == 0 {
+			return Err(ConfigError::Invalid(String::from("protocol.dedup_window must be greater than zero")));
+		}
+		if self.protocol.replay_capacity == 0 {
+			return Err(ConfigError::Invalid(String::from("protocol.replay_capacity must be greater than zero")));
+		}
+		if self.protocol.max_message_size == 0 {
+			return Err(ConfigError::Invalid(String::from("protocol.max_message_size must be greater than zero")));
+		}
+		if self.protocol.reassembly_timeout_ms == 0 {
+			return Err(ConfigError::Invalid(String::from("protocol.reassembly_timeout_ms must be greater than zero")));
+		}
+		if self.protocol.max_partial_messages == 0 {
+			return Err(ConfigError::Invalid(String::from("protocol.max_partial_messages must be greater than zero")));
+		}
+		if self.retry.response_timeout_ms == 0 {
+			return Err(ConfigError::Invalid(String::from("retry.response_timeout_ms must be greater than zero")));
+		}
+		if self.retry.error_delay_ms == 0 {
+			return Err(ConfigError::Invalid(String::from("retry.error_delay_ms must be greater than zero")));
+		}
+		if self.retry.backoff_multiplier == 0 {
+			return Err(ConfigError::Invalid(String::from("retry.backoff_multiplier must be greater than zero")));
+		}
+		if !(0.0..=1.0).contains(&self.retry.backoff_jitter) {
+			return Err(ConfigError::Invalid(String::from("retry.backoff_jitter must be between 0 and 1")));
+		}
+		if self.retry.delivery_deadline_ms == 0 {
+			return Err(ConfigError::Invalid(String::from("retry.delivery_deadline_ms must be greater than zero")));
+		}
+		if self.retry.failover_attempts == 0 {
+			return Err(ConfigError::Invalid(String::from("retry.failover_attempts must be greater than zero")));
+		}
+		if self.keepalive.interval_ms > 0 && self.keepalive.timeout_ms <= self.keepalive.interval_ms {
+			return Err(ConfigError::Invalid(String::from("keepalive.timeout_ms must be longer than keepalive.interval_ms")));
+		}
+		if self.backup_servers.iter().any(|server| server.trim().is_empty()) {
+			return Err(ConfigError::Invalid(String::from("backup server address is empty")));
+		}
+		if self.logging.socket.is_some() && self.logging.output != LogOutput::Syslog && self.logging.output != LogOutput::Journald {
+			return Err(ConfigError::Invalid(String::from("logging.socket only applies to syslog and journald output")));
+		}
+		for (n,handler) in self.handlers.iter().enumerate() {
+			if handler.kind == MatchKind::Pattern {
+				if let Err(why) = Regex::new(&handler.text) {
+					return Err(ConfigError::Invalid(format!("handler #{} has an invalid pattern: {}",n+1,why)));
+				}
+			}
+		}
+		return Ok(());
+	}
+
+	/// Checks only the pad settings, for tools that use the pad without talking to a server.
+	pub fn validate_pad(&self) -> Result<(),ConfigError> {
+		match self.pad.path {
+			None => return Err(ConfigError::Invalid(String::from("no pad file was given"))),
+			Some(ref path) if path.as_os_str().is_empty() => {
+				return Err(ConfigError::Invalid(String::from("pad file path is empty")));
+			},
+			_ => (),
+		};
+		if self.pad.size == Some(0) {
+			return Err(ConfigError::Invalid(String::from("pad size must be greater than zero")));
+		}
+		return Ok(());
+	}
+
+	/// Registers the configured canned replies with a router.
+	pub fn register_handlers(&self,router:&mut Router) -> Result<(),ConfigError> {
+		for (n,handler) in self.handlers.iter().enumerate() {
+			let reply:Vec<u8> = handler.reply.as_bytes().to_vec();
+			let respond = move |_message:&Message| Some(reply.clone());
+			match handler.kind {
+				MatchKind::Exact => {
+					router.exact(&handler.text,respond);
+				},
+				MatchKind::Prefix => {
+					router.prefix(&handler.text,respond);
+				},
+				MatchKind::Command => {
+					router.command(&handler.text,respond);
+				},
+				MatchKind::Pattern => {
+					if let Err(why) = router.pattern(&handler.text,respond) {
+						return Err(ConfigError::Invalid(format!("handler #{} has an invalid pattern: {}",n+1,why)));
+					}
+				},
+			};
+		}
+		return Ok(());
+	}
+}
@@ -0,0 +1,137 @@
+// The lifecycle of the client's connection to the server.
+//
+// The client is always in one of a handful of states: it has no socket, it's asking a server for
+// a subscription, it's subscribed and handling messages, it's waiting a while after something went
+// wrong, or it's shutting down. What it does next depends only on the state it's in, and what
+// happened while it was doing it, so the states and the rules for moving between them live here,
+// apart from the code that does the work. A Connection follows the rules and tells any registered
+// callbacks about every change of state, e.g. so that a device can light an LED while subscribed.
+
+use std::fmt;
+
+/// Where the client is in its connection to the server.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum ConnectionState {
+	/// No socket is bound, e.g. at startup, or after the server in use has changed.
+	Unbound,
+	/// A subscription request has been (or is about to be) sent, and not yet granted.
+	Subscribing,
+	/// The server has granted a subscription, and messages are being handled.
+	Subscribed,
+	/// A subscription request went unanswered, or something went wrong; the client is waiting before
+	/// it asks for a subscription again.
+	Backoff,
+	/// The client is stopping. Nothing leads out of this state.
+	Shutdown,
+}
+
+impl fmt::Display for ConnectionState {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		let name:&str = match *self {
+			ConnectionState::Unbound => "unbound",
+			ConnectionState::Subscribing => "subscribing",
+			ConnectionState::Subscribed => "subscribed",
+			ConnectionState::Backoff => "backing off",
+			ConnectionState::Shutdown => "shut down",
+		};
+		return write!(f,"{}",name);
+	}
+}
+
+/// Something that happened to the connection.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum ConnectionEvent {
+	/// A socket was bound.
+	Bound,
+	/// The server granted a subscription.
+	Granted,
+	/// The server didn't answer a subscription request in time.
+	Unanswered,
+	/// Something went wrong: sending or receiving failed, the server refused a subscription, or a
+	/// message failed to validate.
+	Failed,
+	/// The wait after a failure is over.
+	Retry,
+	/// The socket is no longer any use: the server ended the subscription or went silent, or a
+	/// different server (which may need a socket of the other address family) is to be used.
+	Reset,
+	/// The client has been asked to stop.
+	Shutdown,
+}
+
+/// The state that `event` leads to from `state`, or None if the event can't happen in that state.
+pub fn transition(state:ConnectionState,event:ConnectionEvent) -> Option<ConnectionState> {
+	match (state,event) {
+		(ConnectionState::Shutdown,_) => return None,
+		(_,ConnectionEvent::Shutdown) => return Some(ConnectionState::Shutdown),
+		(ConnectionState::Unbound,ConnectionEvent::Bound) => return Some(ConnectionState::Subscribing),
+		(ConnectionState::Subscribing,ConnectionEvent::Granted) => return Some(ConnectionState::Subscribed),
+		(ConnectionState::Subscribing,ConnectionEvent::Unanswered) => return Some(ConnectionState::Backoff),
+		(ConnectionState::Subscribing,ConnectionEvent::Failed) => return Some(ConnectionState::Backoff),
+		(ConnectionState::Subscribed,ConnectionEvent::Failed) => return Some(ConnectionState::Backoff),
+		(ConnectionState::Backoff,ConnectionEvent::Retry) => return Some(ConnectionState::Subscribing),
+		(ConnectionState::Subscribing,ConnectionEvent::Reset) => return Some(ConnectionState::Unbound),
+		(ConnectionState::Subscribed,ConnectionEvent::Reset) => return Some(ConnectionState::Unbound),
+		(ConnectionState::Backoff,ConnectionEvent::Reset) => return Some(ConnectionState::Unbound),
+		_ => return None,
+	};
+}
+
+/// A change of state, as reported to callbacks.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Change {
+	pub from:ConnectionState,
+	pub to:ConnectionState,
+	/// What caused the change.
+	pub event:ConnectionEvent,
+}
+
+// Something to run when the state changes.
+type Callback = Box<dyn FnMut(&Change)>;
+
+/// The state of the connection, and the callbacks to tell when it changes.
+pub struct Connection {
+	state:ConnectionState,
+	callbacks:Vec<Callback>,
+}
+
+impl Default for Connection {
+	fn default() -> Connection {
+		return Connection::new();
+	}
+}
+
+impl Connection {
+	/// Starts out Unbound.
+	pub fn new() -> Connection {
+		return Connection {
+			state:ConnectionState::Unbound,
+			callbacks:Vec::new(),
+		};
+	}
+
+	pub fn state(&self) -> ConnectionState {
+		return self.state;
+	}
+
+	/// Registers a callback to be run, in the order registered, whenever the state changes.
+	pub fn on_change<F>(&mut self,callback:F) where F:FnMut(&Change) + 'static {
+		self.callbacks.push(Box::new(callback));
+	}
+
+	/// Moves to the state that `event` leads to, and returns it. Returns None, and stays in the
+	/// same state, if the event can't happen in the current state.
+	pub fn handle(&mut self,event:ConnectionEvent) -> Option<ConnectionState> {
+		let next:ConnectionState = transition(self.state,event)?;
+		let change:Change = Change {
+			from:self.state,
+			to:next,
+			event,
+		};
+		self.state = next;
+		for callback in self.callbacks.iter_mut() {
+			callback(&change);
+		}
+		return Some(next);
+	}
+}
@@ -0,0 +1,79 @@
+// Duplicate detection.
+//
+// The server sometimes sends the same payload twice (and so can anyone who captures one), so the
+// client drops any payload it has already received recently, before spending time decrypting it.
+// This never drops a message the server meant to send again: every message is encrypted with a new
+// random nonce, so the same content sent twice makes two different payloads.
+//
+// Payloads are identified by a SHA3 hash of the whole datagram, rather than by any part of it,
+// because this check comes before the signature is verified: keyed on the nonce alone, a forged or
+// corrupted datagram ending in the same bytes as a real one would get the real one dropped. The
+// DuplicateFilter keeps the most recent hashes in a ring buffer, for eviction in arrival order, and
+// a hash set, for lookup, so checking a payload takes the same time however many are remembered.
+
+use std::collections::{HashSet,VecDeque};
+
+use tiny_keccak::Keccak;
+
+// What a payload is remembered by: the first half of its hash, which is plenty to tell apart the
+// payloads a filter holds.
+type Key = [u8;16];
+
+/// The most recently received payloads, for spotting repeats.
+pub struct DuplicateFilter {
+	capacity:usize,
+	// Oldest first.
+	order:VecDeque<Key>,
+	seen:HashSet<Key>,
+}
+
+impl DuplicateFilter {
+	/// Creates a filter that remembers the last `capacity` payloads.
+	pub fn new(capacity:usize) -> DuplicateFilter {
+		let capacity:usize = capacity.max(1);
+		return DuplicateFilter {
+			capacity,
+			order:VecDeque::with_capacity(capacity),
+			seen:HashSet::with_capacity(capacity),
+		};
+	}
+
+	/// Records a payload. Returns false if it's one of the last `capacity` payloads recorded.
+	pub fn admit(&mut self,payload:&[u8]) -> bool {
+		let key:Key = key(payload);
+		if !self.seen.insert(key) {
+			return false;
+		}
+		if self.order.len() == self.capacity {
+			if let Some(oldest) = self.order.pop_front() {
+				self.seen.remove(&oldest);
+			}
+		}
+		self.order.push_back(key);
+		return true;
+	}
+
+	/// How many payloads are remembered.
+	pub fn len(&self) -> usize {
+		return self.order.len();
+	}
+
+	pub fn is_empty(&self) -> bool {
+		return self.order.is_empty();
+	}
+
+	pub fn capacity(&self) -> usize {
+		return self.capacity;
+	}
+}
+
+// Hashes a whole payload.
+fn key(payload:&[u8]) -> Key {
+	let mut hash:[u8;32] = [0;32];
+	let mut sha3 = Keccak::new_sha3_256();
+	sha3.update(payload);
+	sha3.finalize(&mut hash);
+	let mut key:Key = [0;16];
+	key.copy_from_slice(&hash[..16]);
+	return key;
+}
@@ -0,0 +1,273 @@
+// Reliable delivery of messages to the server.
+//
+// Teamech runs over UDP, so any datagram can be lost on its way to the server, and nothing in
+// sendbytes() notices. The server acknowledges every message it receives with an Ack status code,
+// so messages that matter (e.g. device reports) can instead be queued in a DeliveryQueue, which
+// resends them until they're acknowledged or their deadline passes, waiting longer after each
+// attempt.
+//
+// Acknowledgements don't say which message they're for, so reliable messages are sent one at a
+// time: an Ack is credited to the message at the front of the queue, and the next one isn't sent
+// until that one has been delivered or given up on. A message sent in fragments needs one Ack for
+// each of them. Unreliable messages are acknowledged too, so the queue is told about each one
+// sent (see sent_unreliable()), and the Acks owed to them are used up first. An Ack that could
+// belong to either is never credited to the reliable message: at worst, a message that was
+// delivered is resent, or reported as timed out, but one that wasn't is never reported delivered.
+// Acks owed to unreliable messages are written off once none has been sent for the longest wait
+// between attempts, by when any that are still missing were lost.
+//
+// Every attempt is encrypted afresh, because the server discards exact copies of payloads it has
+// already seen (without acknowledging them). This means that if only the acknowledgement was lost,
+// the server will see the message twice: delivery is at-least-once.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::time::{Duration,Instant};
+
+use fragment;
+use net::Sender;
+
+/// How persistently a DeliveryQueue resends messages.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct RetryPolicy {
+	/// How long to wait for an acknowledgement after the first attempt.
+	pub initial_interval:Duration,
+	/// The most the wait between attempts can grow to.
+	pub max_interval:Duration,
+	/// What the wait is multiplied by after each attempt.
+	pub multiplier:u32,
+	/// How long after it was queued to give up on a message.
+	pub deadline:Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> RetryPolicy {
+		return RetryPolicy {
+			initial_interval:Duration::from_millis(1_000),
+			max_interval:Duration::from_millis(8_000),
+			multiplier:2,
+			deadline:Duration::from_millis(30_000),
+		};
+	}
+}
+
+/// Identifies a message queued for reliable delivery.
+pub type DeliveryId = u64;
+
+/// How a reliable message's delivery turned out.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Outcome {
+	/// The server acknowledged the message.
+	Delivered,
+	/// The deadline passed without an acknowledgement.
+	TimedOut,
+}
+
+/// The result of trying to deliver a message.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Delivery {
+	pub id:DeliveryId,
+	pub outcome:Outcome,
+	/// How many times the message was sent.
+	pub attempts:u32,
+	/// How long it took from being queued to being delivered or given up on.
+	pub elapsed:Duration,
+}
+
+impl Delivery {
+	pub fn delivered(&self) -> bool {
+		return self.outcome == Outcome::Delivered;
+	}
+}
+
+/// Lets a thread that queued a message through an Outbox find out whether it was delivered.
+pub struct Receipt {
+	result:mpsc::Receiver<Delivery>,
+}
+
+impl Receipt {
+	pub fn new(result:mpsc::Receiver<Delivery>) -> Receipt {
+		return Receipt {
+			result,
+		};
+	}
+
+	/// Blocks until the message has been delivered or given up on. Returns None if the client
+	/// shut down first.
+	pub fn wait(&self) -> Option<Delivery> {
+		return self.result.recv().ok();
+	}
+
+	/// Returns the result if it's available yet, without blocking.
+	pub fn check(&self) -> Option<Delivery> {
+		return self.result.try_recv().ok();
+	}
+}
+
+// A message waiting to be delivered.
+struct Pending {
+	id:DeliveryId,
+	bytes:Vec<u8>,
+	queued:Instant,
+	attempts:u32,
+	// How many more acknowledgements the last attempt needs.
+	awaiting:usize,
+	// When to send it next (or for the first time).
+	nextsend:Instant,
+	// How long to wait for an acknowledgement after the next attempt.
+	interval:Duration,
+	notify:Option<mpsc::Sender<Delivery>>,
+}
+
+/// Messages waiting for reliable delivery to the server.
+pub struct DeliveryQueue {
+	policy:RetryPolicy,
+	pending:VecDeque<Pending>,
+	nextid:DeliveryId,
+	// Acknowledgements still owed to unreliable messages, and when the last of those was sent.
+	unreliable:usize,
+	unreliablesent:Option<Instant>,
+}
+
+impl DeliveryQueue {
+	pub fn new(policy:RetryPolicy) -> DeliveryQueue {
+		return DeliveryQueue {
+			policy,
+			pending:VecDeque::new(),
+			nextid:0,
+			unreliable:0,
+			unreliablesent:None,
+		};
+	}
+
+	/// Queues a message for delivery. It will be sent the next time poll() is called, unless
+	/// other messages are ahead of it.
+	pub fn push(&mut self,bytes:Vec<u8>) -> DeliveryId {
+		return self.enqueue(bytes,None);
+	}
+
+	/// Queues a message for delivery, and sends its result to `notify` as well as returning it
+	/// from poll() or acknowledge().
+	pub fn push_notify(&mut self,bytes:Vec<u8>,notify:mpsc::Sender<Delivery>) -> DeliveryId {
+		return self.enqueue(bytes,Some(notify));
+	}
+
+	fn enqueue(&mut self,bytes:Vec<u8>,notify:Option<mpsc::Sender<Delivery>>) -> DeliveryId {
+		let id:DeliveryId = self.nextid;
+		self.nextid += 1;
+		let now:Instant = Instant::now();
+		self.pending.push_back(Pending {
+			id,
+			bytes,
+			queued:now,
+			attempts:0,
+			awaiting:0,
+			nextsend:now,
+			interval:self.policy.initial_interval,
+			notify,
+		});
+		return id;
+	}
+
+	/// How many messages are waiting to be delivered, including the one in flight.
+	pub fn len(&self) -> usize {
+		return self.pending.len();
+	}
+
+	pub fn is_empty(&self) -> bool {
+		return self.pending.is_empty();
+	}
+
+	/// When poll() next needs to be called, if there's anything waiting.
+	pub fn next_deadline(&self) -> Option<Instant> {
+		return self.pending.front().map(|pending| pending.nextsend);
+	}
+
+	/// Notes that `packets` packets were sent to the server outside the queue, so that the
+	/// acknowledgements the server sends for them aren't credited to a reliable message.
+	pub fn sent_unreliable(&mut self,packets:usize) {
+		if packets == 0 {
+			return;
+		}
+		self.unreliable = self.unreliable.saturating_add(packets);
+		self.unreliablesent = Some(Instant::now());
+	}
+
+	/// Credits an acknowledgement from the server to the message in flight, if there is one and
+	/// the acknowledgement can't be for an unreliable message. Returns the delivery once the
+	/// message has been completely acknowledged.
+	pub fn acknowledge(&mut self) -> Option<Delivery> {
+		if let Some(sent) = self.unreliablesent {
+			if sent.elapsed() > self.policy.max_interval {
+				self.unreliable = 0;
+				self.unreliablesent = None;
+			}
+		}
+		if self.unreliable > 0 {
+			self.unreliable -= 1;
+			return None;
+		}
+		match self.pending.front_mut() {
+			Some(pending) if pending.attempts > 0 => {
+				pending.awaiting = pending.awaiting.saturating_sub(1);
+				if pending.awaiting > 0 {
+					return None;
+				}
+			},
+			_ => return None,
+		};
+		let pending:Pending = self.pending.pop_front()?;
+		return Some(finish(pending,Outcome::Delivered));
+	}
+
+	/// Gives up on messages whose deadlines have passed, and sends (or resends) the message at the
+	/// front of the queue if it's due. Returns the messages that were given up on.
+	pub fn poll(&mut self,sender:&Sender) -> Vec<Delivery> {
+		let mut finished:Vec<Delivery> = Vec::new();
+		let now:Instant = Instant::now();
+		while let Some(pending) = self.pending.pop_front() {
+			if now.duration_since(pending.queued) >= self.policy.deadline {
+				finished.push(finish(pending,Outcome::TimedOut));
+				continue;
+			}
+			self.pending.push_front(pending);
+			break;
+		}
+		let policy:RetryPolicy = self.policy;
+		if let Some(pending) = self.pending.front_mut() {
+			if pending.nextsend <= now {
+				// A failed send counts as an attempt; it'll be retried like a lost one.
+				if let Err(why) = sender.send(&pending.bytes) {
+					warn!("Sending message failed - {}",why);
+				}
+				pending.attempts += 1;
+				pending.awaiting = fragment::count(&pending.bytes);
+				// Don't wait past the deadline, so that it's noticed on time. (Either can be too far
+				// off for an Instant to hold, if the policy is unlimited.)
+				pending.nextsend = [now.checked_add(pending.interval),pending.queued.checked_add(policy.deadline)]
+					.iter().flatten().min().cloned().unwrap_or(now);
+				pending.interval = match pending.interval.checked_mul(policy.multiplier) {
+					Some(interval) => cmp::min(interval,policy.max_interval),
+					None => policy.max_interval,
+				};
+			}
+		}
+		return finished;
+	}
+}
+
+// Reports the result of a delivery to whoever is waiting for it.
+fn finish(pending:Pending,outcome:Outcome) -> Delivery {
+	let delivery:Delivery = Delivery {
+		id:pending.id,
+		outcome,
+		attempts:pending.attempts,
+		elapsed:pending.queued.elapsed(),
+	};
+	if let Some(notify) = pending.notify {
+		// Nobody may be waiting any more, which is fine.
+		let _ = notify.send(delivery);
+	}
+	return delivery;
+}
@@ -0,0 +1,170 @@
+// Choosing between several servers.
+//
+// A client can be given more than one server: every address its server's name resolves to, and
+// any backup servers, in order of preference. It subscribes to the most preferred one that
+// answers. When the server it's using stops answering, it fails over to the next one in the list
+// (going back to the start after the last), and while it's using anything but its most preferred
+// server, it periodically asks the servers it prefers for a subscription, and fails back to the
+// first one that grants it.
+
+use std::net::SocketAddr;
+use std::time::{Duration,Instant};
+
+/// When to fail over, and how often to try failing back.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct FailoverPolicy {
+	/// How many subscription requests in a row a server can leave unanswered before the next
+	/// server is tried.
+	pub attempts:u32,
+	/// How often to ask more preferred servers whether they're back, or None never to fail back.
+	pub failback_interval:Option<Duration>,
+}
+
+impl Default for FailoverPolicy {
+	fn default() -> FailoverPolicy {
+		return FailoverPolicy {
+			attempts:3,
+			failback_interval:Some(Duration::from_millis(60_000)),
+		};
+	}
+}
+
+/// A change of server.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Switch {
+	pub from:SocketAddr,
+	pub to:SocketAddr,
+}
+
+/// The servers a client can use, and which of them it's using.
+pub struct Failover {
+	// Most preferred first.
+	servers:Vec<SocketAddr>,
+	current:usize,
+	policy:FailoverPolicy,
+	// Unanswered subscription requests to the current server, in a row.
+	failures:u32,
+	nextprobe:Option<Instant>,
+}
+
+impl Failover {
+	/// Starts with the first (most preferred) of `servers`. Returns None if there aren't any.
+	pub fn new(servers:Vec<SocketAddr>,policy:FailoverPolicy) -> Option<Failover> {
+		if servers.is_empty() {
+			return None;
+		}
+		return Some(Failover {
+			servers,
+			current:0,
+			policy,
+			failures:0,
+			nextprobe:None,
+		});
+	}
+
+	pub fn servers(&self) -> &[SocketAddr] {
+		return &self.servers;
+	}
+
+	/// The server in use.
+	pub fn current(&self) -> SocketAddr {
+		return self.servers[self.current];
+	}
+
+	/// Whether the server in use is the most preferred one.
+	pub fn on_primary(&self) -> bool {
+		return self.current == 0;
+	}
+
+	/// Records that the current server granted a subscription.
+	pub fn subscribed(&mut self) {
+		self.failures = 0;
+		self.nextprobe = match (self.current,self.policy.failback_interval) {
+			(0,_) | (_,None) => None,
+			(_,Some(interval)) => Some(Instant::now() + interval),
+		};
+	}
+
+	/// Records that the current server didn't answer a subscription request (or refused it).
+	/// Returns true once it has used up its attempts, and it's time to fail_over().
+	pub fn unanswered(&mut self) -> bool {
+		self.failures += 1;
+		return self.failures >= self.policy.attempts.max(1);
+	}
+
+	/// Moves on to the next server straight away, e.g. because the current one has stopped
+	/// answering after granting a subscription. Returns None if there's only one server.
+	pub fn fail_over(&mut self) -> Option<Switch> {
+		self.failures = 0;
+		self.nextprobe = None;
+		if self.servers.len() < 2 {
+			return None;
+		}
+		let from:SocketAddr = self.current();
+		self.current = (self.current+1)%self.servers.len();
+		return Some(Switch {
+			from,
+			to:self.current(),
+		});
+	}
+
+	/// Replaces the list of servers, e.g. after looking their names up again. The current server
+	/// stays in use if it's still on the list; otherwise the most preferred one is used. Returns
+	/// whether the list changed. An empty list is ignored.
+	pub fn update(&mut self,servers:Vec<SocketAddr>) -> bool {
+		if servers.is_empty() || servers == self.servers {
+			return false;
+		}
+		let current:SocketAddr = self.current();
+		match servers.iter().position(|server| *server == current) {
+			Some(index) => self.current = index,
+			None => {
+				self.current = 0;
+				self.failures = 0;
+			},
+		};
+		self.servers = servers;
+		if self.current == 0 {
+			self.nextprobe = None;
+		} else if self.nextprobe.is_none() {
+			self.nextprobe = self.policy.failback_interval.map(|interval| Instant::now() + interval);
+		}
+		return true;
+	}
+
+	/// When the more preferred servers should next be asked whether they're back, if at all.
+	pub fn next_probe(&self) -> Option<Instant> {
+		return self.nextprobe;
+	}
+
+	/// If it's time, returns the servers to ask for a subscription (every server more preferred
+	/// than the current one), and schedules the next time.
+	pub fn probe(&mut self,now:Instant) -> &[SocketAddr] {
+		match (self.nextprobe,self.policy.failback_interval) {
+			(Some(due),Some(interval)) if due <= now => {
+				self.nextprobe = Some(now + interval);
+				return &self.servers[..self.current];
+			},
+			_ => return &[],
+		};
+	}
+
+	/// Whether a server is more preferred than the current one.
+	pub fn prefers(&self,server:SocketAddr) -> bool {
+		return self.servers[..self.current].contains(&server);
+	}
+
+	/// Switches back to a more preferred server that has granted a subscription. Returns None if
+	/// the server isn't more preferred than the current one.
+	pub fn fail_back(&mut self,server:SocketAddr) -> Option<Switch> {
+		let index:usize = self.servers[..self.current].iter().position(|candidate| *candidate == server)?;
+		let from:SocketAddr = self.current();
+		self.current = index;
+		self.failures = 0;
+		self.nextprobe = None;
+		return Some(Switch {
+			from,
+			to:server,
+		});
+	}
+}
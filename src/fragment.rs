@@ -0,0 +1,317 @@
+// Splitting large messages into fragments, and putting them back together.
+//
+// Teamech servers (and clients before this one) receive into a 500-byte buffer, so a packet bigger
+// than that can't be delivered whole, even though this client's own buffer
+// (net::RECEIVE_BUFFER_SIZE) would take it. Messages that don't fit in 500 bytes are sent as
+// numbered fragments instead, each one a separate packet whose content is laid out as
+// [FRAGMENT_MARKER][message id (4)][fragment index (2)][fragment count (2)][data],
+// with all numbers big endian. The receiver collects the fragments of each message id, and hands
+// the message on once all of them have arrived, in whatever order. Messages that are never
+// completed are dropped after a timeout, and messages that would be too big are refused, so a
+// misbehaving sender can't use up the receiver's memory.
+//
+// A small message that happens to begin with FRAGMENT_MARKER is sent as a single fragment (of
+// one), so that every message starting with the marker is a fragment.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::time::{Duration,Instant};
+
+use rand;
+
+use packet;
+
+/// The first byte of every fragment (ASCII DLE, "data link escape").
+pub static FRAGMENT_MARKER:u8 = 0x10;
+
+/// The length of the header at the start of every fragment.
+pub static HEADER_SIZE:usize = 9;
+
+/// The largest datagram every Teamech server and client can be assumed to receive.
+pub static MAX_DATAGRAM_SIZE:usize = 500;
+
+/// The most message content that fits in one datagram, after the timestamp, signature and nonce.
+pub static MAX_CONTENT_SIZE:usize = MAX_DATAGRAM_SIZE - packet::OVERHEAD;
+
+/// The most message data that fits in one fragment.
+pub static MAX_FRAGMENT_DATA:usize = MAX_CONTENT_SIZE - HEADER_SIZE;
+
+/// Problems with fragments, either when splitting a message or when reassembling one.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum FragmentError {
+	/// A fragment is too short to hold its header.
+	Truncated {
+		length:usize,
+	},
+	/// A fragment's index is outside the number of fragments it claims there are.
+	BadIndex {
+		id:u32,
+		index:u16,
+		count:u16,
+	},
+	/// Fragments of the same message disagree about how many fragments there are.
+	CountMismatch {
+		id:u32,
+		expected:u16,
+		got:u16,
+	},
+	/// A message is bigger than the receiver will accept.
+	TooLarge {
+		id:u32,
+		size:usize,
+		limit:usize,
+	},
+	/// A message is too big to send even in fragments.
+	TooManyFragments {
+		size:usize,
+	},
+}
+
+impl fmt::Display for FragmentError {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		match self {
+			FragmentError::Truncated { length } => {
+				write!(f,"fragment of {} bytes is shorter than its {}-byte header",length,HEADER_SIZE)
+			},
+			FragmentError::BadIndex { id, index, count } => {
+				write!(f,"fragment {} of message {:08x} is out of range (message has {} fragments)",index,id,count)
+			},
+			FragmentError::CountMismatch { id, expected, got } => {
+				write!(f,"fragment of message {:08x} claims {} fragments, but earlier ones claimed {}",id,got,expected)
+			},
+			FragmentError::TooLarge { id, size, limit } => {
+				write!(f,"message {:08x} would be {} bytes, more than the limit of {}",id,size,limit)
+			},
+			FragmentError::TooManyFragments { size } => {
+				write!(f,"message of {} bytes is too large to send, even in fragments",size)
+			},
+		}
+	}
+}
+
+impl error::Error for FragmentError {}
+
+/// Whether a message's content is a fragment.
+pub fn is_fragment(content:&[u8]) -> bool {
+	return content.first() == Some(&FRAGMENT_MARKER);
+}
+
+/// How many packets a message will be sent as.
+pub fn count(bytes:&[u8]) -> usize {
+	if bytes.len() <= MAX_CONTENT_SIZE && !is_fragment(bytes) {
+		return 1;
+	}
+	return bytes.len().div_ceil(MAX_FRAGMENT_DATA).max(1);
+}
+
+/// Splits a message into the contents of the packets it should be sent as: the message itself,
+/// if it fits in one packet, or otherwise fragments with a new random message id.
+pub fn split(bytes:&[u8]) -> Result<Vec<Vec<u8>>,FragmentError> {
+	let count:usize = count(bytes);
+	if count == 1 && !is_fragment(bytes) {
+		return Ok(vec![bytes.to_vec()]);
+	}
+	if count > u16::MAX as usize {
+		return Err(FragmentError::TooManyFragments {
+			size:bytes.len(),
+		});
+	}
+	let id:u32 = rand::random::<u32>();
+	let mut fragments:Vec<Vec<u8>> = Vec::with_capacity(count);
+	for index in 0..count {
+		let data:&[u8] = &bytes[index*MAX_FRAGMENT_DATA..bytes.len().min((index+1)*MAX_FRAGMENT_DATA)];
+		let mut fragment:Vec<u8> = Vec::with_capacity(HEADER_SIZE+data.len());
+		fragment.push(FRAGMENT_MARKER);
+		fragment.extend_from_slice(&id.to_be_bytes());
+		fragment.extend_from_slice(&(index as u16).to_be_bytes());
+		fragment.extend_from_slice(&(count as u16).to_be_bytes());
+		fragment.extend_from_slice(data);
+		fragments.push(fragment);
+	}
+	return Ok(fragments);
+}
+
+/// A fragment's header.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Header {
+	pub id:u32,
+	pub index:u16,
+	pub count:u16,
+}
+
+impl Header {
+	/// Reads the header of a fragment.
+	pub fn parse(fragment:&[u8]) -> Result<Header,FragmentError> {
+		if fragment.len() < HEADER_SIZE || !is_fragment(fragment) {
+			return Err(FragmentError::Truncated {
+				length:fragment.len(),
+			});
+		}
+		let header:Header = Header {
+			id:u32::from_be_bytes([fragment[1],fragment[2],fragment[3],fragment[4]]),
+			index:u16::from_be_bytes([fragment[5],fragment[6]]),
+			count:u16::from_be_bytes([fragment[7],fragment[8]]),
+		};
+		if header.index >= header.count {
+			return Err(FragmentError::BadIndex {
+				id:header.id,
+				index:header.index,
+				count:header.count,
+			});
+		}
+		return Ok(header);
+	}
+}
+
+/// Limits on reassembly, which protect the receiver from senders that never finish messages or
+/// send enormous ones.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct ReassemblyLimits {
+	/// The largest message that will be reassembled.
+	pub max_message_size:usize,
+	/// How long to wait for the rest of a message after its first fragment arrives.
+	pub timeout:Duration,
+	/// How many messages can be partly received at once. When this is exceeded, the oldest one
+	/// is dropped.
+	pub max_partial:usize,
+}
+
+impl Default for ReassemblyLimits {
+	fn default() -> ReassemblyLimits {
+		return ReassemblyLimits {
+			max_message_size:1 << 20,
+			timeout:Duration::from_millis(10_000),
+			max_partial:8,
+		};
+	}
+}
+
+// A message that has been partly received.
+struct Partial {
+	started:Instant,
+	fragments:Vec<Option<Vec<u8>>>,
+	received:usize,
+	size:usize,
+}
+
+/// Collects fragments until their messages are complete.
+pub struct Reassembler {
+	limits:ReassemblyLimits,
+	partial:HashMap<u32,Partial>,
+}
+
+impl Reassembler {
+	pub fn new(limits:ReassemblyLimits) -> Reassembler {
+		return Reassembler {
+			limits,
+			partial:HashMap::new(),
+		};
+	}
+
+	/// Takes one fragment. Returns the whole message if this was the last fragment missing from
+	/// it, and None if more are still to come. Repeated fragments are ignored.
+	pub fn accept(&mut self,fragment:&[u8]) -> Result<Option<Vec<u8>>,FragmentError> {
+		let header:Header = Header::parse(fragment)?;
+		let data:&[u8] = &fragment[HEADER_SIZE..];
+		// Every fragment but the last is full, so the message is at least this big.
+		let minsize:usize = (header.count as usize - 1)*MAX_FRAGMENT_DATA + 1;
+		if minsize > self.limits.max_message_size {
+			return Err(FragmentError::TooLarge {
+				id:header.id,
+				size:minsize,
+				limit:self.limits.max_message_size,
+			});
+		}
+		if header.count == 1 {
+			return Ok(Some(data.to_vec()));
+		}
+		if !self.partial.contains_key(&header.id) {
+			self.evict();
+			self.partial.insert(header.id,Partial {
+				started:Instant::now(),
+				fragments:vec![None;header.count as usize],
+				received:0,
+				size:0,
+			});
+		}
+		let complete:bool = {
+			let partial:&mut Partial = match self.partial.get_mut(&header.id) {
+				Some(partial) => partial,
+				None => return Ok(None),
+			};
+			if partial.fragments.len() != header.count as usize {
+				let expected:u16 = partial.fragments.len() as u16;
+				self.partial.remove(&header.id);
+				return Err(FragmentError::CountMismatch {
+					id:header.id,
+					expected,
+					got:header.count,
+				});
+			}
+			let slot:&mut Option<Vec<u8>> = &mut partial.fragments[header.index as usize];
+			if slot.is_none() {
+				*slot = Some(data.to_vec());
+				partial.received += 1;
+				partial.size += data.len();
+			}
+			if partial.size > self.limits.max_message_size {
+				let size:usize = partial.size;
+				self.partial.remove(&header.id);
+				return Err(FragmentError::TooLarge {
+					id:header.id,
+					size,
+					limit:self.limits.max_message_size,
+				});
+			}
+			partial.received == partial.fragments.len()
+		};
+		if !complete {
+			return Ok(None);
+		}
+		let partial:Partial = match self.partial.remove(&header.id) {
+			Some(partial) => partial,
+			None => return Ok(None),
+		};
+		let mut message:Vec<u8> = Vec::with_capacity(partial.size);
+		for data in partial.fragments.into_iter().flatten() {
+			message.extend_from_slice(&data);
+		}
+		return Ok(Some(message));
+	}
+
+	// Makes room for another partial message, if necessary, by dropping the oldest.
+	fn evict(&mut self) {
+		while self.partial.len() >= self.limits.max_partial.max(1) {
+			let oldest:Option<u32> = self.partial.iter()
+				.min_by_key(|(_,partial)| partial.started)
+				.map(|(id,_)| *id);
+			match oldest {
+				Some(id) => {
+					self.partial.remove(&id);
+				},
+				None => return,
+			};
+		}
+	}
+
+	/// Drops messages whose timeouts have passed. Returns how many were dropped.
+	pub fn expire(&mut self) -> usize {
+		let timeout:Duration = self.limits.timeout;
+		let before:usize = self.partial.len();
+		self.partial.retain(|_,partial| partial.started.elapsed() < timeout);
+		return before - self.partial.len();
+	}
+
+	/// When the next partial message will time out, if there are any.
+	pub fn next_deadline(&self) -> Option<Instant> {
+		return self.partial.values()
+			.map(|partial| partial.started + self.limits.timeout)
+			.min();
+	}
+
+	/// How many messages are partly received.
+	pub fn pending(&self) -> usize {
+		return self.partial.len();
+	}
+}
@@ -0,0 +1,176 @@
+// Message handlers and routing.
+//
+// Device logic is written as implementations of MessageHandler (or plain closures) and registered
+// with a Router, which the client's network loop consults every time a new message arrives from
+// the server. This keeps device code out of the network loop entirely. When the client shuts down
+// cleanly, every handler gets a chance to tidy up (e.g. switch off the equipment it controls).
+
+use std::net::SocketAddr;
+
+use regex::{self,Regex};
+
+/// A validated, decrypted message received from the server.
+#[derive(Debug,Clone,Copy)]
+pub struct Message<'a> {
+	/// The message content, decoded as UTF-8 (invalid sequences are replaced).
+	pub text:&'a str,
+	/// The message content in byte form, without the timestamp.
+	pub bytes:&'a [u8],
+	/// Unix timestamp of the message, in milliseconds.
+	pub timestamp:u64,
+	/// Address of the server the message arrived from.
+	pub server:SocketAddr,
+}
+
+impl<'a> Message<'a> {
+	/// The first whitespace-separated word of the message, if any.
+	pub fn command(&self) -> Option<&'a str> {
+		return self.text.split_whitespace().next();
+	}
+
+	/// Everything after the first word of the message, with surrounding whitespace removed.
+	pub fn arguments(&self) -> &'a str {
+		let trimmed:&'a str = self.text.trim_start();
+		match trimmed.find(char::is_whitespace) {
+			Some(n) => return trimmed[n..].trim(),
+			None => return "",
+		};
+	}
+}
+
+/// Something that can respond to messages from the server.
+/// Returning `Some(reply)` causes the reply to be encrypted and sent back to the server.
+pub trait MessageHandler {
+	fn handle(&mut self,message:&Message) -> Option<Vec<u8>>;
+
+	/// Called once when the client shuts down cleanly. Does nothing unless overridden.
+	fn shutdown(&mut self) {
+	}
+}
+
+impl<F> MessageHandler for F where F:FnMut(&Message) -> Option<Vec<u8>> {
+	fn handle(&mut self,message:&Message) -> Option<Vec<u8>> {
+		return self(message);
+	}
+}
+
+/// The ways a Router can decide whether a handler applies to a message.
+#[derive(Debug)]
+pub enum Route {
+	/// The message text must be exactly this string.
+	Exact(String),
+	/// The message text must start with this string.
+	Prefix(String),
+	/// The message text must match this regular expression somewhere.
+	Pattern(Regex),
+	/// The first word of the message text must be exactly this string.
+	Command(String),
+}
+
+impl Route {
+	/// Checks whether a message should be handled by the handler registered on this route.
+	pub fn matches(&self,message:&Message) -> bool {
+		match self {
+			Route::Exact(text) => return message.text == text,
+			Route::Prefix(prefix) => return message.text.starts_with(prefix.as_str()),
+			Route::Pattern(pattern) => return pattern.is_match(message.text),
+			Route::Command(command) => return message.command() == Some(command.as_str()),
+		};
+	}
+}
+
+/// Dispatches messages to registered handlers.
+/// Routes are tried in the order they were registered, and the first one that matches gets the
+/// message. If none match, the fallback handler (if any) gets it instead.
+#[derive(Default)]
+pub struct Router {
+	routes:Vec<(Route,Box<dyn MessageHandler>)>,
+	fallback:Option<Box<dyn MessageHandler>>,
+	hooks:Vec<Box<dyn FnMut()>>,
+}
+
+impl Router {
+	pub fn new() -> Router {
+		return Router::default();
+	}
+
+	/// Registers a handler on an arbitrary route.
+	pub fn route<H:MessageHandler+'static>(&mut self,route:Route,handler:H) -> &mut Router {
+		self.routes.push((route,Box::new(handler)));
+		return self;
+	}
+
+	/// Registers a handler for messages that are exactly the given text.
+	pub fn exact<H:MessageHandler+'static>(&mut self,text:&str,handler:H) -> &mut Router {
+		return self.route(Route::Exact(text.to_owned()),handler);
+	}
+
+	/// Registers a handler for messages that start with the given text.
+	pub fn prefix<H:MessageHandler+'static>(&mut self,prefix:&str,handler:H) -> &mut Router {
+		return self.route(Route::Prefix(prefix.to_owned()),handler);
+	}
+
+	/// Registers a handler for messages matching the given regular expression. Fails if the
+	/// expression does not compile.
+	pub fn pattern<H:MessageHandler+'static>(&mut self,pattern:&str,handler:H) -> Result<&mut Router,regex::Error> {
+		let compiled:Regex = Regex::new(pattern)?;
+		return Ok(self.route(Route::Pattern(compiled),handler));
+	}
+
+	/// Registers a handler for messages whose first word is the given command.
+	pub fn command<H:MessageHandler+'static>(&mut self,command:&str,handler:H) -> &mut Router {
+		return self.route(Route::Command(command.to_owned()),handler);
+	}
+
+	/// Sets the handler for messages that no route matches, replacing any previous one.
+	pub fn fallback<H:MessageHandler+'static>(&mut self,handler:H) -> &mut Router {
+		self.fallback = Some(Box::new(handler));
+		return self;
+	}
+
+	/// Registers code to run when the client shuts down cleanly, after the handlers' own shutdown()
+	/// methods, e.g. for tidying up after device code that isn't a handler.
+	pub fn on_shutdown<F:FnMut()+'static>(&mut self,hook:F) -> &mut Router {
+		self.hooks.push(Box::new(hook));
+		return self;
+	}
+
+	/// Tells every handler (in the order they were registered, then the fallback), and then every
+	/// shutdown hook, that the client is shutting down.
+	pub fn shutdown(&mut self) {
+		for (_,handler) in self.routes.iter_mut() {
+			handler.shutdown();
+		}
+		if let Some(ref mut handler) = self.fallback {
+			handler.shutdown();
+		}
+		for hook in self.hooks.iter_mut() {
+			hook();
+		}
+	}
+
+	/// Hands a message to the first matching handler and returns its reply, if any.
+	pub fn dispatch(&mut self,message:&Message) -> Option<Vec<u8>> {
+		for (route,handler) in self.routes.iter_mut() {
+			if route.matches(message) {
+				return handler.handle(message);
+			}
+		}
+		match self.fallback {
+			Some(ref mut handler) => return handler.handle(message),
+			None => return None,
+		};
+	}
+}
+
+// Routers are handlers themselves, so a device module can build its own router and have it
+// mounted under a prefix or command on the main one.
+impl MessageHandler for Router {
+	fn handle(&mut self,message:&Message) -> Option<Vec<u8>> {
+		return self.dispatch(message);
+	}
+
+	fn shutdown(&mut self) {
+		Router::shutdown(self);
+	}
+}
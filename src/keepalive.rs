@@ -0,0 +1,90 @@
+// Keeping the subscription alive.
+//
+// The server only tells the client that its subscription has ended if it still remembers the
+// client. If the server restarts, or a NAT router between them forgets the client's port mapping,
+// messages just stop arriving, and nothing in the protocol says so. So while subscribed, the
+// client sends a heartbeat every so often: a subscription request, which renews the subscription,
+// keeps NAT mappings open, and gets an answer from the server. If nothing at all is heard from the
+// server for longer than a timeout, the client assumes it has lost the server, and subscribes
+// again from scratch.
+
+use std::time::{Duration,Instant};
+
+/// How often to send heartbeats, and how long a silence means the server is gone.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct KeepalivePolicy {
+	/// How often to send a heartbeat, or None not to send them (and not to watch for silence).
+	pub interval:Option<Duration>,
+	/// How long the server can go without being heard from before it's given up on.
+	pub timeout:Duration,
+}
+
+impl Default for KeepalivePolicy {
+	fn default() -> KeepalivePolicy {
+		return KeepalivePolicy {
+			interval:Some(Duration::from_millis(30_000)),
+			timeout:Duration::from_millis(90_000),
+		};
+	}
+}
+
+/// Heartbeat timing for one subscription.
+pub struct Keepalive {
+	policy:KeepalivePolicy,
+	lastheard:Instant,
+	nextbeat:Option<Instant>,
+	// Heartbeats sent since the server was last heard from.
+	unanswered:u32,
+}
+
+impl Keepalive {
+	/// Starts timing a subscription that has just been granted (so the server has just been heard
+	/// from).
+	pub fn new(policy:KeepalivePolicy) -> Keepalive {
+		let now:Instant = Instant::now();
+		return Keepalive {
+			policy,
+			lastheard:now,
+			nextbeat:policy.interval.map(|interval| now + interval),
+			unanswered:0,
+		};
+	}
+
+	/// Records that a valid packet arrived from the server. Returns how many heartbeats it had
+	/// missed before this (not counting the latest, which this may be the answer to).
+	pub fn heard(&mut self) -> u32 {
+		let missed:u32 = self.unanswered.saturating_sub(1);
+		self.lastheard = Instant::now();
+		self.unanswered = 0;
+		return missed;
+	}
+
+	/// If a heartbeat is due, schedules the next one and returns how many heartbeats before this
+	/// one have gone unanswered. The caller sends the heartbeat.
+	pub fn beat(&mut self,now:Instant) -> Option<u32> {
+		let interval:Duration = self.policy.interval?;
+		if self.nextbeat.is_none_or(|due| due > now) {
+			return None;
+		}
+		self.nextbeat = Some(now + interval);
+		let missed:u32 = self.unanswered;
+		self.unanswered += 1;
+		return Some(missed);
+	}
+
+	/// How long the server has been silent, if that's longer than the timeout.
+	pub fn silence(&self,now:Instant) -> Option<Duration> {
+		self.policy.interval?;
+		let silence:Duration = now.saturating_duration_since(self.lastheard);
+		if silence < self.policy.timeout {
+			return None;
+		}
+		return Some(silence);
+	}
+
+	/// When beat() or silence() next needs checking, if ever.
+	pub fn next_deadline(&self) -> Option<Instant> {
+		let nextbeat:Instant = self.nextbeat?;
+		return Some(nextbeat.min(self.lastheard + self.policy.timeout));
+	}
+}
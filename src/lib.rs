@@ -0,0 +1,114 @@
+/* Teamech Embedded Client Library v0.1
+ * September 2018
+ * License: GPL v3.0
+ *
+ * This source code is provided with ABSOLUTELY NO WARRANTY. You are fully responsible for any
+ * operations that your computers carry out as a result of running this code or anything derived
+ * from it. The developer assumes the full absolution of liability described in the GPL v3.0
+ * license.
+ *
+ * This library contains the parts of the embedded client that don't need to change from one
+ * device to the next, so that device firmwares can depend on it instead of copying the template
+ * wholesale. The binary in main.rs is a thin consumer of it.
+ */
+
+#[macro_use]
+extern crate log;
+extern crate memmap;
+extern crate rand;
+extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(unix)]
+extern crate signal_hook;
+extern crate socket2;
+extern crate tiny_keccak;
+extern crate toml;
+
+pub mod teacrypt;
+pub mod clock;
+pub mod packet;
+pub mod status;
+pub mod fragment;
+pub mod replay;
+pub mod dedup;
+pub mod handler;
+pub mod net;
+pub mod delivery;
+pub mod backoff;
+pub mod resolve;
+pub mod failover;
+pub mod keepalive;
+pub mod connection;
+pub mod shutdown;
+pub mod notify;
+pub mod logging;
+pub mod scheduler;
+pub mod reactor;
+pub mod config;
+
+use std::time::{SystemTime,UNIX_EPOCH};
+
+/// Gets the unixtime in milliseconds, or 0 if the clock is before the epoch. Timestamps on messages
+/// come from a clock::Clock instead, which keeps moving even then.
+pub fn systime() -> u64 {
+	match SystemTime::now().duration_since(UNIX_EPOCH) {
+		Ok(time) => {
+			return time.as_secs()*1_000 + (time.subsec_nanos() as u64)/1_000_000;
+		},
+		Err(_why) => {
+			return 0;
+		},
+	};
+}
+
+/// Converts a number of days since the Unix epoch to a (year, month, day) civil date, with months
+/// and days counted from 1. This is Howard Hinnant's days-to-civil algorithm for the proleptic
+/// Gregorian calendar, so it works for any date, before the epoch or after.
+pub(crate) fn civil(days:i64) -> (i64,u64,u64) {
+	let z:i64 = days + 719_468;
+	let era:i64 = z.div_euclid(146_097);
+	let doe:i64 = z - era*146_097;
+	let yoe:i64 = (doe - doe/1_460 + doe/36_524 - doe/146_096)/365;
+	let doy:i64 = doe - (365*yoe + yoe/4 - yoe/100);
+	let mp:i64 = (5*doy + 2)/153;
+	let day:i64 = doy - (153*mp + 2)/5 + 1;
+	let month:i64 = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year:i64 = yoe + era*400 + if month <= 2 { 1 } else { 0 };
+	return (year,month as u64,day as u64);
+}
+
+/// Splits up an unsigned 64-bit int into eight bytes (unsigned 8-bit ints).
+/// Endianness is preserved, but Teamech still needs to modified for big endian because the
+/// specification requires that all messages be little endian.
+pub fn int2bytes(n:&u64) -> [u8;8] {
+	let mut result:[u8;8] = [0;8];
+	for (i,byte) in result.iter_mut().rev().enumerate() {
+		*byte = (0xFF & (*n >> (i*8))) as u8;
+	}
+	return result;
+}
+
+/// Inverse of the above. Combines eight bytes into one 64-bit int.
+/// Same endianness concerns as above apply.
+pub fn bytes2int(b:&[u8;8]) -> u64 {
+	let mut result:u64 = 0;
+	for byte in b.iter() {
+		result = (result << 8) + (*byte as u64);
+	}
+	return result;
+}
+
+/// Converts a slice of bytes into a hexadecimal string. This is used mainly for debugging, when
+/// printing a binary string.
+pub fn bytes2hex(v:&[u8]) -> String {
+	let mut result:String = String::new();
+	for (x,byte) in v.iter().enumerate() {
+		result.push_str(&format!("{:02x}",byte));
+		if x < v.len()-1 {
+			result.push(' ');
+		}
+	}
+	return result;
+}
@@ -0,0 +1,435 @@
+// Leveled, structured logging.
+//
+// Everything the client has to say while running goes through the `log` crate's macros, each
+// record with a level, a target (the module it came from, or `MESSAGES` for the messages exchanged
+// with the server), and sometimes key-value fields. The Logger here decides which records are
+// wanted, using a filter like "info,teamech::delivery=debug,messages=off", and writes them out:
+// as text or JSON lines on stdout or stderr, or to the local syslog or journald socket, so that log
+// shippers on the device get something they can parse.
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::Write;
+use std::path::{Path,PathBuf};
+use std::process;
+use std::str::FromStr;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+use log;
+use log::{Level,LevelFilter,Log,Metadata,Record,SetLoggerError};
+use log::kv::{Key,Value,VisitSource};
+
+use civil;
+use systime;
+
+/// The target of records about messages exchanged with the server: what it sent, and what the
+/// client replied. Turning it off (e.g. "info,messages=off") quietens busy devices without losing
+/// anything else.
+pub static MESSAGES:&str = "messages";
+
+/// Name the client logs under in syslog and the journal.
+pub static IDENTIFIER:&str = "teamech";
+
+static SYSLOG_SOCKET:&str = "/dev/log";
+static JOURNALD_SOCKET:&str = "/run/systemd/journal/socket";
+
+/// How records are written out.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	/// One line per record: timestamp, level, target, message and fields.
+	Text,
+	/// One JSON object per line, with the fields as members.
+	Json,
+}
+
+impl FromStr for LogFormat {
+	type Err = ();
+	fn from_str(text:&str) -> Result<LogFormat,()> {
+		match text {
+			"text" => return Ok(LogFormat::Text),
+			"json" => return Ok(LogFormat::Json),
+			_ => return Err(()),
+		};
+	}
+}
+
+/// Where records are written to.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+	Stdout,
+	Stderr,
+	/// The local syslog daemon's socket, with the level as the severity.
+	Syslog,
+	/// The systemd journal's native socket, with the target and fields as journal fields (the
+	/// format setting doesn't apply).
+	Journald,
+}
+
+impl FromStr for LogOutput {
+	type Err = ();
+	fn from_str(text:&str) -> Result<LogOutput,()> {
+		match text {
+			"stdout" => return Ok(LogOutput::Stdout),
+			"stderr" => return Ok(LogOutput::Stderr),
+			"syslog" => return Ok(LogOutput::Syslog),
+			"journald" => return Ok(LogOutput::Journald),
+			_ => return Err(()),
+		};
+	}
+}
+
+/// A filter that couldn't be parsed.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct FilterError(pub String);
+
+impl fmt::Display for FilterError {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		return write!(f,"invalid log filter \"{}\" (expected e.g. \"info,teamech::delivery=debug\")",self.0);
+	}
+}
+
+impl error::Error for FilterError {}
+
+/// Which records to log: a level for everything, and levels for particular targets, which also
+/// cover the targets under them (so "teamech=warn" covers "teamech::delivery").
+#[derive(Debug,Clone,PartialEq,Eq,Deserialize)]
+#[serde(try_from = "String")]
+pub struct LogFilter {
+	level:LevelFilter,
+	targets:Vec<(String,LevelFilter)>,
+}
+
+impl LogFilter {
+	pub fn new(level:LevelFilter) -> LogFilter {
+		return LogFilter {
+			level,
+			targets:Vec::new(),
+		};
+	}
+
+	/// The level records from `target` need to be logged: that of the most specific target that
+	/// covers it, or the overall level.
+	pub fn level(&self,target:&str) -> LevelFilter {
+		let mut best:Option<&(String,LevelFilter)> = None;
+		for entry in self.targets.iter() {
+			let covers:bool = target == entry.0 || (target.starts_with(entry.0.as_str()) && target[entry.0.len()..].starts_with("::"));
+			if covers && best.is_none_or(|best| best.0.len() < entry.0.len()) {
+				best = Some(entry);
+			}
+		}
+		return best.map(|entry| entry.1).unwrap_or(self.level);
+	}
+
+	/// The most verbose level anything is logged at.
+	pub fn max(&self) -> LevelFilter {
+		return self.targets.iter().map(|entry| entry.1).fold(self.level,|max,level| max.max(level));
+	}
+
+	/// Logs nothing less severe than `level`, whatever the rest of the filter says.
+	pub fn cap(&mut self,level:LevelFilter) {
+		self.level = self.level.min(level);
+		for entry in self.targets.iter_mut() {
+			entry.1 = entry.1.min(level);
+		}
+	}
+}
+
+impl Default for LogFilter {
+	fn default() -> LogFilter {
+		return LogFilter::new(LevelFilter::Info);
+	}
+}
+
+impl FromStr for LogFilter {
+	type Err = FilterError;
+	fn from_str(text:&str) -> Result<LogFilter,FilterError> {
+		let mut filter:LogFilter = LogFilter::default();
+		for directive in text.split(',').map(|directive| directive.trim()).filter(|directive| !directive.is_empty()) {
+			let invalid = |_| FilterError(text.to_owned());
+			match directive.split_once('=') {
+				Some((target,level)) if !target.trim().is_empty() => {
+					filter.targets.push((target.trim().to_owned(),level.trim().parse::<LevelFilter>().map_err(invalid)?));
+				},
+				Some(_) => return Err(FilterError(text.to_owned())),
+				None => filter.level = directive.parse::<LevelFilter>().map_err(invalid)?,
+			};
+		}
+		return Ok(filter);
+	}
+}
+
+impl TryFrom<String> for LogFilter {
+	type Error = FilterError;
+	fn try_from(text:String) -> Result<LogFilter,FilterError> {
+		return text.parse::<LogFilter>();
+	}
+}
+
+// Where the rendered records go.
+enum Sink {
+	Stdout,
+	Stderr,
+	#[cfg(unix)]
+	Socket(UnixDatagram,PathBuf),
+}
+
+/// Writes out the records its filter lets through.
+pub struct Logger {
+	filter:LogFilter,
+	format:LogFormat,
+	output:LogOutput,
+	timestamps:bool,
+	sink:Sink,
+}
+
+impl Logger {
+	/// Logs to stdout, with timestamps.
+	pub fn new(filter:LogFilter,format:LogFormat) -> Logger {
+		return Logger {
+			filter,
+			format,
+			output:LogOutput::Stdout,
+			timestamps:true,
+			sink:Sink::Stdout,
+		};
+	}
+
+	/// Whether text and JSON records start with the time. Syslog and the journal add their own.
+	pub fn timestamps(mut self,timestamps:bool) -> Logger {
+		self.timestamps = timestamps;
+		return self;
+	}
+
+	/// Logs to `output` instead, through `socket` if it's syslog or the journal and that isn't at
+	/// the usual place.
+	pub fn output(mut self,output:LogOutput,socket:Option<&Path>) -> Result<Logger,io::Error> {
+		self.sink = match output {
+			LogOutput::Stdout => Sink::Stdout,
+			LogOutput::Stderr => Sink::Stderr,
+			LogOutput::Syslog => Logger::socket(socket.unwrap_or(Path::new(SYSLOG_SOCKET)))?,
+			LogOutput::Journald => Logger::socket(socket.unwrap_or(Path::new(JOURNALD_SOCKET)))?,
+		};
+		self.output = output;
+		return Ok(self);
+	}
+
+	#[cfg(unix)]
+	fn socket(path:&Path) -> Result<Sink,io::Error> {
+		let socket:UnixDatagram = UnixDatagram::unbound()?;
+		// Fail now, rather than losing every record later, if nothing is listening.
+		socket.connect(path)?;
+		return Ok(Sink::Socket(socket,path.to_path_buf()));
+	}
+
+	#[cfg(not(unix))]
+	fn socket(_path:&Path) -> Result<Sink,io::Error> {
+		return Err(io::Error::new(io::ErrorKind::Unsupported,"syslog and journald output need Unix sockets"));
+	}
+
+	/// Makes this the logger for the whole process. Can only be done once.
+	pub fn init(self) -> Result<(),SetLoggerError> {
+		log::set_max_level(self.filter.max());
+		return log::set_boxed_logger(Box::new(self));
+	}
+
+	/// A record as it would be written out (without the trailing newline, for text and JSON).
+	pub fn render(&self,record:&Record) -> Vec<u8> {
+		let mut fields:Fields = Fields(Vec::new());
+		let _ = record.key_values().visit(&mut fields);
+		let stamped:bool = self.timestamps && (self.output == LogOutput::Stdout || self.output == LogOutput::Stderr);
+		if self.output == LogOutput::Journald {
+			return journalentry(record,&fields);
+		}
+		let line:String = match self.format {
+			LogFormat::Text => {
+				let mut line:String = String::new();
+				if stamped {
+					line.push_str(&rfc3339(systime()));
+					line.push(' ');
+				}
+				let _ = write!(line,"{:<5} {}: {}",record.level(),record.target(),record.args());
+				for (key,value) in fields.0.iter() {
+					let _ = write!(line," {}={}",key,value.text());
+				}
+				line
+			},
+			LogFormat::Json => {
+				let mut line:String = String::from("{");
+				if stamped {
+					let _ = write!(line,"\"time\":{},",jsonstring(&rfc3339(systime())));
+				}
+				let _ = write!(line,"\"level\":{},\"target\":{},\"message\":{}",jsonstring(record.level().as_str()),
+					jsonstring(record.target()),jsonstring(&record.args().to_string()));
+				for (key,value) in fields.0.iter() {
+					let _ = write!(line,",{}:{}",jsonstring(key),value.json());
+				}
+				line.push('}');
+				line
+			},
+		};
+		if self.output == LogOutput::Syslog {
+			// Facility "daemon", and the severity that matches the level.
+			return format!("<{}>{}[{}]: {}",3*8 + severity(record.level()),IDENTIFIER,process::id(),line).into_bytes();
+		}
+		return line.into_bytes();
+	}
+}
+
+impl Log for Logger {
+	fn enabled(&self,metadata:&Metadata) -> bool {
+		return metadata.level() <= self.filter.level(metadata.target());
+	}
+
+	fn log(&self,record:&Record) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+		let rendered:Vec<u8> = self.render(record);
+		// There's nowhere to report a failure to log, so records that can't be written are lost.
+		match self.sink {
+			Sink::Stdout => {
+				let mut stdout = io::stdout().lock();
+				let _ = stdout.write_all(&rendered).and_then(|_| stdout.write_all(b"\n"));
+			},
+			Sink::Stderr => {
+				let mut stderr = io::stderr().lock();
+				let _ = stderr.write_all(&rendered).and_then(|_| stderr.write_all(b"\n"));
+			},
+			#[cfg(unix)]
+			Sink::Socket(ref socket,ref path) => {
+				if socket.send(&rendered).is_err() {
+					// The daemon may have been restarted since, leaving the socket connected to a
+					// listener that's gone. Connect to whatever is listening now, and try once more.
+					if socket.connect(path).is_ok() {
+						let _ = socket.send(&rendered);
+					}
+				}
+			},
+		};
+	}
+
+	fn flush(&self) {
+		let _ = io::stdout().flush();
+		let _ = io::stderr().flush();
+	}
+}
+
+// A field's value, kept as a number or boolean where it was one, so that JSON can say so.
+enum FieldValue {
+	Plain(String),
+	Text(String),
+}
+
+impl FieldValue {
+	fn text(&self) -> String {
+		match *self {
+			FieldValue::Plain(ref value) => return value.clone(),
+			FieldValue::Text(ref value) if value.is_empty() || value.contains(|c:char| c.is_whitespace() || c == '"' || c == '=') => {
+				return format!("{:?}",value);
+			},
+			FieldValue::Text(ref value) => return value.clone(),
+		};
+	}
+
+	fn json(&self) -> String {
+		match *self {
+			FieldValue::Plain(ref value) => return value.clone(),
+			FieldValue::Text(ref value) => return jsonstring(value),
+		};
+	}
+}
+
+// Collects a record's key-value fields.
+struct Fields(Vec<(String,FieldValue)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+	fn visit_pair(&mut self,key:Key<'kvs>,value:Value<'kvs>) -> Result<(),log::kv::Error> {
+		let value:FieldValue = match (value.to_bool(),value.to_u64(),value.to_i64()) {
+			(Some(flag),_,_) => FieldValue::Plain(flag.to_string()),
+			(_,Some(number),_) => FieldValue::Plain(number.to_string()),
+			(_,_,Some(number)) => FieldValue::Plain(number.to_string()),
+			_ => FieldValue::Text(value.to_string()),
+		};
+		self.0.push((key.as_str().to_owned(),value));
+		return Ok(());
+	}
+}
+
+// Syslog's severity for each level.
+fn severity(level:Level) -> u8 {
+	match level {
+		Level::Error => return 3,
+		Level::Warn => return 4,
+		Level::Info => return 6,
+		Level::Debug | Level::Trace => return 7,
+	};
+}
+
+// A record in the journal's native format: one FIELD=value line per field, with values that
+// contain newlines given as a length and the raw bytes instead.
+fn journalentry(record:&Record,fields:&Fields) -> Vec<u8> {
+	let mut entry:Vec<u8> = Vec::new();
+	let mut field = |name:&str,value:&str| {
+		entry.extend_from_slice(name.as_bytes());
+		if value.contains('\n') {
+			entry.push(b'\n');
+			entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+		} else {
+			entry.push(b'=');
+		}
+		entry.extend_from_slice(value.as_bytes());
+		entry.push(b'\n');
+	};
+	field("MESSAGE",&record.args().to_string());
+	field("PRIORITY",&severity(record.level()).to_string());
+	field("SYSLOG_IDENTIFIER",IDENTIFIER);
+	field("TARGET",record.target());
+	for (key,value) in fields.0.iter() {
+		// Journal field names are upper case letters, digits and underscores.
+		let name:String = key.chars().map(|c| match c.is_ascii_alphanumeric() {
+			true => c.to_ascii_uppercase(),
+			false => '_',
+		}).collect();
+		match *value {
+			FieldValue::Plain(ref value) | FieldValue::Text(ref value) => field(name.trim_start_matches('_'),value),
+		};
+	}
+	return entry;
+}
+
+// A JSON string literal.
+fn jsonstring(text:&str) -> String {
+	let mut quoted:String = String::with_capacity(text.len() + 2);
+	quoted.push('"');
+	for c in text.chars() {
+		match c {
+			'"' => quoted.push_str("\\\""),
+			'\\' => quoted.push_str("\\\\"),
+			'\n' => quoted.push_str("\\n"),
+			'\r' => quoted.push_str("\\r"),
+			'\t' => quoted.push_str("\\t"),
+			c if (c as u32) < 0x20 => {
+				let _ = write!(quoted,"\\u{:04x}",c as u32);
+			},
+			c => quoted.push(c),
+		};
+	}
+	quoted.push('"');
+	return quoted;
+}
+
+/// A time in milliseconds since the Unix epoch, as an RFC 3339 timestamp in UTC (e.g.
+/// "2018-09-01T12:00:00.000Z").
+pub fn rfc3339(millis:u64) -> String {
+	let days:i64 = (millis/86_400_000) as i64;
+	let ms:u64 = millis%86_400_000;
+	let (year,month,day) = civil(days);
+	return format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",year,month,day,ms/3_600_000,ms/60_000%60,ms/1_000%60,ms%1_000);
+}
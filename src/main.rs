@@ -13,522 +13,1197 @@
     version = "0.1.0"
     authors = ["ellie"]
 
+    [lib]
+    name = "teamech"
+
     [dependencies]
     tiny-keccak = "1.4.2"
     rand = "0.3"
+    regex = "1"
+    memmap = "0.7"
+    serde = "1"
+    serde_derive = "1"
+    toml = "0.5"
+    socket2 = "0.5"
+    log = { version = "0.4.21", features = ["std","kv"] }
 
-*/
+    [target.'cfg(unix)'.dependencies]
+    signal-hook = "0.3"
 
-static MSG_VALID_TIME:u64 = 10_000; // Tolerance interval in ms for packet timestamps outside of which to mark them as suspicious
+*/
 
-extern crate rand;
-extern crate tiny_keccak;
-use tiny_keccak::Keccak;
+#[macro_use]
+extern crate log;
+extern crate teamech;
+use teamech::bytes2hex;
+use teamech::clock;
+use teamech::clock::{Clock,ClockPolicy,TimestampValidator};
+use teamech::teacrypt;
+use teamech::reactor;
+use teamech::teacrypt::{Pad,PadReport};
+use teamech::config::Config;
+use teamech::packet::{Packet,PacketError};
+use teamech::handler::{Router,Message};
+use teamech::net;
+use teamech::net::{sendbytes,sendmessage,sendstatus,Sender,RECEIVE_BUFFER_SIZE};
+use teamech::fragment;
+use teamech::fragment::Reassembler;
+use teamech::replay::ReplayCache;
+use teamech::dedup::DuplicateFilter;
+use teamech::status::{StatusCode,StatusCounters};
+use teamech::delivery::{Delivery,DeliveryQueue};
+use teamech::backoff::Backoff;
+use teamech::failover::{Failover,Switch};
+use teamech::keepalive::Keepalive;
+use teamech::connection::{Change,Connection,ConnectionEvent,ConnectionState};
+use teamech::notify::{Notifier,Watchdog};
+use teamech::shutdown;
+use teamech::logging;
+use teamech::logging::Logger;
+use teamech::shutdown::ShutdownRequest;
+use teamech::resolve::{Lookup,ServerNames,SystemResolver};
+use teamech::scheduler::Scheduler;
+use teamech::reactor::{Reactor,Event,Outbox,Mailbox};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::env;
 use std::env::args;
+use std::path::{Path,PathBuf};
 use std::process;
 use std::thread::sleep;
-use std::error::Error;
-use std::io;
-use std::io::prelude::*;
-use std::time::{Duration,SystemTime,UNIX_EPOCH};
-use std::net::{UdpSocket,SocketAddr,ToSocketAddrs};
-use std::collections::HashSet;
-use std::fs;
-use std::path::Path;
-
-// gets the unixtime in milliseconds.
-fn systime() -> u64 {
-	match SystemTime::now().duration_since(UNIX_EPOCH) {
-		Ok(time) => {
-			return time.as_secs()*1_000 + (time.subsec_nanos() as u64)/1_000_000 ;
-		},
-		Err(_why) => {
-			return 0;
+use std::time::{Duration,Instant};
+use std::net::{UdpSocket,SocketAddr};
+
+mod cli;
+use cli::{Command,Options};
+
+// ATTENTION
+// This is where you register handlers for incoming messages. Each handler receives the decoded
+// message (its text, bytes, timestamp, and the server's address) and may return a reply, which
+// will be encrypted and sent back to the server. Handlers can be closures like the one below, or
+// types implementing MessageHandler that live in their own modules; routes can match on the exact
+// text, a prefix, a regular expression, or the first word of the message (e.g. commands to read a
+// sensor or provide status information, or change the state of a piece of equipment).
+fn register_handlers(router:&mut Router) {
+	router.exact("Hello world!",|_message:&Message| Some(b"Hello world!".to_vec()));
+}
+
+// ATTENTION
+// This is where you register code that should run periodically (not just once every time a new
+// message comes in), e.g. reading a sensor and reporting its value to the server. Each task gets
+// a Sender it can use to send messages to the server, and runs whenever its schedule comes due;
+// schedules can be intervals ("every 30s") or cron-like ("*/5 * * * *"), optionally with jitter.
+// For example:
+//     scheduler.every("report",Duration::new(30,0),|sender:&Sender| {
+//         sender.send(b"Still here!")?;
+//         Ok(())
+//     }).jitter(Duration::new(1,0));
+// Code that needs its own thread (e.g. waiting on a hardware interrupt) can be given a clone of
+// the outbox, and use it to send messages to the server at any time. Messages that mustn't be
+// lost can be sent with outbox.send_reliable(), which resends them until the server acknowledges
+// them, and returns a receipt that says whether it did.
+fn register_tasks(_scheduler:&mut Scheduler,_outbox:&Outbox) {
+}
+
+// ATTENTION
+// This is where you register code that should run when the connection to the server changes state,
+// e.g. lighting an LED while the client is subscribed. Each callback is told the old state, the
+// new one, and what caused the change (see connection.rs). For example:
+//     connection.on_change(|change:&Change| {
+//         set_led(change.to == ConnectionState::Subscribed);
+//     });
+fn register_callbacks(_connection:&mut Connection) {
+}
+
+fn main() {
+	let command:Command = match cli::parse(args().skip(1)) {
+		Ok(command) => command,
+		Err(why) => {
+			println!("{}",why);
+			println!("Try '{} --help' for more information.",cli::NAME);
+			process::exit(2);
 		},
 	};
+	match command {
+		Command::Help(topic) => print!("{}",cli::help(topic.as_deref())),
+		Command::Version => println!("{} {}",cli::NAME,cli::VERSION),
+		Command::Run(options) => run(configure(&options,true)),
+		Command::Send(options,message) => send(configure(&options,true),&message),
+		Command::GenPad { path, size, force } => genpad(&path,size,force),
+		Command::CheckPad(options) => checkpad(configure(&options,false)),
+		Command::Decode(options,payload) => decode(configure(&options,false),&payload),
+	};
 }
 
-// int2bytes: splits up an unsigned 64-bit int into eight bytes (unsigned 8-bit ints).
-// Endianness is preserved, but Teamech still needs to modified for big endian because the
-// specification requires that all messages be little endian.
-fn int2bytes(n:&u64) -> [u8;8] {
-	let mut result:[u8;8] = [0;8];
-	for i in 0..8 {
-		result[7-i] = (0xFF & (*n >> i*8)) as u8;
+// Works out the configuration to use: start from the configuration file, if one was given (with
+// --config, or in the TEAMECH_CONFIG environment variable), then apply overrides from the
+// environment and the command line, in that order. Commands that don't talk to the server only
+// need a pad.
+fn configure(options:&Options,needserver:bool) -> Config {
+	let configpath:Option<PathBuf> = match options.config {
+		Some(ref path) => Some(path.clone()),
+		None => env::var_os("TEAMECH_CONFIG").map(PathBuf::from),
+	};
+	let mut config:Config = match configpath {
+		Some(ref path) => match Config::load(path) {
+			Ok(config) => config,
+			Err(why) => {
+				println!("{}",why);
+				process::exit(1);
+			},
+		},
+		None => Config::default(),
+	};
+	if let Err(why) = config.apply_env() {
+		println!("{}",why);
+		process::exit(1);
+	}
+	options.apply(&mut config);
+	let valid = match needserver {
+		true => config.validate(),
+		false => config.validate_pad(),
+	};
+	if let Err(why) = valid {
+		println!("{}",why);
+		println!("Try '{} --help' for more information.",cli::NAME);
+		process::exit(2);
 	}
-	return result;
+	startlogging(&config);
+	return config;
 }
 
-// bytes2int: Inverse of the above. Combines eight bytes into one 64-bit int.
-// Same endianness concerns as above apply.
-fn bytes2int(b:&[u8;8]) -> u64 {
-	let mut result:u64 = 0;
-	for i in 0..8 {
-		result += (b[i] as u64)*2u64.pow(((7-i) as u32)*8u32);
+// Sets up logging as configured (see logging.rs), or quits if that isn't possible. Until then,
+// problems (e.g. with the configuration itself) are just printed.
+fn startlogging(config:&Config) {
+	let logger:Logger = match config.logging.logger() {
+		Ok(logger) => logger,
+		Err(why) => {
+			println!("Could not set up logging - {}",why);
+			process::exit(1);
+		},
+	};
+	if let Err(why) = logger.init() {
+		println!("Could not set up logging - {}",why);
+		process::exit(1);
 	}
-	return result;
 }
 
-// bytes2hex converts a vector of bytes into a hexadecimal string. This is used mainly for
-// // debugging, when printing a binary string.
-fn bytes2hex(v:&Vec<u8>) -> String {
-	let mut result:String = String::from("");
-	for x in 0..v.len() {
-		if v[x] == 0x00 {
-			result.push_str(&format!("00"));
-		} else if v[x] < 0x10 {
-			result.push_str(&format!("0{:x?}",v[x]));
-		} else {
-			result.push_str(&format!("{:x?}",v[x]));
-		}
-		if x < v.len()-1 {
-			result.push_str(" ");
-		}
+// Opens the configured pad, or quits - without a pad, we can't talk to the server at all.
+fn openpad(config:&Config) -> Pad {
+	let padpath:PathBuf = config.pad.path.clone().unwrap_or_default();
+	match Pad::open_window(&padpath,config.pad.mode(),config.pad.window()) {
+		Ok(pad) => return pad,
+		Err(why) => {
+			error!("Could not open pad file {} - {}",padpath.display(),why);
+			process::exit(1);
+		},
+	};
+}
+
+// The configured server names, which are looked up again every network.dns_ttl_ms.
+fn servernames(config:&Config) -> ServerNames {
+	let names:Vec<String> = config.server.iter().chain(config.backup_servers.iter()).cloned().collect();
+	return ServerNames::new(names,Box::new(SystemResolver),config.network.dns_ttl());
+}
+
+// Logs any names a lookup couldn't resolve, and returns the addresses it found that the configured
+// bind address can reach (i.e. not IPv6 ones from an IPv4 address, or the other way around).
+fn usable(config:&Config,lookup:Lookup) -> Vec<SocketAddr> {
+	for (name,why) in lookup.failed.iter() {
+		warn!("Could not look up server address {} - {}",name,why);
 	}
-	return result;
+	return lookup.addresses.into_iter().filter(|addr| config.network.reaches(addr)).collect();
+}
+
+fn addresslist(addrs:&[SocketAddr]) -> String {
+	let names:Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+	return names.join(", ");
+}
+
+// Looks up the configured servers, or quits. A name can resolve to several addresses, which are
+// all used, in the order they were returned.
+fn resolve(config:&Config,names:&mut ServerNames) -> Failover {
+	let serverhosts:Vec<SocketAddr> = usable(config,names.lookup());
+	match Failover::new(serverhosts,config.retry.failover_policy()) {
+		Some(failover) => {
+			if failover.servers().len() > 1 {
+				info!("Servers, in order of preference: {}",addresslist(failover.servers()));
+			}
+			return failover;
+		},
+		None => {
+			// Failure to find a server is always a fatal error - if this doesn't work, we have
+			// nothing to do.
+			error!("No usable server address was found.");
+			process::exit(1);
+		},
+	};
+}
+
+// Looks the server names up again if it's time to, and starts using the new addresses if they've
+// changed. Returns whether the server in use has changed.
+fn reresolve(config:&Config,names:&mut ServerNames,failover:&mut Failover) -> bool {
+	match names.refresh(Instant::now()) {
+		Some(lookup) => return relisted(config,lookup,failover),
+		None => return false,
+	};
 }
 
-// Teacrypt implementation: Generate single-use key and secret seed.
-// Generates a single-use encryption key from a provided key size, pad file and authentication 
-// nonce, and returns the key and its associated secret seed.
-fn keygen(nonce:&[u8;8],padpath:&Path,keysize:&usize) -> Result<(Vec<u8>,Vec<u8>),io::Error> {
-	let mut padfile:fs::File = match fs::File::open(&padpath) {
-		Err(e) => return Err(e),
-		Ok(file) => file,
+// Like reresolve(), but looks the names up on another thread, so that the caller can carry on
+// meanwhile, and wakes the client up through `outbox` when it's done. The new addresses are used
+// once a later call finds the lookup finished.
+fn reresolve_in_background(config:&Config,names:&mut ServerNames,failover:&mut Failover,outbox:&Outbox) -> bool {
+	let waker:Outbox = outbox.clone();
+	names.refresh_in_background(Instant::now(),move || {
+		let _ = waker.wake();
+	});
+	match names.finished() {
+		Some(lookup) => return relisted(config,lookup,failover),
+		None => return false,
 	};
-	// Finding the pad size this way won't work if the pad is a block device instead of a regular
-	// file. If using the otherwise-valid strategy of using a filesystemless flash device as a pad,
-	// this block will need to be extended to use a different method of detecting the pad size.
-	let padsize:u64 = match fs::metadata(&padpath) {
-		Err(e) => return Err(e),
-		Ok(metadata) => metadata.len(),
+}
+
+// Starts using the addresses from a new lookup of the server names, if they've changed. Returns
+// whether the server in use has changed.
+fn relisted(config:&Config,lookup:Lookup,failover:&mut Failover) -> bool {
+	let changed:bool = lookup.changed;
+	let previous:SocketAddr = failover.current();
+	let serverhosts:Vec<SocketAddr> = usable(config,lookup);
+	if !changed || !failover.update(serverhosts) {
+		return false;
+	}
+	info!("Server addresses have changed; now using {}.",addresslist(failover.servers()));
+	if failover.current() == previous {
+		return false;
+	}
+	warn!("Server {} is no longer listed; switching to {}.",previous,failover.current());
+	return true;
+}
+
+// Counts a subscription request the server didn't answer. Once it has used up its attempts, its
+// name is looked up again, in case its address has changed, and if it hasn't, the next server is
+// tried.
+fn unanswered(config:&Config,names:&mut ServerNames,failover:&mut Failover) {
+	if !failover.unanswered() {
+		return;
+	}
+	names.expire();
+	if reresolve(config,names,failover) {
+		return;
+	}
+	failedover(failover.fail_over());
+}
+
+// Logs a change of server.
+fn failedover(switch:Option<Switch>) {
+	if let Some(switch) = switch {
+		warn!("Server {} is not responding; failing over to {}.",switch.from,switch.to);
+	}
+}
+
+// Binds the local socket to an address from NetworkConfig::local_address(), or quits.
+fn bind(config:&Config,local:SocketAddr) -> UdpSocket {
+	match net::bind(local,config.network.dual_stack) {
+		Ok(socket) => return socket,
+		Err(why) =>	{
+			// Error condition: bind to local address failed. This is probably caused by a
+			// network issue, a transient OS issue (e.g. network permissions/firewall), or
+			// another program (or another instance of this one) occupying the port the user
+			// specified. In any case, we can't continue, so we'll let the user know what the
+			// problem is and quit.
+			error!("Could not bind to local address {}: {}",local,why);
+			process::exit(1);
+		},
 	};
-	let mut inbin:[u8;1] = [0];
-	let mut seed:[u8;8] = [0;8];
-	let mut seednonce:[u8;8] = nonce.clone();
-	let mut newseednonce:[u8;8] = [0;8];
-	// Hash the nonce, previous hash, and previous byte retrieved eight times, using each hash to 
-	// index one byte from the pad file. These eight bytes are the secret seed.
-	// The hash is *truncated* to the first eight bytes (64 bits), then *moduloed* to the length of
-	// the pad file. (If you try to decrypt by just moduloing the whole hash against the pad
-	// length, it won't work.)
-	for x in 0..8 {
-		let mut sha3 = Keccak::new_sha3_256();
-		sha3.update(&nonce.clone());
-		sha3.update(&seednonce);
-		if x >= 1 {
-			sha3.update(&[seed[x-1]]);
-		}
-		sha3.finalize(&mut newseednonce);
-		seednonce = newseednonce;
-		let _ = padfile.seek(io::SeekFrom::Start(bytes2int(&seednonce) % padsize));
-		let _ = padfile.read_exact(&mut inbin);
-		seed[x] = inbin[0];
-	}
-	let mut keybytes:Vec<u8> = Vec::with_capacity(*keysize);
-	let mut keynonce:[u8;8] = seed;
-	let mut newkeynonce:[u8;8] = [0;8];
-	// Hash the seed, previous hash, and previous byte retrieved n times, where n is the length of
-	// the key to be generated. Use each hash to index bytes from the pad file (with the same
-	// method as before). These bytes are the key.
-	for x in 0..*keysize {
-		let mut sha3 = Keccak::new_sha3_256();
-		sha3.update(&seed);
-		sha3.update(&keynonce);
-		if x >= 1 {
-			sha3.update(&[keybytes[x-1]]);
-		}
-		sha3.finalize(&mut newkeynonce);
-		keynonce = newkeynonce;
-		let _ = padfile.seek(io::SeekFrom::Start(bytes2int(&keynonce) % padsize));
-		let _ = padfile.read_exact(&mut inbin);
-		keybytes.push(inbin[0]);
-	}
-	return Ok((keybytes,seed.to_vec()));
-}
-
-// Teacrypt implementation: Encrypt a message for transmission.
-// Depends on keygen function; generates a random nonce, produces a key, signs the message using
-// the secret seed, and returns the resulting encrypted payload (including the message,
-// signature, and nonce).
-fn encrypt(message:&Vec<u8>,padpath:&Path) -> Result<Vec<u8>,io::Error> {
-	let nonce:u64 = rand::random::<u64>();
-	let noncebytes:[u8;8] = int2bytes(&nonce);
-	let keysize:usize = message.len()+8;
-	// Use the keygen function to create a key of length n + 8, where n is the length of the
-	// message to be encrypted. (The extra eight bytes are for encrypting the signature.)
-	let (keybytes,seed) = match keygen(&noncebytes,&padpath,&keysize) {
-		Ok((k,s)) => (k,s),
-		Err(e) => return Err(e),
+}
+
+// Sets up the reactor for a socket, or quits.
+fn react<'a>(listener:&'a UdpSocket,mailbox:&Mailbox) -> Reactor<'a> {
+	match Reactor::new(listener,mailbox) {
+		Ok(reactor) => return reactor,
+		Err(why) => {
+			// This is probably a platform error - it's not clear to me when this would happen,
+			// but it probably means that the OS doesn't support read timeouts on UDP sockets,
+			// which is weird and means this program won't really work. Hopefully, the error
+			// message will be useful to the user.
+			error!("Could not set up socket for waiting on events: {}",why);
+			process::exit(1);
+		},
 	};
-	let mut signature:[u8;8] = [0;8];
-	let mut sha3 = Keccak::new_sha3_256();
-	// Generate the signature by hashing the secret seed, the unencrypted message, and the key used
-	// to encrypt the signature and message. 
-	sha3.update(&seed);
-	sha3.update(&message);
-	sha3.update(&keybytes);
-	sha3.finalize(&mut signature);
-	let mut verimessage = Vec::new();
-	verimessage.append(&mut message.clone());
-	verimessage.append(&mut signature.to_vec());
-	let mut payload = Vec::new();
-	for x in 0..keysize {
-		payload.push(verimessage[x] ^ keybytes[x]);
-	}
-	payload.append(&mut noncebytes.to_vec());
-	return Ok(payload);
-}
-
-// Teacrypt implementation: Decrypt a received message.
-// Depends on keygen function; uses the nonce attached to the payload to generate the same key and
-// secret seed, decrypt the payload, and verify the resulting message with its signature. The
-// signature will only validate if the message was the original one encrypted with the same pad 
-// file as the one used to decrypt it; if it has been tampered with, generated with a different
-// pad, or is just random junk data, the validity check will fail and this function will return an
-// io::ErrorKind::InvalidData error.
-fn decrypt(payload:&Vec<u8>,padpath:&Path) -> Result<Vec<u8>,io::Error> {
-	let mut noncebytes:[u8;8] = [0;8];
-	// Detach the nonce from the payload, and use it to generate the key and secret seed.
-	noncebytes.copy_from_slice(&payload[payload.len()-8..payload.len()]);
-	let keysize = payload.len()-8;
-	let ciphertext:Vec<u8> = payload[0..payload.len()-8].to_vec();
-	let (keybytes,seed) = match keygen(&noncebytes,&padpath,&keysize) {
-		Ok((k,s)) => (k,s),
-		Err(e) => return Err(e),
+}
+
+// How an attempt to subscribe to the server turned out.
+enum Subscription {
+	// The server accepted the subscription, with a response stamped with this time.
+	Subscribed(u64),
+	// The server didn't answer in time; it's worth asking again straight away.
+	NoResponse,
+	// Something went wrong; wait a while before asking again.
+	Failed,
+}
+
+// Counts a status code received from the server, and logs it if it isn't one we know about.
+fn record(statuses:&mut StatusCounters,code:StatusCode) -> StatusCode {
+	let count:u64 = statuses.record(code);
+	if let StatusCode::Unknown(byte) = code {
+		warn!("Server sent an unknown status code 0x{:02x} (seen {} times, {} unknown in total). Is this the latest client version?",
+																							byte,count,statuses.unknown());
+	}
+	return code;
+}
+
+// Checks a message's nonce against the replay cache, and warns if the cache was so full that it had
+// to forget the nonce of a message recent enough to be replayed.
+fn admit(replays:&mut ReplayCache,packet:&Packet,clock:&Clock) -> bool {
+	let evicted:u64 = replays.evicted();
+	let admitted:bool = replays.admit(packet.nonce,packet.timestamp,clock.now());
+	if replays.evicted() > evicted {
+		warn!("Replay cache is full ({} nonces), so a recent message could be replayed ({} forgotten early in total). Consider raising protocol.replay_capacity.",
+																							replays.len(),replays.evicted());
+	}
+	return admitted;
+}
+
+// Asks the server to subscribe us through `sender`, and waits up to the response timeout for it to
+// answer.
+fn subscribe(sender:&Sender,reactor:&Reactor,pad:&Pad,config:&Config,inbin:&mut [u8],statuses:&mut StatusCounters) -> Subscription {
+	let serverhost:&SocketAddr = &sender.server();
+	info!("Trying to contact server...");
+	if let Err(why) = sender.send(&[]) {
+		warn!("Could not send authentication payload - {}",why);
+		return Subscription::Failed;
+	}
+	// Give the server a moment to respond before trying again.
+	let authdeadline:Instant = Instant::now() + Duration::from_millis(config.retry.response_timeout_ms);
+	loop {
+		match reactor.wait(inbin,Some(authdeadline)) {
+			Err(why) => {
+				warn!("Could not receive authentication response - {}",why);
+				return Subscription::Failed;
+			},
+			Ok(Event::Deadline) => return Subscription::NoResponse,
+			Ok(Event::Woken) => (),
+			Ok(Event::Datagram(nrecv,srcaddr)) => {
+				if nrecv != 25 || srcaddr != *serverhost {
+					warn!("Got invalid message of length {} from {}.",nrecv,srcaddr);
+					return Subscription::Failed;
+				}
+				match Packet::decode(&inbin[0..25],pad) {
+					// A 25-byte packet always has exactly one byte of content, i.e. is a status code.
+					Ok(packet) => match record(statuses,StatusCode::from_byte(packet.content[0])) {
+						StatusCode::Subscribed => {
+							info!("Subscribed to server at {}",serverhost);
+							return Subscription::Subscribed(packet.timestamp);
+						},
+						StatusCode::Rejected => {
+							warn!("Pad file is correct, but subscription was rejected by server. Server may be full.");
+							return Subscription::Failed;
+						},
+						StatusCode::ValidationFailed => {
+							warn!("Server could not validate the subscription request. Local pad file probably doesn't match the server's.");
+							return Subscription::Failed;
+						},
+						StatusCode::DecryptFailed => {
+							warn!("Server could not decrypt the subscription request.");
+							return Subscription::Failed;
+						},
+						StatusCode::Ack | StatusCode::Unsubscribe => {
+							// Neither is an answer to a subscription request; keep waiting for one.
+						},
+						StatusCode::Unknown(_) => (),
+					}, // decode Ok
+					Err(PacketError::Crypto(teacrypt::Error::SignatureInvalid)) => {
+						warn!("Response from server did not validate. Local pad file is incorrect or invalid.");
+						return Subscription::Failed;
+					},
+					Err(why) => {
+						warn!("Failed to decrypt response from server - {}",why);
+						return Subscription::Failed;
+					},
+				}; // match decode
+			}, // recv Ok
+		}; // match recv
+	} // loop until authdeadline
+}
+
+// Warns if the local clock hasn't been set, since that affects which messages are accepted.
+fn checkclock(validator:&TimestampValidator) {
+	if clock::is_set() {
+		return;
+	}
+	match validator.policy() {
+		ClockPolicy::Strict => {
+			warn!("Local clock is not set, so messages from the server will be rejected as stale. Setting protocol.clock_skew to \"estimate\" corrects for this.");
+		},
+		ClockPolicy::Estimate => {
+			info!("Local clock is not set; timestamps will be corrected using the server's clock once subscribed.");
+		},
+		ClockPolicy::Ignore => (),
 	};
-	let mut verimessage = Vec::new();
-	// Decrypt the message and signature using the key.
-	for x in 0..keysize {
-		verimessage.push(ciphertext[x] ^ keybytes[x]);
-	}
-	let mut signature:[u8;8] = [0;8];
-	// Detach the signature from the decrypted message, and use it to verify the integrity of the
-	// message. If the check succeeds, return Ok() containing the message content; if it fails,
-	// return an io::ErrorKind::InvalidData error.
-	signature.copy_from_slice(&verimessage[verimessage.len()-8..verimessage.len()]);
-	let message:Vec<u8> = verimessage[0..verimessage.len()-8].to_vec();
-	let mut rightsum:[u8;8] = [0;8];
-	let mut sha3 = Keccak::new_sha3_256();
-	sha3.update(&seed);
-	sha3.update(&message);
-	sha3.update(&keybytes);
-	sha3.finalize(&mut rightsum);
-	if signature == rightsum {
-		return Ok(message);
+}
+
+// Estimates the server's clock offset from its answer to a subscription request sent when the
+// local clock read `sent`.
+fn calibrate(validator:&mut TimestampValidator,sent:u64,servertime:u64) {
+	let received:u64 = validator.clock().local();
+	let offset:i64 = validator.calibrate(sent,received,servertime);
+	if offset.unsigned_abs() > validator.tolerance() {
+		let direction:&str = match offset < 0 {
+			true => "behind",
+			false => "ahead of",
+		};
+		info!("Server clock is {} ms {} the local clock; correcting for it.",offset.unsigned_abs(),direction);
+	}
+}
+
+// Logs a message exchanged with the server, under its own target (see logging::MESSAGES), so that
+// it can be turned off separately.
+fn logmessage(direction:&str,server:&SocketAddr,bytes:&[u8],showhex:bool) {
+	let text:Cow<str> = String::from_utf8_lossy(bytes);
+	if showhex {
+		info!(target:logging::MESSAGES,direction=direction,server:% = server,hex=bytes2hex(bytes).as_str(); "{}",text);
 	} else {
-		return Err(io::Error::new(io::ErrorKind::InvalidData,"Payload signature verification failed"));
+		info!(target:logging::MESSAGES,direction=direction,server:% = server; "{}",text);
 	}
 }
 
-// Sends a vector of bytes to a specific host over a specific socket, automatically retrying in the event of certain errors
-// and aborting in the event of others.
-fn sendraw(listener:&UdpSocket,destaddr:&SocketAddr,payload:&Vec<u8>) -> Result<(),io::Error> {
-	// loop until either the send completes or an unignorable error occurs.
+// Connects to the server, sends one message, and waits for the server to acknowledge it.
+fn send(config:Config,message:&str) {
+	let pad:Pad = openpad(&config);
+	let mut names:ServerNames = servernames(&config);
+	let mut failover:Failover = resolve(&config,&mut names);
+	let mut local:SocketAddr = config.network.local_address(&failover.current());
+	let mut listener:UdpSocket = bind(&config,local);
+	let (_outbox,mailbox) = reactor::mailbox();
+	let mut inbin:Vec<u8> = vec![0;RECEIVE_BUFFER_SIZE];
+	let mut statuses:StatusCounters = StatusCounters::new();
+	let mut validator:TimestampValidator = config.protocol.timestamp_validator();
+	checkclock(&validator);
+	// Give every server its full number of attempts before giving up.
+	let attempts:usize = config.retry.failover_attempts as usize*failover.servers().len();
+	let mut backoff:Backoff = Backoff::new(config.retry.backoff_policy());
+	let mut subscribed:bool = false;
+	for attempt in 0..attempts {
+		// Wait a while before asking again, longer each time.
+		if attempt > 0 {
+			match backoff.fail() {
+				Some(delay) => sleep(delay),
+				None => break,
+			};
+		}
+		// A server of the other address family needs a socket of its own.
+		if config.network.local_address(&failover.current()) != local {
+			local = config.network.local_address(&failover.current());
+			listener = bind(&config,local);
+		}
+		let reactor:Reactor = react(&listener,&mailbox);
+		let sent:u64 = validator.clock().local();
+		let sender:Sender = Sender::new(&listener,failover.current(),&pad,validator.clock());
+		match subscribe(&sender,&reactor,&pad,&config,&mut inbin,&mut statuses) {
+			Subscription::Subscribed(servertime) => {
+				calibrate(&mut validator,sent,servertime);
+				subscribed = true;
+				break;
+			},
+			Subscription::NoResponse | Subscription::Failed => unanswered(&config,&mut names,&mut failover),
+		};
+	}
+	if !subscribed {
+		error!("Could not subscribe to any server.");
+		process::exit(1);
+	}
+	let serverhost:SocketAddr = failover.current();
+	let reactor:Reactor = react(&listener,&mailbox);
+	logmessage("sent",&serverhost,message.as_bytes(),config.logging.show_hex);
+	// Resend the message until the server acknowledges it, ignoring anything else it sends.
+	let sender:Sender = Sender::new(&listener,serverhost,&pad,validator.clock());
+	let mut deliveries:DeliveryQueue = DeliveryQueue::new(config.retry.delivery_policy());
+	deliveries.push(message.as_bytes().to_vec());
 	loop {
-		match listener.send_to(&payload[..],destaddr) {
-			Ok(nsend) => match nsend < payload.len() {
-				// If the message sends in its entirety, exit with success. If it sends
-				// incompletely, try again.
-				false => return Ok(()),
-				true => (),
+		if let Some(delivery) = deliveries.poll(&sender).pop() {
+			error!("Message was not acknowledged by server at {} after {} attempts.",serverhost,delivery.attempts);
+			process::exit(1);
+		}
+		match reactor.wait(&mut inbin,deliveries.next_deadline()) {
+			Err(why) => {
+				error!("Could not receive acknowledgement - {}",why);
+				process::exit(1);
 			},
-			Err(why) => match why.kind() {
-				// Interrupted just means we need to try again.
-				// WouldBlock for a send operation usually means that the transmit buffer is full.
-				io::ErrorKind::Interrupted => (),
-				io::ErrorKind::WouldBlock => {
-					return Err(why);
-				},
-				_ => {
-					return Err(why);
-				},
+			Ok(Event::Deadline) | Ok(Event::Woken) => (),
+			Ok(Event::Datagram(nrecv,srcaddr)) => {
+				if srcaddr != serverhost {
+					continue;
+				}
+				if let Ok(packet) = Packet::decode(&inbin[0..nrecv],&pad) {
+					if packet.status() != Some(StatusCode::Ack) {
+						continue;
+					}
+					if let Some(delivery) = deliveries.acknowledge() {
+						info!("Message acknowledged by server after {} attempts.",delivery.attempts);
+						return;
+					}
+				}
 			},
 		};
 	}
 }
 
-// Automatically encrypts a vector of bytes and sends them over the socket.
-fn sendbytes(listener:&UdpSocket,destaddr:&SocketAddr,bytes:&Vec<u8>,padpath:&Path) -> Result<(),io::Error> {
-    let mut stampedbytes = bytes.clone();
-    stampedbytes.append(&mut int2bytes(&systime()).to_vec());
-	let payload = match encrypt(&stampedbytes,&padpath) {
-	    Err(why) => {
-	        return Err(why);
-	    },
-	    Ok(b) => b,
-	};
-	return sendraw(&listener,&destaddr,&payload);
+// Writes a new random pad file.
+fn genpad(path:&Path,size:u64,force:bool) {
+	if let Err(why) = teacrypt::generate_pad(path,size,force) {
+		println!("Could not generate pad file {} - {}",path.display(),why);
+		process::exit(1);
+	}
+	println!("Wrote {} random bytes to {}.",size,path.display());
 }
 
-fn main() {
-	if args().count() < 3 || args().count() > 4 {
-		// If the user provides the wrong number of arguments, remind them of how to use this program.
-		println!("Usage: teamech-console [host:remoteport] [localport] [keyfile]");
+// Reads the whole pad and reports on whether it looks usable.
+fn checkpad(config:Config) {
+	let pad:Pad = openpad(&config);
+	let report:PadReport = match pad.report() {
+		Ok(report) => report,
+		Err(why) => {
+			println!("Could not read pad file {} - {}",pad.path().display(),why);
+			process::exit(1);
+		},
+	};
+	println!("Pad: {}",pad.path().display());
+	println!("Size: {} bytes (from offset {})",report.size,pad.offset());
+	println!("Entropy: {:.4} bits per byte",report.entropy());
+	let missing:usize = report.missing();
+	if missing > 0 {
+		// A pad made of random bytes will contain every value unless it's very small.
+		println!("Warning: {} of the 256 byte values never appear in the pad. It may not be random.",missing);
 		process::exit(1);
 	}
-	let mut argv:Vec<String> = Vec::new();
-	let mut flags:HashSet<char> = HashSet::new();
-	let mut switches:HashSet<String> = HashSet::new();
-    for arg in args() {
-        // bin arguments into -flags, --switches, and positional arguments.
-        if arg.starts_with("--") {
-            let _ = switches.insert(arg);
-        } else if arg.starts_with("-") {
-            for c in arg.as_bytes()[1..arg.len()].iter() {
-                let _ = flags.insert(*c as char);
-            }
-        } else {
-            argv.push(arg);
-        }
-    }
-	let mut port:u16 = 0;
-	let mut padpath:&Path = Path::new("");
-	// If a port number was specified (3 arguments), try to parse it and use it. If the second
-	// argument of three was not a valid port number, or there were only three arguments
-	// provided, then we will pass 0 to the OS as the port number, which tells it to
-	// automatically allocate a free UDP port. Unlike for the server, this is a perfectly
-	// reasonable thing to do for the client.
-	if argv.len() == 4 {
-		padpath = Path::new(&argv[3]);
-		if let Ok(n) = argv[2].parse::<u16>() {
-			port = n;
-		} else {
-			println!("Warning: Argument #2 failed to parse as a valid port number. Passing port 0 (auto-allocate) to the OS instead.");
-		}
-	} else if argv.len() == 3 {
-		padpath = Path::new(&argv[2]);
-	}
-	let serverhosts:Vec<SocketAddr> = match argv[1].to_socket_addrs() {
-		Err(_) => {
-			// Failure to parse a remote address is always a fatal error - if this doesn't work, we
-			// have nothing to do.
-			println!("Could not parse argument #1 as an IP address or hostname.");
+	println!("Pad looks usable.");
+}
+
+// Decrypts a captured payload and shows what's inside.
+fn decode(config:Config,payload:&[u8]) {
+	let pad:Pad = openpad(&config);
+	match Packet::decode(payload,&pad) {
+		Ok(packet) => {
+			println!("Text: {}",String::from_utf8_lossy(&packet.content));
+			println!("Bytes: {}",bytes2hex(&packet.content));
+			println!("Timestamp: {} ms ({:+} ms relative to the local clock)",packet.timestamp,
+																			config.protocol.timestamp_validator().skew(packet.timestamp));
+			if let Some(status) = packet.status() {
+				println!("Status code: {}",status);
+			}
+			if fragment::is_fragment(&packet.content) {
+				match fragment::Header::parse(&packet.content) {
+					Ok(header) => println!("Fragment: {} of {} of message {:08x}",header.index+1,header.count,header.id),
+					Err(why) => println!("Malformed fragment - {}",why),
+				};
+			}
+		},
+		Err(why) => {
+			println!("Could not decode payload - {}",why);
 			process::exit(1);
 		},
-		Ok(addrs) => addrs.collect(),
 	};
-	let serverhost:SocketAddr = serverhosts[0];
-	'recovery:loop {
-		// Recovery and operator loop structure is similar to that used in the server; the operator
-		// loop runs constantly while the program is active, while the recovery loop catches breaks
-		// from the operator and smoothly restarts the program in the event of a problem.
-		let listener:UdpSocket = match UdpSocket::bind(&format!("0.0.0.0:{}",port)) {
-			Ok(socket) => socket,
-			Err(why) =>	{
-				// Error condition: bind to local address failed. This is probably caused by a
-				// network issue, a transient OS issue (e.g. network permissions/firewall), or
-				// another program (or another instance of this one) occupying the port the user 
-				// specified. In any case, we can't continue, so we'll let the user know what the
-				// problem is and quit.
-				println!("Could not bind to local address: {}",why.description());
-				process::exit(1);
+}
+
+// Everything the running client keeps track of. Most of it lasts as long as the client does; the
+// rest is started afresh for each socket or subscription.
+struct Client {
+	config:Config,
+	pad:Pad,
+	validator:TimestampValidator,
+	names:ServerNames,
+	failover:Failover,
+	router:Router,
+	scheduler:Scheduler,
+	mailbox:Mailbox,
+	statuses:StatusCounters,
+	// Reliable messages survive resubscribing, and are resent once the client is subscribed again.
+	deliveries:DeliveryQueue,
+	reassembler:Reassembler,
+	// Nonces of messages already accepted, which are kept across resubscriptions (and, if the
+	// cache has a file, across restarts), so that captured packets can't be replayed.
+	replays:ReplayCache,
+	saveinterval:Duration,
+	inbin:Vec<u8>, // input buffer for receiving bytes
+	// For the current socket: its local address, and the messages that have already been received
+	// on it, to merge double-sends.
+	local:SocketAddr,
+	duplicates:DuplicateFilter,
+	// For the current subscription: the server that granted it, and when to send heartbeats.
+	serverhost:SocketAddr,
+	keepalive:Keepalive,
+	// Failures in a row, and how long to wait in the Backoff state.
+	retries:Backoff,
+	backoff:Duration,
+	// Whether the client ran out of retries.
+	gaveup:bool,
+	stop:ShutdownRequest,
+	// For waking the client up from other threads, e.g. when a lookup of the server names is done.
+	outbox:Outbox,
+	// Where to tell systemd how the client is doing, if anywhere, and when to ping its watchdog.
+	notifier:Notifier,
+	watchdog:Watchdog,
+}
+
+impl Client {
+	// Sets everything up, or quits if that isn't possible.
+	fn new(config:Config,outbox:Outbox,mailbox:Mailbox,scheduler:Scheduler,stop:ShutdownRequest) -> Client {
+		let validator:TimestampValidator = config.protocol.timestamp_validator();
+		checkclock(&validator);
+		let pad:Pad = openpad(&config);
+		let mut names:ServerNames = servernames(&config);
+		let failover:Failover = resolve(&config,&mut names);
+		let mut router:Router = Router::new();
+		register_handlers(&mut router);
+		if let Err(why) = config.register_handlers(&mut router) {
+			error!("{}",why);
+			process::exit(1);
+		}
+		let mut replays:ReplayCache = config.protocol.replay_cache();
+		match replays.load(validator.clock().now()) {
+			Ok(0) => (),
+			Ok(count) => info!("Loaded {} recent message nonces from the replay cache.",count),
+			Err(why) => warn!("{}. Starting with an empty replay cache.",why),
+		};
+		if replays.evicted() > 0 {
+			warn!("Replay cache file holds more recent nonces than protocol.replay_capacity allows; {} were forgotten.",replays.evicted());
+		}
+		let notifier:Notifier = match config.systemd.notify {
+			true => Notifier::from_env(),
+			false => Notifier::default(),
+		};
+		let watchdog:Watchdog = match config.systemd.watchdog && notifier.is_enabled() {
+			true => Watchdog::from_env(),
+			false => Watchdog::new(None),
+		};
+		if let Some(interval) = watchdog.interval() {
+			info!("Pinging the systemd watchdog every {} ms.",interval.as_millis());
+		}
+		return Client {
+			pad,
+			validator,
+			router,
+			scheduler,
+			mailbox,
+			statuses:StatusCounters::new(),
+			deliveries:DeliveryQueue::new(config.retry.delivery_policy()),
+			reassembler:Reassembler::new(config.protocol.reassembly_limits()),
+			replays,
+			saveinterval:Duration::from_millis(config.protocol.replay_save_interval_ms),
+			inbin:vec![0;RECEIVE_BUFFER_SIZE],
+			local:config.network.local_address(&failover.current()),
+			duplicates:DuplicateFilter::new(config.protocol.dedup_window),
+			serverhost:failover.current(),
+			keepalive:Keepalive::new(config.keepalive.policy()),
+			retries:Backoff::new(config.retry.backoff_policy()),
+			backoff:Duration::from_millis(config.retry.error_delay_ms),
+			gaveup:false,
+			stop,
+			outbox,
+			notifier,
+			watchdog,
+			names,
+			failover,
+			config,
+		};
+	}
+
+	// Unbound: binds a socket that can reach the server in use.
+	fn bind(&mut self) -> UdpSocket {
+		self.local = self.config.network.local_address(&self.failover.current());
+		self.duplicates = DuplicateFilter::new(self.config.protocol.dedup_window);
+		return bind(&self.config,self.local);
+	}
+
+	// Counts a subscription request the server didn't answer, like unanswered(), but without
+	// waiting for the server names to be looked up again: the next server is tried while that's
+	// going on, and if the server has moved, its new address is used once the lookup is in.
+	fn unanswered(&mut self) {
+		if !self.failover.unanswered() {
+			return;
+		}
+		self.names.expire();
+		if reresolve_in_background(&self.config,&mut self.names,&mut self.failover,&self.outbox) {
+			return;
+		}
+		failedover(self.failover.fail_over());
+	}
+
+	// Subscribing: asks the server in use for a subscription.
+	fn subscribe(&mut self,listener:&UdpSocket) -> ConnectionEvent {
+		if self.stop.is_requested() {
+			return ConnectionEvent::Shutdown;
+		}
+		self.watchdog.ping(&self.notifier,Instant::now());
+		reresolve_in_background(&self.config,&mut self.names,&mut self.failover,&self.outbox);
+		// A server of the other address family needs a socket of its own.
+		if self.config.network.local_address(&self.failover.current()) != self.local {
+			return ConnectionEvent::Reset;
+		}
+		let reactor:Reactor = react(listener,&self.mailbox);
+		let sent:u64 = self.validator.clock().local();
+		let sender:Sender = Sender::new(listener,self.failover.current(),&self.pad,self.validator.clock());
+		match subscribe(&sender,&reactor,&self.pad,&self.config,&mut self.inbin,&mut self.statuses) {
+			Subscription::Subscribed(servertime) => {
+				calibrate(&mut self.validator,sent,servertime);
+				self.failover.subscribed();
+				self.serverhost = self.failover.current();
+				self.keepalive = Keepalive::new(self.config.keepalive.policy());
+				self.retries.reset();
+				return ConnectionEvent::Granted;
+			},
+			Subscription::NoResponse => {
+				self.unanswered();
+				return self.retry(ConnectionEvent::Unanswered);
+			},
+			Subscription::Failed => {
+				self.unanswered();
+				return self.retry(ConnectionEvent::Failed);
 			},
 		};
-		match listener.set_nonblocking(true) {
-			Ok(_) => (),
-			Err(why) => {
-				// This is probably a platform error - it's not clear to me when this would happen,
-				// but it probably means that the OS doesn't support nonblocking UDP sockets, which
-				// is weird and means this program won't really work. Hopefully, the error message
-				// will be useful to the user.
-				println!("Could not set socket to nonblocking mode: {}",why.description());
-				process::exit(1);
+	}
+
+	// Counts a failed attempt to subscribe, and works out how long to back off for, which grows
+	// with each failure in a row (see backoff.rs). Returns `event`, or Shutdown if the client has
+	// run out of retries.
+	fn retry(&mut self,event:ConnectionEvent) -> ConnectionEvent {
+		match self.retries.fail() {
+			Some(delay) => {
+				if event == ConnectionEvent::Failed {
+					info!("Trying again in {} ms.",delay.as_millis());
+				}
+				self.backoff = delay;
+				return event;
 			},
-		}
-		// Set up some system state machinery
-		let mut inbin:[u8;500] = [0;500]; // input buffer for receiving bytes
-		let mut lastmsgs:Vec<Vec<u8>> = Vec::new(); // keeps track of messages that have already been received, to merge double-sends.
-		'authtry:loop {
-			println!("Trying to contact server...");
-			match sendbytes(&listener,&serverhost,&vec![],&padpath) {
+			None => return self.giveup(),
+		};
+	}
+
+	// Backoff: waits before asking for a subscription again, unless the client is shut down in the
+	// meantime. Anything that arrives from the server is ignored.
+	fn back_off(&mut self,listener:&UdpSocket) -> ConnectionEvent {
+		let reactor:Reactor = react(listener,&self.mailbox);
+		let deadline:Instant = Instant::now() + self.backoff;
+		loop {
+			if self.stop.is_requested() {
+				return ConnectionEvent::Shutdown;
+			}
+			let now:Instant = Instant::now();
+			if now >= deadline {
+				return ConnectionEvent::Retry;
+			}
+			// Backing off can take longer than the watchdog allows, so keep pinging it meanwhile.
+			self.watchdog.ping(&self.notifier,now);
+			let wake:Option<Instant> = [Some(deadline),self.watchdog.next_ping()].iter().flatten().min().cloned();
+			match reactor.wait(&mut self.inbin,wake) {
+				Ok(_) => (),
 				Err(why) => {
-				    println!("Could not send authentication payload - {}",why.description());
-					sleep(Duration::new(5,0));
-					continue 'authtry;
+					warn!("Could not receive packet - {}",why);
+					sleep(wake.unwrap_or(deadline).saturating_duration_since(Instant::now()));
 				},
-				Ok(_) => (),
 			};
-			for _ in 0..10 {
-				sleep(Duration::new(0,100_000_000));
-				match listener.recv_from(&mut inbin) {
-					Err(why) => match why.kind() {
-						io::ErrorKind::WouldBlock => (),
-						_ => {
-						    println!("Could not receive authentication response - {}",why.description());
-							sleep(Duration::new(5,0));
-							continue 'authtry;
-						},
-					},
-					Ok((nrecv,srcaddr)) => {
-					    if nrecv == 25 && srcaddr == serverhost {
-						    match decrypt(&inbin[0..25].to_vec(),&padpath) {
-						        Ok(message) => match message[0] {
-						            0x02 => {
-						                println!("Subscribed to server at {}",serverhost);
-							            break 'authtry;
-							        },
-							        0x19 => {
-							            println!("Pad file is correct, but subscription was rejected by server. Server may be full.");
-							            sleep(Duration::new(5,0));
-							        },
-							        other => {
-							            println!("Server at {} sent an unknown status code {}. Is this the latest client version?",
-							                                                                                        serverhost,other);
-							        },
-							    }, // decrypt Ok
-							    Err(why) => match why.kind() {
-							        io::ErrorKind::InvalidData => {
-							            println!("Response from server did not validate. Local pad file is incorrect or invalid.");
-							            sleep(Duration::new(5,0));
-							        }
-							        _ => {
-							            println!("Failed to decrypt response from server - {}",why.description());
-							            sleep(Duration::new(5,0));
-							        },
-							    }, // match why.kind
-                            }; // match inbin[0]
-                        } else { // if nrecv == 1
-							println!("Got invalid message of length {} from {}.",nrecv,srcaddr);
-							sleep(Duration::new(5,0));
-                        }
-					}, // recv Ok
-				}; // match recv
-			} // for 0..10
-		} // 'authtry
+		}
+	}
+
+	fn giveup(&mut self) -> ConnectionEvent {
+		error!("Giving up after {} failures in a row.",self.retries.failures());
+		self.gaveup = true;
+		return ConnectionEvent::Shutdown;
+	}
+
+	// Subscribed: handles whatever happens until something ends the subscription.
+	fn operate(&mut self,listener:&UdpSocket) -> ConnectionEvent {
 		// Yay! If we made it down here, that means we're successfully authenticated and
 		// subscribed, and can start doing the things this program is actually meant for.
-		'operator:loop {
-			sleep(Duration::new(0,1_000_000));
-			// ATTENTION
-			// Code that should run continuously (not just once every time a new message comes in)
-			// should go here. It may call sendbytes() as necessary to send outgoing messages, and
-			// should let go of the thread often (e.g. don't add any infinite loops here) to allow
-			// messages to be received.
-			'receiver:loop {
-				match listener.recv_from(&mut inbin) {
-					Err(why) => match why.kind() {
-						io::ErrorKind::WouldBlock => break 'receiver,
-						_ => {
-							// Receive error
-							println!("Could not receive packet: {}. Trying again in 5 seconds...",why.description());
-							sleep(Duration::new(5,0));
-						},
+		let reactor:Reactor = react(listener,&self.mailbox);
+		loop {
+			if let Some(event) = self.step(listener,&reactor) {
+				return event;
+			}
+		}
+	}
+
+	// Does one round of work while subscribed: runs what has come due, then waits for something to
+	// happen and deals with it. Returns the event that ends the subscription, if anything does.
+	fn step(&mut self,listener:&UdpSocket,reactor:&Reactor) -> Option<ConnectionEvent> {
+		if self.stop.is_requested() {
+			return Some(ConnectionEvent::Shutdown);
+		}
+		// Tell systemd the client is still getting round its loop. If a handler or task hangs, the
+		// pings stop, and systemd restarts the client.
+		self.watchdog.ping(&self.notifier,Instant::now());
+		let serverhost:SocketAddr = self.serverhost;
+		let pad:&Pad = &self.pad;
+		let clock:&Clock = self.validator.clock();
+		let showhex:bool = self.config.logging.show_hex;
+		// Run any periodic tasks that have come due (see register_tasks()), and send anything other
+		// threads have queued up in the meantime.
+		let sender:Sender = Sender::new(listener,serverhost,pad,clock);
+		let unreliable:Cell<usize> = Cell::new(0);
+		self.scheduler.run_due(&sender.counting(&unreliable));
+		self.deliveries.sent_unreliable(unreliable.get());
+		post(&self.mailbox,&mut self.deliveries,&sender);
+		// Send or resend reliable messages, and give up on any that have run out of time. A message
+		// going unacknowledged for that long means the server has stopped answering, so try the
+		// next one.
+		let abandoned:Vec<Delivery> = self.deliveries.poll(&sender);
+		for delivery in abandoned.iter() {
+			warn!("Message was not acknowledged by server after {} attempts; giving up.",delivery.attempts);
+		}
+		if !abandoned.is_empty() {
+			self.names.expire();
+			if reresolve_in_background(&self.config,&mut self.names,&mut self.failover,&self.outbox) {
+				return Some(ConnectionEvent::Reset);
+			}
+			if self.failover.servers().len() > 1 {
+				failedover(self.failover.fail_over());
+				return Some(ConnectionEvent::Reset);
+			}
+		}
+		// Renew the subscription now and then, which also keeps NAT mappings open, and if the
+		// server has gone quiet for too long, assume it's gone and subscribe again.
+		let now:Instant = Instant::now();
+		if let Some(silence) = self.keepalive.silence(now) {
+			warn!("Server {} has been silent for {} ms; subscribing again.",serverhost,silence.as_millis());
+			return Some(ConnectionEvent::Reset);
+		}
+		if let Some(missed) = self.keepalive.beat(now) {
+			if missed > 0 {
+				warn!("Server {} has not answered the last {} heartbeat(s).",serverhost,missed);
+			}
+			if let Err(why) = sendbytes(listener,&serverhost,&[],pad,clock) {
+				warn!("Could not send heartbeat - {}",why);
+			}
+		}
+		// Look the server names up again if it's time, in case the server has moved.
+		if reresolve_in_background(&self.config,&mut self.names,&mut self.failover,&self.outbox) {
+			return Some(ConnectionEvent::Reset);
+		}
+		// While using a backup server, check now and then whether a more preferred one is back.
+		// (Servers of the other address family can only be checked from a dual-stack socket.)
+		for probe in self.failover.probe(Instant::now()) {
+			let _ = sendbytes(listener,probe,&[],pad,clock);
+		}
+		// Save the replay cache if it's due.
+		if self.replays.next_save(self.saveinterval).is_some_and(|due| due <= Instant::now()) {
+			if let Err(why) = self.replays.save() {
+				warn!("{}",why);
+			}
+		}
+		// Forget about fragmented messages that were never finished.
+		let expired:usize = self.reassembler.expire();
+		if expired > 0 {
+			warn!("Gave up waiting for the rest of {} fragmented message(s).",expired);
+		}
+		// Sleep until a message arrives, the next task comes due, or another thread queues a
+		// message to send.
+		let deadline:Option<Instant> = [
+			self.scheduler.next_deadline(),
+			self.deliveries.next_deadline(),
+			self.reassembler.next_deadline(),
+			self.replays.next_save(self.saveinterval),
+			self.failover.next_probe(),
+			self.names.next_lookup(),
+			self.keepalive.next_deadline(),
+			self.watchdog.next_ping(),
+		].iter().flatten().min().cloned();
+		let (nrecv,srcaddr) = match reactor.wait(&mut self.inbin,deadline) {
+			Err(why) => {
+				// Receive error. Wait a while before trying again, longer each time this happens in a
+				// row.
+				match self.retries.fail() {
+					Some(delay) => {
+						warn!("Could not receive packet: {}. Trying again in {} ms...",why,delay.as_millis());
+						sleep(delay);
+						return None;
 					},
-					Ok((nrecv,srcaddr)) => {
-						if srcaddr != serverhost {
-							continue 'operator;
-						}
-						if nrecv > 24 {
-							if lastmsgs.contains(&inbin[0..nrecv].to_vec()) {
-								// Ignore the payload if it's a duplicate. This will never
-								// false-positive, because even repeated messages will be encrypted
-								// with different keys and generate different payloads. Repeated
-								// payloads are always messages that were double-sent or replayed,
-								// and not the client deliberately sending the same thing again.
-								continue 'operator;
-							} else {
-								lastmsgs.push(inbin[0..nrecv].to_vec());
-								if lastmsgs.len() > 32 {
-									lastmsgs.reverse();
-									let _ = lastmsgs.pop();
-									lastmsgs.reverse();
-								}
+					None => return Some(self.giveup()),
+				};
+			},
+			Ok(Event::Deadline) | Ok(Event::Woken) => return None,
+			Ok(Event::Datagram(nrecv,srcaddr)) => (nrecv,srcaddr),
+		};
+		if srcaddr != serverhost {
+			// A more preferred server granting a subscription means it's back.
+			if self.failover.prefers(srcaddr) {
+				if let Ok(packet) = Packet::decode(&self.inbin[0..nrecv],pad) {
+					if packet.status() == Some(StatusCode::Subscribed) && self.validator.check(packet.timestamp).is_ok()
+																&& admit(&mut self.replays,&packet,clock) {
+						if let Some(switch) = self.failover.fail_back(srcaddr) {
+							info!("Server {} is available again; failing back from {}.",switch.to,switch.from);
+							// The server being left is still answering, and would otherwise keep
+							// relaying messages here until the subscription ran out.
+							if let Err(why) = sendstatus(listener,&switch.from,StatusCode::Unsubscribe,pad,clock) {
+								warn!("Could not tell server {} that the client is leaving - {}",switch.from,why);
 							}
-							let payload:Vec<u8> = inbin[0..nrecv].to_vec();
-							match decrypt(&payload,&padpath) {
-								Err(why) => match why.kind() {
-									io::ErrorKind::InvalidData => {
-										// Validation failed
-										println!("Warning: Message failed to validate. Pad file may be incorrect.");
-										let _ = sendbytes(&listener,&srcaddr,&vec![0x15],&padpath);
-										sleep(Duration::new(2,0));
-										break 'operator;
-									},
-									_ => {
-										// Other decryption error.
-										println!("Decrypting of message failed - {}.",why.description());
-										let _ = sendbytes(&listener,&srcaddr,&vec![0x1A],&padpath);
-									},
-								},
-								Ok(message) => {
-									let messagechars:Vec<u8> = message[0..message.len()-8].to_vec();
-									let mut messagetext:String = String::from_utf8_lossy(&messagechars).to_string();
-									let mut timestamp:[u8;8] = [0;8];
-									timestamp.copy_from_slice(&message[message.len()-8..message.len()]);
-									let msgtime:u64 = bytes2int(&timestamp);
-									if msgtime + MSG_VALID_TIME < systime() {
-										continue 'operator;
-									} else if msgtime - MSG_VALID_TIME > systime() {
-										continue 'operator;
-						            }
-						            if nrecv == 25 {
-							            // payloads of one byte are messages from the server.
-								        println!("[SRV]: 0x{}",&bytes2hex(&vec![message[0]]));
-							            if inbin[0] == 0x19 { // END OF MEDIUM
-								            // Handle deauthentications
-								            println!("Subscription expiration notification received - renewing subscription to {}",serverhost);
-								            continue 'recovery;
-							            }
-							            continue 'operator;
-						            } 
-									if switches.contains("--showhex") || flags.contains(&'h') {
-									    println!("\r[REM]: {} [{}]",messagetext,bytes2hex(&messagechars));
-									} else {
-									    println!("\r[REM]: {}",messagetext);
-									}
-									// ATTENTION
-									// From here on, you can add your own handlers for incoming
-									// messages and whatever else that should happen every time a
-									// new message arrives (this block will not run continuously;
-									// see above for where to put your code if you want that).
-									// You can put if statements and match blocks here. Here are
-									// the available variables:
-									// - payload:Vec<u8> - the message in encrypted form.
-									// - messagetext:String - the verbatim text of the message.
-									// - messagechars:Vec<u8> - the message in byte form.
-									// - message:Vec<u8> - the message in byte form, including the
-									//                     timestamp.
-									// - timestamp:[u8;8] - Unix timestamp of the message (bytes).
-									// - msgtime:u64 - Unix timestamp of the message (int).
-									// - serverhost:SocketAddr - address of the server (with port).
-									// - lastmsgs:Vec<Vec<u8>> - the last <32 messages received
-									//                           from the server in encrypted form.
-									let _ = sendbytes(&listener,&srcaddr,&vec![0x06],&padpath);
-									let mut reply:String = String::new();
-			                        match &messagetext as &str { 
-			                            // Match various messages that you might want to do
-			                            // something upon receiving (e.g. commands to read a sensor
-			                            // or provide status information, or change the state of a
-			                            // piece of equipment).
-				                        "Hello world!" => { 
-					                        reply = String::from("Hello world!");
-				                        },
-				                        _ => (),
-			                        }; // match &messagetext
-			                        if reply.len() > 0 {
-			                            let replybytes:Vec<u8> = reply.as_bytes().to_vec();
-					                    if switches.contains("--showhex") || flags.contains(&'h') {
-						                    println!("\r[LOC]: {} [{}]",reply,bytes2hex(&replybytes));
-					                    } else {
-						                    println!("\r[LOC]: {}",reply);
-					                    }
-					                    // Send (and encrypt) the message.
-					                    match sendbytes(&listener,&serverhost,&replybytes,&padpath) {
-						                    Err(why) => {
-							                    println!("Encrypting message failed - {}",why.description());
-							                    continue 'operator;
-						                    },
-						                    Ok(_) => (),
-					                    };
-					                } // if reply.len() > 0
-								}, // decrypt Ok(message)
-							}; // match decrypt
-						} // if nrecv > 24
-					}, // recv Ok((nrecv,srcaddr))
-				}; // match recv_from
-			} // 'receiver
-		} // 'operator
-	} // 'recovery
-} // fn main
+							return Some(ConnectionEvent::Reset);
+						}
+					}
+				}
+			}
+			return None;
+		}
+		if nrecv <= 24 {
+			return None;
+		}
+		if !self.duplicates.admit(&self.inbin[0..nrecv]) {
+			// Ignore the payload if it's a duplicate (see dedup.rs).
+			return None;
+		}
+		let packet:Packet = match Packet::decode(&self.inbin[0..nrecv],pad) {
+			Err(PacketError::Crypto(teacrypt::Error::SignatureInvalid)) => {
+				// Validation failed
+				warn!("Message failed to validate. Pad file may be incorrect.");
+				let _ = sendstatus(listener,&srcaddr,StatusCode::ValidationFailed,pad,clock);
+				self.backoff = Duration::from_millis(self.config.retry.validation_delay_ms);
+				return Some(ConnectionEvent::Failed);
+			},
+			Err(why) => {
+				// Other decryption error.
+				warn!("Decrypting of message failed - {}.",why);
+				let _ = sendstatus(listener,&srcaddr,StatusCode::DecryptFailed,pad,clock);
+				return None;
+			},
+			Ok(packet) => packet,
+		};
+		let msgtime:u64 = packet.timestamp;
+		if let Err(stale) = self.validator.check(msgtime) {
+			warn!("Ignored message from {} - {}.",srcaddr,stale);
+			return None;
+		}
+		if !admit(&mut self.replays,&packet,clock) {
+			warn!("Ignored replayed message from {} (timestamp {}).",srcaddr,msgtime);
+			return None;
+		}
+		self.retries.reset();
+		let missed:u32 = self.keepalive.heard();
+		if missed > 0 {
+			info!("Server {} is answering again, after missing {} heartbeat(s).",serverhost,missed);
+		}
+		if let Some(code) = packet.status() {
+			// payloads of one byte are status codes from the server.
+			info!(target:logging::MESSAGES,direction="received",server:% = serverhost,status=code.to_byte(); "{}",code);
+			match record(&mut self.statuses,code) {
+				StatusCode::Rejected => {
+					// Handle deauthentications
+					info!("Subscription expiration notification received - renewing subscription to {}",serverhost);
+					return Some(ConnectionEvent::Reset);
+				},
+				StatusCode::ValidationFailed => {
+					warn!("Server could not validate a message. Pad file may be incorrect.");
+				},
+				StatusCode::DecryptFailed => {
+					warn!("Server could not decrypt a message.");
+				},
+				StatusCode::Ack => {
+					// Acknowledgements don't say what they're for; the delivery queue works out
+					// whether this one can only be for the reliable message it's waiting on.
+					let _ = self.deliveries.acknowledge();
+				},
+				// Answers to heartbeats need no action.
+				StatusCode::Subscribed => (),
+				// Only clients send this.
+				StatusCode::Unsubscribe => (),
+				// Already logged by record().
+				StatusCode::Unknown(_) => (),
+			};
+			return None;
+		}
+		let _ = sendstatus(listener,&srcaddr,StatusCode::Ack,pad,clock);
+		// Large messages arrive in fragments, which are acknowledged one by one, and handled once
+		// they've all arrived.
+		let messagechars:Vec<u8> = match fragment::is_fragment(&packet.content) {
+			false => packet.content,
+			true => match self.reassembler.accept(&packet.content) {
+				Ok(Some(message)) => message,
+				Ok(None) => return None,
+				Err(why) => {
+					warn!("Dropped fragmented message - {}",why);
+					return None;
+				},
+			},
+		};
+		let messagetext:String = String::from_utf8_lossy(&messagechars).to_string();
+		logmessage("received",&serverhost,&messagechars,showhex);
+		let incoming:Message = Message {
+			text:&messagetext,
+			bytes:&messagechars,
+			timestamp:msgtime,
+			server:serverhost,
+		};
+		// Hand the message to whichever handler was registered for it in register_handlers().
+		if let Some(replybytes) = self.router.dispatch(&incoming) {
+			logmessage("sent",&serverhost,&replybytes,showhex);
+			// Send (and encrypt) the message.
+			self.deliveries.sent_unreliable(fragment::count(&replybytes));
+			if let Err(why) = sendmessage(listener,&serverhost,&replybytes,pad,clock) {
+				warn!("Encrypting message failed - {}",why);
+			}
+		}
+		return None;
+	}
 
+	// Shutdown: finishes delivering what it can (if subscribed), tells the server it's leaving,
+	// lets the handlers tidy up, and saves the replay cache. Returns the exit status.
+	fn shut_down(&mut self,listener:Option<&UdpSocket>,subscribed:bool) -> i32 {
+		let mut undelivered:usize = 0;
+		match listener {
+			Some(listener) if subscribed => {
+				undelivered += self.drain(listener);
+				if self.config.shutdown.goodbye {
+					if let Err(why) = sendstatus(listener,&self.serverhost,StatusCode::Unsubscribe,&self.pad,self.validator.clock()) {
+						warn!("Could not tell the server that the client is leaving - {}",why);
+					}
+				}
+			},
+			// Without a subscription, nothing can be delivered.
+			_ => undelivered += self.mailbox.take().iter().filter(|letter| letter.notify.is_some()).count(),
+		};
+		// Anything still queued is dropped, which tells whoever is waiting on its receipt that it
+		// wasn't delivered.
+		undelivered += self.deliveries.len();
+		if undelivered > 0 {
+			warn!("{} reliable message(s) were not delivered.",undelivered);
+		}
+		self.router.shutdown();
+		if let Err(why) = self.replays.save() {
+			warn!("{}",why);
+		}
+		info!("Shut down.");
+		if self.gaveup {
+			return EXIT_GAVE_UP;
+		}
+		if undelivered > 0 {
+			return EXIT_UNDELIVERED;
+		}
+		return 0;
+	}
+
+	// Sends whatever other threads have queued, and waits for reliable messages to be acknowledged,
+	// for up to the drain timeout. Returns how many were given up on.
+	fn drain(&mut self,listener:&UdpSocket) -> usize {
+		let reactor:Reactor = react(listener,&self.mailbox);
+		let deadline:Instant = Instant::now() + self.config.shutdown.drain_timeout();
+		let sender:Sender = Sender::new(listener,self.serverhost,&self.pad,self.validator.clock());
+		post(&self.mailbox,&mut self.deliveries,&sender);
+		if !self.deliveries.is_empty() {
+			info!("Waiting for {} reliable message(s) to be delivered...",self.deliveries.len());
+		}
+		let mut abandoned:usize = 0;
+		loop {
+			self.watchdog.ping(&self.notifier,Instant::now());
+			post(&self.mailbox,&mut self.deliveries,&sender);
+			abandoned += self.deliveries.poll(&sender).len();
+			if self.deliveries.is_empty() || Instant::now() >= deadline {
+				return abandoned;
+			}
+			let wake:Option<Instant> = [self.deliveries.next_deadline(),Some(deadline),self.watchdog.next_ping()].iter().flatten().min().cloned();
+			match reactor.wait(&mut self.inbin,wake) {
+				Ok(Event::Datagram(nrecv,srcaddr)) if srcaddr == self.serverhost => {
+					if let Ok(packet) = Packet::decode(&self.inbin[0..nrecv],&self.pad) {
+						if packet.status() == Some(StatusCode::Ack) {
+							let _ = self.deliveries.acknowledge();
+						}
+					}
+				},
+				Ok(_) => (),
+				Err(why) => {
+					warn!("Could not receive acknowledgement - {}",why);
+					return abandoned;
+				},
+			};
+		}
+	}
+}
+
+// Sends the messages other threads have queued through their outboxes, or queues them for
+// reliable delivery.
+fn post(mailbox:&Mailbox,deliveries:&mut DeliveryQueue,sender:&Sender) {
+	for letter in mailbox.take() {
+		match letter.notify {
+			Some(notify) => {
+				deliveries.push_notify(letter.bytes,notify);
+			},
+			None => {
+				deliveries.sent_unreliable(fragment::count(&letter.bytes));
+				if let Err(why) = sender.send(&letter.bytes) {
+					warn!("Sending queued message failed - {}",why);
+				}
+			},
+		};
+	}
+}
+
+// Keeps systemd up to date with the connection's state: READY=1 once the client is first
+// subscribed, STATUS= on every change, and STOPPING=1 when it starts shutting down.
+fn notify_systemd(connection:&mut Connection,notifier:&Notifier) {
+	if !notifier.is_enabled() {
+		return;
+	}
+	let notifier:Notifier = notifier.clone();
+	let mut ready:bool = false;
+	connection.on_change(move |change:&Change| {
+		let mut state:String = format!("STATUS={}",change.to);
+		if change.to == ConnectionState::Subscribed && !ready {
+			state.push_str("\nREADY=1");
+			ready = true;
+		}
+		if change.to == ConnectionState::Shutdown {
+			state.push_str("\nSTOPPING=1");
+		}
+		if let Err(why) = notifier.notify(&state) {
+			warn!("Could not notify systemd - {}",why);
+		}
+	});
+}
+
+// Exit statuses of the run command, besides 0 for a clean shutdown, 1 for fatal errors, 2 for bad
+// usage, and 128 plus the signal number when stopped by a second signal.
+static EXIT_UNDELIVERED:i32 = 3;
+static EXIT_GAVE_UP:i32 = 4;
+
+// Runs the client until it's stopped by a signal, or can't carry on.
+fn run(config:Config) {
+	let mut scheduler:Scheduler = Scheduler::new();
+	let (outbox,mailbox) = reactor::mailbox();
+	register_tasks(&mut scheduler,&outbox);
+	let stop:ShutdownRequest = ShutdownRequest::new(outbox.clone());
+	if let Err(why) = shutdown::on_signals(&stop) {
+		warn!("Could not handle signals - {}",why);
+	}
+	let mut client:Client = Client::new(config,outbox,mailbox,scheduler,stop);
+	let mut connection:Connection = Connection::new();
+	notify_systemd(&mut connection,&client.notifier);
+	register_callbacks(&mut connection);
+	// The client does whatever its state calls for, and what happens decides the next state (see
+	// connection.rs). A new socket is bound whenever the server in use changes, or stops
+	// recognising the client.
+	let mut listener:Option<UdpSocket> = None;
+	let mut previous:ConnectionState = connection.state();
+	loop {
+		let event:ConnectionEvent = match connection.state() {
+			ConnectionState::Unbound => {
+				listener = Some(client.bind());
+				ConnectionEvent::Bound
+			},
+			ConnectionState::Backoff => match listener {
+				Some(ref listener) => client.back_off(listener),
+				None => ConnectionEvent::Reset,
+			},
+			ConnectionState::Subscribing => match listener {
+				Some(ref listener) => client.subscribe(listener),
+				None => ConnectionEvent::Reset,
+			},
+			ConnectionState::Subscribed => match listener {
+				Some(ref listener) => client.operate(listener),
+				None => ConnectionEvent::Reset,
+			},
+			ConnectionState::Shutdown => {
+				let subscribed:bool = previous == ConnectionState::Subscribed;
+				process::exit(client.shut_down(listener.as_ref(),subscribed));
+			},
+		};
+		previous = connection.state();
+		connection.handle(event);
+	}
+}
@@ -0,0 +1,156 @@
+// Sending messages to the server.
+//
+// The client's socket can be IPv4 or IPv6, or dual-stack: an IPv6 socket that also reaches IPv4
+// hosts, which it sees as IPv4-mapped IPv6 addresses (::ffff:a.b.c.d). Addresses are converted to
+// that form when sending, and back when receiving (see canonical()), so that the rest of the
+// client only ever deals with plain IPv4 addresses for IPv4 hosts.
+
+use std::cell::Cell;
+use std::io;
+use std::net::{UdpSocket,SocketAddr,SocketAddrV6};
+
+use socket2::{Domain,Protocol,Socket,Type};
+
+use clock::Clock;
+use fragment;
+use packet::Packet;
+use status::StatusCode;
+use teacrypt::Pad;
+
+/// How big receive buffers should be: big enough for any UDP datagram, so that nothing received
+/// is ever truncated.
+pub static RECEIVE_BUFFER_SIZE:usize = 65_536;
+
+/// Binds a UDP socket to a local address. With `dualstack`, an IPv6 socket will also reach IPv4
+/// hosts; otherwise it reaches only IPv6 ones, whatever the operating system's default is. An
+/// IPv4 socket only ever reaches IPv4 hosts.
+pub fn bind(local:SocketAddr,dualstack:bool) -> Result<UdpSocket,io::Error> {
+	let socket:Socket = Socket::new(Domain::for_address(local),Type::DGRAM,Some(Protocol::UDP))?;
+	if local.is_ipv6() {
+		socket.set_only_v6(!dualstack)?;
+	}
+	socket.bind(&local.into())?;
+	return Ok(socket.into());
+}
+
+/// Turns an IPv4-mapped IPv6 address, as a dual-stack socket reports IPv4 hosts, back into an
+/// IPv4 address. Other addresses are returned unchanged.
+pub fn canonical(addr:SocketAddr) -> SocketAddr {
+	if let SocketAddr::V6(v6) = addr {
+		if let Some(ip) = v6.ip().to_ipv4_mapped() {
+			return SocketAddr::new(ip.into(),v6.port());
+		}
+	}
+	return addr;
+}
+
+// The address to send to in order to reach `destaddr` from the socket: an IPv4-mapped address
+// for an IPv4 host, if the socket is IPv6, or otherwise `destaddr` itself.
+fn route(listener:&UdpSocket,destaddr:&SocketAddr) -> SocketAddr {
+	if let SocketAddr::V4(v4) = destaddr {
+		if let Ok(SocketAddr::V6(_)) = listener.local_addr() {
+			return SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(),v4.port(),0,0));
+		}
+	}
+	return *destaddr;
+}
+
+/// Sends a slice of bytes to a specific host over a specific socket, automatically retrying in
+/// the event of certain errors and aborting in the event of others.
+pub fn sendraw(listener:&UdpSocket,destaddr:&SocketAddr,payload:&[u8]) -> Result<(),io::Error> {
+	let destaddr:SocketAddr = route(listener,destaddr);
+	// loop until either the send completes or an unignorable error occurs.
+	loop {
+		match listener.send_to(payload,destaddr) {
+			Ok(nsend) => {
+				// If the message sends in its entirety, exit with success. If it sends
+				// incompletely, try again.
+				if nsend >= payload.len() {
+					return Ok(());
+				}
+			},
+			Err(why) => match why.kind() {
+				// Interrupted just means we need to try again.
+				// WouldBlock for a send operation usually means that the transmit buffer is full.
+				io::ErrorKind::Interrupted => (),
+				_ => {
+					return Err(why);
+				},
+			},
+		};
+	}
+}
+
+/// Automatically timestamps and encrypts a slice of bytes and sends them over the socket. The
+/// timestamp comes from `clock`, so it's corrected for the server's clock offset if that has been
+/// estimated (see clock.rs).
+pub fn sendbytes(listener:&UdpSocket,destaddr:&SocketAddr,bytes:&[u8],pad:&Pad,clock:&Clock) -> Result<(),io::Error> {
+	let payload = match Packet::encode(bytes,clock.now(),pad) {
+		Err(why) => {
+			return Err(io::Error::other(why));
+		},
+		Ok(b) => b,
+	};
+	return sendraw(listener,destaddr,&payload);
+}
+
+/// Sends a message of any size, splitting it into fragments if it doesn't fit in one packet.
+pub fn sendmessage(listener:&UdpSocket,destaddr:&SocketAddr,bytes:&[u8],pad:&Pad,clock:&Clock) -> Result<(),io::Error> {
+	let pieces:Vec<Vec<u8>> = match fragment::split(bytes) {
+		Ok(pieces) => pieces,
+		Err(why) => return Err(io::Error::new(io::ErrorKind::InvalidInput,why)),
+	};
+	for piece in pieces.iter() {
+		sendbytes(listener,destaddr,piece,pad,clock)?;
+	}
+	return Ok(());
+}
+
+/// Sends a status code (a one-byte message) over the socket.
+pub fn sendstatus(listener:&UdpSocket,destaddr:&SocketAddr,code:StatusCode,pad:&Pad,clock:&Clock) -> Result<(),io::Error> {
+	return sendbytes(listener,destaddr,&[code.to_byte()],pad,clock);
+}
+
+/// A handle for sending encrypted messages to the server, without access to the rest of the
+/// client's state. This is what scheduled tasks get to talk to the server with.
+#[derive(Clone,Copy)]
+pub struct Sender<'a> {
+	listener:&'a UdpSocket,
+	server:SocketAddr,
+	pad:&'a Pad,
+	clock:&'a Clock,
+	sent:Option<&'a Cell<usize>>,
+}
+
+impl<'a> Sender<'a> {
+	pub fn new(listener:&'a UdpSocket,server:SocketAddr,pad:&'a Pad,clock:&'a Clock) -> Sender<'a> {
+		return Sender {
+			listener,
+			server,
+			pad,
+			clock,
+			sent:None,
+		};
+	}
+
+	/// Adds the number of packets sent through this Sender (and its copies) to `sent`, e.g. so
+	/// that the server's acknowledgements of them can be told apart (see delivery.rs).
+	pub fn counting(mut self,sent:&'a Cell<usize>) -> Sender<'a> {
+		self.sent = Some(sent);
+		return self;
+	}
+
+	/// Encrypts and sends a message to the server, in fragments if necessary.
+	pub fn send(&self,bytes:&[u8]) -> Result<(),io::Error> {
+		if let Some(sent) = self.sent {
+			// Counted even if sending fails part way, since some of it may have got through.
+			sent.set(sent.get() + fragment::count(bytes));
+		}
+		return sendmessage(self.listener,&self.server,bytes,self.pad,self.clock);
+	}
+
+	/// Address of the server messages are sent to.
+	pub fn server(&self) -> SocketAddr {
+		return self.server;
+	}
+}
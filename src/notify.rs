@@ -0,0 +1,151 @@
+// Telling systemd how the client is doing.
+//
+// When the client runs as a systemd service with Type=notify, systemd passes it the path of a
+// socket in NOTIFY_SOCKET, and waits for READY=1 on it before considering the service started. The
+// client sends that once it's first subscribed, and STATUS= lines as its connection changes state,
+// which `systemctl status` shows. If the service has WatchdogSec= set, systemd also passes
+// WATCHDOG_USEC, and restarts the service unless it hears WATCHDOG=1 at least that often. The
+// client's main loop sends those pings, so a client that hangs (e.g. in a handler) is restarted.
+// Outside systemd, none of these variables are set, and nothing is sent.
+
+use std::env;
+use std::io;
+use std::process;
+use std::time::{Duration,Instant};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram,SocketAddr as UnixSocketAddr};
+#[cfg(unix)]
+use std::sync::Arc;
+
+/// Sends notifications to systemd's notification socket, if there is one. Cheap to clone.
+#[derive(Clone,Default)]
+pub struct Notifier {
+	#[cfg(unix)]
+	target:Option<Arc<(UnixDatagram,UnixSocketAddr)>>,
+}
+
+impl Notifier {
+	/// A notifier for the socket named in NOTIFY_SOCKET, or one that does nothing if that isn't
+	/// set (or can't be used, in which case a warning is logged).
+	pub fn from_env() -> Notifier {
+		let socket:String = match env::var("NOTIFY_SOCKET") {
+			Ok(socket) if !socket.is_empty() => socket,
+			_ => return Notifier::default(),
+		};
+		match Notifier::connect(&socket) {
+			Ok(notifier) => return notifier,
+			Err(why) => {
+				warn!("Could not use systemd notification socket {} - {}",socket,why);
+				return Notifier::default();
+			},
+		};
+	}
+
+	/// A notifier for the given socket: a path, or (on Linux) an abstract socket name starting with
+	/// '@'.
+	#[cfg(unix)]
+	pub fn connect(socket:&str) -> Result<Notifier,io::Error> {
+		let address:UnixSocketAddr = match socket.strip_prefix('@') {
+			Some(name) => abstractaddr(name)?,
+			None => UnixSocketAddr::from_pathname(socket)?,
+		};
+		return Ok(Notifier {
+			target:Some(Arc::new((UnixDatagram::unbound()?,address))),
+		});
+	}
+
+	#[cfg(not(unix))]
+	pub fn connect(_socket:&str) -> Result<Notifier,io::Error> {
+		return Err(io::Error::new(io::ErrorKind::Unsupported,"systemd notifications need Unix sockets"));
+	}
+
+	/// Whether there's anywhere to send notifications.
+	pub fn is_enabled(&self) -> bool {
+		#[cfg(unix)]
+		return self.target.is_some();
+		#[cfg(not(unix))]
+		return false;
+	}
+
+	/// Sends notification lines (e.g. "READY=1\nSTATUS=subscribed"), if there's anywhere to send
+	/// them.
+	pub fn notify(&self,state:&str) -> Result<(),io::Error> {
+		#[cfg(unix)]
+		{
+			if let Some(ref target) = self.target {
+				let (ref socket,ref address) = **target;
+				socket.send_to_addr(state.as_bytes(),address)?;
+			}
+		}
+		#[cfg(not(unix))]
+		let _ = state;
+		return Ok(());
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn abstractaddr(name:&str) -> Result<UnixSocketAddr,io::Error> {
+	use std::os::linux::net::SocketAddrExt;
+	return UnixSocketAddr::from_abstract_name(name.as_bytes());
+}
+
+#[cfg(all(unix,not(target_os = "linux")))]
+fn abstractaddr(_name:&str) -> Result<UnixSocketAddr,io::Error> {
+	return Err(io::Error::new(io::ErrorKind::Unsupported,"abstract sockets are only supported on Linux"));
+}
+
+/// When to ping systemd's watchdog.
+pub struct Watchdog {
+	interval:Option<Duration>,
+	nextping:Option<Instant>,
+}
+
+impl Watchdog {
+	/// Pings at half the watchdog's timeout, as systemd recommends, or never if there's no
+	/// timeout.
+	pub fn new(timeout:Option<Duration>) -> Watchdog {
+		let interval:Option<Duration> = timeout.map(|timeout| timeout/2);
+		return Watchdog {
+			interval,
+			nextping:interval.map(|_| Instant::now()),
+		};
+	}
+
+	/// Uses the timeout in WATCHDOG_USEC, if it's set, and meant for this process (WATCHDOG_PID
+	/// isn't set, or is this process's ID).
+	pub fn from_env() -> Watchdog {
+		let forus:bool = match env::var("WATCHDOG_PID") {
+			Ok(pid) => pid.trim().parse::<u32>().ok() == Some(process::id()),
+			Err(_) => true,
+		};
+		let timeout:Option<Duration> = match env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.trim().parse::<u64>().ok()) {
+			Some(usec) if forus && usec > 0 => Some(Duration::from_micros(usec)),
+			_ => None,
+		};
+		return Watchdog::new(timeout);
+	}
+
+	/// How often the watchdog is pinged, if it is.
+	pub fn interval(&self) -> Option<Duration> {
+		return self.interval;
+	}
+
+	/// When the watchdog next needs pinging, if ever.
+	pub fn next_ping(&self) -> Option<Instant> {
+		return self.nextping;
+	}
+
+	/// Pings the watchdog if it's time.
+	pub fn ping(&mut self,notifier:&Notifier,now:Instant) {
+		match (self.nextping,self.interval) {
+			(Some(due),Some(interval)) if due <= now => {
+				self.nextping = Some(now + interval);
+				if let Err(why) = notifier.notify("WATCHDOG=1") {
+					warn!("Could not ping the systemd watchdog - {}",why);
+				}
+			},
+			_ => (),
+		};
+	}
+}
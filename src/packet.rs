@@ -0,0 +1,118 @@
+// Teamech packets: timestamped messages, encrypted with Teacrypt.
+//
+// Before encryption, every message is followed by an eight-byte timestamp (milliseconds since the
+// Unix epoch, big endian), so a packet on the wire is laid out as
+// [encrypted content][encrypted timestamp (8)][encrypted signature (8)][nonce (8)].
+// Messages with one byte of content are status codes (see status.rs).
+
+use std::error;
+use std::fmt;
+
+use super::{int2bytes,bytes2int};
+use status::StatusCode;
+use teacrypt;
+use teacrypt::{Pad,encrypt,decrypt};
+
+/// The length of the timestamp attached to every message.
+pub static TIMESTAMP_SIZE:usize = 8;
+
+/// The number of bytes a packet adds to its message: the timestamp, then the signature and nonce
+/// that encryption adds.
+pub static OVERHEAD:usize = TIMESTAMP_SIZE + teacrypt::OVERHEAD;
+
+/// The smallest possible packet: an empty message with its timestamp, signature and nonce.
+pub static MIN_PACKET_SIZE:usize = OVERHEAD;
+
+/// Reasons a received datagram could not be turned into a Packet.
+#[derive(Debug)]
+pub enum PacketError {
+	/// The datagram is shorter than the smallest possible packet.
+	TooShort {
+		length:usize,
+	},
+	/// The datagram could not be decrypted, or didn't validate.
+	Crypto(teacrypt::Error),
+}
+
+impl fmt::Display for PacketError {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PacketError::TooShort { length } => {
+				write!(f,"packet of {} bytes is shorter than the minimum of {}",length,MIN_PACKET_SIZE)
+			},
+			PacketError::Crypto(why) => write!(f,"{}",why),
+		}
+	}
+}
+
+impl error::Error for PacketError {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			PacketError::Crypto(why) => Some(why),
+			_ => None,
+		}
+	}
+}
+
+impl From<teacrypt::Error> for PacketError {
+	fn from(why:teacrypt::Error) -> PacketError {
+		return PacketError::Crypto(why);
+	}
+}
+
+/// A decrypted, validated packet.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Packet {
+	/// The message content, without the timestamp.
+	pub content:Vec<u8>,
+	/// When the message was sent, in milliseconds since the Unix epoch.
+	pub timestamp:u64,
+	/// The random nonce the payload was encrypted with, which identifies it.
+	pub nonce:[u8;8],
+}
+
+impl Packet {
+	/// Decrypts and validates a datagram. Every malformed input is reported as an error; nothing
+	/// a remote host sends can make this panic.
+	pub fn decode(payload:&[u8],pad:&Pad) -> Result<Packet,PacketError> {
+		if payload.len() < MIN_PACKET_SIZE {
+			return Err(PacketError::TooShort {
+				length:payload.len(),
+			});
+		}
+		let message:Vec<u8> = decrypt(payload,pad)?;
+		// decrypt() strips exactly teacrypt::OVERHEAD bytes, so the length check above guarantees
+		// there's a whole timestamp here, but don't rely on it.
+		if message.len() < TIMESTAMP_SIZE {
+			return Err(PacketError::TooShort {
+				length:payload.len(),
+			});
+		}
+		let split:usize = message.len()-TIMESTAMP_SIZE;
+		let mut timestamp:[u8;8] = [0;8];
+		timestamp.copy_from_slice(&message[split..]);
+		let mut nonce:[u8;8] = [0;8];
+		nonce.copy_from_slice(&payload[payload.len()-8..]);
+		return Ok(Packet {
+			content:message[..split].to_vec(),
+			timestamp:bytes2int(&timestamp),
+			nonce,
+		});
+	}
+
+	/// Timestamps and encrypts a message for transmission.
+	pub fn encode(content:&[u8],timestamp:u64,pad:&Pad) -> Result<Vec<u8>,teacrypt::Error> {
+		let mut stampedbytes:Vec<u8> = Vec::with_capacity(content.len()+TIMESTAMP_SIZE);
+		stampedbytes.extend_from_slice(content);
+		stampedbytes.extend_from_slice(&int2bytes(&timestamp));
+		return encrypt(&stampedbytes,pad);
+	}
+
+	/// If this packet is a status code (i.e. has exactly one byte of content), returns the code.
+	pub fn status(&self) -> Option<StatusCode> {
+		if self.content.len() == 1 {
+			return Some(StatusCode::from_byte(self.content[0]));
+		}
+		return None;
+	}
+}
@@ -0,0 +1,202 @@
+// Event-driven waiting on the client socket.
+//
+// Instead of polling a nonblocking socket every millisecond, the client blocks on the socket
+// until something happens: a datagram arrives, the next scheduled task comes due, or another
+// thread queues a message for the server through an Outbox. When nothing is happening, the
+// client doesn't use any CPU time at all.
+
+use std::io;
+use std::net::{UdpSocket,SocketAddr,IpAddr,Ipv4Addr,Ipv6Addr};
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc;
+use std::time::{Duration,Instant};
+
+use delivery::{Delivery,Receipt};
+use net;
+
+/// Something that woke the client up.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Event {
+	/// A datagram of the given length arrived from the given address.
+	Datagram(usize,SocketAddr),
+	/// The deadline passed without anything arriving.
+	Deadline,
+	/// Another thread queued messages in the Mailbox.
+	Woken,
+}
+
+// Where wake-up datagrams for the current socket should be sent, shared between the mailbox and
+// all of its outboxes. This changes whenever the client rebinds its socket.
+type WakeAddr = Arc<Mutex<Option<SocketAddr>>>;
+
+// Sockets to send wake-up datagrams from, one for each address family, shared by all of a
+// mailbox's outboxes. Each is bound the first time it's needed (the client may never use the other
+// family, or have it at all), and reused from then on.
+#[derive(Default)]
+struct Wakers {
+	v4:Mutex<Option<UdpSocket>>,
+	v6:Mutex<Option<UdpSocket>>,
+}
+
+/// Creates a connected Outbox and Mailbox. Messages sent through the Outbox (or any of its
+/// clones) from any thread are queued in the Mailbox, and wake up a Reactor attached to it.
+pub fn mailbox() -> (Outbox,Mailbox) {
+	let (sender,receiver) = mpsc::channel();
+	let wakeaddr:WakeAddr = Arc::new(Mutex::new(None));
+	let outbox:Outbox = Outbox {
+		queue:sender,
+		wakeaddr:wakeaddr.clone(),
+		wakers:Arc::new(Wakers::default()),
+	};
+	let mailbox:Mailbox = Mailbox {
+		queue:receiver,
+		wakeaddr,
+	};
+	return (outbox,mailbox);
+}
+
+/// A message queued in a Mailbox.
+pub struct Letter {
+	pub bytes:Vec<u8>,
+	/// Where to report the result, for messages that should be delivered reliably.
+	pub notify:Option<mpsc::Sender<Delivery>>,
+}
+
+/// The sending half of a mailbox, for threads that need to send messages to the server on their
+/// own (e.g. in response to a hardware interrupt). Cheap to clone.
+#[derive(Clone)]
+pub struct Outbox {
+	queue:mpsc::Sender<Letter>,
+	wakeaddr:WakeAddr,
+	wakers:Arc<Wakers>,
+}
+
+impl Outbox {
+	/// Queues a message to be encrypted and sent to the server, and wakes up the client to send it.
+	/// Fails only if the client has shut down.
+	pub fn send(&self,bytes:Vec<u8>) -> Result<(),io::Error> {
+		return self.post(Letter {
+			bytes,
+			notify:None,
+		});
+	}
+
+	/// Like send(), but the client resends the message until the server acknowledges it (see
+	/// delivery.rs). The receipt can be used to find out whether it was delivered.
+	pub fn send_reliable(&self,bytes:Vec<u8>) -> Result<Receipt,io::Error> {
+		let (notify,result) = mpsc::channel();
+		self.post(Letter {
+			bytes,
+			notify:Some(notify),
+		})?;
+		return Ok(Receipt::new(result));
+	}
+
+	fn post(&self,letter:Letter) -> Result<(),io::Error> {
+		if self.queue.send(letter).is_err() {
+			return Err(io::Error::new(io::ErrorKind::BrokenPipe,"client mailbox has been closed"));
+		}
+		return self.wake();
+	}
+
+	/// Wakes up the client without sending anything, e.g. so that it notices it has been asked to
+	/// shut down.
+	pub fn wake(&self) -> Result<(),io::Error> {
+		let wakeaddr:Option<SocketAddr> = match self.wakeaddr.lock() {
+			Ok(addr) => *addr,
+			Err(poisoned) => *poisoned.into_inner(),
+		};
+		// If the client isn't currently attached to a socket, the message will be picked up as
+		// soon as it is.
+		let addr:SocketAddr = match wakeaddr {
+			Some(addr) => addr,
+			None => return Ok(()),
+		};
+		let (waker,unspecified) = match addr {
+			SocketAddr::V4(_) => (&self.wakers.v4,IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+			SocketAddr::V6(_) => (&self.wakers.v6,IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+		};
+		let mut waker = match waker.lock() {
+			Ok(waker) => waker,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		if waker.is_none() {
+			*waker = Some(UdpSocket::bind(SocketAddr::new(unspecified,0))?);
+		}
+		if let Some(ref socket) = *waker {
+			socket.send_to(&[],addr)?;
+		}
+		return Ok(());
+	}
+}
+
+/// The receiving half of a mailbox, owned by the client.
+pub struct Mailbox {
+	queue:mpsc::Receiver<Letter>,
+	wakeaddr:WakeAddr,
+}
+
+impl Mailbox {
+	/// Takes all of the messages queued so far.
+	pub fn take(&self) -> Vec<Letter> {
+		return self.queue.try_iter().collect();
+	}
+}
+
+/// Waits for events on the client socket.
+pub struct Reactor<'a> {
+	socket:&'a UdpSocket,
+}
+
+impl<'a> Reactor<'a> {
+	/// Puts the socket into blocking mode and attaches the mailbox to it, so that outboxes will
+	/// wake up this reactor from now on.
+	pub fn new(socket:&'a UdpSocket,mailbox:&Mailbox) -> Result<Reactor<'a>,io::Error> {
+		socket.set_nonblocking(false)?;
+		let local:SocketAddr = socket.local_addr()?;
+		// A socket bound to the unspecified address can be reached via loopback.
+		let wakeaddr:SocketAddr = match local.ip() {
+			IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST),local.port()),
+			IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST),local.port()),
+			_ => local,
+		};
+		match mailbox.wakeaddr.lock() {
+			Ok(mut addr) => *addr = Some(wakeaddr),
+			Err(poisoned) => *poisoned.into_inner() = Some(wakeaddr),
+		};
+		return Ok(Reactor {
+			socket,
+		});
+	}
+
+	/// Blocks until a datagram arrives, the deadline passes, or an outbox wakes the reactor up.
+	/// With no deadline, waits indefinitely. Received datagrams are written into `buf`.
+	pub fn wait(&self,buf:&mut [u8],deadline:Option<Instant>) -> Result<Event,io::Error> {
+		let timeout:Option<Duration> = match deadline {
+			Some(deadline) => {
+				let now:Instant = Instant::now();
+				if deadline <= now {
+					return Ok(Event::Deadline);
+				}
+				// A zero timeout would mean "block forever" to the OS, so round up.
+				Some(deadline.duration_since(now).max(Duration::from_millis(1)))
+			},
+			None => None,
+		};
+		self.socket.set_read_timeout(timeout)?;
+		loop {
+			match self.socket.recv_from(buf) {
+				// No real Teamech payload is empty, so an empty datagram is always a wake-up call
+				// from an outbox.
+				Ok((0,_)) => return Ok(Event::Woken),
+				Ok((nrecv,srcaddr)) => return Ok(Event::Datagram(nrecv,net::canonical(srcaddr))),
+				Err(why) => match why.kind() {
+					// Timeouts are reported as WouldBlock on Unix and TimedOut on Windows.
+					io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => return Ok(Event::Deadline),
+					io::ErrorKind::Interrupted => (),
+					_ => return Err(why),
+				},
+			};
+		}
+	}
+}
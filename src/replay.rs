@@ -0,0 +1,256 @@
+// Replay protection.
+//
+// Someone who captures a packet on its way to the client can send it again later. Once its
+// timestamp is too old, the timestamp check rejects it; until then, the ReplayCache catches it.
+// The cache remembers the nonce of every message the client has accepted for as long as that
+// message's timestamp could still pass the check, and turns away any message whose nonce it has
+// already seen. Because entries expire by time rather than by count, a burst of traffic can't
+// push a recent nonce out of the cache (short of its hard capacity limit, which is only there to
+// bound memory use, and is counted when it's hit so that the client can warn about it).
+//
+// The cache can also be saved to a file, so that restarting the client doesn't open a window for
+// replays. The file has one line per nonce: the nonce in hex, a space, and the message's
+// timestamp in milliseconds.
+
+use std::collections::{BTreeSet,HashMap};
+use std::error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path,PathBuf};
+use std::time::{Duration,Instant};
+
+/// A problem reading or writing the replay cache file.
+#[derive(Debug)]
+pub enum ReplayError {
+	Io(PathBuf,io::Error),
+	/// A line of the file couldn't be understood.
+	Corrupt {
+		path:PathBuf,
+		line:usize,
+	},
+}
+
+impl fmt::Display for ReplayError {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ReplayError::Io(path,why) => write!(f,"could not access replay cache {}: {}",path.display(),why),
+			ReplayError::Corrupt { path, line } => write!(f,"replay cache {} is corrupt at line {}",path.display(),line),
+		}
+	}
+}
+
+impl error::Error for ReplayError {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			ReplayError::Io(_,why) => Some(why),
+			_ => None,
+		}
+	}
+}
+
+/// Nonces of recently accepted messages.
+pub struct ReplayCache {
+	// How long after its timestamp a nonce is remembered, or None to remember nonces until the
+	// capacity is reached.
+	window:Option<u64>,
+	capacity:usize,
+	seen:HashMap<[u8;8],u64>,
+	// The same entries, oldest timestamp first, for expiring them.
+	byage:BTreeSet<(u64,[u8;8])>,
+	path:Option<PathBuf>,
+	dirty:bool,
+	savedat:Instant,
+	evicted:u64,
+}
+
+impl ReplayCache {
+	/// Creates an empty cache. Nonces are remembered until `window` milliseconds after their
+	/// message's timestamp (or, with no window, indefinitely), but never more than `capacity` of
+	/// them. If a path is given, save() writes the cache there.
+	pub fn new(window:Option<u64>,capacity:usize,path:Option<PathBuf>) -> ReplayCache {
+		return ReplayCache {
+			window,
+			capacity:capacity.max(1),
+			seen:HashMap::new(),
+			byage:BTreeSet::new(),
+			path,
+			dirty:false,
+			savedat:Instant::now(),
+			evicted:0,
+		};
+	}
+
+	pub fn path(&self) -> Option<&Path> {
+		return self.path.as_deref();
+	}
+
+	/// Reads the nonces saved in the cache file, if there is one, and returns how many were recent
+	/// enough to keep.
+	/// A missing file is not an error, since there's nothing to read the first time the client runs.
+	pub fn load(&mut self,now:u64) -> Result<usize,ReplayError> {
+		let path:PathBuf = match self.path {
+			Some(ref path) => path.clone(),
+			None => return Ok(0),
+		};
+		let text:String = match fs::read_to_string(&path) {
+			Ok(text) => text,
+			Err(ref why) if why.kind() == io::ErrorKind::NotFound => return Ok(0),
+			Err(why) => return Err(ReplayError::Io(path,why)),
+		};
+		self.expire(now);
+		let mut count:usize = 0;
+		for (n,line) in text.lines().enumerate() {
+			if line.trim().is_empty() {
+				continue;
+			}
+			let (nonce,timestamp) = match parseline(line) {
+				Some(entry) => entry,
+				None => {
+					return Err(ReplayError::Corrupt {
+						path,
+						line:n+1,
+					});
+				},
+			};
+			// Nonces that have already expired aren't worth a place in the cache, and could
+			// otherwise push out ones that haven't.
+			if !self.expired(timestamp,now) {
+				self.insert(nonce,timestamp);
+				count += 1;
+			}
+		}
+		return Ok(count);
+	}
+
+	/// Records a message's nonce. Returns false if the nonce has been seen before (i.e. the message
+	/// is a replay), or if the message is too old for the cache to be able to tell.
+	pub fn admit(&mut self,nonce:[u8;8],timestamp:u64,now:u64) -> bool {
+		self.expire(now);
+		if self.seen.contains_key(&nonce) {
+			return false;
+		}
+		if self.expired(timestamp,now) {
+			return false;
+		}
+		self.insert(nonce,timestamp);
+		return true;
+	}
+
+	// Whether a message with this timestamp is too old for its nonce to be remembered.
+	fn expired(&self,timestamp:u64,now:u64) -> bool {
+		match self.window {
+			Some(window) => return timestamp.saturating_add(window) < now,
+			None => return false,
+		};
+	}
+
+	fn insert(&mut self,nonce:[u8;8],timestamp:u64) {
+		if let Some(old) = self.seen.insert(nonce,timestamp) {
+			self.byage.remove(&(old,nonce));
+		}
+		self.byage.insert((timestamp,nonce));
+		self.dirty = true;
+		while self.seen.len() > self.capacity {
+			match self.byage.pop_first() {
+				Some((_,oldest)) => {
+					self.seen.remove(&oldest);
+					self.evicted += 1;
+				},
+				None => break,
+			};
+		}
+	}
+
+	/// Forgets nonces whose messages are too old to pass the timestamp check any more.
+	pub fn expire(&mut self,now:u64) {
+		let window:u64 = match self.window {
+			Some(window) => window,
+			None => return,
+		};
+		while let Some(&(timestamp,nonce)) = self.byage.first() {
+			if timestamp.saturating_add(window) >= now {
+				break;
+			}
+			self.byage.pop_first();
+			self.seen.remove(&nonce);
+			self.dirty = true;
+		}
+	}
+
+	/// How many nonces are remembered.
+	pub fn len(&self) -> usize {
+		return self.seen.len();
+	}
+
+	pub fn is_empty(&self) -> bool {
+		return self.seen.is_empty();
+	}
+
+	/// How many nonces had to be forgotten early because the cache was full. Expired nonces are
+	/// always forgotten first, so every one of these was still inside the window, and a replay of
+	/// its message would have got through.
+	pub fn evicted(&self) -> u64 {
+		return self.evicted;
+	}
+
+	/// When the cache should next be saved, if it has changed since it was last saved and has a
+	/// file to be saved to. Saving is rate-limited to once per `interval`, to spare flash storage.
+	pub fn next_save(&self,interval:Duration) -> Option<Instant> {
+		if !self.dirty || self.path.is_none() {
+			return None;
+		}
+		return Some(self.savedat + interval);
+	}
+
+	/// Writes the cache to its file, if it has one. The file is replaced atomically, so a crash
+	/// part way through leaves the previous version intact.
+	pub fn save(&mut self) -> Result<(),ReplayError> {
+		let path:PathBuf = match self.path {
+			Some(ref path) => path.clone(),
+			None => return Ok(()),
+		};
+		// Even if this fails, don't try again until the next interval.
+		self.savedat = Instant::now();
+		let mut text:String = String::with_capacity(self.byage.len()*32);
+		for (timestamp,nonce) in self.byage.iter() {
+			for byte in nonce.iter() {
+				text.push_str(&format!("{:02x}",byte));
+			}
+			text.push_str(&format!(" {}\n",timestamp));
+		}
+		// Next to the file, with .tmp added to its whole name, so that files with the same stem
+		// don't share one.
+		let mut tempname:OsString = path.clone().into_os_string();
+		tempname.push(".tmp");
+		let temppath:PathBuf = PathBuf::from(tempname);
+		let written:io::Result<()> = fs::File::create(&temppath)
+			.and_then(|mut file| {
+				file.write_all(text.as_bytes())?;
+				return file.sync_all();
+			})
+			.and_then(|_| fs::rename(&temppath,&path));
+		if let Err(why) = written {
+			return Err(ReplayError::Io(path,why));
+		}
+		self.dirty = false;
+		return Ok(());
+	}
+}
+
+// Parses one line of the cache file.
+fn parseline(line:&str) -> Option<([u8;8],u64)> {
+	let mut words = line.split_whitespace();
+	let noncehex:&str = words.next()?;
+	let timestamp:u64 = words.next()?.parse().ok()?;
+	if noncehex.len() != 16 || words.next().is_some() {
+		return None;
+	}
+	let mut nonce:[u8;8] = [0;8];
+	for (n,byte) in nonce.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(noncehex.get(n*2..n*2+2)?,16).ok()?;
+	}
+	return Some((nonce,timestamp));
+}
@@ -0,0 +1,199 @@
+// Looking up server names, and looking them up again.
+//
+// A server's address can change while the client is running (e.g. a server on a home connection
+// with a dynamic DNS record), and a client that only looked it up at startup would keep talking
+// to the old one until restarted. So ServerNames remembers the names it was given, and looks them
+// up again once the last lookup is older than its TTL, or straight away when the server stops
+// answering. Lookups go through the Resolve trait, so that they can be faked in tests.
+//
+// Each name keeps the addresses it last resolved to, so that one name failing to resolve (e.g. a
+// DNS server timing out) doesn't make its servers disappear from the list while the others' are
+// still there. Looking names up can take a long time, so the client does it on another thread
+// (see refresh_in_background()), rather than leaving the network loop stuck until it's done.
+
+use std::io;
+use std::net::{SocketAddr,ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration,Instant};
+
+// What each name resolved to, in the same order as the names.
+type Answers = Vec<Result<Vec<SocketAddr>,io::Error>>;
+
+/// Something that turns server names into addresses. Lookups can be made from any thread.
+pub trait Resolve:Send + Sync {
+	/// Looks up a name (a hostname or IP address, with a port), and returns every address it
+	/// stands for, in order of preference.
+	fn resolve(&self,name:&str) -> Result<Vec<SocketAddr>,io::Error>;
+}
+
+/// Looks names up with the operating system's resolver.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+	fn resolve(&self,name:&str) -> Result<Vec<SocketAddr>,io::Error> {
+		return Ok(name.to_socket_addrs()?.collect());
+	}
+}
+
+/// The result of looking up all of the server names.
+#[derive(Debug)]
+pub struct Lookup {
+	/// Every address found, in the order of the names and then of the resolver's answers, without
+	/// repeats.
+	pub addresses:Vec<SocketAddr>,
+	/// The names that couldn't be looked up, and why.
+	pub failed:Vec<(String,io::Error)>,
+	/// Whether the addresses are different from the last lookup's.
+	pub changed:bool,
+}
+
+/// The names of the servers, and the addresses they stood for when last looked up.
+pub struct ServerNames {
+	names:Vec<String>,
+	resolver:Arc<dyn Resolve>,
+	// How long a lookup is good for, or None to look names up again only on request.
+	ttl:Option<Duration>,
+	// The addresses each name last resolved to, and all of them together.
+	known:Vec<Vec<SocketAddr>>,
+	addresses:Vec<SocketAddr>,
+	nextlookup:Option<Instant>,
+	// The answers of a lookup running on another thread, if there is one.
+	running:Option<mpsc::Receiver<Answers>>,
+}
+
+impl ServerNames {
+	/// Nothing is looked up until lookup() is called.
+	pub fn new(names:Vec<String>,resolver:Box<dyn Resolve>,ttl:Option<Duration>) -> ServerNames {
+		return ServerNames {
+			known:vec![Vec::new();names.len()],
+			names,
+			resolver:Arc::from(resolver),
+			ttl,
+			addresses:Vec::new(),
+			nextlookup:None,
+			running:None,
+		};
+	}
+
+	pub fn names(&self) -> &[String] {
+		return &self.names;
+	}
+
+	/// The addresses found by the last successful lookup.
+	pub fn addresses(&self) -> &[SocketAddr] {
+		return &self.addresses;
+	}
+
+	/// Looks up every name now, and waits for the answers. A name that can't be looked up keeps
+	/// the addresses it had last time (if any).
+	pub fn lookup(&mut self) -> Lookup {
+		let answers:Answers = resolveall(&*self.resolver,&self.names);
+		return self.update(answers);
+	}
+
+	// Takes in the answers of a lookup.
+	fn update(&mut self,answers:Answers) -> Lookup {
+		let mut failed:Vec<(String,io::Error)> = Vec::new();
+		for (n,answer) in answers.into_iter().enumerate() {
+			match answer {
+				Ok(ref found) if found.is_empty() => {
+					failed.push((self.names[n].clone(),io::Error::new(io::ErrorKind::NotFound,"no addresses found")));
+				},
+				Ok(found) => self.known[n] = found,
+				Err(why) => failed.push((self.names[n].clone(),why)),
+			};
+		}
+		let mut addresses:Vec<SocketAddr> = Vec::new();
+		for address in self.known.iter().flatten() {
+			if !addresses.contains(address) {
+				addresses.push(*address);
+			}
+		}
+		self.nextlookup = self.ttl.map(|ttl| Instant::now() + ttl);
+		let changed:bool = addresses != self.addresses;
+		self.addresses = addresses.clone();
+		return Lookup {
+			addresses,
+			failed,
+			changed,
+		};
+	}
+
+	/// Starts looking every name up again on another thread, if the last lookup is out of date
+	/// and there isn't one running already. `done` is called on that thread once the answers are
+	/// in (e.g. to wake the client up), and finished() then returns them.
+	pub fn refresh_in_background<F:FnOnce() + Send + 'static>(&mut self,now:Instant,done:F) {
+		if !self.is_due(now) {
+			return;
+		}
+		let (sender,receiver) = mpsc::channel();
+		let resolver:Arc<dyn Resolve> = self.resolver.clone();
+		let names:Vec<String> = self.names.clone();
+		thread::spawn(move || {
+			// If nobody is waiting for the answers any more, there's nothing to do with them.
+			if sender.send(resolveall(&*resolver,&names)).is_ok() {
+				done();
+			}
+		});
+		self.running = Some(receiver);
+	}
+
+	/// Whether a lookup started by refresh_in_background() hasn't been taken in yet.
+	pub fn is_running(&self) -> bool {
+		return self.running.is_some();
+	}
+
+	/// Takes in the answers of the lookup started by refresh_in_background(), if it has finished.
+	pub fn finished(&mut self) -> Option<Lookup> {
+		let answers:Answers = match self.running {
+			Some(ref receiver) => match receiver.try_recv() {
+				Ok(answers) => answers,
+				Err(mpsc::TryRecvError::Empty) => return None,
+				// The lookup thread panicked; try again once the lookup is due.
+				Err(mpsc::TryRecvError::Disconnected) => {
+					self.running = None;
+					return None;
+				},
+			},
+			None => return None,
+		};
+		self.running = None;
+		return Some(self.update(answers));
+	}
+
+	/// When the names should next be looked up, if ever. While a lookup is running, that's not
+	/// until it has finished.
+	pub fn next_lookup(&self) -> Option<Instant> {
+		if self.is_running() {
+			return None;
+		}
+		return self.nextlookup;
+	}
+
+	/// Whether the last lookup is out of date, and another isn't already running.
+	pub fn is_due(&self,now:Instant) -> bool {
+		return self.next_lookup().is_some_and(|due| due <= now);
+	}
+
+	/// Marks the last lookup as out of date, e.g. because the server stopped answering.
+	pub fn expire(&mut self) {
+		self.nextlookup = Some(Instant::now());
+	}
+
+	/// Looks the names up again if the last lookup is out of date, and returns the lookup if so.
+	/// Waits for the answers; see refresh_in_background() for a way that doesn't.
+	pub fn refresh(&mut self,now:Instant) -> Option<Lookup> {
+		if !self.is_due(now) {
+			return None;
+		}
+		return Some(self.lookup());
+	}
+}
+
+// Looks up each name in turn.
+fn resolveall(resolver:&dyn Resolve,names:&[String]) -> Answers {
+	return names.iter().map(|name| resolver.resolve(name)).collect();
+}
@@ -0,0 +1,424 @@
+// Periodic task scheduling.
+//
+// Code that should run continuously (e.g. "report the temperature every 30 seconds") is registered
+// with a Scheduler as a task, instead of being written directly into the client's network loop.
+// The client calls run_due() whenever it gets the chance, and each task that has come due is run
+// once with a Sender it can use to talk to the server. A task that fails or panics is logged and
+// rescheduled as usual, without affecting the other tasks or the client itself.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self,AssertUnwindSafe};
+use std::str::FromStr;
+use std::time::{Duration,Instant};
+
+use rand;
+
+use super::{civil,systime};
+use net::Sender;
+
+/// The longest interval a schedule can be written with: a year. Anything longer is surely a
+/// mistake, and a long enough interval can't be added to the scheduler's clock at all.
+pub const MAX_INTERVAL:Duration = Duration::from_secs(365*86_400);
+
+/// What a task returns. Errors are logged and counted by the scheduler, and otherwise ignored.
+pub type TaskResult = Result<(),Box<dyn Error>>;
+
+/// Something that can be run periodically by the Scheduler.
+pub trait Task {
+	fn run(&mut self,sender:&Sender) -> TaskResult;
+}
+
+impl<F> Task for F where F:FnMut(&Sender) -> TaskResult {
+	fn run(&mut self,sender:&Sender) -> TaskResult {
+		return self(sender);
+	}
+}
+
+/// When a task should run.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Schedule {
+	/// At a fixed, non-zero interval, starting one interval after the task is first considered.
+	Every(Duration),
+	/// Whenever the (UTC) wall clock matches a cron-like specification.
+	Cron(CronSpec),
+}
+
+/// What to do when the scheduler falls behind a task's schedule (because the client was busy,
+/// or a task took longer than its own interval to run).
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum MissedTicks {
+	/// Run the task once, then carry on from the next tick that is still in the future.
+	Skip,
+	/// Run the task once for every tick that was missed, back to back, until caught up.
+	Burst,
+	/// Run the task once, then wait a full interval from now before running it again.
+	Delay,
+}
+
+/// A schedule specification that failed to parse.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ScheduleError(String);
+
+impl fmt::Display for ScheduleError {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		return write!(f,"invalid schedule: {}",self.0);
+	}
+}
+
+impl Error for ScheduleError {}
+
+// Parses a duration like "500ms", "30s", "5m", "2h" or "1d".
+fn parse_duration(text:&str) -> Result<Duration,ScheduleError> {
+	let text:&str = text.trim();
+	let split:usize = match text.find(|c:char| !c.is_ascii_digit()) {
+		Some(n) => n,
+		None => return Err(ScheduleError(format!("duration '{}' has no unit",text))),
+	};
+	let amount:u64 = match text[..split].parse::<u64>() {
+		Ok(n) => n,
+		Err(_) => return Err(ScheduleError(format!("duration '{}' has no amount",text))),
+	};
+	let unitms:u64 = match &text[split..] {
+		"ms" => 1,
+		"s" => 1_000,
+		"m" => 60_000,
+		"h" => 3_600_000,
+		"d" => 86_400_000,
+		other => return Err(ScheduleError(format!("unknown duration unit '{}'",other))),
+	};
+	if amount == 0 {
+		return Err(ScheduleError(String::from("interval must be greater than zero")));
+	}
+	match amount.checked_mul(unitms).map(Duration::from_millis) {
+		Some(interval) if interval <= MAX_INTERVAL => return Ok(interval),
+		_ => return Err(ScheduleError(format!("duration '{}' is longer than a year",text))),
+	};
+}
+
+impl FromStr for Schedule {
+	type Err = ScheduleError;
+	/// Accepts either an interval ("every 30s", "every 5m", or just "30s"), or a five-field cron
+	/// specification ("*/5 * * * *"; see CronSpec).
+	fn from_str(text:&str) -> Result<Schedule,ScheduleError> {
+		let text:&str = text.trim();
+		if text.split_whitespace().count() == 5 {
+			return Ok(Schedule::Cron(text.parse::<CronSpec>()?));
+		}
+		let interval:&str = match text.strip_prefix("every ") {
+			Some(rest) => rest,
+			None => text,
+		};
+		return Ok(Schedule::Every(parse_duration(interval)?));
+	}
+}
+
+/// A cron-like specification with five fields: minute (0-59), hour (0-23), day of month (1-31),
+/// month (1-12) and day of week (0-7, where both 0 and 7 are Sunday), evaluated in UTC.
+/// Each field may be `*`, a number, a range (`1-5`), a step (`*/15` or `0-30/10`), or a
+/// comma-separated list of any of these. As in cron, if both the day of month and the day of week
+/// are restricted, a day matching either one will do.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct CronSpec {
+	minutes:u64,
+	hours:u64,
+	days:u64,
+	months:u64,
+	weekdays:u64,
+	anyday:bool,
+	anyweekday:bool,
+}
+
+// Parses one cron field into a bitmask of the values it allows.
+fn parse_cronfield(field:&str,min:u64,max:u64) -> Result<u64,ScheduleError> {
+	let mut mask:u64 = 0;
+	for part in field.split(',') {
+		let (range,step):(&str,u64) = match part.find('/') {
+			Some(n) => match part[n+1..].parse::<u64>() {
+				Ok(step) if step > 0 => (&part[..n],step),
+				_ => return Err(ScheduleError(format!("bad step in cron field '{}'",field))),
+			},
+			None => (part,1),
+		};
+		let (start,end):(u64,u64) = if range == "*" {
+			(min,max)
+		} else if let Some(n) = range.find('-') {
+			match (range[..n].parse::<u64>(),range[n+1..].parse::<u64>()) {
+				(Ok(a),Ok(b)) => (a,b),
+				_ => return Err(ScheduleError(format!("bad range in cron field '{}'",field))),
+			}
+		} else {
+			match range.parse::<u64>() {
+				// A single value with a step means "from here to the end".
+				Ok(a) if part.contains('/') => (a,max),
+				Ok(a) => (a,a),
+				Err(_) => return Err(ScheduleError(format!("bad value in cron field '{}'",field))),
+			}
+		};
+		if start < min || end > max || start > end {
+			return Err(ScheduleError(format!("cron field '{}' is out of range {}-{}",field,min,max)));
+		}
+		let mut value:u64 = start;
+		while value <= end {
+			mask |= 1 << value;
+			value += step;
+		}
+	}
+	return Ok(mask);
+}
+
+impl FromStr for CronSpec {
+	type Err = ScheduleError;
+	fn from_str(text:&str) -> Result<CronSpec,ScheduleError> {
+		let fields:Vec<&str> = text.split_whitespace().collect();
+		if fields.len() != 5 {
+			return Err(ScheduleError(format!("cron specification '{}' does not have five fields",text)));
+		}
+		let mut weekdays:u64 = parse_cronfield(fields[4],0,7)?;
+		// Sunday can be written as either 0 or 7.
+		if weekdays & (1 << 7) != 0 {
+			weekdays = (weekdays | 1) & !(1 << 7);
+		}
+		return Ok(CronSpec {
+			minutes:parse_cronfield(fields[0],0,59)?,
+			hours:parse_cronfield(fields[1],0,23)?,
+			days:parse_cronfield(fields[2],1,31)?,
+			months:parse_cronfield(fields[3],1,12)?,
+			weekdays,
+			anyday:fields[2] == "*",
+			anyweekday:fields[4] == "*",
+		});
+	}
+}
+
+impl CronSpec {
+	fn matchesday(&self,days:u64) -> bool {
+		let (_year,month,day) = civil(days as i64);
+		// The epoch was a Thursday.
+		let weekday:u64 = (days + 4) % 7;
+		if self.months & (1 << month) == 0 {
+			return false;
+		}
+		let daymatch:bool = self.days & (1 << day) != 0;
+		let weekdaymatch:bool = self.weekdays & (1 << weekday) != 0;
+		if !self.anyday && !self.anyweekday {
+			return daymatch || weekdaymatch;
+		}
+		return daymatch && weekdaymatch;
+	}
+
+	/// Finds the first matching minute strictly after the given unix time (in milliseconds), and
+	/// returns it as a unix time in milliseconds. Returns None if nothing matches within the next
+	/// four years (e.g. "0 0 31 2 *").
+	pub fn next_after(&self,unixms:u64) -> Option<u64> {
+		let mut minute:u64 = unixms/60_000 + 1;
+		let limit:u64 = minute + 4*366*24*60;
+		while minute < limit {
+			let days:u64 = minute/1_440;
+			if !self.matchesday(days) {
+				minute = (days + 1)*1_440;
+				continue;
+			}
+			let hour:u64 = (minute/60) % 24;
+			if self.hours & (1 << hour) == 0 {
+				minute = (minute/60 + 1)*60;
+				continue;
+			}
+			if self.minutes & (1 << (minute % 60)) != 0 {
+				return Some(minute*60_000);
+			}
+			minute += 1;
+		}
+		return None;
+	}
+}
+
+/// Per-task settings, returned by Scheduler::add so they can be adjusted with method chaining.
+pub struct ScheduledTask {
+	name:String,
+	schedule:Schedule,
+	missed:MissedTicks,
+	jitter:Duration,
+	task:Box<dyn Task>,
+	// The tick the task is scheduled for, and the time it will actually run (the tick plus
+	// jitter). Jitter is never carried from one tick to the next, so it doesn't accumulate.
+	due:Option<Instant>,
+	fire:Option<Instant>,
+	runs:u64,
+	failures:u64,
+}
+
+impl ScheduledTask {
+	/// Delays each run by a random amount up to the given duration, so that a fleet of devices
+	/// started at the same time doesn't report in lockstep.
+	pub fn jitter(&mut self,jitter:Duration) -> &mut ScheduledTask {
+		self.jitter = jitter;
+		return self;
+	}
+
+	/// Sets the policy for ticks missed while the scheduler was behind. Defaults to Skip.
+	pub fn missed(&mut self,policy:MissedTicks) -> &mut ScheduledTask {
+		self.missed = policy;
+		return self;
+	}
+
+	pub fn name(&self) -> &str {
+		return &self.name;
+	}
+
+	/// The number of times this task has been run.
+	pub fn runs(&self) -> u64 {
+		return self.runs;
+	}
+
+	/// The number of runs of this task that returned an error or panicked.
+	pub fn failures(&self) -> u64 {
+		return self.failures;
+	}
+
+	// Works out the tick following `due` (or the first tick, if `due` is None) according to the
+	// schedule and missed-tick policy. Returns None if the schedule will never fire again, which
+	// includes ticks too far off for an Instant to hold.
+	fn nexttick(&self,due:Option<Instant>,now:Instant) -> Option<Instant> {
+		match self.schedule {
+			Schedule::Every(interval) => match due {
+				None => return now.checked_add(interval),
+				Some(due) => match self.missed {
+					MissedTicks::Burst => return due.checked_add(interval),
+					MissedTicks::Delay => return now.checked_add(interval),
+					MissedTicks::Skip => {
+						let mut next:Instant = due.checked_add(interval)?;
+						if next <= now {
+							// Worked out in nanoseconds, since a short interval can fall behind by more
+							// ticks than a u32 (and so Duration's multiplication) can count.
+							let behind:u128 = now.duration_since(next).as_nanos();
+							let ticks:u128 = behind/interval.as_nanos().max(1) + 1;
+							let skipped:u128 = ticks.saturating_mul(interval.as_nanos());
+							let skip:Duration = Duration::new(
+								u64::try_from(skipped/1_000_000_000).unwrap_or(u64::MAX),
+								(skipped%1_000_000_000) as u32,
+							);
+							next = match next.checked_add(skip) {
+								Some(next) => next,
+								None => now.checked_add(interval)?,
+							};
+						}
+						return Some(next);
+					},
+				},
+			},
+			Schedule::Cron(ref spec) => {
+				// Cron ticks are found on the wall clock, then converted into an Instant so that the
+				// rest of the scheduler doesn't have to care about the clock being changed.
+				let nowms:u64 = systime();
+				let fromms:u64 = match (due,self.missed) {
+					(Some(due),MissedTicks::Burst) if due < now => {
+						nowms.saturating_sub(now.duration_since(due).as_millis() as u64)
+					},
+					_ => nowms,
+				};
+				let nextms:u64 = spec.next_after(fromms)?;
+				return now.checked_add(Duration::from_millis(nextms.saturating_sub(nowms)));
+			},
+		};
+	}
+
+	fn withjitter(&self,tick:Instant) -> Instant {
+		if self.jitter == Duration::from_millis(0) {
+			return tick;
+		}
+		let range:u64 = u64::try_from(self.jitter.as_millis()).unwrap_or(u64::MAX).saturating_add(1);
+		let jitterms:u64 = rand::random::<u64>() % range;
+		return tick.checked_add(Duration::from_millis(jitterms)).unwrap_or(tick);
+	}
+}
+
+/// Runs registered tasks according to their schedules.
+#[derive(Default)]
+pub struct Scheduler {
+	tasks:Vec<ScheduledTask>,
+}
+
+impl Scheduler {
+	pub fn new() -> Scheduler {
+		return Scheduler::default();
+	}
+
+	/// Registers a task to be run on the given schedule.
+	///
+	/// Panics if the schedule is an interval of zero, which would run the task on every pass of the
+	/// client's loop. (Schedules parsed from text can't be zero; see Schedule::from_str.)
+	pub fn add<T:Task+'static>(&mut self,name:&str,schedule:Schedule,task:T) -> &mut ScheduledTask {
+		if schedule == Schedule::Every(Duration::from_millis(0)) {
+			panic!("scheduled task '{}' has an interval of zero",name);
+		}
+		self.tasks.push(ScheduledTask {
+			name:name.to_owned(),
+			schedule,
+			missed:MissedTicks::Skip,
+			jitter:Duration::from_millis(0),
+			task:Box::new(task),
+			due:None,
+			fire:None,
+			runs:0,
+			failures:0,
+		});
+		let last:usize = self.tasks.len()-1;
+		return &mut self.tasks[last];
+	}
+
+	/// Registers a task to be run at a fixed interval.
+	pub fn every<T:Task+'static>(&mut self,name:&str,interval:Duration,task:T) -> &mut ScheduledTask {
+		return self.add(name,Schedule::Every(interval),task);
+	}
+
+	/// The registered tasks, in the order they were added.
+	pub fn tasks(&self) -> &[ScheduledTask] {
+		return &self.tasks;
+	}
+
+	/// The earliest time at which a task will next need to run, if any are scheduled.
+	pub fn next_deadline(&self) -> Option<Instant> {
+		return self.tasks.iter().filter_map(|t| t.fire).min();
+	}
+
+	/// Runs every task that has come due. Tasks that haven't been scheduled yet (i.e. were added
+	/// since the last call) are scheduled, counting from now.
+	pub fn run_due(&mut self,sender:&Sender) {
+		let now:Instant = Instant::now();
+		for entry in self.tasks.iter_mut() {
+			if entry.due.is_none() {
+				if let Some(tick) = entry.nexttick(None,now) {
+					entry.due = Some(tick);
+					entry.fire = Some(entry.withjitter(tick));
+				}
+				continue;
+			}
+			let fire:Instant = match entry.fire {
+				Some(fire) => fire,
+				None => continue,
+			};
+			if fire > now {
+				continue;
+			}
+			entry.runs += 1;
+			// Keep one task's failure (or panic) from taking the whole client down with it.
+			let outcome = panic::catch_unwind(AssertUnwindSafe(|| entry.task.run(sender)));
+			match outcome {
+				Ok(Ok(())) => (),
+				Ok(Err(why)) => {
+					entry.failures += 1;
+					warn!("Scheduled task '{}' failed - {}",entry.name,why);
+				},
+				Err(_) => {
+					entry.failures += 1;
+					error!("Scheduled task '{}' panicked.",entry.name);
+				},
+			};
+			let after:Instant = Instant::now();
+			entry.due = entry.nexttick(entry.due,after);
+			entry.fire = entry.due.map(|tick| entry.withjitter(tick));
+		}
+	}
+}
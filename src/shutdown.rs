@@ -0,0 +1,78 @@
+// Stopping the client cleanly.
+//
+// A device being switched off or restarted (e.g. by systemd, which sends SIGTERM, or by someone
+// pressing Ctrl-C, which sends SIGINT) shouldn't just vanish: it should finish delivering the
+// reliable messages it has queued, tell the server it's leaving, and give device code a chance to
+// tidy up. So signals don't stop the client directly; they set a ShutdownRequest, which wakes the
+// client up, and the client shuts itself down in an orderly way. If that takes too long, a second
+// signal stops it straight away.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+
+use reactor::Outbox;
+
+/// Whether the client has been asked to shut down. Cheap to clone; every clone shares the same
+/// request, so device code can be given one and use it to stop the client (e.g. when a power
+/// button is pressed).
+#[derive(Clone)]
+pub struct ShutdownRequest {
+	requested:Arc<AtomicBool>,
+	outbox:Outbox,
+}
+
+impl ShutdownRequest {
+	/// Requests made with this will wake up the client through `outbox`.
+	pub fn new(outbox:Outbox) -> ShutdownRequest {
+		return ShutdownRequest {
+			requested:Arc::new(AtomicBool::new(false)),
+			outbox,
+		};
+	}
+
+	/// Asks the client to shut down, and wakes it up so that it notices.
+	pub fn request(&self) {
+		self.requested.store(true,Ordering::SeqCst);
+		// If the client isn't waiting on its socket, it will notice when it next checks.
+		let _ = self.outbox.wake();
+	}
+
+	pub fn is_requested(&self) -> bool {
+		return self.requested.load(Ordering::SeqCst);
+	}
+}
+
+/// Requests a shutdown when the process receives SIGINT or SIGTERM. If one arrives after a
+/// shutdown has already been requested, the process exits straight away, with 128 plus the signal
+/// number as its status, as shells do for processes killed by signals.
+#[cfg(unix)]
+pub fn on_signals(request:&ShutdownRequest) -> Result<(),io::Error> {
+	use std::process;
+	use std::thread;
+	use signal_hook::consts::{SIGINT,SIGTERM};
+	use signal_hook::iterator::Signals;
+	use signal_hook::low_level::signal_name;
+
+	let mut signals:Signals = Signals::new([SIGINT,SIGTERM])?;
+	let request:ShutdownRequest = request.clone();
+	thread::spawn(move || {
+		for signal in signals.forever() {
+			let name:&str = signal_name(signal).unwrap_or("signal");
+			if request.is_requested() {
+				warn!("Received {} again; stopping now.",name);
+				process::exit(128 + signal);
+			}
+			info!("Received {}; shutting down.",name);
+			request.request();
+		}
+	});
+	return Ok(());
+}
+
+/// Signals aren't handled on this platform; the client runs until it's killed, or a shutdown is
+/// requested some other way.
+#[cfg(not(unix))]
+pub fn on_signals(_request:&ShutdownRequest) -> Result<(),io::Error> {
+	return Ok(());
+}
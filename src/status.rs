@@ -0,0 +1,137 @@
+// Teamech status codes.
+//
+// A message with exactly one byte of content is a status code rather than an ordinary message.
+// The codes borrow their values from the ASCII control characters of similar meaning:
+//
+//   Code  ASCII  Name              Sent by  Meaning
+//   0x02  STX    Subscribed        server   the client's subscription was accepted (or renewed)
+//   0x04  EOT    Unsubscribe       client   the client is shutting down, and its subscription
+//                                           can be forgotten
+//   0x06  ACK    Ack               both     the last message was received and validated
+//   0x15  NAK    ValidationFailed  both     a message's signature didn't validate - the two ends'
+//                                           pads probably don't match
+//   0x19  EM     Rejected          server   the subscription was rejected (e.g. the server is full)
+//                                           or has expired, and needs to be renewed
+//   0x1A  SUB    DecryptFailed     both     a message could not be decrypted for some other reason
+//
+// Any other single byte is kept as Unknown, so that a newer server can introduce codes without
+// breaking older clients; the client counts and logs them instead.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// A status code, as sent in a one-byte message.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum StatusCode {
+	/// 0x02: the subscription was accepted.
+	Subscribed,
+	/// 0x04: the client is leaving.
+	Unsubscribe,
+	/// 0x06: the last message was received and validated.
+	Ack,
+	/// 0x15: a message failed to validate.
+	ValidationFailed,
+	/// 0x19: the subscription was rejected, or has expired.
+	Rejected,
+	/// 0x1A: a message could not be decrypted.
+	DecryptFailed,
+	/// Any code this version of the client doesn't know about.
+	Unknown(u8),
+}
+
+impl StatusCode {
+	/// Interprets a status byte. This never fails; unrecognized bytes become Unknown.
+	pub fn from_byte(byte:u8) -> StatusCode {
+		return StatusCode::known(byte).unwrap_or(StatusCode::Unknown(byte));
+	}
+
+	/// Interprets a status byte, rejecting any this version of the client doesn't know about.
+	pub fn known(byte:u8) -> Option<StatusCode> {
+		match byte {
+			0x02 => return Some(StatusCode::Subscribed),
+			0x04 => return Some(StatusCode::Unsubscribe),
+			0x06 => return Some(StatusCode::Ack),
+			0x15 => return Some(StatusCode::ValidationFailed),
+			0x19 => return Some(StatusCode::Rejected),
+			0x1A => return Some(StatusCode::DecryptFailed),
+			_ => return None,
+		};
+	}
+
+	/// The byte sent on the wire for this code.
+	pub fn to_byte(self) -> u8 {
+		match self {
+			StatusCode::Subscribed => return 0x02,
+			StatusCode::Unsubscribe => return 0x04,
+			StatusCode::Ack => return 0x06,
+			StatusCode::ValidationFailed => return 0x15,
+			StatusCode::Rejected => return 0x19,
+			StatusCode::DecryptFailed => return 0x1A,
+			StatusCode::Unknown(byte) => return byte,
+		};
+	}
+
+	/// A short human-readable description of the code.
+	pub fn describe(self) -> &'static str {
+		match self {
+			StatusCode::Subscribed => return "subscribed",
+			StatusCode::Unsubscribe => return "unsubscribed",
+			StatusCode::Ack => return "acknowledged",
+			StatusCode::ValidationFailed => return "validation failed",
+			StatusCode::Rejected => return "subscription rejected or expired",
+			StatusCode::DecryptFailed => return "decryption failed",
+			StatusCode::Unknown(_) => return "unknown status code",
+		};
+	}
+}
+
+impl From<u8> for StatusCode {
+	fn from(byte:u8) -> StatusCode {
+		return StatusCode::from_byte(byte);
+	}
+}
+
+impl From<StatusCode> for u8 {
+	fn from(code:StatusCode) -> u8 {
+		return code.to_byte();
+	}
+}
+
+impl fmt::Display for StatusCode {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		return write!(f,"0x{:02x} ({})",self.to_byte(),self.describe());
+	}
+}
+
+/// Tallies the status codes received, so that codes this client doesn't understand can be
+/// reported without flooding the log.
+#[derive(Debug,Clone,Default)]
+pub struct StatusCounters {
+	counts:HashMap<StatusCode,u64>,
+}
+
+impl StatusCounters {
+	pub fn new() -> StatusCounters {
+		return StatusCounters::default();
+	}
+
+	/// Counts one more occurrence of a code, and returns how many times it has now been seen.
+	pub fn record(&mut self,code:StatusCode) -> u64 {
+		let count:&mut u64 = self.counts.entry(code).or_insert(0);
+		*count += 1;
+		return *count;
+	}
+
+	/// How many times a code has been seen.
+	pub fn count(&self,code:StatusCode) -> u64 {
+		return self.counts.get(&code).cloned().unwrap_or(0);
+	}
+
+	/// How many codes have been seen in total that this client didn't recognize.
+	pub fn unknown(&self) -> u64 {
+		return self.counts.iter()
+			.filter(|(code,_)| matches!(code,StatusCode::Unknown(_)))
+			.map(|(_,count)| count)
+			.sum();
+	}
+}
@@ -0,0 +1,502 @@
+// Teacrypt: the symmetric pad-based encryption and signing scheme used by Teamech.
+//
+// A Teacrypt payload is laid out as [ciphertext][encrypted signature (8)][nonce (8)]. The key
+// used to encrypt the message and signature is generated from the nonce and the pad file, which
+// must be identical on both ends of the link.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex};
+
+use memmap::{Mmap,MmapOptions};
+
+use rand;
+use rand::{OsRng,Rng};
+use tiny_keccak::Keccak;
+
+use super::{int2bytes,bytes2int};
+
+/// The number of bytes encryption adds to a message: an eight-byte signature and an eight-byte
+/// nonce. No valid payload can be shorter than this.
+pub static OVERHEAD:usize = 16;
+
+/// Errors that can occur while encrypting or decrypting a Teacrypt payload.
+#[derive(Debug)]
+pub enum Error {
+	/// The pad file could not be opened.
+	Io(io::Error),
+	/// The pad file (or the requested window of it) is zero bytes long.
+	PadEmpty,
+	/// The pad ended before the given offset, even though it was supposed to be `size` bytes long.
+	/// This usually means the pad file was truncated after it was opened.
+	PadTooSmall {
+		offset:u64,
+		size:u64,
+	},
+	/// Reading the pad at the given offset failed.
+	ReadFailed {
+		offset:u64,
+		source:io::Error,
+	},
+	/// The requested pad window doesn't fit inside the pad file.
+	WindowOutOfRange {
+		offset:u64,
+		size:u64,
+		filesize:u64,
+	},
+	/// The payload is too short to contain a signature and nonce, so it can't be a Teacrypt
+	/// payload at all.
+	PayloadTooShort {
+		length:usize,
+	},
+	/// The payload decrypted, but its signature did not match its content. This means the payload
+	/// was tampered with, was encrypted with a different pad, or is just random junk data.
+	SignatureInvalid,
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Io(why) => write!(f,"pad access failed: {}",why),
+			Error::PadEmpty => write!(f,"pad is empty"),
+			Error::PadTooSmall { offset, size } => {
+				write!(f,"pad ended before offset {} (expected {} bytes)",offset,size)
+			},
+			Error::ReadFailed { offset, source } => write!(f,"pad read failed at offset {}: {}",offset,source),
+			Error::WindowOutOfRange { offset, size, filesize } => {
+				write!(f,"pad window of {} bytes at offset {} does not fit in a pad file of {} bytes",size,offset,filesize)
+			},
+			Error::PayloadTooShort { length } => {
+				write!(f,"payload of {} bytes is shorter than the minimum of {}",length,OVERHEAD)
+			},
+			Error::SignatureInvalid => write!(f,"payload signature verification failed"),
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			Error::Io(why) => Some(why),
+			Error::ReadFailed { source, .. } => Some(source),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(why:io::Error) -> Error {
+		return Error::Io(why);
+	}
+}
+
+// Where a Pad gets its bytes from.
+enum PadSource {
+	// The pad file is kept open, and each byte is read from it as it is needed.
+	File(Mutex<fs::File>),
+	// The whole pad file has been read into memory.
+	Memory(Vec<u8>),
+	// The pad file is memory-mapped, and the OS pages it in as needed.
+	Mapped(Mmap),
+}
+
+/// Handle to the pad file shared between the server and all of its clients.
+/// The pad file is opened once, when the handle is created, and can be served from the open file,
+/// from memory, or from a memory map. Handles are cheap to clone, and clones share the same
+/// underlying file, buffer, or map.
+#[derive(Clone)]
+pub struct Pad {
+	path:PathBuf,
+	// Where the pad starts in the file. Only needed when reading from the file directly; loaded
+	// and mapped pads start at zero.
+	offset:u64,
+	size:u64,
+	source:Arc<PadSource>,
+}
+
+impl fmt::Debug for Pad {
+	fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+		let mode:&str = match *self.source {
+			PadSource::File(_) => "file",
+			PadSource::Memory(_) => "memory",
+			PadSource::Mapped(_) => "mapped",
+		};
+		return write!(f,"Pad {{ path: {:?}, offset: {}, size: {}, mode: {} }}",self.path,self.offset,self.size,mode);
+	}
+}
+
+/// How a Pad should serve bytes from its pad file.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PadMode {
+	/// Keep the file open and read each byte from it as it is needed. This uses the least memory,
+	/// but costs two system calls per key byte.
+	File,
+	/// Read the entire pad into memory. This is the fastest option, but the pad has to fit
+	/// comfortably in RAM.
+	Memory,
+	/// Memory-map the pad. This is nearly as fast as loading it, without needing the whole pad in
+	/// RAM at once.
+	Mapped,
+}
+
+/// The part of a pad file (or device) to use as the pad. By default, the whole thing is used;
+/// setting an offset and/or size allows a partition, a slice of a raw flash device, or a slice of
+/// a larger file to be used instead.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct PadWindow {
+	/// Where the pad starts, in bytes from the start of the file.
+	pub offset:u64,
+	/// How long the pad is, in bytes. If None, the pad runs to the end of the file.
+	pub size:Option<u64>,
+}
+
+// Finds the size of a pad file. For regular files this comes from the file's metadata, but block
+// devices (e.g. a filesystemless flash device used as a pad) report a length of zero there, so for
+// anything else we find the size by seeking to the end instead.
+fn padlength(padfile:&mut fs::File) -> Result<u64,io::Error> {
+	let metadata:fs::Metadata = padfile.metadata()?;
+	if metadata.is_file() {
+		return Ok(metadata.len());
+	}
+	let length:u64 = padfile.seek(io::SeekFrom::End(0))?;
+	padfile.seek(io::SeekFrom::Start(0))?;
+	return Ok(length);
+}
+
+impl Pad {
+	/// Opens the pad file, keeping it open and reading bytes from it as they are needed.
+	pub fn open<P:AsRef<Path>>(path:P) -> Result<Pad,Error> {
+		return Pad::open_window(path,PadMode::File,PadWindow::default());
+	}
+
+	/// Reads the entire pad file into memory.
+	pub fn load<P:AsRef<Path>>(path:P) -> Result<Pad,Error> {
+		return Pad::open_window(path,PadMode::Memory,PadWindow::default());
+	}
+
+	/// Memory-maps the pad file.
+	pub fn map<P:AsRef<Path>>(path:P) -> Result<Pad,Error> {
+		return Pad::open_window(path,PadMode::Mapped,PadWindow::default());
+	}
+
+	/// Opens part of a pad file or block device as the pad, using the given mode. Fails if the
+	/// window doesn't fit inside the file.
+	pub fn open_window<P:AsRef<Path>>(path:P,mode:PadMode,window:PadWindow) -> Result<Pad,Error> {
+		let mut padfile:fs::File = fs::File::open(path.as_ref())?;
+		let filesize:u64 = padlength(&mut padfile)?;
+		let size:u64 = match window.size {
+			Some(size) => size,
+			None => filesize.saturating_sub(window.offset),
+		};
+		if size == 0 {
+			return Err(Error::PadEmpty);
+		}
+		if window.offset > filesize || size > filesize - window.offset {
+			return Err(Error::WindowOutOfRange {
+				offset:window.offset,
+				size,
+				filesize,
+			});
+		}
+		let source:PadSource = match mode {
+			PadMode::File => PadSource::File(Mutex::new(padfile)),
+			PadMode::Memory => {
+				let mut padbytes:Vec<u8> = Vec::with_capacity(size as usize);
+				padfile.seek(io::SeekFrom::Start(window.offset))?;
+				padfile.take(size).read_to_end(&mut padbytes)?;
+				if (padbytes.len() as u64) < size {
+					return Err(Error::PadTooSmall {
+						offset:padbytes.len() as u64,
+						size,
+					});
+				}
+				PadSource::Memory(padbytes)
+			},
+			PadMode::Mapped => {
+				// Safety: the map is read-only, and nothing in this program writes to the pad. If
+				// some other process truncates the pad file while it's mapped, reads from it may
+				// fault, but swapping out a pad under a running client is already a fatal mistake.
+				let padmap:Mmap = unsafe {
+					MmapOptions::new().offset(window.offset).len(size as usize).map(&padfile)?
+				};
+				PadSource::Mapped(padmap)
+			},
+		};
+		return Ok(Pad {
+			path:path.as_ref().to_path_buf(),
+			offset:window.offset,
+			size,
+			source:Arc::new(source),
+		});
+	}
+
+	/// The path of the pad file this handle refers to.
+	pub fn path(&self) -> &Path {
+		return &self.path;
+	}
+
+	/// The size of the pad, in bytes.
+	pub fn size(&self) -> u64 {
+		return self.size;
+	}
+
+	/// Where the pad starts in its file, in bytes.
+	pub fn offset(&self) -> u64 {
+		return self.offset;
+	}
+
+	/// Retrieves the byte at the given offset into the pad.
+	pub fn byte(&self,offset:u64) -> Result<u8,Error> {
+		if offset >= self.size {
+			return Err(Error::PadTooSmall {
+				offset,
+				size:self.size,
+			});
+		}
+		match *self.source {
+			PadSource::File(ref padfile) => {
+				let mut padfile = match padfile.lock() {
+					Ok(padfile) => padfile,
+					Err(poisoned) => poisoned.into_inner(),
+				};
+				let mut inbin:[u8;1] = [0];
+				// Every failure here has to be reported - quietly carrying on would produce a key
+				// from whatever happened to be left in the buffer.
+				let readresult = padfile.seek(io::SeekFrom::Start(self.offset + offset))
+					.and_then(|_| padfile.read_exact(&mut inbin));
+				match readresult {
+					Ok(()) => return Ok(inbin[0]),
+					Err(ref why) if why.kind() == io::ErrorKind::UnexpectedEof => {
+						return Err(Error::PadTooSmall {
+							offset,
+							size:self.size,
+						});
+					},
+					Err(why) => {
+						return Err(Error::ReadFailed {
+							offset,
+							source:why,
+						});
+					},
+				};
+			},
+			PadSource::Memory(ref padbytes) => match padbytes.get(offset as usize) {
+				Some(byte) => return Ok(*byte),
+				None => return Err(Error::PadTooSmall { offset, size:self.size }),
+			},
+			PadSource::Mapped(ref padmap) => return Ok(padmap[offset as usize]),
+		};
+	}
+}
+
+/// Statistics about the contents of a pad, for checking that it's fit for use.
+pub struct PadReport {
+	/// The size of the pad, in bytes.
+	pub size:u64,
+	/// How many times each byte value occurs in the pad.
+	pub counts:[u64;256],
+}
+
+impl PadReport {
+	/// The number of byte values that never occur in the pad. A good pad contains every one.
+	pub fn missing(&self) -> usize {
+		return self.counts.iter().filter(|n| **n == 0).count();
+	}
+
+	/// The Shannon entropy of the pad's byte distribution, in bits per byte. Random data comes out
+	/// very close to 8.
+	pub fn entropy(&self) -> f64 {
+		let total:f64 = self.size as f64;
+		return self.counts.iter()
+			.filter(|n| **n > 0)
+			.map(|n| {
+				let p:f64 = *n as f64/total;
+				-p*p.log2()
+			})
+			.sum();
+	}
+}
+
+impl Pad {
+	/// Reads the whole pad and counts its byte values. Fails if any part of the pad can't be read.
+	pub fn report(&self) -> Result<PadReport,Error> {
+		let mut counts:[u64;256] = [0;256];
+		match *self.source {
+			PadSource::File(ref padfile) => {
+				let mut padfile = match padfile.lock() {
+					Ok(padfile) => padfile,
+					Err(poisoned) => poisoned.into_inner(),
+				};
+				if let Err(why) = padfile.seek(io::SeekFrom::Start(self.offset)) {
+					return Err(Error::ReadFailed { offset:0, source:why });
+				}
+				let mut inbin:Vec<u8> = vec![0;65_536];
+				let mut position:u64 = 0;
+				while position < self.size {
+					let want:usize = inbin.len().min((self.size - position) as usize);
+					match padfile.read(&mut inbin[..want]) {
+						Ok(0) => return Err(Error::PadTooSmall { offset:position, size:self.size }),
+						Ok(nread) => {
+							for byte in inbin[..nread].iter() {
+								counts[*byte as usize] += 1;
+							}
+							position += nread as u64;
+						},
+						Err(ref why) if why.kind() == io::ErrorKind::Interrupted => (),
+						Err(why) => return Err(Error::ReadFailed { offset:position, source:why }),
+					};
+				}
+			},
+			PadSource::Memory(ref padbytes) => for byte in padbytes.iter() {
+				counts[*byte as usize] += 1;
+			},
+			PadSource::Mapped(ref padmap) => for byte in padmap.iter() {
+				counts[*byte as usize] += 1;
+			},
+		};
+		return Ok(PadReport {
+			size:self.size,
+			counts,
+		});
+	}
+}
+
+/// Writes a new pad file of the given size, filled with bytes from the operating system's secure
+/// random number generator. Refuses to overwrite an existing file unless `overwrite` is set.
+pub fn generate_pad<P:AsRef<Path>>(path:P,size:u64,overwrite:bool) -> Result<(),io::Error> {
+	let mut rng:OsRng = OsRng::new()?;
+	let mut options:fs::OpenOptions = fs::OpenOptions::new();
+	options.write(true);
+	if overwrite {
+		options.create(true).truncate(true);
+	} else {
+		options.create_new(true);
+	}
+	let mut padfile:fs::File = options.open(path.as_ref())?;
+	let mut outbin:Vec<u8> = vec![0;65_536];
+	let mut remaining:u64 = size;
+	while remaining > 0 {
+		let chunk:usize = outbin.len().min(remaining as usize);
+		rng.fill_bytes(&mut outbin[..chunk]);
+		padfile.write_all(&outbin[..chunk])?;
+		remaining -= chunk as u64;
+	}
+	padfile.sync_all()?;
+	return Ok(());
+}
+
+/// Generates a single-use encryption key from a provided key size, pad and authentication nonce,
+/// and returns the key and its associated secret seed.
+pub fn keygen(nonce:&[u8;8],pad:&Pad,keysize:usize) -> Result<(Vec<u8>,Vec<u8>),Error> {
+	let padsize:u64 = pad.size();
+	if padsize == 0 {
+		// Would otherwise be a modulo-by-zero panic below.
+		return Err(Error::PadEmpty);
+	}
+	let mut seed:[u8;8] = [0;8];
+	let mut seednonce:[u8;8] = *nonce;
+	let mut newseednonce:[u8;8] = [0;8];
+	// Hash the nonce, previous hash, and previous byte retrieved eight times, using each hash to
+	// index one byte from the pad file. These eight bytes are the secret seed.
+	// The hash is *truncated* to the first eight bytes (64 bits), then *moduloed* to the length of
+	// the pad file. (If you try to decrypt by just moduloing the whole hash against the pad
+	// length, it won't work.)
+	for x in 0..8 {
+		let mut sha3 = Keccak::new_sha3_256();
+		sha3.update(nonce);
+		sha3.update(&seednonce);
+		if x >= 1 {
+			sha3.update(&[seed[x-1]]);
+		}
+		sha3.finalize(&mut newseednonce);
+		seednonce = newseednonce;
+		seed[x] = pad.byte(bytes2int(&seednonce) % padsize)?;
+	}
+	let mut keybytes:Vec<u8> = Vec::with_capacity(keysize);
+	let mut keynonce:[u8;8] = seed;
+	let mut newkeynonce:[u8;8] = [0;8];
+	// Hash the seed, previous hash, and previous byte retrieved n times, where n is the length of
+	// the key to be generated. Use each hash to index bytes from the pad file (with the same
+	// method as before). These bytes are the key.
+	for x in 0..keysize {
+		let mut sha3 = Keccak::new_sha3_256();
+		sha3.update(&seed);
+		sha3.update(&keynonce);
+		if x >= 1 {
+			sha3.update(&[keybytes[x-1]]);
+		}
+		sha3.finalize(&mut newkeynonce);
+		keynonce = newkeynonce;
+		keybytes.push(pad.byte(bytes2int(&keynonce) % padsize)?);
+	}
+	return Ok((keybytes,seed.to_vec()));
+}
+
+/// Encrypts a message for transmission.
+/// Generates a random nonce, produces a key, signs the message using the secret seed, and returns
+/// the resulting encrypted payload (including the message, signature, and nonce).
+pub fn encrypt(message:&[u8],pad:&Pad) -> Result<Vec<u8>,Error> {
+	let nonce:u64 = rand::random::<u64>();
+	let noncebytes:[u8;8] = int2bytes(&nonce);
+	let keysize:usize = message.len()+8;
+	// Use the keygen function to create a key of length n + 8, where n is the length of the
+	// message to be encrypted. (The extra eight bytes are for encrypting the signature.)
+	let (keybytes,seed) = keygen(&noncebytes,pad,keysize)?;
+	let mut signature:[u8;8] = [0;8];
+	let mut sha3 = Keccak::new_sha3_256();
+	// Generate the signature by hashing the secret seed, the unencrypted message, and the key used
+	// to encrypt the signature and message.
+	sha3.update(&seed);
+	sha3.update(message);
+	sha3.update(&keybytes);
+	sha3.finalize(&mut signature);
+	let mut payload:Vec<u8> = Vec::with_capacity(keysize+8);
+	for (x,byte) in message.iter().chain(signature.iter()).enumerate() {
+		payload.push(byte ^ keybytes[x]);
+	}
+	payload.extend_from_slice(&noncebytes);
+	return Ok(payload);
+}
+
+/// Decrypts a received payload.
+/// Uses the nonce attached to the payload to generate the same key and secret seed, decrypt the
+/// payload, and verify the resulting message with its signature. The signature will only validate
+/// if the message was the original one encrypted with the same pad as the one used to decrypt it;
+/// if it was not, this function returns `Error::SignatureInvalid`. Payloads too short to hold a
+/// signature and nonce are rejected with `Error::PayloadTooShort` before the pad is touched.
+pub fn decrypt(payload:&[u8],pad:&Pad) -> Result<Vec<u8>,Error> {
+	if payload.len() < OVERHEAD {
+		return Err(Error::PayloadTooShort {
+			length:payload.len(),
+		});
+	}
+	let mut noncebytes:[u8;8] = [0;8];
+	// Detach the nonce from the payload, and use it to generate the key and secret seed.
+	noncebytes.copy_from_slice(&payload[payload.len()-8..]);
+	let keysize = payload.len()-8;
+	let ciphertext:&[u8] = &payload[..keysize];
+	let (keybytes,seed) = keygen(&noncebytes,pad,keysize)?;
+	// Decrypt the message and signature using the key.
+	let verimessage:Vec<u8> = ciphertext.iter().zip(keybytes.iter()).map(|(c,k)| c ^ k).collect();
+	let mut signature:[u8;8] = [0;8];
+	// Detach the signature from the decrypted message, and use it to verify the integrity of the
+	// message. If the check succeeds, return Ok() containing the message content; if it fails,
+	// return a SignatureInvalid error.
+	signature.copy_from_slice(&verimessage[verimessage.len()-8..]);
+	let message:Vec<u8> = verimessage[..verimessage.len()-8].to_vec();
+	let mut rightsum:[u8;8] = [0;8];
+	let mut sha3 = Keccak::new_sha3_256();
+	sha3.update(&seed);
+	sha3.update(&message);
+	sha3.update(&keybytes);
+	sha3.finalize(&mut rightsum);
+	if signature == rightsum {
+		return Ok(message);
+	} else {
+		return Err(Error::SignatureInvalid);
+	}
+}
@@ -0,0 +1,85 @@
+// Backing off between subscription attempts: how the wait grows, how jitter spreads it out, and
+// giving up once the retry budget is spent.
+
+extern crate teamech;
+
+mod common;
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use common::{Client,StandIn};
+use teamech::backoff::{Backoff,BackoffPolicy};
+use teamech::teacrypt::Pad;
+
+fn policy(jitter:f64,budget:Option<u32>) -> BackoffPolicy {
+	return BackoffPolicy {
+		initial:Duration::from_millis(100),
+		max:Duration::from_millis(1_000),
+		multiplier:2,
+		jitter,
+		budget,
+	};
+}
+
+fn millis(delay:Option<Duration>) -> u128 {
+	return delay.expect("gave up too soon").as_millis();
+}
+
+#[test]
+fn waits_grow_up_to_the_maximum() {
+	let mut backoff:Backoff = Backoff::new(policy(0.0,None));
+	let delays:Vec<u128> = (0..7).map(|_| millis(backoff.fail())).collect();
+	assert_eq!(delays,vec![100,200,400,800,1_000,1_000,1_000]);
+	assert_eq!(backoff.failures(),7);
+}
+
+#[test]
+fn success_starts_over() {
+	let mut backoff:Backoff = Backoff::new(policy(0.0,None));
+	backoff.fail();
+	backoff.fail();
+	backoff.reset();
+	assert_eq!(backoff.failures(),0);
+	assert_eq!(millis(backoff.fail()),100);
+}
+
+#[test]
+fn jitter_shortens_waits_by_varying_amounts() {
+	let mut seen:HashSet<u128> = HashSet::new();
+	for _ in 0..100 {
+		let mut backoff:Backoff = Backoff::new(policy(0.5,None));
+		backoff.fail();
+		// The second wait is 200 ms, less up to half of it.
+		let delay:u128 = millis(backoff.fail());
+		assert!((100..=200).contains(&delay),"{} ms",delay);
+		seen.insert(delay);
+	}
+	// A fleet of devices would all wait the same time without jitter.
+	assert!(seen.len() > 10,"{:?}",seen);
+}
+
+#[test]
+fn gives_up_once_the_budget_is_spent() {
+	let mut backoff:Backoff = Backoff::new(policy(0.0,Some(2)));
+	assert!(backoff.fail().is_some());
+	assert!(backoff.fail().is_some());
+	assert!(backoff.fail().is_none());
+	// Success restores the budget.
+	backoff.reset();
+	assert!(backoff.fail().is_some());
+}
+
+#[test]
+fn client_gives_up_on_a_server_that_never_answers() {
+	let pad:Pad = common::pad("budget");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",false);
+	let mut client:Client = Client::start_with("budget",&[String::from("--server"),server.address.to_string()],&[
+		("TEAMECH_RETRY_BUDGET","3"),
+		("TEAMECH_MAX_BACKOFF_MS","200"),
+	]);
+	let output:Vec<String> = client.logged("Giving up after 4 failures in a row.");
+	let attempts:usize = output.iter().filter(|line| line.contains("Trying to contact server")).count();
+	assert_eq!(attempts,4,"{:?}",output);
+	assert_eq!(client.exit_code(),Some(4));
+}
@@ -0,0 +1,29 @@
+// The byte helpers the library exports: packing integers into bytes and back, and showing bytes
+// as hex.
+
+extern crate teamech;
+
+use teamech::{bytes2hex,bytes2int,int2bytes};
+
+#[test]
+fn integers_survive_the_round_trip() {
+	for n in [0,1,0xff,0x100,0x0123_4567_89ab_cdef,u64::MAX - 1,u64::MAX].iter() {
+		assert_eq!(bytes2int(&int2bytes(n)),*n);
+	}
+}
+
+#[test]
+fn integers_are_packed_most_significant_byte_first() {
+	assert_eq!(int2bytes(&0x0123_4567_89ab_cdef),[0x01,0x23,0x45,0x67,0x89,0xab,0xcd,0xef]);
+	assert_eq!(int2bytes(&0),[0;8]);
+	assert_eq!(bytes2int(&[0,0,0,0,0,0,0,1]),1);
+	assert_eq!(bytes2int(&[0xff;8]),u64::MAX);
+}
+
+#[test]
+fn hex_separates_bytes_with_spaces() {
+	assert_eq!(bytes2hex(&[]),"");
+	assert_eq!(bytes2hex(&[0x0a]),"0a");
+	assert_eq!(bytes2hex(b"Hi!"),"48 69 21");
+	assert_eq!(bytes2hex(&int2bytes(&0xdead_beef)),"00 00 00 00 de ad be ef");
+}
@@ -0,0 +1,187 @@
+// The command line: each command, help and version, and the mistakes that are reported as usage
+// errors.
+
+extern crate teamech;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Command,Output};
+
+use common::StandIn;
+use teamech::bytes2hex;
+use teamech::packet::Packet;
+use teamech::teacrypt::Pad;
+
+static NAME:&str = env!("CARGO_PKG_NAME");
+
+// Runs the program to completion, and returns its exit code and what it printed.
+fn run(args:&[&str]) -> (Option<i32>,String) {
+	let output:Output = Command::new(env!("CARGO_BIN_EXE_teamech-embedded-template"))
+		.args(args)
+		.env_remove("TEAMECH_CONFIG")
+		.env_remove("NOTIFY_SOCKET")
+		.env("TEAMECH_RESPONSE_TIMEOUT_MS","100")
+		.env("TEAMECH_ERROR_DELAY_MS","100")
+		.output()
+		.expect("could not run the client");
+	return (output.status.code(),String::from_utf8_lossy(&output.stdout).into_owned());
+}
+
+// Checks that the program refused to run with a usage error mentioning `problem`.
+fn usage_error(args:&[&str],problem:&str) {
+	let (code,output) = run(args);
+	assert_eq!(code,Some(2),"{:?}: {}",args,output);
+	assert!(output.contains(problem),"{:?}: {}",args,output);
+	assert!(output.contains(&format!("Try '{} --help'",NAME)),"{:?}: {}",args,output);
+}
+
+fn temppath(name:&str) -> PathBuf {
+	let path:PathBuf = env::temp_dir().join(format!("teamech-test-{}-{}.pad",name,std::process::id()));
+	let _ = fs::remove_file(&path);
+	return path;
+}
+
+#[test]
+fn help_and_version() {
+	let (code,output) = run(&["--help"]);
+	assert_eq!(code,Some(0));
+	assert!(output.starts_with(&format!("Usage: {} [COMMAND] [OPTIONS] [ARGS]",NAME)),"{}",output);
+	assert!(output.contains("  -s, --server HOST:PORT"),"{}",output);
+	for args in [&["genpad","--help"][..],&["help","genpad"][..],&["genpad","-h","--size","nonsense"][..]].iter() {
+		let (code,output) = run(args);
+		assert_eq!(code,Some(0));
+		assert!(output.starts_with(&format!("Usage: {} genpad [--size BYTES] [--force] PATH",NAME)),"{:?}: {}",args,output);
+	}
+	let (code,output) = run(&["-V"]);
+	assert_eq!(code,Some(0));
+	assert_eq!(output,format!("{} {}\n",NAME,env!("CARGO_PKG_VERSION")));
+	assert_eq!(run(&["decode","--version"]).1,output);
+}
+
+#[test]
+fn unknown_and_malformed_options_are_usage_errors() {
+	usage_error(&["--frobnicate"],"unknown option '--frobnicate' for run");
+	usage_error(&["check-pad","--force"],"unknown option '--force' for check-pad");
+	usage_error(&["run","--server"],"option --server needs a value");
+	usage_error(&["run","--quiet=yes"],"option --quiet does not take a value (got 'yes')");
+	usage_error(&["run","-p","http"],"'http' is not a valid port number for -p");
+	usage_error(&["run","--bind=localhost"],"'localhost' is not a valid IP address for --bind");
+	usage_error(&["run","--pad-mode","flash"],"'flash' is not a valid pad mode");
+	usage_error(&["run","--log-format","xml"],"'xml' is not a valid log format");
+	usage_error(&["run","a:1","2","pad","extra"],"too many arguments for run");
+	usage_error(&["run","a:1","port","pad"],"'port' is not a valid local port number");
+	usage_error(&["send","--server","127.0.0.1:3840"],"send needs a message to send");
+}
+
+#[test]
+fn byte_counts_take_suffixes() {
+	let path:PathBuf = temppath("clisize");
+	let (code,output) = run(&["genpad","--size","4K",path.to_str().unwrap()]);
+	assert_eq!(code,Some(0),"{}",output);
+	assert_eq!(fs::metadata(&path).unwrap().len(),4_096);
+	let (code,_output) = run(&["genpad","--force","--size=1m",path.to_str().unwrap()]);
+	assert_eq!(code,Some(0));
+	assert_eq!(fs::metadata(&path).unwrap().len(),1 << 20);
+	let _ = fs::remove_file(&path);
+	for size in ["","K","1X","-1","1.5K","18446744073709551615G"].iter() {
+		usage_error(&["genpad","--size",size,"pad"],&format!("'{}' is not a valid number of bytes",size));
+	}
+	usage_error(&["check-pad","--pad-offset","ten"],"'ten' is not a valid number of bytes");
+	usage_error(&["genpad","--size","0","pad"],"pad size must be greater than zero");
+}
+
+#[test]
+fn genpad_keeps_existing_files() {
+	let path:PathBuf = temppath("clikeep");
+	fs::write(&path,b"keep me").unwrap();
+	let (code,output) = run(&["genpad","--size","1K",path.to_str().unwrap()]);
+	assert_eq!(code,Some(1),"{}",output);
+	assert!(output.starts_with("Could not generate pad file"),"{}",output);
+	assert_eq!(fs::read(&path).unwrap(),b"keep me");
+	let (code,output) = run(&["genpad","--size","1K","--force",path.to_str().unwrap()]);
+	assert_eq!((code,output),(Some(0),format!("Wrote 1024 random bytes to {}.\n",path.display())));
+	let _ = fs::remove_file(&path);
+	usage_error(&["genpad"],"genpad needs exactly one path");
+	usage_error(&["genpad","one","two"],"genpad needs exactly one path");
+}
+
+#[test]
+fn check_pad_reports_on_a_window() {
+	let path:PathBuf = temppath("clicheck");
+	assert_eq!(run(&["genpad","--size","128K",path.to_str().unwrap()]).0,Some(0));
+	let (code,output) = run(&["check-pad","--pad-offset","1K","--pad-size","64K",path.to_str().unwrap()]);
+	assert_eq!(code,Some(0),"{}",output);
+	assert!(output.contains("Size: 65536 bytes (from offset 1024)\n"),"{}",output);
+	assert!(output.ends_with("Pad looks usable.\n"),"{}",output);
+	// A pad that's all one byte value is not.
+	fs::write(&path,vec![0x55;4_096]).unwrap();
+	let (code,output) = run(&["check-pad","--pad",path.to_str().unwrap()]);
+	assert_eq!(code,Some(1),"{}",output);
+	assert!(output.contains("Warning: 255 of the 256 byte values never appear"),"{}",output);
+	let _ = fs::remove_file(&path);
+	usage_error(&["check-pad"],"no pad file was given");
+	usage_error(&["check-pad","one","two"],"check-pad takes at most one pad path");
+}
+
+#[test]
+fn decode_shows_a_captured_payload() {
+	let padpath:PathBuf = common::padfile("clidecode");
+	let pad:Pad = Pad::load(&padpath).unwrap();
+	let payload:Vec<u8> = Packet::encode(b"lamp on",1_538_395_200_123,&pad).unwrap();
+	// The hex can be split up and separated however it was captured.
+	let hex:String = bytes2hex(&payload);
+	let (left,right) = hex.split_at(12);
+	let colons:String = right.replace(' ',":");
+	let (code,output) = run(&["decode","-k",padpath.to_str().unwrap(),left,&colons]);
+	assert_eq!(code,Some(0),"{}",output);
+	assert!(output.starts_with("Text: lamp on\nBytes: 6c 61 6d 70 20 6f 6e\nTimestamp: 1538395200123 ms ("),"{}",output);
+	let (code,output) = run(&["decode","-k",padpath.to_str().unwrap(),&bytes2hex(&[0x55;24])]);
+	assert_eq!(code,Some(1),"{}",output);
+	assert!(output.starts_with("Could not decode payload - payload signature verification failed"),"{}",output);
+	usage_error(&["decode","-k",padpath.to_str().unwrap(),"zz"],"'zz' is not hexadecimal");
+	usage_error(&["decode","-k",padpath.to_str().unwrap(),"abc"],"hex payload has an odd number of digits");
+	usage_error(&["decode"],"decode needs a payload, in hex");
+	let _ = fs::remove_file(&padpath);
+}
+
+#[test]
+fn send_delivers_one_message() {
+	let padpath:PathBuf = common::padfile("clisend");
+	let pad:Pad = Pad::load(&padpath).unwrap();
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let address:String = server.address.to_string();
+	let (code,output) = run(&["send","-s",&address,"--pad",padpath.to_str().unwrap(),"lamp","on"]);
+	assert_eq!(code,Some(0),"{}",output);
+	assert!(output.contains("Message acknowledged by server after"),"{}",output);
+	let from:SocketAddr = server.subscribed();
+	assert_eq!(server.expect("the message",|received| received.content == b"lamp on").from,from);
+	let _ = fs::remove_file(&padpath);
+}
+
+// Commands that don't talk to a server refuse the options that only matter when talking to one,
+// rather than quietly ignoring them.
+#[test]
+fn offline_commands_refuse_network_options() {
+	let networkoptions:[&[&str];7] = [
+		&["--server","127.0.0.1:3840"],
+		&["-s","127.0.0.1:3840"],
+		&["--backup","127.0.0.1:3841"],
+		&["--bind","127.0.0.1"],
+		&["--dual-stack"],
+		&["--port=3840"],
+		&["--showhex"],
+	];
+	for command in [&["genpad","pad"][..],&["check-pad","pad"][..],&["decode","00"][..]].iter() {
+		for option in networkoptions.iter() {
+			let mut args:Vec<&str> = vec![command[0]];
+			args.extend_from_slice(option);
+			args.push(command[1]);
+			let name:&str = option[0].split('=').next().unwrap();
+			usage_error(&args,&format!("option {} only applies to run and send, not {}",name,command[0]));
+		}
+	}
+}
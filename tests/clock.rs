@@ -0,0 +1,150 @@
+// Checking timestamps against the local clock, or against an estimate of the server's, including
+// clocks that have never been set (or are set before the epoch) and timestamps at the very ends of
+// the range.
+
+extern crate teamech;
+
+use std::thread::sleep;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use teamech::clock;
+use teamech::clock::{Clock,ClockPolicy,StaleTimestamp,TimestampValidator};
+use teamech::systime;
+
+// An hour, in milliseconds.
+const HOUR:u64 = 3_600_000;
+
+#[test]
+fn zero_timestamps_are_stale() {
+	let validator:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Strict);
+	// What a device that stamps its messages with systime() would send while its clock is before the
+	// epoch.
+	let stale:StaleTimestamp = validator.check(0).unwrap_err();
+	assert!(stale.skew < -(clock::EARLIEST_PLAUSIBLE_TIME as i64),"{:?}",stale);
+	assert!(stale.to_string().starts_with("timestamp 0 is "),"{}",stale);
+	assert!(stale.to_string().ends_with(" ms in the past (tolerance is 10000 ms)"),"{}",stale);
+	assert!(clock::is_set());
+}
+
+#[test]
+fn extreme_timestamps_saturate() {
+	let validator:TimestampValidator = TimestampValidator::new(u64::MAX,ClockPolicy::Strict);
+	// The difference is too big for an i64, so it's clamped rather than wrapping around.
+	assert_eq!(validator.skew(u64::MAX),i64::MAX);
+	assert!(validator.skew(0) < 0);
+	// With no limit, nothing is stale, however far off.
+	assert!(validator.check(0).is_ok());
+	assert!(validator.check(u64::MAX).is_ok());
+	let strict:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Strict);
+	assert_eq!(strict.check(u64::MAX).unwrap_err().skew,i64::MAX);
+	// Offsets at either extreme can't push the corrected clock past either end of its range.
+	let mut estimate:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Estimate);
+	assert_eq!(estimate.calibrate(0,0,u64::MAX),i64::MAX);
+	assert!(estimate.clock().now() > i64::MAX as u64);
+	assert_eq!(estimate.skew(0),i64::MIN);
+	assert_eq!(estimate.calibrate(u64::MAX,u64::MAX,0),i64::MIN);
+	assert_eq!(estimate.clock().now(),0);
+	assert!(estimate.check(0).is_ok());
+	assert_eq!(estimate.skew(u64::MAX),i64::MAX);
+}
+
+#[test]
+fn calibration_assumes_the_server_answered_halfway() {
+	let mut validator:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Estimate);
+	// A local clock that has never been set, answered by a server that knows better.
+	assert_eq!(validator.calibrate(0,0,1_538_395_200_000),1_538_395_200_000);
+	assert_eq!(validator.clock().offset(),1_538_395_200_000);
+	// Sent at 1000, answered at 1400: the server's 2200 was at our 1200.
+	assert_eq!(validator.calibrate(1_000,1_400,2_200),1_000);
+	// A server that's behind gives a negative offset.
+	assert_eq!(validator.calibrate(10_000,10_000,4_000),-6_000);
+	// If the local clock stepped back while waiting, the answer is taken to be instant.
+	assert_eq!(validator.calibrate(1_000,500,1_000),0);
+	// The other policies leave the offset alone.
+	let mut strict:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Strict);
+	assert_eq!(strict.calibrate(0,0,HOUR),0);
+	assert_eq!(strict.clock().offset(),0);
+	assert_eq!(TimestampValidator::new(10_000,ClockPolicy::Ignore).calibrate(0,0,HOUR),0);
+}
+
+// Each validator keeps its own estimate, so calibrating one (e.g. in another thread, or for another
+// server) leaves the rest alone.
+#[test]
+fn validators_keep_their_own_offsets() {
+	let mut first:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Estimate);
+	let second:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Estimate);
+	let local:u64 = systime();
+	first.calibrate(local,local,local + HOUR);
+	assert_eq!(first.clock().offset(),HOUR as i64);
+	assert_eq!(second.clock().offset(),0);
+	assert!(first.clock().now() >= systime() + HOUR - 1_000);
+	assert!(second.clock().now() < systime() + 1_000);
+	assert!(second.check(systime()).is_ok());
+	// A fresh clock goes by the local one.
+	assert_eq!(Clock::new().offset(),0);
+}
+
+// A local clock before the epoch reads as the time since the Clock was made, which keeps moving,
+// so that once the offset has been estimated, the corrected time keeps up with the server's rather
+// than being stuck at one moment.
+#[test]
+fn clocks_before_the_epoch_keep_moving() {
+	let clock:Clock = Clock::new();
+	let early:SystemTime = UNIX_EPOCH - Duration::from_secs(HOUR);
+	let first:u64 = clock.reading(early);
+	assert!(first < 1_000,"{}",first);
+	sleep(Duration::from_millis(50));
+	let second:u64 = clock.reading(early);
+	assert!(second >= first + 50,"{} then {}",first,second);
+	// A clock after the epoch reads as it is.
+	assert_eq!(clock.reading(UNIX_EPOCH + Duration::from_millis(HOUR)),HOUR);
+	// Calibrated against a server whose clock is right, the readings are corrected to its time,
+	// which keeps passing.
+	let mut validator:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Estimate);
+	let clock:Clock = *validator.clock();
+	let sent:u64 = clock.reading(early);
+	let servertime:u64 = systime();
+	let offset:i64 = validator.calibrate(sent,clock.reading(early),servertime);
+	assert!(offset > clock::EARLIEST_PLAUSIBLE_TIME as i64,"{}",offset);
+	sleep(Duration::from_millis(50));
+	let corrected:i64 = clock.reading(early) as i64 + offset;
+	let actual:i64 = systime() as i64;
+	assert!(corrected > servertime as i64,"{} then {}",servertime,corrected);
+	assert!((corrected - actual).abs() < 1_000,"{} against {}",corrected,actual);
+}
+
+#[test]
+fn each_policy_checks_a_different_clock() {
+	let strict:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Strict);
+	let mut estimate:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Estimate);
+	let ignore:TimestampValidator = TimestampValidator::new(10_000,ClockPolicy::Ignore);
+	// The server's clock is an hour ahead of ours.
+	let local:u64 = systime();
+	estimate.calibrate(local,local,local + HOUR);
+	let servertime:u64 = systime() + HOUR;
+	// Strict trusts the local clock, and so refuses the server's timestamps.
+	assert!(strict.check(systime()).is_ok());
+	let stale:StaleTimestamp = strict.check(servertime).unwrap_err();
+	assert!(stale.skew > (HOUR - 10_000) as i64,"{:?}",stale);
+	assert!(stale.to_string().contains(" ms in the future"),"{}",stale);
+	// Estimate goes by the server's clock instead.
+	assert!(estimate.check(servertime).is_ok());
+	assert!(estimate.check(systime()).is_err());
+	assert!(estimate.skew(servertime).abs() < 10_000);
+	// Ignore takes anything.
+	for timestamp in [0,systime(),servertime,u64::MAX].iter() {
+		assert!(ignore.check(*timestamp).is_ok());
+	}
+}
+
+#[test]
+fn tolerance_applies_both_ways() {
+	let validator:TimestampValidator = TimestampValidator::new(HOUR,ClockPolicy::Strict);
+	assert_eq!((validator.tolerance(),validator.policy()),(HOUR,ClockPolicy::Strict));
+	assert!(validator.check(systime() + HOUR/2).is_ok());
+	assert!(validator.check(systime() - HOUR/2).is_ok());
+	assert!(validator.check(systime() + 2*HOUR).is_err());
+	let stale:StaleTimestamp = validator.check(systime() - 2*HOUR).unwrap_err();
+	assert!(stale.skew <= -2*(HOUR as i64),"{:?}",stale);
+	assert_eq!(stale.tolerance,HOUR);
+}
@@ -0,0 +1,236 @@
+// Helpers shared by the integration tests.
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::io::{BufRead,BufReader};
+use std::net::{UdpSocket,SocketAddr};
+use std::path::PathBuf;
+use std::process::{Child,Command,Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration,Instant};
+
+use teamech::clock::Clock;
+use teamech::net::{sendbytes,sendstatus};
+use teamech::packet::Packet;
+use teamech::status::StatusCode;
+use teamech::teacrypt::Pad;
+
+// Writes a deterministic pad file for one test, so that tests running in parallel don't share
+// files. The caller removes it.
+pub fn padfile(name:&str) -> PathBuf {
+	let padpath:PathBuf = env::temp_dir().join(format!("teamech-test-{}-{}.pad",name,std::process::id()));
+	let padbytes:Vec<u8> = (0..65_536u32).map(|n| (n.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+	fs::write(&padpath,&padbytes).expect("could not write test pad");
+	return padpath;
+}
+
+// Loads the pad that padfile() writes.
+pub fn pad(name:&str) -> Pad {
+	let padpath:PathBuf = padfile(name);
+	let pad:Pad = Pad::load(&padpath).expect("could not load test pad");
+	let _ = fs::remove_file(&padpath);
+	return pad;
+}
+
+// Something a stand-in server received: the message content, who from, and whether the server
+// answered it.
+pub struct Received {
+	pub content:Vec<u8>,
+	pub from:SocketAddr,
+	pub answered:bool,
+}
+
+// A server that grants subscriptions and acknowledges messages while it's answering, and ignores
+// everything while it isn't.
+pub struct StandIn {
+	pub address:SocketAddr,
+	pub socket:UdpSocket,
+	answering:Arc<AtomicBool>,
+	pub log:mpsc::Receiver<Received>,
+}
+
+impl StandIn {
+	// Starts a server on a local address, e.g. "127.0.0.1:0".
+	pub fn start(pad:&Pad,local:&str,answering:bool) -> StandIn {
+		let socket:UdpSocket = UdpSocket::bind(local).unwrap();
+		let address:SocketAddr = socket.local_addr().unwrap();
+		let flag:Arc<AtomicBool> = Arc::new(AtomicBool::new(answering));
+		let (received,log) = mpsc::channel();
+		let listener:UdpSocket = socket.try_clone().unwrap();
+		let pad:Pad = pad.clone();
+		let answering:Arc<AtomicBool> = flag.clone();
+		thread::spawn(move || {
+			let clock:Clock = Clock::new();
+			let mut buf:[u8;500] = [0;500];
+			while let Ok((nrecv,srcaddr)) = listener.recv_from(&mut buf) {
+				let packet:Packet = match Packet::decode(&buf[..nrecv],&pad) {
+					Ok(packet) => packet,
+					Err(_) => continue,
+				};
+				let answered:bool = answering.load(Ordering::SeqCst);
+				if answered {
+					let reply:StatusCode = match packet.content.is_empty() {
+						true => StatusCode::Subscribed,
+						false => StatusCode::Ack,
+					};
+					if packet.status().is_none() {
+						let _ = sendstatus(&listener,&srcaddr,reply,&pad,&clock);
+					}
+				}
+				let report:Received = Received {
+					content:packet.content,
+					from:srcaddr,
+					answered,
+				};
+				if received.send(report).is_err() {
+					return;
+				}
+			}
+		});
+		return StandIn {
+			address,
+			socket,
+			answering:flag,
+			log,
+		};
+	}
+
+	pub fn answer(&self,answering:bool) {
+		self.answering.store(answering,Ordering::SeqCst);
+	}
+
+	// Waits for something matching `want` to arrive, and returns it.
+	pub fn expect<F:Fn(&Received) -> bool>(&self,what:&str,want:F) -> Received {
+		let deadline:Instant = Instant::now() + Duration::from_secs(10);
+		while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+			match self.log.recv_timeout(left) {
+				Ok(received) => {
+					if want(&received) {
+						return received;
+					}
+				},
+				Err(_) => break,
+			};
+		}
+		panic!("server at {} never received {}",self.address,what);
+	}
+
+	// Waits for the client to subscribe, and returns its address.
+	pub fn subscribed(&self) -> SocketAddr {
+		return self.expect("a subscription request it answered",|received| received.content.is_empty() && received.answered).from;
+	}
+
+	// Sends the client a message, and waits for it to be acknowledged.
+	pub fn exchange(&self,client:SocketAddr,pad:&Pad) {
+		sendbytes(&self.socket,&client,b"are you there?",pad,&Clock::new()).unwrap();
+		self.expect("an acknowledgement",|received| received.content == [StatusCode::Ack.to_byte()]);
+	}
+}
+
+// The client binary, which is killed when this is dropped. Its output is collected line by line.
+pub struct Client {
+	child:Child,
+	output:mpsc::Receiver<String>,
+	padpath:PathBuf,
+}
+
+impl Client {
+	// Runs the client with the given options, a test pad, and short timeouts.
+	pub fn start(name:&str,options:&[String]) -> Client {
+		return Client::start_with(name,options,&[]);
+	}
+
+	// Like start(), with extra environment variables (which override the short timeouts).
+	pub fn start_with(name:&str,options:&[String],vars:&[(&str,&str)]) -> Client {
+		let padpath:PathBuf = padfile(name);
+		let mut command:Command = Command::new(env!("CARGO_BIN_EXE_teamech-embedded-template"));
+		command.arg("run")
+			.args(options)
+			.arg("--pad").arg(&padpath)
+			.env_remove("TEAMECH_CONFIG")
+			.env_remove("NOTIFY_SOCKET")
+			.env_remove("WATCHDOG_USEC")
+			.env_remove("WATCHDOG_PID")
+			.env("TEAMECH_RESPONSE_TIMEOUT_MS","100")
+			.env("TEAMECH_ERROR_DELAY_MS","100")
+			.env("TEAMECH_FAILOVER_ATTEMPTS","2")
+			.env("TEAMECH_FAILBACK_INTERVAL_MS","300");
+		for (name,value) in vars {
+			command.env(name,value);
+		}
+		let mut child:Child = command.stdout(Stdio::piped())
+			.spawn()
+			.expect("could not start the client");
+		let stdout = child.stdout.take().unwrap();
+		let (lines,output) = mpsc::channel();
+		thread::spawn(move || {
+			for line in BufReader::new(stdout).lines() {
+				match line {
+					Ok(line) => {
+						if lines.send(line).is_err() {
+							return;
+						}
+					},
+					Err(_) => return,
+				};
+			}
+		});
+		return Client {
+			child,
+			output,
+			padpath,
+		};
+	}
+
+	// Waits for the client to print a line containing `text`, and returns everything it printed
+	// before that.
+	pub fn logged(&self,text:&str) -> Vec<String> {
+		let deadline:Instant = Instant::now() + Duration::from_secs(10);
+		let mut before:Vec<String> = Vec::new();
+		while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+			match self.output.recv_timeout(left) {
+				Ok(line) => {
+					if line.contains(text) {
+						return before;
+					}
+					before.push(line);
+				},
+				Err(_) => break,
+			};
+		}
+		panic!("client never logged '{}' (output so far: {:?})",text,before);
+	}
+
+	// Sends the client a signal, e.g. "TERM".
+	pub fn signal(&self,name:&str) {
+		let status = Command::new("kill").arg("-s").arg(name).arg(self.child.id().to_string()).status()
+			.expect("could not run kill");
+		assert!(status.success(),"could not send SIG{} to the client",name);
+	}
+
+	// Waits for the client to exit by itself, and returns its exit code.
+	pub fn exit_code(&mut self) -> Option<i32> {
+		let deadline:Instant = Instant::now() + Duration::from_secs(10);
+		while Instant::now() < deadline {
+			if let Some(status) = self.child.try_wait().expect("could not check on the client") {
+				return status.code();
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		panic!("client never exited");
+	}
+}
+
+impl Drop for Client {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+		let _ = fs::remove_file(&self.padpath);
+	}
+}
@@ -0,0 +1,285 @@
+// Configuration: reading TOML files, TEAMECH_* overrides, which source wins, and the settings that
+// are rejected.
+
+extern crate log;
+extern crate teamech;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::net::{IpAddr,SocketAddr};
+use std::path::{Path,PathBuf};
+use std::sync::{Mutex,MutexGuard};
+use std::time::Duration;
+
+use log::LevelFilter;
+use common::{Client,StandIn};
+use teamech::backoff::Backoff;
+use teamech::clock::ClockPolicy;
+use teamech::config::{Config,ConfigError,MatchKind,PadConfig,PadModeConfig};
+use teamech::logging::{LogFormat,LogOutput};
+use teamech::teacrypt::{Pad,PadMode};
+
+// Environment variables are shared by the whole test process, and inherited by the clients it
+// starts, so tests that set them or start clients take turns.
+static ENVIRONMENT:Mutex<()> = Mutex::new(());
+
+fn environment() -> MutexGuard<'static,()> {
+	match ENVIRONMENT.lock() {
+		Ok(guard) => return guard,
+		Err(poisoned) => return poisoned.into_inner(),
+	};
+}
+
+// Writes a configuration file for one test. The caller removes it.
+fn configfile(name:&str,text:&str) -> PathBuf {
+	let path:PathBuf = env::temp_dir().join(format!("teamech-test-{}-{}.toml",name,std::process::id()));
+	fs::write(&path,text).unwrap();
+	return path;
+}
+
+fn load(name:&str,text:&str) -> Result<Config,ConfigError> {
+	let path:PathBuf = configfile(name,text);
+	let config:Result<Config,ConfigError> = Config::load(&path);
+	let _ = fs::remove_file(&path);
+	return config;
+}
+
+// A configuration that passes validation, to break one setting at a time.
+fn valid() -> Config {
+	return Config {
+		server:Some(String::from("teamech.example.com:3840")),
+		pad:PadConfig {
+			path:Some(PathBuf::from("/etc/teamech/network.pad")),
+			..PadConfig::default()
+		},
+		..Config::default()
+	};
+}
+
+// Checks that validation fails, mentioning `problem`, once `breakage` is done to a valid
+// configuration.
+fn rejected<F:Fn(&mut Config)>(problem:&str,breakage:F) {
+	let mut config:Config = valid();
+	breakage(&mut config);
+	match config.validate() {
+		Err(ConfigError::Invalid(ref why)) => assert!(why.contains(problem),"{}: {}",problem,why),
+		other => panic!("{}: {:?}",problem,other),
+	};
+}
+
+#[test]
+fn files_are_read_over_the_defaults() {
+	let config:Config = load("parse","
+		server = \"teamech.example.com:3840\"
+		backup_servers = [\"backup.example.com:3840\"]
+
+		[network]
+		bind = \"::\"
+		dual_stack = true
+
+		[pad]
+		path = \"/dev/mmcblk0p3\"
+		mode = \"mapped\"
+		size = 1048576
+
+		[protocol]
+		clock_skew = \"ignore\"
+
+		[retry]
+		retry_budget = 5
+		failback_interval_ms = 0
+
+		[logging]
+		level = \"warn,messages=off\"
+		format = \"json\"
+		output = \"stderr\"
+
+		[[handlers]]
+		match = \"command\"
+		text = \"ping\"
+		reply = \"pong\"
+	").unwrap();
+	assert_eq!(config.server,Some(String::from("teamech.example.com:3840")));
+	assert_eq!(config.backup_servers,vec![String::from("backup.example.com:3840")]);
+	assert_eq!(config.network.bind,Some("::".parse::<IpAddr>().unwrap()));
+	assert!(config.network.dual_stack);
+	assert_eq!(config.pad.path,Some(PathBuf::from("/dev/mmcblk0p3")));
+	assert_eq!((config.pad.mode(),config.pad.offset,config.pad.size),(PadMode::Mapped,0,Some(1 << 20)));
+	assert_eq!(config.protocol.clock_skew,ClockPolicy::Ignore);
+	assert_eq!(config.retry.backoff_policy().budget,Some(5));
+	assert_eq!(config.retry.failover_policy().failback_interval,None);
+	assert_eq!(config.logging.level.level("teamech"),LevelFilter::Warn);
+	assert_eq!(config.logging.level.level("messages"),LevelFilter::Off);
+	assert_eq!((config.logging.format,config.logging.output),(LogFormat::Json,LogOutput::Stderr));
+	assert_eq!(config.handlers.len(),1);
+	assert_eq!((config.handlers[0].kind,config.handlers[0].text.as_str()),(MatchKind::Command,"ping"));
+	// Everything else keeps its default.
+	assert_eq!(config.network.port,0);
+	assert_eq!(config.protocol.timestamp_tolerance_ms,10_000);
+	assert_eq!(config.retry.backoff_policy().initial,Duration::from_millis(1_000));
+	assert_eq!(config.keepalive.policy().interval,Some(Duration::from_secs(30)));
+	assert!(config.shutdown.goodbye);
+	assert!(config.validate().is_ok());
+}
+
+#[test]
+fn bad_files_are_rejected() {
+	for (name,text) in [
+		("notoml","server = "),
+		("unknownsetting","[network]\nbindd = \"::\""),
+		("unknownsection","[netwrok]\nport = 3840"),
+		("wrongtype","[network]\nport = \"3840\""),
+		("badmode","[pad]\nmode = \"flash\""),
+		("badfilter","[logging]\nlevel = \"loud\""),
+		("badmatch","[[handlers]]\nmatch = \"fuzzy\"\ntext = \"a\"\nreply = \"b\""),
+	].iter() {
+		match load(name,text) {
+			Err(ConfigError::Parse(ref path,_)) => assert!(path.to_string_lossy().contains(name)),
+			other => panic!("{}: {:?}",name,other),
+		};
+	}
+	match Config::load(Path::new("/nonexistent/teamech.toml")) {
+		Err(ConfigError::Io(..)) => (),
+		other => panic!("{:?}",other),
+	};
+}
+
+#[test]
+fn environment_overrides_the_file() {
+	let _environment:MutexGuard<()> = environment();
+	let mut config:Config = load("envbase","server = \"file.example.com:3840\"\n[pad]\nmode = \"memory\"\noffset = 512").unwrap();
+	env::set_var("TEAMECH_SERVER","env.example.com:3840");
+	env::set_var("TEAMECH_BACKUP_SERVERS"," one.example.com:3840, ,two.example.com:3840");
+	env::set_var("TEAMECH_PAD_MODE","mapped");
+	env::set_var("TEAMECH_CLOCK_SKEW","strict");
+	env::set_var("TEAMECH_KEEPALIVE_INTERVAL_MS","0");
+	env::set_var("TEAMECH_LOG_FORMAT","json");
+	env::set_var("TEAMECH_GOODBYE","false");
+	let applied:Result<(),ConfigError> = config.apply_env();
+	for var in ["TEAMECH_SERVER","TEAMECH_BACKUP_SERVERS","TEAMECH_PAD_MODE","TEAMECH_CLOCK_SKEW",
+		"TEAMECH_KEEPALIVE_INTERVAL_MS","TEAMECH_LOG_FORMAT","TEAMECH_GOODBYE"].iter() {
+		env::remove_var(var);
+	}
+	applied.unwrap();
+	assert_eq!(config.server,Some(String::from("env.example.com:3840")));
+	assert_eq!(config.backup_servers,vec![String::from("one.example.com:3840"),String::from("two.example.com:3840")]);
+	assert_eq!(config.pad.mode,PadModeConfig::Mapped);
+	// Settings without a variable set keep the file's value.
+	assert_eq!(config.pad.offset,512);
+	assert_eq!(config.protocol.clock_skew,ClockPolicy::Strict);
+	assert_eq!(config.keepalive.policy().interval,None);
+	assert_eq!(config.logging.format,LogFormat::Json);
+	assert!(!config.shutdown.goodbye);
+	// Values that don't parse are reported with the variable's name.
+	for (var,value) in [("TEAMECH_PORT","70000"),("TEAMECH_PAD_MODE","flash"),("TEAMECH_DUAL_STACK","yes")].iter() {
+		env::set_var(var,value);
+		let applied:Result<(),ConfigError> = config.apply_env();
+		env::remove_var(var);
+		match applied {
+			Err(ConfigError::Env { var:ref reported, value:ref got }) => assert_eq!((reported.as_str(),got.as_str()),(*var,*value)),
+			other => panic!("{}: {:?}",var,other),
+		};
+	}
+}
+
+#[test]
+fn each_bad_setting_is_rejected() {
+	assert!(valid().validate().is_ok());
+	rejected("no server address",|config:&mut Config| config.server = None);
+	rejected("server address is empty",|config:&mut Config| config.server = Some(String::from(" ")));
+	rejected("no pad file",|config:&mut Config| config.pad.path = None);
+	rejected("pad file path is empty",|config:&mut Config| config.pad.path = Some(PathBuf::new()));
+	rejected("pad size",|config:&mut Config| config.pad.size = Some(0));
+	rejected("dual_stack",|config:&mut Config| {
+		config.network.dual_stack = true;
+		config.network.bind = Some("0.0.0.0".parse().unwrap());
+	});
+	rejected("timestamp_tolerance_ms",|config:&mut Config| config.protocol.timestamp_tolerance_ms = 0);
+	rejected("dedup_window",|config:&mut Config| config.protocol.dedup_window = 0);
+	rejected("replay_capacity",|config:&mut Config| config.protocol.replay_capacity = 0);
+	rejected("max_message_size",|config:&mut Config| config.protocol.max_message_size = 0);
+	rejected("reassembly_timeout_ms",|config:&mut Config| config.protocol.reassembly_timeout_ms = 0);
+	rejected("max_partial_messages",|config:&mut Config| config.protocol.max_partial_messages = 0);
+	rejected("response_timeout_ms",|config:&mut Config| config.retry.response_timeout_ms = 0);
+	rejected("error_delay_ms",|config:&mut Config| config.retry.error_delay_ms = 0);
+	rejected("backoff_multiplier",|config:&mut Config| config.retry.backoff_multiplier = 0);
+	rejected("backoff_jitter",|config:&mut Config| config.retry.backoff_jitter = 1.5);
+	rejected("backoff_jitter",|config:&mut Config| config.retry.backoff_jitter = -0.1);
+	rejected("delivery_deadline_ms",|config:&mut Config| config.retry.delivery_deadline_ms = 0);
+	rejected("failover_attempts",|config:&mut Config| config.retry.failover_attempts = 0);
+	rejected("keepalive.timeout_ms",|config:&mut Config| config.keepalive.timeout_ms = config.keepalive.interval_ms);
+	rejected("backup server address is empty",|config:&mut Config| config.backup_servers = vec![String::new()]);
+	rejected("logging.socket",|config:&mut Config| config.logging.socket = Some(PathBuf::from("/dev/log")));
+	rejected("invalid pattern",|config:&mut Config| {
+		config.handlers = load("badpattern","[[handlers]]\nmatch = \"pattern\"\ntext = \"(unclosed\"\nreply = \"no\"").unwrap().handlers;
+	});
+	// Tools that only use the pad don't need a server.
+	let mut config:Config = valid();
+	config.server = None;
+	assert!(config.validate_pad().is_ok());
+	config.pad.path = None;
+	assert!(config.validate_pad().is_err());
+}
+
+// A delay of zero would have the client hammer the server with subscription requests, however it's
+// set.
+#[test]
+fn error_delay_must_not_be_zero() {
+	let _environment:MutexGuard<()> = environment();
+	let mut config:Config = valid();
+	env::set_var("TEAMECH_ERROR_DELAY_MS","0");
+	let applied:Result<(),ConfigError> = config.apply_env();
+	env::remove_var("TEAMECH_ERROR_DELAY_MS");
+	applied.unwrap();
+	assert_eq!(config.retry.error_delay_ms,0);
+	match config.validate() {
+		Err(ConfigError::Invalid(ref why)) => assert_eq!(why,"retry.error_delay_ms must be greater than zero"),
+		other => panic!("{:?}",other),
+	};
+}
+
+#[test]
+fn backoff_grows_up_to_the_maximum() {
+	let mut config:Config = load("backoff","[retry]\nerror_delay_ms = 250\nbackoff_multiplier = 3\nmax_backoff_ms = 5000\nbackoff_jitter = 0.0").unwrap();
+	let mut backoff:Backoff = Backoff::new(config.retry.backoff_policy());
+	let delays:Vec<u128> = (0..6).map(|_| backoff.fail().unwrap().as_millis()).collect();
+	assert_eq!(delays,vec![250,750,2_250,5_000,5_000,5_000]);
+	// A maximum below the first delay is taken to mean no growth at all, rather than less than the
+	// delay asked for.
+	config.retry.max_backoff_ms = 100;
+	let mut backoff:Backoff = Backoff::new(config.retry.backoff_policy());
+	let delays:Vec<u128> = (0..3).map(|_| backoff.fail().unwrap().as_millis()).collect();
+	assert_eq!(delays,vec![250,250,250]);
+	// With a retry budget, the client gives up once it's spent.
+	config.retry.retry_budget = 2;
+	let mut backoff:Backoff = Backoff::new(config.retry.backoff_policy());
+	assert!(backoff.fail().is_some());
+	assert!(backoff.fail().is_some());
+	assert_eq!(backoff.fail(),None);
+}
+
+// The command line beats the environment, which beats the configuration file. Each source names a
+// different server, and the client subscribes to whichever one wins.
+#[test]
+fn command_line_beats_environment_beats_file() {
+	let _environment:MutexGuard<()> = environment();
+	let pad:Pad = common::pad("precedence");
+	let fileserver:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let envserver:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let cliserver:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let path:PathBuf = configfile("precedence",&format!("server = \"{}\"",fileserver.address));
+	let configvar:(&str,&str) = ("TEAMECH_CONFIG",path.to_str().unwrap());
+	let envaddress:String = envserver.address.to_string();
+	let envvar:(&str,&str) = ("TEAMECH_SERVER",&envaddress);
+	let subscribed = |client:Client,server:&StandIn| {
+		let address:SocketAddr = server.address;
+		client.logged(&format!("Subscribed to server at {}",address));
+		server.subscribed();
+	};
+	subscribed(Client::start_with("precedence-file",&[],&[configvar]),&fileserver);
+	subscribed(Client::start_with("precedence-env",&[],&[configvar,envvar]),&envserver);
+	subscribed(Client::start_with("precedence-cli",&[String::from("--server"),cliserver.address.to_string()],&[configvar,envvar]),&cliserver);
+	let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,119 @@
+// The connection state machine: every transition, the events that can't happen in each state, and
+// the callbacks that are told about changes.
+
+extern crate teamech;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use teamech::connection::{Change,Connection,ConnectionEvent,ConnectionState,transition};
+// Both enums have a Shutdown, which is always spelled out in full.
+use teamech::connection::ConnectionEvent::{Bound,Granted,Unanswered,Failed,Retry,Reset};
+use teamech::connection::ConnectionState::{Unbound,Subscribing,Subscribed,Backoff};
+
+static STATES:[ConnectionState;5] = [Unbound,Subscribing,Subscribed,Backoff,ConnectionState::Shutdown];
+static EVENTS:[ConnectionEvent;7] = [Bound,Granted,Unanswered,Failed,Retry,Reset,ConnectionEvent::Shutdown];
+
+#[test]
+fn binding_starts_subscribing() {
+	assert_eq!(transition(Unbound,Bound),Some(Subscribing));
+}
+
+#[test]
+fn a_granted_subscription_is_subscribed() {
+	assert_eq!(transition(Subscribing,Granted),Some(Subscribed));
+}
+
+#[test]
+fn failures_back_off() {
+	assert_eq!(transition(Subscribing,Unanswered),Some(Backoff));
+	assert_eq!(transition(Subscribing,Failed),Some(Backoff));
+	assert_eq!(transition(Subscribed,Failed),Some(Backoff));
+}
+
+#[test]
+fn backing_off_ends_in_subscribing() {
+	assert_eq!(transition(Backoff,Retry),Some(Subscribing));
+}
+
+#[test]
+fn resets_unbind() {
+	assert_eq!(transition(Subscribing,Reset),Some(Unbound));
+	assert_eq!(transition(Subscribed,Reset),Some(Unbound));
+	assert_eq!(transition(Backoff,Reset),Some(Unbound));
+}
+
+#[test]
+fn shutdown_is_reachable_from_anywhere_and_final() {
+	for state in STATES.iter().filter(|state| **state != ConnectionState::Shutdown) {
+		assert_eq!(transition(*state,ConnectionEvent::Shutdown),Some(ConnectionState::Shutdown),"from {:?}",state);
+	}
+	for event in EVENTS.iter() {
+		assert_eq!(transition(ConnectionState::Shutdown,*event),None,"on {:?}",event);
+	}
+}
+
+#[test]
+fn nothing_else_is_a_transition() {
+	let valid:[(ConnectionState,ConnectionEvent);9] = [
+		(Unbound,Bound),
+		(Subscribing,Granted),
+		(Subscribing,Unanswered),
+		(Subscribing,Failed),
+		(Subscribing,Reset),
+		(Subscribed,Failed),
+		(Subscribed,Reset),
+		(Backoff,Retry),
+		(Backoff,Reset),
+	];
+	for state in STATES.iter() {
+		for event in EVENTS.iter() {
+			if *event == ConnectionEvent::Shutdown || valid.contains(&(*state,*event)) {
+				continue;
+			}
+			assert_eq!(transition(*state,*event),None,"{:?} on {:?}",state,event);
+		}
+	}
+}
+
+// Registers a callback that records every change it's told about.
+fn recorder(connection:&mut Connection) -> Rc<RefCell<Vec<Change>>> {
+	let changes:Rc<RefCell<Vec<Change>>> = Rc::new(RefCell::new(Vec::new()));
+	let log:Rc<RefCell<Vec<Change>>> = changes.clone();
+	connection.on_change(move |change:&Change| log.borrow_mut().push(*change));
+	return changes;
+}
+
+#[test]
+fn callbacks_hear_about_changes() {
+	let mut connection:Connection = Connection::new();
+	assert_eq!(connection.state(),Unbound);
+	let first:Rc<RefCell<Vec<Change>>> = recorder(&mut connection);
+	let second:Rc<RefCell<Vec<Change>>> = recorder(&mut connection);
+	assert_eq!(connection.handle(Bound),Some(Subscribing));
+	assert_eq!(connection.handle(Unanswered),Some(Backoff));
+	assert_eq!(connection.handle(Retry),Some(Subscribing));
+	assert_eq!(connection.handle(Granted),Some(Subscribed));
+	assert_eq!(connection.handle(Reset),Some(Unbound));
+	let expected:Vec<Change> = vec![
+		Change { from:Unbound, to:Subscribing, event:Bound },
+		Change { from:Subscribing, to:Backoff, event:Unanswered },
+		Change { from:Backoff, to:Subscribing, event:Retry },
+		Change { from:Subscribing, to:Subscribed, event:Granted },
+		Change { from:Subscribed, to:Unbound, event:Reset },
+	];
+	assert_eq!(*first.borrow(),expected);
+	assert_eq!(*second.borrow(),expected);
+}
+
+#[test]
+fn impossible_events_change_nothing() {
+	let mut connection:Connection = Connection::new();
+	let changes:Rc<RefCell<Vec<Change>>> = recorder(&mut connection);
+	assert_eq!(connection.handle(Granted),None);
+	assert_eq!(connection.state(),Unbound);
+	connection.handle(ConnectionEvent::Shutdown);
+	assert_eq!(connection.handle(Bound),None);
+	assert_eq!(connection.state(),ConnectionState::Shutdown);
+	assert_eq!(changes.borrow().len(),1);
+}
@@ -0,0 +1,96 @@
+// Duplicate detection: repeated payloads, the window of payloads remembered, and datagrams that
+// only look like ones already received.
+
+extern crate teamech;
+
+mod common;
+
+use teamech::dedup::DuplicateFilter;
+use teamech::packet::Packet;
+use teamech::systime;
+use teamech::teacrypt::{Pad,OVERHEAD};
+
+// A distinct payload for each number.
+fn payload(n:u32) -> Vec<u8> {
+	let mut payload:Vec<u8> = vec![0x55;24];
+	payload[..4].copy_from_slice(&n.to_be_bytes());
+	return payload;
+}
+
+#[test]
+fn repeats_are_dropped() {
+	let pad:Pad = common::pad("dedup-repeats");
+	let mut filter:DuplicateFilter = DuplicateFilter::new(8);
+	let first:Vec<u8> = Packet::encode(b"lamp on",systime(),&pad).unwrap();
+	let again:Vec<u8> = Packet::encode(b"lamp on",systime(),&pad).unwrap();
+	assert!(filter.is_empty());
+	assert!(filter.admit(&first));
+	assert!(!filter.admit(&first));
+	assert!(!filter.admit(&first));
+	// The same message sent on purpose is encrypted with a new nonce, and isn't a repeat.
+	assert!(filter.admit(&again));
+	assert_eq!(filter.len(),2);
+	// Payloads too short to decrypt are remembered like any other.
+	assert!(filter.admit(b""));
+	assert!(!filter.admit(b""));
+	assert!(filter.admit(b"x"));
+}
+
+#[test]
+fn only_the_last_few_are_remembered() {
+	let mut filter:DuplicateFilter = DuplicateFilter::new(3);
+	assert_eq!(filter.capacity(),3);
+	for n in 0..3 {
+		assert!(filter.admit(&payload(n)));
+	}
+	// A repeat doesn't count as a new arrival, so it doesn't push anything out.
+	assert!(!filter.admit(&payload(0)));
+	assert_eq!(filter.len(),3);
+	assert!(filter.admit(&payload(3)));
+	assert_eq!(filter.len(),3);
+	// The oldest payload was forgotten, and the rest still are remembered.
+	assert!(!filter.admit(&payload(1)));
+	assert!(!filter.admit(&payload(2)));
+	assert!(!filter.admit(&payload(3)));
+	assert!(filter.admit(&payload(0)));
+	assert!(!filter.admit(&payload(0)));
+	assert_eq!(filter.len(),3);
+	// A filter for no payloads still remembers the last one.
+	let mut filter:DuplicateFilter = DuplicateFilter::new(0);
+	assert!(filter.admit(&payload(0)));
+	assert!(!filter.admit(&payload(0)));
+	assert!(filter.admit(&payload(1)));
+	assert!(filter.admit(&payload(0)));
+}
+
+// The ring buffer and the set must forget the same payloads, or the filter either drops payloads
+// it should have forgotten or grows without limit. Cycling through many more payloads than it
+// holds shows both.
+#[test]
+fn forgets_as_much_as_it_remembers() {
+	let mut filter:DuplicateFilter = DuplicateFilter::new(16);
+	for round in 0..100 {
+		for n in 0..20 {
+			assert!(filter.admit(&payload(n)),"round {}, payload {}",round,n);
+			assert!(!filter.admit(&payload(n)));
+		}
+		assert_eq!(filter.len(),16);
+	}
+}
+
+#[test]
+fn lookalikes_do_not_hide_the_real_payload() {
+	let pad:Pad = common::pad("dedup-lookalikes");
+	let mut filter:DuplicateFilter = DuplicateFilter::new(8);
+	let real:Vec<u8> = Packet::encode(b"open the door",systime(),&pad).unwrap();
+	// A forged or corrupted datagram ending in the same signature and nonce as a real one.
+	let mut forged:Vec<u8> = real.clone();
+	forged[0] ^= 0x01;
+	let mut truncated:Vec<u8> = real[..real.len() - OVERHEAD - 1].to_vec();
+	truncated.extend_from_slice(&real[real.len() - OVERHEAD..]);
+	assert!(filter.admit(&forged));
+	assert!(filter.admit(&truncated));
+	assert!(filter.admit(&real[real.len() - OVERHEAD..]));
+	assert!(filter.admit(&real));
+	assert!(!filter.admit(&real));
+}
@@ -0,0 +1,182 @@
+// Reliable delivery against a lossy stand-in server on the loopback interface.
+
+extern crate teamech;
+
+mod common;
+
+use std::cell::Cell;
+use std::net::{UdpSocket,SocketAddr};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration,Instant};
+
+use teamech::clock::Clock;
+use teamech::delivery::{DeliveryQueue,Outcome,RetryPolicy};
+use teamech::net::{Sender,sendstatus};
+use teamech::packet::Packet;
+use teamech::status::StatusCode;
+use teamech::teacrypt::Pad;
+
+// Starts a server that drops the first `drops` messages it receives, and acknowledges the rest.
+// Reports the content of every message it receives, dropped or not.
+fn lossyserver(pad:Pad,drops:usize) -> (SocketAddr,mpsc::Receiver<Vec<u8>>) {
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	let address:SocketAddr = socket.local_addr().unwrap();
+	let (received,log) = mpsc::channel();
+	thread::spawn(move || {
+		let mut buf:[u8;500] = [0;500];
+		let mut count:usize = 0;
+		while let Ok((nrecv,srcaddr)) = socket.recv_from(&mut buf) {
+			let packet:Packet = Packet::decode(&buf[..nrecv],&pad).unwrap();
+			count += 1;
+			if received.send(packet.content).is_err() {
+				return;
+			}
+			if count > drops {
+				sendstatus(&socket,&srcaddr,StatusCode::Ack,&pad,&Clock::new()).unwrap();
+			}
+		}
+	});
+	return (address,log);
+}
+
+fn policy() -> RetryPolicy {
+	return RetryPolicy {
+		initial_interval:Duration::from_millis(20),
+		max_interval:Duration::from_millis(80),
+		multiplier:2,
+		deadline:Duration::from_millis(600),
+	};
+}
+
+// Runs the client side: sends everything in the queue, crediting acknowledgements, until the
+// queue is empty. Returns whether each message was delivered, in the order they finished.
+fn deliver(socket:&UdpSocket,server:SocketAddr,pad:&Pad,queue:&mut DeliveryQueue) -> Vec<(Outcome,u32)> {
+	let clock:Clock = Clock::new();
+	let sender:Sender = Sender::new(socket,server,pad,&clock);
+	let mut results:Vec<(Outcome,u32)> = Vec::new();
+	let mut buf:[u8;500] = [0;500];
+	while !queue.is_empty() {
+		for delivery in queue.poll(&sender) {
+			results.push((delivery.outcome,delivery.attempts));
+		}
+		socket.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+		if let Ok((nrecv,_)) = socket.recv_from(&mut buf) {
+			let packet:Packet = Packet::decode(&buf[..nrecv],pad).unwrap();
+			if packet.status() == Some(StatusCode::Ack) {
+				if let Some(delivery) = queue.acknowledge() {
+					results.push((delivery.outcome,delivery.attempts));
+				}
+			}
+		}
+	}
+	return results;
+}
+
+#[test]
+fn retransmits_until_acknowledged() {
+	let pad:Pad = common::pad("retransmit");
+	let (server,log) = lossyserver(pad.clone(),2);
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let mut queue:DeliveryQueue = DeliveryQueue::new(policy());
+	queue.push(b"temperature 21.5".to_vec());
+	let results:Vec<(Outcome,u32)> = deliver(&socket,server,&pad,&mut queue);
+	assert_eq!(results,vec![(Outcome::Delivered,3)]);
+	let received:Vec<Vec<u8>> = log.try_iter().collect();
+	assert_eq!(received,vec![b"temperature 21.5".to_vec();3]);
+}
+
+#[test]
+fn sends_one_message_at_a_time() {
+	let pad:Pad = common::pad("ordering");
+	let (server,log) = lossyserver(pad.clone(),1);
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let mut queue:DeliveryQueue = DeliveryQueue::new(policy());
+	queue.push(b"first".to_vec());
+	queue.push(b"second".to_vec());
+	let results:Vec<(Outcome,u32)> = deliver(&socket,server,&pad,&mut queue);
+	assert_eq!(results,vec![(Outcome::Delivered,2),(Outcome::Delivered,1)]);
+	let received:Vec<Vec<u8>> = log.try_iter().collect();
+	assert_eq!(received,vec![b"first".to_vec(),b"first".to_vec(),b"second".to_vec()]);
+}
+
+#[test]
+fn gives_up_at_the_deadline() {
+	let pad:Pad = common::pad("deadline");
+	let (server,_log) = lossyserver(pad.clone(),usize::MAX);
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let mut queue:DeliveryQueue = DeliveryQueue::new(policy());
+	let (notify,result) = mpsc::channel();
+	let id = queue.push_notify(b"lost".to_vec(),notify);
+	let results:Vec<(Outcome,u32)> = deliver(&socket,server,&pad,&mut queue);
+	assert_eq!(results.len(),1);
+	assert_eq!(results[0].0,Outcome::TimedOut);
+	// 20 + 40 + 80 + 80 + ... ms of waiting fits eight attempts into 600 ms.
+	assert!(results[0].1 >= 4 && results[0].1 <= 9,"made {} attempts",results[0].1);
+	let delivery = result.try_recv().unwrap();
+	assert_eq!(delivery.id,id);
+	assert!(!delivery.delivered());
+	assert!(delivery.elapsed >= Duration::from_millis(600));
+}
+
+// The server acknowledges unreliable messages too, and those acknowledgements are used up before
+// any is credited to the reliable message in flight.
+#[test]
+fn unreliable_acknowledgements_are_not_credited() {
+	let pad:Pad = common::pad("unreliable");
+	let server:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let clock:Clock = Clock::new();
+	let sender:Sender = Sender::new(&socket,server.local_addr().unwrap(),&pad,&clock);
+	let mut queue:DeliveryQueue = DeliveryQueue::new(policy());
+	queue.push(b"door opened".to_vec());
+	assert!(queue.poll(&sender).is_empty());
+	// A task sends a status report, and a long log excerpt that goes in three fragments.
+	let sent:Cell<usize> = Cell::new(0);
+	let counted:Sender = sender.counting(&sent);
+	counted.send(b"battery 80%").unwrap();
+	counted.send(&[b'.';1_200]).unwrap();
+	assert_eq!(sent.get(),4);
+	queue.sent_unreliable(sent.get());
+	queue.sent_unreliable(0);
+	for _ in 0..4 {
+		assert_eq!(queue.acknowledge(),None);
+	}
+	let delivery = queue.acknowledge().unwrap();
+	assert_eq!((delivery.outcome,delivery.attempts),(Outcome::Delivered,1));
+	// Acknowledgements owed to unreliable messages are written off once they're too late to come.
+	queue.push(b"door closed".to_vec());
+	assert!(queue.poll(&sender).is_empty());
+	queue.sent_unreliable(1);
+	thread::sleep(policy().max_interval + Duration::from_millis(20));
+	assert_eq!(queue.acknowledge().unwrap().outcome,Outcome::Delivered);
+}
+
+// However many attempts there are, the wait between them stops growing at the maximum rather than
+// overflowing, and it never runs past the deadline.
+#[test]
+fn waits_never_overflow() {
+	let pad:Pad = common::pad("unlimited");
+	let server:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let clock:Clock = Clock::new();
+	let sender:Sender = Sender::new(&socket,server.local_addr().unwrap(),&pad,&clock);
+	let mut queue:DeliveryQueue = DeliveryQueue::new(RetryPolicy {
+		initial_interval:Duration::from_secs(u64::MAX/2),
+		max_interval:Duration::MAX,
+		multiplier:u32::MAX,
+		deadline:Duration::from_millis(200),
+	});
+	let queued:Instant = Instant::now();
+	queue.push(b"soon".to_vec());
+	let mut results:Vec<Outcome> = Vec::new();
+	while results.is_empty() {
+		results.extend(queue.poll(&sender).iter().map(|delivery| delivery.outcome));
+		if let Some(deadline) = queue.next_deadline() {
+			assert!(deadline <= queued + Duration::from_millis(250));
+			thread::sleep(deadline.saturating_duration_since(Instant::now()));
+		}
+	}
+	assert_eq!(results,vec![Outcome::TimedOut]);
+}
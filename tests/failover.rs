@@ -0,0 +1,64 @@
+// Failover between two stand-in servers on the loopback interface, with the client binary
+// running against them.
+
+extern crate teamech;
+
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{Client,StandIn};
+use teamech::clock::Clock;
+use teamech::net::sendstatus;
+use teamech::status::StatusCode;
+use teamech::teacrypt::Pad;
+
+// Runs the client with a primary and a backup server.
+fn client(name:&str,primary:&StandIn,backup:&StandIn) -> Client {
+	return Client::start(name,&[
+		String::from("--server"),primary.address.to_string(),
+		String::from("--backup"),backup.address.to_string(),
+	]);
+}
+
+#[test]
+fn fails_over_to_backup_and_back() {
+	let pad:Pad = common::pad("failback");
+	let primary:StandIn = StandIn::start(&pad,"127.0.0.1:0",false);
+	let backup:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let client:Client = client("failback",&primary,&backup);
+	// The primary doesn't answer, so the client moves on to the backup.
+	client.logged(&format!("Server {} is not responding; failing over to {}.",primary.address,backup.address));
+	let address:SocketAddr = backup.subscribed();
+	backup.exchange(address,&pad);
+	// Once the primary is back, the client notices and returns to it.
+	primary.answer(true);
+	client.logged(&format!("Server {} is available again; failing back from {}.",primary.address,backup.address));
+	// The backup is told the client is leaving, rather than left to relay messages to it until
+	// the subscription runs out.
+	let goodbye = backup.expect("a goodbye",|received| received.content == [StatusCode::Unsubscribe.to_byte()]);
+	assert_eq!(goodbye.from,address);
+	// The first subscription is the client checking on the primary; it then subscribes again
+	// from a new socket.
+	let probe:SocketAddr = primary.subscribed();
+	let address:SocketAddr = primary.expect("a subscription from a new socket",|received| {
+		return received.content.is_empty() && received.answered && received.from != probe;
+	}).from;
+	primary.exchange(address,&pad);
+}
+
+#[test]
+fn fails_over_when_server_stops_answering() {
+	let pad:Pad = common::pad("failover");
+	let primary:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let backup:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let client:Client = client("failover",&primary,&backup);
+	let address:SocketAddr = primary.subscribed();
+	primary.exchange(address,&pad);
+	// The primary ends the subscription and then goes quiet, so renewing it fails.
+	primary.answer(false);
+	sendstatus(&primary.socket,&address,StatusCode::Rejected,&pad,&Clock::new()).unwrap();
+	client.logged(&format!("Server {} is not responding; failing over to {}.",primary.address,backup.address));
+	let address:SocketAddr = backup.subscribed();
+	backup.exchange(address,&pad);
+}
@@ -0,0 +1,189 @@
+// Fragmenting large messages: splitting, reassembly in any order, and the limits that keep a
+// misbehaving sender from using up the receiver's memory.
+
+extern crate teamech;
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use teamech::fragment;
+use teamech::fragment::{FragmentError,Header,Reassembler,ReassemblyLimits};
+use teamech::fragment::{FRAGMENT_MARKER,HEADER_SIZE,MAX_CONTENT_SIZE,MAX_DATAGRAM_SIZE,MAX_FRAGMENT_DATA};
+use teamech::packet;
+use teamech::packet::Packet;
+use teamech::teacrypt::Pad;
+
+// A message where every byte can be told from its neighbours.
+fn message(size:usize) -> Vec<u8> {
+	return (0..size).map(|n| (n % 251) as u8).collect();
+}
+
+// A fragment written by hand, for fragments split() would never make.
+fn fragment(id:u32,index:u16,count:u16,data:&[u8]) -> Vec<u8> {
+	let mut fragment:Vec<u8> = vec![FRAGMENT_MARKER];
+	fragment.extend_from_slice(&id.to_be_bytes());
+	fragment.extend_from_slice(&index.to_be_bytes());
+	fragment.extend_from_slice(&count.to_be_bytes());
+	fragment.extend_from_slice(data);
+	return fragment;
+}
+
+fn limits(max_message_size:usize,timeout:Duration,max_partial:usize) -> ReassemblyLimits {
+	return ReassemblyLimits { max_message_size, timeout, max_partial };
+}
+
+#[test]
+fn messages_survive_the_round_trip() {
+	let pad:Pad = common::pad("fragments");
+	let mut reassembler:Reassembler = Reassembler::new(ReassemblyLimits::default());
+	let original:Vec<u8> = message(2_000);
+	let fragments:Vec<Vec<u8>> = fragment::split(&original).unwrap();
+	assert_eq!(fragments.len(),5);
+	assert_eq!(fragment::count(&original),5);
+	for (index,piece) in fragments.iter().enumerate() {
+		assert_eq!(Header::parse(piece).unwrap().index as usize,index);
+		// Every fragment fits in a datagram any Teamech host can receive.
+		assert!(piece.len() <= MAX_CONTENT_SIZE);
+		assert!(Packet::encode(piece,0,&pad).unwrap().len() <= MAX_DATAGRAM_SIZE);
+	}
+	assert_eq!(fragments[0].len(),HEADER_SIZE + MAX_FRAGMENT_DATA);
+	let (last,rest) = fragments.split_last().unwrap();
+	for piece in rest.iter() {
+		assert_eq!(reassembler.accept(piece),Ok(None));
+	}
+	assert_eq!(reassembler.pending(),1);
+	assert_eq!(reassembler.accept(last),Ok(Some(original)));
+	assert_eq!(reassembler.pending(),0);
+}
+
+// The limits follow from the datagram size and the packet's overhead, so a full fragment makes a
+// datagram of exactly the largest size.
+#[test]
+fn limits_fill_a_datagram_exactly() {
+	let pad:Pad = common::pad("fragment-limits");
+	assert_eq!(MAX_CONTENT_SIZE,MAX_DATAGRAM_SIZE - packet::OVERHEAD);
+	assert_eq!(MAX_FRAGMENT_DATA,MAX_CONTENT_SIZE - HEADER_SIZE);
+	assert_eq!(Packet::encode(&message(MAX_CONTENT_SIZE),0,&pad).unwrap().len(),MAX_DATAGRAM_SIZE);
+	let fragments:Vec<Vec<u8>> = fragment::split(&message(MAX_CONTENT_SIZE*3)).unwrap();
+	assert_eq!(Packet::encode(&fragments[0],0,&pad).unwrap().len(),MAX_DATAGRAM_SIZE);
+}
+
+#[test]
+fn small_messages_are_sent_whole() {
+	for size in [0,1,MAX_CONTENT_SIZE].iter() {
+		let original:Vec<u8> = message(*size);
+		assert_eq!(fragment::split(&original).unwrap(),vec![original.clone()]);
+	}
+	assert_eq!(fragment::split(&message(MAX_CONTENT_SIZE + 1)).unwrap().len(),2);
+	// Unless they'd be mistaken for a fragment, in which case they're a fragment of one.
+	let marked:Vec<u8> = vec![FRAGMENT_MARKER,b'!'];
+	let fragments:Vec<Vec<u8>> = fragment::split(&marked).unwrap();
+	assert_eq!(fragments.len(),1);
+	assert_eq!(Header::parse(&fragments[0]).unwrap().count,1);
+	let mut reassembler:Reassembler = Reassembler::new(ReassemblyLimits::default());
+	assert_eq!(reassembler.accept(&fragments[0]),Ok(Some(marked)));
+}
+
+#[test]
+fn fragments_can_arrive_in_any_order() {
+	let mut reassembler:Reassembler = Reassembler::new(ReassemblyLimits::default());
+	let first:Vec<u8> = message(1_500);
+	let second:Vec<u8> = message(1_000);
+	let mut firstpieces:Vec<Vec<u8>> = fragment::split(&first).unwrap();
+	let secondpieces:Vec<Vec<u8>> = fragment::split(&second).unwrap();
+	firstpieces.reverse();
+	// Two messages interleaved, one of them backwards.
+	assert_eq!(reassembler.accept(&firstpieces[0]),Ok(None));
+	assert_eq!(reassembler.accept(&secondpieces[1]),Ok(None));
+	assert_eq!(reassembler.accept(&firstpieces[1]),Ok(None));
+	assert_eq!(reassembler.accept(&firstpieces[2]),Ok(None));
+	assert_eq!(reassembler.accept(&secondpieces[2]),Ok(None));
+	assert_eq!(reassembler.pending(),2);
+	assert_eq!(reassembler.accept(&firstpieces[3]),Ok(Some(first)));
+	assert_eq!(reassembler.accept(&secondpieces[0]),Ok(Some(second)));
+}
+
+#[test]
+fn repeated_fragments_are_ignored() {
+	let mut reassembler:Reassembler = Reassembler::new(ReassemblyLimits::default());
+	let original:Vec<u8> = message(1_000);
+	let fragments:Vec<Vec<u8>> = fragment::split(&original).unwrap();
+	assert_eq!(reassembler.accept(&fragments[0]),Ok(None));
+	assert_eq!(reassembler.accept(&fragments[0]),Ok(None));
+	assert_eq!(reassembler.accept(&fragments[1]),Ok(None));
+	assert_eq!(reassembler.accept(&fragments[1]),Ok(None));
+	// A repeat doesn't stand in for the fragment that's still missing.
+	assert_eq!(reassembler.pending(),1);
+	assert_eq!(reassembler.accept(&fragments[2]),Ok(Some(original)));
+	// Once a message is complete, a late repeat starts over rather than delivering it twice.
+	assert_eq!(reassembler.accept(&fragments[2]),Ok(None));
+}
+
+#[test]
+fn unfinished_messages_time_out() {
+	let mut reassembler:Reassembler = Reassembler::new(limits(1 << 20,Duration::from_millis(50),8));
+	let fragments:Vec<Vec<u8>> = fragment::split(&message(1_000)).unwrap();
+	assert_eq!(reassembler.next_deadline(),None);
+	assert_eq!(reassembler.accept(&fragments[0]),Ok(None));
+	assert!(reassembler.next_deadline().is_some());
+	assert_eq!(reassembler.expire(),0);
+	thread::sleep(Duration::from_millis(60));
+	assert_eq!(reassembler.expire(),1);
+	assert_eq!((reassembler.pending(),reassembler.next_deadline()),(0,None));
+	// The rest of the message is no use without its first fragment.
+	assert_eq!(reassembler.accept(&fragments[1]),Ok(None));
+	assert_eq!(reassembler.accept(&fragments[2]),Ok(None));
+}
+
+#[test]
+fn oldest_partial_messages_make_way() {
+	let mut reassembler:Reassembler = Reassembler::new(limits(1 << 20,Duration::from_secs(10),2));
+	let messages:Vec<Vec<u8>> = (1..4).map(|n| message(600*n)).collect();
+	let fragments:Vec<Vec<Vec<u8>>> = messages.iter().map(|m| fragment::split(m).unwrap()).collect();
+	for pieces in fragments.iter() {
+		assert_eq!(reassembler.accept(&pieces[0]),Ok(None));
+		thread::sleep(Duration::from_millis(2));
+	}
+	assert_eq!(reassembler.pending(),2);
+	// The first message was dropped to make room for the third, so it never completes.
+	assert_eq!(reassembler.accept(&fragments[0][1]),Ok(None));
+	assert_eq!(reassembler.accept(&fragments[2][1]),Ok(None));
+	assert_eq!(reassembler.accept(&fragments[2][2]),Ok(None));
+	assert_eq!(reassembler.accept(&fragments[2][3]),Ok(Some(messages[2].clone())));
+}
+
+#[test]
+fn oversized_messages_are_refused() {
+	let mut reassembler:Reassembler = Reassembler::new(limits(1_000,Duration::from_secs(10),8));
+	// Refused from its first fragment, since a message of 5 fragments is at least 1,869 bytes.
+	let fragments:Vec<Vec<u8>> = fragment::split(&message(2_000)).unwrap();
+	match reassembler.accept(&fragments[4]) {
+		Err(FragmentError::TooLarge { size:1_869, limit:1_000, .. }) => (),
+		other => panic!("{:?}",other),
+	};
+	assert_eq!(reassembler.pending(),0);
+	// Fragments bigger than split() makes can still add up to too much, and that's caught too.
+	assert_eq!(reassembler.accept(&fragment(7,0,2,&[0;600])),Ok(None));
+	match reassembler.accept(&fragment(7,1,2,&[0;600])) {
+		Err(FragmentError::TooLarge { id:7, size:1_200, limit:1_000 }) => (),
+		other => panic!("{:?}",other),
+	};
+	assert_eq!(reassembler.pending(),0);
+	// And messages too big for the header to count aren't split at all.
+	let huge:usize = MAX_FRAGMENT_DATA*(u16::MAX as usize) + 1;
+	assert_eq!(fragment::split(&vec![0;huge]),Err(FragmentError::TooManyFragments { size:huge }));
+}
+
+#[test]
+fn malformed_fragments_are_rejected() {
+	let mut reassembler:Reassembler = Reassembler::new(ReassemblyLimits::default());
+	assert_eq!(reassembler.accept(&[FRAGMENT_MARKER,0,0,0]),Err(FragmentError::Truncated { length:4 }));
+	assert_eq!(reassembler.accept(&fragment(1,2,2,b"data")),Err(FragmentError::BadIndex { id:1, index:2, count:2 }));
+	assert_eq!(reassembler.accept(&fragment(1,0,0,b"")),Err(FragmentError::BadIndex { id:1, index:0, count:0 }));
+	assert_eq!(reassembler.accept(&fragment(2,0,3,b"data")),Ok(None));
+	assert_eq!(reassembler.accept(&fragment(2,1,4,b"data")),Err(FragmentError::CountMismatch { id:2, expected:3, got:4 }));
+	// The message is abandoned, rather than trusting either count.
+	assert_eq!(reassembler.pending(),0);
+}
@@ -0,0 +1,112 @@
+// Routing messages to handlers: each kind of route, which route wins when several match, and the
+// fallback handler.
+
+extern crate teamech;
+
+use std::net::SocketAddr;
+
+use teamech::handler::{Message,Router};
+
+// Handles a message the way the client does, and returns the reply as text.
+fn dispatch(router:&mut Router,text:&str) -> Option<String> {
+	let server:SocketAddr = "127.0.0.1:3840".parse().unwrap();
+	let message:Message = Message {
+		text,
+		bytes:text.as_bytes(),
+		timestamp:0,
+		server,
+	};
+	return router.dispatch(&message).map(|reply| String::from_utf8(reply).unwrap());
+}
+
+// A handler that replies with a fixed string.
+fn reply(text:&'static str) -> impl FnMut(&Message) -> Option<Vec<u8>> {
+	return move |_message:&Message| Some(text.as_bytes().to_vec());
+}
+
+#[test]
+fn exact_routes_need_the_whole_text() {
+	let mut router:Router = Router::new();
+	router.exact("status",reply("ok"));
+	assert_eq!(dispatch(&mut router,"status"),Some(String::from("ok")));
+	assert_eq!(dispatch(&mut router,"status now"),None);
+	assert_eq!(dispatch(&mut router,"Status"),None);
+}
+
+#[test]
+fn prefix_routes_need_the_start() {
+	let mut router:Router = Router::new();
+	router.prefix("led:",|message:&Message| Some(message.bytes["led:".len()..].to_vec()));
+	assert_eq!(dispatch(&mut router,"led:on"),Some(String::from("on")));
+	assert_eq!(dispatch(&mut router,"set led:on"),None);
+}
+
+#[test]
+fn pattern_routes_match_anywhere() {
+	let mut router:Router = Router::new();
+	router.pattern(r"temp(erature)?\s+\d+",reply("noted")).unwrap();
+	assert_eq!(dispatch(&mut router,"set temperature 21"),Some(String::from("noted")));
+	assert_eq!(dispatch(&mut router,"temp 5"),Some(String::from("noted")));
+	assert_eq!(dispatch(&mut router,"temp high"),None);
+	assert!(router.pattern("(unclosed",reply("never")).is_err());
+}
+
+#[test]
+fn command_routes_need_the_first_word() {
+	let mut router:Router = Router::new();
+	router.command("lamp",|message:&Message| Some(message.arguments().as_bytes().to_vec()));
+	assert_eq!(dispatch(&mut router,"  lamp   on  "),Some(String::from("on")));
+	assert_eq!(dispatch(&mut router,"lamp"),Some(String::new()));
+	assert_eq!(dispatch(&mut router,"lamps on"),None);
+	assert_eq!(dispatch(&mut router,"the lamp"),None);
+}
+
+#[test]
+fn earlier_routes_win() {
+	let mut router:Router = Router::new();
+	router.exact("lamp on",reply("exact"));
+	router.command("lamp",reply("command"));
+	router.prefix("lamp",reply("prefix"));
+	router.pattern(".*",reply("pattern")).unwrap();
+	assert_eq!(dispatch(&mut router,"lamp on"),Some(String::from("exact")));
+	assert_eq!(dispatch(&mut router,"lamp off"),Some(String::from("command")));
+	assert_eq!(dispatch(&mut router,"lamplight"),Some(String::from("prefix")));
+	assert_eq!(dispatch(&mut router,"anything"),Some(String::from("pattern")));
+	// Registered the other way round, the catch-all pattern takes everything.
+	let mut router:Router = Router::new();
+	router.pattern(".*",reply("pattern")).unwrap();
+	router.exact("lamp on",reply("exact"));
+	assert_eq!(dispatch(&mut router,"lamp on"),Some(String::from("pattern")));
+}
+
+#[test]
+fn matched_routes_that_stay_silent_stop_the_search() {
+	let mut router:Router = Router::new();
+	router.command("lamp",|_message:&Message| None);
+	router.prefix("lamp",reply("prefix"));
+	router.fallback(reply("fallback"));
+	assert_eq!(dispatch(&mut router,"lamp on"),None);
+}
+
+#[test]
+fn fallback_gets_unmatched_messages() {
+	let mut router:Router = Router::new();
+	assert_eq!(dispatch(&mut router,"anything"),None);
+	router.exact("ping",reply("pong"));
+	router.fallback(|message:&Message| Some(format!("unknown: {}",message.text).into_bytes()));
+	assert_eq!(dispatch(&mut router,"ping"),Some(String::from("pong")));
+	assert_eq!(dispatch(&mut router,"pong"),Some(String::from("unknown: pong")));
+}
+
+#[test]
+fn routers_can_be_mounted_on_routers() {
+	let mut lamp:Router = Router::new();
+	lamp.exact("lamp on",reply("lamp is on"));
+	lamp.fallback(reply("lamp says what?"));
+	let mut router:Router = Router::new();
+	router.command("lamp",lamp);
+	router.fallback(reply("main says what?"));
+	assert_eq!(dispatch(&mut router,"lamp on"),Some(String::from("lamp is on")));
+	assert_eq!(dispatch(&mut router,"lamp dance"),Some(String::from("lamp says what?")));
+	assert_eq!(dispatch(&mut router,"fan on"),Some(String::from("main says what?")));
+}
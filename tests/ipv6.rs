@@ -0,0 +1,99 @@
+// IPv6, dual-stack and explicit bind addresses, with the client binary running against stand-in
+// servers on ::1 and 127.0.0.1.
+
+extern crate teamech;
+
+mod common;
+
+use std::net::{UdpSocket,SocketAddr};
+
+use common::{Client,StandIn};
+use teamech::clock::Clock;
+use teamech::net::sendstatus;
+use teamech::status::StatusCode;
+use teamech::teacrypt::Pad;
+
+// Whether this host has IPv6 loopback at all; if not, there's nothing to test.
+fn have_ipv6() -> bool {
+	if UdpSocket::bind("[::1]:0").is_ok() {
+		return true;
+	}
+	println!("IPv6 loopback is not available; skipping.");
+	return false;
+}
+
+fn options(options:&[&str]) -> Vec<String> {
+	return options.iter().map(|option| option.to_string()).collect();
+}
+
+#[test]
+fn reaches_an_ipv6_server() {
+	if !have_ipv6() {
+		return;
+	}
+	let pad:Pad = common::pad("ipv6-only");
+	let server:StandIn = StandIn::start(&pad,"[::1]:0",true);
+	// With no bind address, the client binds to :: to match the server.
+	let _client:Client = Client::start("ipv6-only",&options(&["--server",&server.address.to_string()]));
+	let address:SocketAddr = server.subscribed();
+	assert!(address.is_ipv6());
+	server.exchange(address,&pad);
+}
+
+#[test]
+fn binds_to_the_given_address() {
+	if !have_ipv6() {
+		return;
+	}
+	let pad:Pad = common::pad("ipv6-bind");
+	let server:StandIn = StandIn::start(&pad,"[::1]:0",true);
+	let _client:Client = Client::start("ipv6-bind",&options(&["--bind","::1","--server",&server.address.to_string()]));
+	let address:SocketAddr = server.subscribed();
+	assert_eq!(address.ip().to_string(),"::1");
+	server.exchange(address,&pad);
+}
+
+#[test]
+fn skips_servers_the_bind_address_cannot_reach() {
+	if !have_ipv6() {
+		return;
+	}
+	let pad:Pad = common::pad("ipv4-bind");
+	let unreachable:StandIn = StandIn::start(&pad,"[::1]:0",true);
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let client:Client = Client::start("ipv4-bind",&options(&[
+		"--bind","127.0.0.1",
+		"--server",&unreachable.address.to_string(),
+		"--backup",&server.address.to_string(),
+	]));
+	// The IPv6 server is dropped from the list, so the IPv4 one is the only server.
+	let output:Vec<String> = client.logged(&format!("Subscribed to server at {}",server.address));
+	assert!(!output.iter().any(|line| line.contains("failing over")),"{:?}",output);
+	let address:SocketAddr = server.subscribed();
+	server.exchange(address,&pad);
+}
+
+#[test]
+fn dual_stack_reaches_both_families() {
+	if !have_ipv6() {
+		return;
+	}
+	let pad:Pad = common::pad("dual-stack");
+	let ipv4:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let ipv6:StandIn = StandIn::start(&pad,"[::1]:0",true);
+	let client:Client = Client::start("dual-stack",&options(&[
+		"--dual-stack",
+		"--server",&ipv4.address.to_string(),
+		"--backup",&ipv6.address.to_string(),
+	]));
+	// The IPv4 server is reached from an IPv6 socket, and the client recognises its replies as
+	// coming from it.
+	let address:SocketAddr = ipv4.subscribed();
+	ipv4.exchange(address,&pad);
+	// Once the IPv4 server stops answering, the client fails over to the IPv6 one.
+	ipv4.answer(false);
+	sendstatus(&ipv4.socket,&address,StatusCode::Rejected,&pad,&Clock::new()).unwrap();
+	client.logged(&format!("failing over to {}",ipv6.address));
+	let address:SocketAddr = ipv6.subscribed();
+	ipv6.exchange(address,&pad);
+}
@@ -0,0 +1,61 @@
+// Keepalive heartbeats, with the client binary running against a stand-in server that can be told
+// to go quiet.
+
+extern crate teamech;
+
+mod common;
+
+use std::net::SocketAddr;
+use std::thread::sleep;
+use std::time::Duration;
+
+use common::{Client,StandIn};
+use teamech::teacrypt::Pad;
+
+// Runs the client against one server, with heartbeats every 100 ms.
+fn client(name:&str,server:&StandIn,timeout_ms:&str) -> Client {
+	return Client::start_with(name,&[String::from("--server"),server.address.to_string()],&[
+		("TEAMECH_KEEPALIVE_INTERVAL_MS","100"),
+		("TEAMECH_KEEPALIVE_TIMEOUT_MS",timeout_ms),
+	]);
+}
+
+#[test]
+fn sends_heartbeats_while_subscribed() {
+	let pad:Pad = common::pad("heartbeat");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let client:Client = client("heartbeat",&server,"2000");
+	let address:SocketAddr = server.subscribed();
+	client.logged(&format!("Subscribed to server at {}",server.address));
+	// Heartbeats are subscription requests from the same socket.
+	for _ in 0..3 {
+		assert_eq!(server.subscribed(),address);
+	}
+	// A few heartbeats go unanswered, which is noticed but not yet given up on.
+	server.answer(false);
+	client.logged("has not answered the last");
+	server.answer(true);
+	client.logged(&format!("Server {} is answering again",server.address));
+	server.exchange(address,&pad);
+}
+
+#[test]
+fn subscribes_again_when_the_server_goes_silent() {
+	let pad:Pad = common::pad("silence");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let client:Client = client("silence",&server,"400");
+	let address:SocketAddr = server.subscribed();
+	client.logged(&format!("Subscribed to server at {}",server.address));
+	// The server forgets about the client without telling it, e.g. because it restarted.
+	server.answer(false);
+	let output:Vec<String> = client.logged(&format!("Server {} has been silent",server.address));
+	assert!(output.iter().any(|line| line.contains("has not answered the last")),"{:?}",output);
+	sleep(Duration::from_millis(200));
+	server.answer(true);
+	client.logged(&format!("Subscribed to server at {}",server.address));
+	// The client starts over with a new socket.
+	let renewed:SocketAddr = server.expect("a subscription from a new socket",|received| {
+		return received.content.is_empty() && received.answered && received.from != address;
+	}).from;
+	server.exchange(renewed,&pad);
+}
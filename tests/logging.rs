@@ -0,0 +1,192 @@
+// Logging: filters, the text and JSON formats, syslog and journald output, and what the client
+// logs while running.
+
+extern crate log;
+extern crate teamech;
+
+mod common;
+
+use std::net::SocketAddr;
+
+use log::{Level,LevelFilter,Record};
+use common::{Client,StandIn};
+use teamech::logging;
+use teamech::logging::{LogFilter,LogFormat,Logger};
+use teamech::teacrypt::Pad;
+
+fn text(bytes:Vec<u8>) -> String {
+	return String::from_utf8(bytes).unwrap();
+}
+
+#[test]
+fn filters_pick_the_most_specific_target() {
+	let filter:LogFilter = "warn,teamech=info,teamech::delivery=debug,messages=off".parse().unwrap();
+	assert_eq!(filter.level("teamech_embedded_template"),LevelFilter::Warn);
+	assert_eq!(filter.level("teamech::scheduler"),LevelFilter::Info);
+	assert_eq!(filter.level("teamech::delivery"),LevelFilter::Debug);
+	assert_eq!(filter.level("teamech::delivery::queue"),LevelFilter::Debug);
+	// Targets only cover whole path segments.
+	assert_eq!(filter.level("teamech::deliveryman"),LevelFilter::Info);
+	assert_eq!(filter.level(logging::MESSAGES),LevelFilter::Off);
+	assert_eq!(filter.max(),LevelFilter::Debug);
+}
+
+#[test]
+fn bad_filters_are_rejected() {
+	assert!("loud".parse::<LogFilter>().is_err());
+	assert!("info,teamech=loud".parse::<LogFilter>().is_err());
+	assert!("=debug".parse::<LogFilter>().is_err());
+	assert_eq!("".parse::<LogFilter>().unwrap(),LogFilter::default());
+}
+
+#[test]
+fn quiet_caps_every_target() {
+	let mut filter:LogFilter = "debug,messages=info,teamech=error".parse().unwrap();
+	filter.cap(LevelFilter::Warn);
+	assert_eq!(filter.level("anything"),LevelFilter::Warn);
+	assert_eq!(filter.level(logging::MESSAGES),LevelFilter::Warn);
+	assert_eq!(filter.level("teamech::delivery"),LevelFilter::Error);
+}
+
+#[test]
+fn text_records_have_level_target_and_fields() {
+	let logger:Logger = Logger::new(LogFilter::default(),LogFormat::Text).timestamps(false);
+	let fields:[(&str,&str);2] = [("direction","received"),("hex","68 69")];
+	let rendered:String = text(logger.render(&Record::builder()
+		.args(format_args!("hi"))
+		.level(Level::Info)
+		.target(logging::MESSAGES)
+		.key_values(&fields)
+		.build()));
+	assert_eq!(rendered,"INFO  messages: hi direction=received hex=\"68 69\"");
+}
+
+#[test]
+fn json_records_are_escaped() {
+	let logger:Logger = Logger::new(LogFilter::default(),LogFormat::Json);
+	let fields:[(&str,u64);1] = [("status",6)];
+	let rendered:String = text(logger.render(&Record::builder()
+		.args(format_args!("say \"hi\"\n"))
+		.level(Level::Warn)
+		.target("teamech::delivery")
+		.key_values(&fields)
+		.build()));
+	assert!(rendered.starts_with("{\"time\":\""),"{}",rendered);
+	assert!(rendered.ends_with("Z\",\"level\":\"WARN\",\"target\":\"teamech::delivery\",\"message\":\"say \\\"hi\\\"\\n\",\"status\":6}"),"{}",rendered);
+}
+
+#[test]
+fn timestamps_are_rfc3339() {
+	assert_eq!(logging::rfc3339(0),"1970-01-01T00:00:00.000Z");
+	assert_eq!(logging::rfc3339(951_782_400_000),"2000-02-29T00:00:00.000Z");
+	assert_eq!(logging::rfc3339(1_538_395_200_123),"2018-10-01T12:00:00.123Z");
+}
+
+#[cfg(unix)]
+mod sockets {
+	use std::env;
+	use std::fs;
+	use std::os::unix::net::UnixDatagram;
+	use std::path::PathBuf;
+	use std::process;
+	use std::time::Duration;
+
+	use log::{Level,LevelFilter,Log,Record};
+	use teamech::logging::{LogFilter,LogFormat,LogOutput,Logger};
+
+	// A stand-in for the syslog daemon or the journal.
+	fn listen(name:&str) -> (UnixDatagram,PathBuf) {
+		let path:PathBuf = env::temp_dir().join(format!("teamech-test-{}-{}.log",name,process::id()));
+		let _ = fs::remove_file(&path);
+		let socket:UnixDatagram = UnixDatagram::bind(&path).unwrap();
+		socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		return (socket,path);
+	}
+
+	fn receive(socket:&UnixDatagram) -> Vec<u8> {
+		let mut buf:[u8;1000] = [0;1000];
+		let size:usize = socket.recv(&mut buf).unwrap();
+		return buf[..size].to_vec();
+	}
+
+	#[test]
+	fn syslog_gets_the_level_as_severity() {
+		let (socket,path) = listen("syslog");
+		let logger:Logger = Logger::new(LogFilter::new(LevelFilter::Info),LogFormat::Text).output(LogOutput::Syslog,Some(&path)).unwrap();
+		logger.log(&Record::builder().args(format_args!("not listening")).level(Level::Warn).target("teamech").build());
+		// Filtered out.
+		logger.log(&Record::builder().args(format_args!("details")).level(Level::Debug).target("teamech").build());
+		logger.log(&Record::builder().args(format_args!("listening")).level(Level::Info).target("teamech").build());
+		assert_eq!(super::text(receive(&socket)),format!("<28>teamech[{}]: WARN  teamech: not listening",process::id()));
+		assert_eq!(super::text(receive(&socket)),format!("<30>teamech[{}]: INFO  teamech: listening",process::id()));
+		let _ = fs::remove_file(&path);
+	}
+
+	#[test]
+	fn journald_gets_fields() {
+		let (socket,path) = listen("journald");
+		let logger:Logger = Logger::new(LogFilter::default(),LogFormat::Json).output(LogOutput::Journald,Some(&path)).unwrap();
+		let fields:[(&str,&str);1] = [("direction","sent")];
+		logger.log(&Record::builder()
+			.args(format_args!("two\nlines"))
+			.level(Level::Error)
+			.target("messages")
+			.key_values(&fields)
+			.build());
+		let mut expected:Vec<u8> = b"MESSAGE\n".to_vec();
+		expected.extend_from_slice(&9u64.to_le_bytes());
+		expected.extend_from_slice(b"two\nlines\nPRIORITY=3\nSYSLOG_IDENTIFIER=teamech\nTARGET=messages\nDIRECTION=sent\n");
+		assert_eq!(receive(&socket),expected);
+		let _ = fs::remove_file(&path);
+	}
+
+	#[test]
+	fn logging_survives_a_daemon_restart() {
+		let (socket,path) = listen("restart");
+		let logger:Logger = Logger::new(LogFilter::default(),LogFormat::Text).output(LogOutput::Syslog,Some(&path)).unwrap();
+		logger.log(&Record::builder().args(format_args!("before")).level(Level::Info).target("teamech").build());
+		assert!(super::text(receive(&socket)).ends_with("INFO  teamech: before"));
+		// The daemon goes away, and comes back with a new socket at the same place.
+		drop(socket);
+		let (socket,path) = listen("restart");
+		logger.log(&Record::builder().args(format_args!("after")).level(Level::Info).target("teamech").build());
+		assert!(super::text(receive(&socket)).ends_with("INFO  teamech: after"));
+		let _ = fs::remove_file(&path);
+	}
+
+	#[test]
+	fn missing_sockets_are_reported() {
+		let path:PathBuf = env::temp_dir().join(format!("teamech-test-nolog-{}.log",process::id()));
+		assert!(Logger::new(LogFilter::default(),LogFormat::Text).output(LogOutput::Syslog,Some(&path)).is_err());
+	}
+}
+
+#[test]
+fn client_logs_messages_as_json() {
+	let pad:Pad = common::pad("jsonlog");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let client:Client = Client::start_with("jsonlog",&[String::from("--server"),server.address.to_string()],&[
+		("TEAMECH_LOG_FORMAT","json"),
+	]);
+	let address:SocketAddr = server.subscribed();
+	server.exchange(address,&pad);
+	let output:Vec<String> = client.logged(&format!("\"level\":\"INFO\",\"target\":\"messages\",\"message\":\"are you there?\",\"direction\":\"received\",\"server\":\"{}\"}}",server.address));
+	assert!(output.iter().all(|line| line.starts_with("{\"time\":") && line.ends_with('}')),"{:?}",output);
+}
+
+#[test]
+fn message_chatter_can_be_turned_off() {
+	let pad:Pad = common::pad("quietmessages");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let client:Client = Client::start("quietmessages",&[
+		String::from("--server"),server.address.to_string(),
+		String::from("--log-level"),String::from("info,messages=off"),
+	]);
+	let address:SocketAddr = server.subscribed();
+	client.logged(&format!("Subscribed to server at {}",server.address));
+	server.exchange(address,&pad);
+	// The next thing logged after the exchange is the shutdown, not the message.
+	client.signal("TERM");
+	let output:Vec<String> = client.logged("Shut down.");
+	assert!(!output.iter().any(|line| line.contains("are you there?")),"{:?}",output);
+}
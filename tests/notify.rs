@@ -0,0 +1,152 @@
+// Telling systemd how the client is doing, checked against a stand-in notification socket.
+#![cfg(unix)]
+
+extern crate teamech;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::{Duration,Instant};
+
+use common::{Client,StandIn};
+use teamech::notify::{Notifier,Watchdog};
+use teamech::teacrypt::Pad;
+
+// A notification socket, like the one systemd listens on.
+struct NotifySocket {
+	path:PathBuf,
+	socket:UnixDatagram,
+}
+
+impl NotifySocket {
+	fn bind(name:&str) -> NotifySocket {
+		let path:PathBuf = env::temp_dir().join(format!("teamech-test-{}-{}.notify",name,std::process::id()));
+		let _ = fs::remove_file(&path);
+		let socket:UnixDatagram = UnixDatagram::bind(&path).expect("could not bind notification socket");
+		socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+		return NotifySocket { path, socket };
+	}
+
+	// Everything received before `deadline`.
+	fn until(&self,deadline:Instant) -> Vec<String> {
+		let mut received:Vec<String> = Vec::new();
+		let mut buf:[u8;1000] = [0;1000];
+		while Instant::now() < deadline {
+			if let Ok(size) = self.socket.recv(&mut buf) {
+				received.push(String::from_utf8_lossy(&buf[..size]).into_owned());
+			}
+		}
+		return received;
+	}
+
+	// Waits up to five seconds for a notification containing `line`.
+	fn expect(&self,line:&str) -> String {
+		let deadline:Instant = Instant::now() + Duration::from_secs(5);
+		let mut buf:[u8;1000] = [0;1000];
+		while Instant::now() < deadline {
+			if let Ok(size) = self.socket.recv(&mut buf) {
+				let state:String = String::from_utf8_lossy(&buf[..size]).into_owned();
+				if state.lines().any(|received| received == line) {
+					return state;
+				}
+			}
+		}
+		panic!("never received {}",line);
+	}
+}
+
+impl Drop for NotifySocket {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+#[test]
+fn notifier_sends_to_the_socket() {
+	let systemd:NotifySocket = NotifySocket::bind("notifier");
+	let notifier:Notifier = Notifier::connect(systemd.path.to_str().unwrap()).unwrap();
+	assert!(notifier.is_enabled());
+	notifier.notify("READY=1\nSTATUS=subscribed").unwrap();
+	assert_eq!(systemd.expect("READY=1"),"READY=1\nSTATUS=subscribed");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn notifier_sends_to_abstract_sockets() {
+	use std::os::linux::net::SocketAddrExt;
+	use std::os::unix::net::SocketAddr as UnixSocketAddr;
+
+	let name:String = format!("teamech-test-abstract-{}",std::process::id());
+	let address:UnixSocketAddr = UnixSocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+	let socket:UnixDatagram = UnixDatagram::bind_addr(&address).unwrap();
+	let notifier:Notifier = Notifier::connect(&format!("@{}",name)).unwrap();
+	notifier.notify("WATCHDOG=1").unwrap();
+	let mut buf:[u8;100] = [0;100];
+	let size:usize = socket.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..size],b"WATCHDOG=1");
+}
+
+#[test]
+fn nothing_is_sent_outside_systemd() {
+	let notifier:Notifier = Notifier::default();
+	assert!(!notifier.is_enabled());
+	assert!(notifier.notify("READY=1").is_ok());
+	let watchdog:Watchdog = Watchdog::new(None);
+	assert_eq!(watchdog.next_ping(),None);
+}
+
+#[test]
+fn watchdog_is_pinged_at_half_its_timeout() {
+	let systemd:NotifySocket = NotifySocket::bind("watchdog");
+	let notifier:Notifier = Notifier::connect(systemd.path.to_str().unwrap()).unwrap();
+	let mut watchdog:Watchdog = Watchdog::new(Some(Duration::from_millis(400)));
+	assert_eq!(watchdog.interval(),Some(Duration::from_millis(200)));
+	let start:Instant = Instant::now();
+	// The first ping is due straight away, and the next not until the interval is up.
+	watchdog.ping(&notifier,start);
+	watchdog.ping(&notifier,start + Duration::from_millis(100));
+	assert_eq!(watchdog.next_ping(),Some(start + Duration::from_millis(200)));
+	watchdog.ping(&notifier,start + Duration::from_millis(200));
+	let pings:Vec<String> = systemd.until(Instant::now() + Duration::from_millis(300));
+	assert_eq!(pings,vec!["WATCHDOG=1","WATCHDOG=1"]);
+}
+
+#[test]
+fn client_reports_readiness_and_pings_the_watchdog() {
+	let pad:Pad = common::pad("sdnotify");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let systemd:NotifySocket = NotifySocket::bind("sdnotify");
+	let mut client:Client = Client::start_with("sdnotify",&[String::from("--server"),server.address.to_string()],&[
+		("NOTIFY_SOCKET",systemd.path.to_str().unwrap()),
+		("WATCHDOG_USEC","200000"),
+	]);
+	client.logged("Pinging the systemd watchdog every 100 ms.");
+	assert_eq!(systemd.expect("READY=1"),"STATUS=subscribed\nREADY=1");
+	// With nothing else going on, the main loop still wakes up to ping the watchdog.
+	let received:Vec<String> = systemd.until(Instant::now() + Duration::from_millis(500));
+	let pings:usize = received.iter().filter(|state| *state == "WATCHDOG=1").count();
+	assert!(pings >= 3,"{:?}",received);
+	client.signal("TERM");
+	assert_eq!(systemd.expect("STOPPING=1"),"STATUS=shut down\nSTOPPING=1");
+	assert_eq!(client.exit_code(),Some(0));
+}
+
+#[test]
+fn watchdog_for_another_process_is_ignored() {
+	let pad:Pad = common::pad("otherpid");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let systemd:NotifySocket = NotifySocket::bind("otherpid");
+	let mut client:Client = Client::start_with("otherpid",&[String::from("--server"),server.address.to_string()],&[
+		("NOTIFY_SOCKET",systemd.path.to_str().unwrap()),
+		("WATCHDOG_USEC","200000"),
+		("WATCHDOG_PID","1"),
+	]);
+	systemd.expect("READY=1");
+	let received:Vec<String> = systemd.until(Instant::now() + Duration::from_millis(300));
+	assert!(!received.iter().any(|state| state.contains("WATCHDOG=1")),"{:?}",received);
+	client.signal("TERM");
+	assert_eq!(client.exit_code(),Some(0));
+}
@@ -0,0 +1,61 @@
+// Packets: the timestamped messages sent on the wire, and the datagrams that can't be one.
+
+extern crate teamech;
+
+mod common;
+
+use teamech::packet;
+use teamech::packet::{Packet,PacketError,MIN_PACKET_SIZE,TIMESTAMP_SIZE};
+use teamech::status::StatusCode;
+use teamech::teacrypt;
+use teamech::teacrypt::{Pad,OVERHEAD};
+
+#[test]
+fn packets_survive_the_round_trip() {
+	let pad:Pad = common::pad("packetroundtrip");
+	for content in [&b""[..],&b"are you there?"[..],&[0;1_000][..]].iter() {
+		let payload:Vec<u8> = Packet::encode(content,1_538_395_200_123,&pad).unwrap();
+		assert_eq!(payload.len(),content.len() + TIMESTAMP_SIZE + OVERHEAD);
+		assert_eq!(payload.len(),content.len() + packet::OVERHEAD);
+		let packet:Packet = Packet::decode(&payload,&pad).unwrap();
+		assert_eq!(packet.content,*content);
+		assert_eq!(packet.timestamp,1_538_395_200_123);
+		assert_eq!(&packet.nonce[..],&payload[payload.len()-8..]);
+		assert_eq!(packet.status(),None);
+	}
+	let payload:Vec<u8> = Packet::encode(&[0x04],u64::MAX,&pad).unwrap();
+	let packet:Packet = Packet::decode(&payload,&pad).unwrap();
+	assert_eq!((packet.timestamp,packet.status()),(u64::MAX,Some(StatusCode::Unsubscribe)));
+}
+
+#[test]
+fn short_datagrams_are_rejected() {
+	let pad:Pad = common::pad("packetshort");
+	for length in [0,1,OVERHEAD,MIN_PACKET_SIZE-1].iter() {
+		match Packet::decode(&vec![0;*length],&pad) {
+			Err(PacketError::TooShort { length:reported }) => assert_eq!(reported,*length),
+			other => panic!("{}: {:?}",length,other),
+		};
+	}
+	// Valid Teacrypt payloads too short to hold a timestamp are still too short.
+	let payload:Vec<u8> = teacrypt::encrypt(&[0;TIMESTAMP_SIZE-1],&pad).unwrap();
+	assert_eq!(payload.len(),MIN_PACKET_SIZE-1);
+	match Packet::decode(&payload,&pad) {
+		Err(PacketError::TooShort { length }) => assert_eq!(length,MIN_PACKET_SIZE-1),
+		other => panic!("{:?}",other),
+	};
+}
+
+#[test]
+fn shortest_datagrams_are_decrypted() {
+	let pad:Pad = common::pad("packetminimum");
+	// An empty message with its timestamp is exactly the minimum size.
+	let payload:Vec<u8> = Packet::encode(b"",42,&pad).unwrap();
+	assert_eq!(payload.len(),MIN_PACKET_SIZE);
+	assert_eq!(Packet::decode(&payload,&pad).unwrap().timestamp,42);
+	// Junk of the same size gets as far as the signature check.
+	match Packet::decode(&[0x55;24],&pad) {
+		Err(PacketError::Crypto(teacrypt::Error::SignatureInvalid)) => (),
+		other => panic!("{:?}",other),
+	};
+}
@@ -0,0 +1,104 @@
+// Using part of a file as the pad: windows at an offset, in each pad mode, and finding the size of
+// pad sources that aren't regular files.
+
+extern crate teamech;
+
+use std::env;
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use teamech::teacrypt::{Error,Pad,PadMode,PadWindow};
+
+// A temporary pad file where each byte can be told from its neighbours. Removed when dropped.
+struct PadFile {
+	path:PathBuf,
+	bytes:Vec<u8>,
+}
+
+impl PadFile {
+	fn write(name:&str,size:usize) -> PadFile {
+		let path:PathBuf = env::temp_dir().join(format!("teamech-test-{}-{}.pad",name,std::process::id()));
+		let bytes:Vec<u8> = (0..size as u32).map(|n| (n.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+		fs::write(&path,&bytes).unwrap();
+		return PadFile { path, bytes };
+	}
+}
+
+impl Drop for PadFile {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+const MODES:[PadMode;3] = [PadMode::File,PadMode::Memory,PadMode::Mapped];
+
+#[test]
+fn windows_read_from_their_offset() {
+	let padfile:PadFile = PadFile::write("window",10_000);
+	// Not a multiple of the page size, which memory maps have to work around.
+	let window:PadWindow = PadWindow { offset:1_234, size:Some(4_000) };
+	for mode in MODES.iter() {
+		let pad:Pad = Pad::open_window(&padfile.path,*mode,window).unwrap();
+		assert_eq!(pad.offset(),1_234);
+		assert_eq!(pad.size(),4_000);
+		let bytes:Vec<u8> = (0..4_000).map(|n| pad.byte(n).unwrap()).collect();
+		assert_eq!(&bytes[..],&padfile.bytes[1_234..5_234],"{:?}",mode);
+		match pad.byte(4_000) {
+			Err(Error::PadTooSmall { offset:4_000, size:4_000 }) => (),
+			other => panic!("{:?}: {:?}",mode,other),
+		};
+	}
+}
+
+#[test]
+fn windows_without_a_size_run_to_the_end() {
+	let padfile:PadFile = PadFile::write("tail",10_000);
+	let window:PadWindow = PadWindow { offset:9_000, size:None };
+	for mode in MODES.iter() {
+		let pad:Pad = Pad::open_window(&padfile.path,*mode,window).unwrap();
+		assert_eq!(pad.size(),1_000);
+		assert_eq!(pad.byte(0).unwrap(),padfile.bytes[9_000]);
+		assert_eq!(pad.byte(999).unwrap(),padfile.bytes[9_999]);
+		assert!(pad.byte(1_000).is_err());
+	}
+	let pad:Pad = Pad::map(&padfile.path).unwrap();
+	assert_eq!((pad.offset(),pad.size()),(0,10_000));
+}
+
+#[test]
+fn windows_must_fit_in_the_file() {
+	let padfile:PadFile = PadFile::write("bounds",10_000);
+	for mode in MODES.iter() {
+		// Exactly the last byte is fine.
+		assert!(Pad::open_window(&padfile.path,*mode,PadWindow { offset:9_999, size:Some(1) }).is_ok());
+		match Pad::open_window(&padfile.path,*mode,PadWindow { offset:9_999, size:Some(2) }) {
+			Err(Error::WindowOutOfRange { offset:9_999, size:2, filesize:10_000 }) => (),
+			other => panic!("{:?}: {:?}",mode,other),
+		};
+		match Pad::open_window(&padfile.path,*mode,PadWindow { offset:20_000, size:Some(1) }) {
+			Err(Error::WindowOutOfRange { offset:20_000, size:1, filesize:10_000 }) => (),
+			other => panic!("{:?}: {:?}",mode,other),
+		};
+		match Pad::open_window(&padfile.path,*mode,PadWindow { offset:u64::MAX, size:Some(u64::MAX) }) {
+			Err(Error::WindowOutOfRange { .. }) => (),
+			other => panic!("{:?}: {:?}",mode,other),
+		};
+	}
+}
+
+// Block devices report a length of zero in their metadata, so their size is found by seeking to
+// the end instead. Directories on most Linux filesystems go down the same path, and seek to a size
+// far beyond what their metadata reports.
+#[test]
+#[cfg(target_os = "linux")]
+fn other_files_are_measured_by_seeking() {
+	let directory:&Path = Path::new(env!("CARGO_MANIFEST_DIR"));
+	let listed:u64 = fs::metadata(directory).unwrap().len();
+	let window:PadWindow = PadWindow { offset:listed + 1_000_000, size:Some(64) };
+	match Pad::open_window(directory,PadMode::File,window) {
+		Ok(pad) => assert_eq!(pad.size(),64),
+		// Some filesystems (e.g. tmpfs) can't seek to the end of a directory at all.
+		Err(Error::Io(_)) => (),
+		Err(why) => panic!("{:?}",why),
+	};
+}
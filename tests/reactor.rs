@@ -0,0 +1,105 @@
+// Waiting on the client socket: datagrams, deadlines, and outboxes waking the client up to send
+// what other threads have queued.
+
+extern crate teamech;
+
+use std::io;
+use std::net::{SocketAddr,UdpSocket};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use teamech::reactor;
+use teamech::reactor::{Event,Letter,Mailbox,Outbox,Reactor};
+
+// A socket for the client, and a reactor attached to it with a fresh mailbox.
+fn attached<F:FnOnce(&UdpSocket,&Reactor,Outbox,Mailbox)>(test:F) {
+	let (outbox,mailbox) = reactor::mailbox();
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let reactor:Reactor = Reactor::new(&socket,&mailbox).unwrap();
+	test(&socket,&reactor,outbox,mailbox);
+}
+
+fn soon() -> Option<Instant> {
+	return Some(Instant::now() + Duration::from_secs(5));
+}
+
+#[test]
+fn deadlines_pass_when_nothing_happens() {
+	attached(|_socket,reactor,_outbox,_mailbox| {
+		let mut buf:[u8;500] = [0;500];
+		let start:Instant = Instant::now();
+		assert_eq!(reactor.wait(&mut buf,Some(start + Duration::from_millis(50))).unwrap(),Event::Deadline);
+		assert!(start.elapsed() >= Duration::from_millis(50),"{:?}",start.elapsed());
+		// A deadline that has already passed doesn't wait at all.
+		let start:Instant = Instant::now();
+		assert_eq!(reactor.wait(&mut buf,Some(start - Duration::from_millis(1))).unwrap(),Event::Deadline);
+		assert!(start.elapsed() < Duration::from_millis(50),"{:?}",start.elapsed());
+	});
+}
+
+#[test]
+fn datagrams_are_received() {
+	attached(|socket,reactor,_outbox,_mailbox| {
+		let server:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		server.send_to(b"lamp on",socket.local_addr().unwrap()).unwrap();
+		let mut buf:[u8;500] = [0;500];
+		assert_eq!(reactor.wait(&mut buf,soon()).unwrap(),Event::Datagram(7,server.local_addr().unwrap()));
+		assert_eq!(&buf[..7],b"lamp on");
+	});
+}
+
+#[test]
+fn queued_messages_wake_the_reactor() {
+	attached(|_socket,reactor,outbox,mailbox| {
+		let sender:Outbox = outbox.clone();
+		thread::spawn(move || {
+			thread::sleep(Duration::from_millis(50));
+			sender.send(b"button pressed".to_vec()).unwrap();
+		});
+		let mut buf:[u8;500] = [0;500];
+		// With no deadline, only the outbox can end the wait.
+		assert_eq!(reactor.wait(&mut buf,None).unwrap(),Event::Woken);
+		let letters:Vec<Letter> = mailbox.take();
+		assert_eq!(letters.len(),1);
+		assert_eq!(letters[0].bytes,b"button pressed");
+		assert!(letters[0].notify.is_none());
+		assert!(mailbox.take().is_empty());
+	});
+}
+
+// Messages queued while no reactor is attached wait in the mailbox, and once the mailbox is gone,
+// outboxes say so.
+#[test]
+fn messages_wait_for_the_client() {
+	let (outbox,mailbox) = reactor::mailbox();
+	outbox.send(b"early".to_vec()).unwrap();
+	outbox.wake().unwrap();
+	assert_eq!(mailbox.take().len(),1);
+	drop(mailbox);
+	assert_eq!(outbox.send(b"late".to_vec()).unwrap_err().kind(),io::ErrorKind::BrokenPipe);
+	assert!(outbox.send_reliable(b"late".to_vec()).is_err());
+}
+
+// Wake-ups all come from the same socket, however many there are and from whichever outbox,
+// rather than each one binding a socket (and using up a port) of its own.
+#[test]
+fn outboxes_share_one_wake_socket() {
+	let (outbox,mailbox) = reactor::mailbox();
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let _reactor:Reactor = Reactor::new(&socket,&mailbox).unwrap();
+	socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	let other:Outbox = outbox.clone();
+	let mut buf:[u8;500] = [0;500];
+	let mut senders:Vec<SocketAddr> = Vec::new();
+	for n in 0..10 {
+		match n % 2 {
+			0 => outbox.send(vec![n]).unwrap(),
+			_ => other.wake().unwrap(),
+		};
+		let (size,from) = socket.recv_from(&mut buf).unwrap();
+		assert_eq!(size,0);
+		senders.push(from);
+	}
+	assert!(senders.iter().all(|from| *from == senders[0]),"{:?}",senders);
+	assert_eq!(mailbox.take().len(),5);
+}
@@ -0,0 +1,145 @@
+// Replay protection: captured packets sent again, within one session and across restarts.
+
+extern crate teamech;
+
+mod common;
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+use teamech::packet::Packet;
+use teamech::replay::ReplayCache;
+use teamech::systime;
+use teamech::teacrypt::Pad;
+
+// A fresh path for a test's replay cache file.
+fn cachepath(name:&str) -> PathBuf {
+	let path:PathBuf = env::temp_dir().join(format!("teamech-test-{}-{}.replay",name,std::process::id()));
+	let _ = fs::remove_file(&path);
+	return path;
+}
+
+// What the client does with a datagram: decode it, then check its nonce.
+fn receive(payload:&[u8],pad:&Pad,cache:&mut ReplayCache) -> bool {
+	let packet:Packet = Packet::decode(payload,pad).expect("could not decode packet");
+	return cache.admit(packet.nonce,packet.timestamp,systime());
+}
+
+#[test]
+fn rejects_replays_in_the_same_session() {
+	let pad:Pad = common::pad("replay-session");
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),1_000,None);
+	let captured:Vec<u8> = Packet::encode(b"open the door",systime(),&pad).unwrap();
+	let other:Vec<u8> = Packet::encode(b"open the door",systime(),&pad).unwrap();
+	assert!(receive(&captured,&pad,&mut cache));
+	assert!(!receive(&captured,&pad,&mut cache));
+	// The same message sent again on purpose is encrypted with a new nonce, and gets through.
+	assert!(receive(&other,&pad,&mut cache));
+}
+
+#[test]
+fn rejects_replays_across_restarts() {
+	let pad:Pad = common::pad("replay-restart");
+	let path:PathBuf = cachepath("restart");
+	let captured:Vec<u8> = Packet::encode(b"open the door",systime(),&pad).unwrap();
+	{
+		let mut cache:ReplayCache = ReplayCache::new(Some(20_000),1_000,Some(path.clone()));
+		assert_eq!(cache.load(systime()).unwrap(),0);
+		assert!(receive(&captured,&pad,&mut cache));
+		cache.save().unwrap();
+	}
+	// The client restarts (or reconnects and rebuilds its state), and the packet is replayed.
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),1_000,Some(path.clone()));
+	assert_eq!(cache.load(systime()).unwrap(),1);
+	assert!(!receive(&captured,&pad,&mut cache));
+	let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn expires_by_time_not_count() {
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),100_000,None);
+	let now:u64 = 1_600_000_000_000;
+	let first:[u8;8] = *b"nonce #0";
+	assert!(cache.admit(first,now,now));
+	// A burst of other messages doesn't push the first nonce out...
+	for n in 1..10_000u64 {
+		assert!(cache.admit(n.to_be_bytes(),now,now));
+	}
+	assert!(!cache.admit(first,now,now+1_000));
+	assert_eq!(cache.evicted(),0);
+	// ...but time does, once the message is too old to pass the timestamp check anyway.
+	assert!(!cache.admit(first,now,now+20_001));
+	assert!(cache.is_empty());
+}
+
+#[test]
+fn forgets_expired_nonces_when_loading() {
+	let path:PathBuf = cachepath("expired");
+	let now:u64 = 1_600_000_000_000;
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),1_000,Some(path.clone()));
+	assert!(cache.admit(*b"oldnonce",now-15_000,now));
+	assert!(cache.admit(*b"newnonce",now,now));
+	cache.save().unwrap();
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),1_000,Some(path.clone()));
+	assert_eq!(cache.load(now+10_000).unwrap(),1);
+	assert_eq!(cache.len(),1);
+	assert!(!cache.admit(*b"newnonce",now,now+10_000));
+	let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn counts_recent_nonces_forgotten_when_full() {
+	let path:PathBuf = cachepath("full");
+	let now:u64 = 1_600_000_000_000;
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),3,Some(path.clone()));
+	for n in 0..3u64 {
+		assert!(cache.admit(n.to_be_bytes(),now-30_000+n,now-20_000));
+	}
+	// Expired nonces make way without being counted...
+	assert!(cache.admit(*b"nonce #3",now,now));
+	assert_eq!((cache.len(),cache.evicted()),(1,0));
+	assert!(cache.admit(*b"nonce #4",now,now));
+	assert!(cache.admit(*b"nonce #5",now+1,now));
+	cache.save().unwrap();
+	// ...but recent ones are, since their messages could now be replayed.
+	assert!(cache.admit(*b"nonce #6",now+2,now));
+	assert_eq!((cache.len(),cache.evicted()),(3,1));
+	assert!(cache.admit(*b"nonce #3",now,now),"the forgotten nonce is let through again");
+	// Expired nonces in the file don't take up room either.
+	fs::write(&path,format!("{}0000000000000000 {}\n",fs::read_to_string(&path).unwrap(),now-30_000)).unwrap();
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),3,Some(path.clone()));
+	assert_eq!(cache.load(now).unwrap(),3);
+	assert_eq!((cache.len(),cache.evicted()),(3,0));
+	let _ = fs::remove_file(&path);
+}
+
+// Saving writes a temporary file first, which mustn't be one that belongs to something else.
+#[test]
+fn saving_leaves_similar_names_alone() {
+	let path:PathBuf = cachepath("similar").with_extension("db");
+	let neighbour:PathBuf = path.with_extension("tmp");
+	fs::write(&neighbour,"not ours\n").unwrap();
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),1_000,Some(path.clone()));
+	assert!(cache.admit(*b"nonce #0",systime(),systime()));
+	cache.save().unwrap();
+	assert_eq!(fs::read_to_string(&neighbour).unwrap(),"not ours\n");
+	let mut tempname:OsString = path.clone().into_os_string();
+	tempname.push(".tmp");
+	assert!(!PathBuf::from(tempname).exists());
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),1_000,Some(path.clone()));
+	assert_eq!(cache.load(systime()).unwrap(),1);
+	let _ = fs::remove_file(&path);
+	let _ = fs::remove_file(&neighbour);
+}
+
+#[test]
+fn reports_corrupt_cache_files() {
+	let path:PathBuf = cachepath("corrupt");
+	fs::write(&path,"0011223344556677 1600000000000\nnot a nonce\n").unwrap();
+	let mut cache:ReplayCache = ReplayCache::new(Some(20_000),1_000,Some(path.clone()));
+	let why:String = cache.load(1_600_000_000_000).unwrap_err().to_string();
+	assert!(why.contains("line 2"),"{}",why);
+	let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,236 @@
+// Looking server names up again, with a stub resolver standing in for DNS.
+
+extern crate teamech;
+
+mod common;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{UdpSocket,SocketAddr};
+use std::sync::{Arc,Mutex,MutexGuard};
+use std::sync::mpsc;
+use std::thread::sleep;
+use std::time::{Duration,Instant};
+
+use common::StandIn;
+use teamech::clock::Clock;
+use teamech::failover::{Failover,FailoverPolicy};
+use teamech::net::sendbytes;
+use teamech::packet::Packet;
+use teamech::resolve::{Lookup,Resolve,ServerNames};
+use teamech::status::StatusCode;
+use teamech::teacrypt::Pad;
+
+static NAME:&str = "teamech.example.com:3840";
+static BACKUP:&str = "backup.example.com:3840";
+
+// Answers lookups from a table that the test can change at any time, like a DNS record being
+// updated. Names that aren't in the table fail to resolve. Lookups wait while the test holds the
+// resolver up, like a slow DNS server.
+#[derive(Clone,Default)]
+struct StubResolver {
+	records:Arc<Mutex<HashMap<String,Vec<SocketAddr>>>>,
+	lookups:Arc<Mutex<usize>>,
+	busy:Arc<Mutex<()>>,
+}
+
+impl StubResolver {
+	fn set(&self,name:&str,addresses:&[SocketAddr]) {
+		self.records.lock().unwrap().insert(name.to_owned(),addresses.to_vec());
+	}
+
+	fn remove(&self,name:&str) {
+		self.records.lock().unwrap().remove(name);
+	}
+
+	fn lookups(&self) -> usize {
+		return *self.lookups.lock().unwrap();
+	}
+
+	// Lookups wait until the guard is dropped.
+	fn hold_up(&self) -> MutexGuard<'_,()> {
+		return self.busy.lock().unwrap();
+	}
+}
+
+impl Resolve for StubResolver {
+	fn resolve(&self,name:&str) -> Result<Vec<SocketAddr>,io::Error> {
+		drop(self.busy.lock().unwrap());
+		*self.lookups.lock().unwrap() += 1;
+		match self.records.lock().unwrap().get(name) {
+			Some(addresses) => return Ok(addresses.clone()),
+			None => return Err(io::Error::new(io::ErrorKind::NotFound,"no such host")),
+		};
+	}
+}
+
+fn address(text:&str) -> SocketAddr {
+	return text.parse().unwrap();
+}
+
+fn policy() -> FailoverPolicy {
+	return FailoverPolicy {
+		attempts:2,
+		failback_interval:None,
+	};
+}
+
+#[test]
+fn looks_names_up_again_after_the_ttl() {
+	let resolver:StubResolver = StubResolver::default();
+	resolver.set(NAME,&[address("192.0.2.1:3840")]);
+	let mut names:ServerNames = ServerNames::new(vec![NAME.to_owned()],Box::new(resolver.clone()),Some(Duration::from_millis(50)));
+	assert_eq!(names.lookup().addresses,vec![address("192.0.2.1:3840")]);
+	resolver.set(NAME,&[address("192.0.2.2:3840")]);
+	// Until the TTL runs out, the old address stands.
+	assert!(names.refresh(Instant::now()).is_none());
+	assert_eq!(resolver.lookups(),1);
+	sleep(Duration::from_millis(60));
+	let lookup:Lookup = names.refresh(Instant::now()).expect("names were not looked up again");
+	assert!(lookup.changed);
+	assert_eq!(lookup.addresses,vec![address("192.0.2.2:3840")]);
+	// Looking up the same answer again isn't a change.
+	sleep(Duration::from_millis(60));
+	assert!(!names.refresh(Instant::now()).unwrap().changed);
+}
+
+#[test]
+fn keeps_old_addresses_when_lookups_fail() {
+	let resolver:StubResolver = StubResolver::default();
+	resolver.set(NAME,&[address("192.0.2.1:3840")]);
+	let mut names:ServerNames = ServerNames::new(vec![NAME.to_owned()],Box::new(resolver.clone()),None);
+	names.lookup();
+	resolver.remove(NAME);
+	names.expire();
+	let lookup:Lookup = names.refresh(Instant::now()).unwrap();
+	assert!(!lookup.changed);
+	assert_eq!(lookup.addresses,vec![address("192.0.2.1:3840")]);
+	assert_eq!(lookup.failed.len(),1);
+	assert_eq!(lookup.failed[0].0,NAME);
+	// Without a TTL, nothing is looked up again until it's asked for.
+	assert_eq!(names.next_lookup(),None);
+}
+
+// Each name keeps its own addresses when it fails to resolve, even if the others resolve fine, so
+// that a server isn't dropped (and the client switched away from it) over one failed lookup.
+#[test]
+fn one_failing_name_keeps_its_servers() {
+	let (a,b) = (address("192.0.2.1:3840"),address("192.0.2.2:3840"));
+	let resolver:StubResolver = StubResolver::default();
+	resolver.set(NAME,&[a]);
+	resolver.set(BACKUP,&[b]);
+	let mut names:ServerNames = ServerNames::new(vec![NAME.to_owned(),BACKUP.to_owned()],Box::new(resolver.clone()),None);
+	let mut failover:Failover = Failover::new(names.lookup().addresses,policy()).unwrap();
+	failover.fail_over();
+	assert_eq!(failover.current(),b);
+	resolver.remove(BACKUP);
+	names.expire();
+	let lookup:Lookup = names.refresh(Instant::now()).unwrap();
+	assert!(!lookup.changed);
+	assert_eq!(lookup.addresses,vec![a,b]);
+	assert_eq!(lookup.failed.len(),1);
+	assert_eq!(lookup.failed[0].0,BACKUP);
+	assert!(!failover.update(lookup.addresses));
+	assert_eq!(failover.current(),b);
+	// A name that resolves again replaces its own addresses, and only those.
+	resolver.remove(NAME);
+	resolver.set(BACKUP,&[address("192.0.2.3:3840")]);
+	names.expire();
+	let lookup:Lookup = names.refresh(Instant::now()).unwrap();
+	assert!(lookup.changed);
+	assert_eq!(lookup.addresses,vec![a,address("192.0.2.3:3840")]);
+}
+
+// Lookups can run on another thread, which says when it's done, while the caller carries on.
+#[test]
+fn looks_names_up_in_the_background() {
+	let resolver:StubResolver = StubResolver::default();
+	resolver.set(NAME,&[address("192.0.2.1:3840")]);
+	let mut names:ServerNames = ServerNames::new(vec![NAME.to_owned()],Box::new(resolver.clone()),Some(Duration::from_secs(60)));
+	names.lookup();
+	resolver.set(NAME,&[address("192.0.2.2:3840")]);
+	// Nothing is started before the lookup is due.
+	names.refresh_in_background(Instant::now(),|| panic!("looked up before the TTL ran out"));
+	assert!(!names.is_running());
+	names.expire();
+	let (done,finished) = mpsc::channel();
+	let slow = resolver.hold_up();
+	let waker:mpsc::Sender<()> = done.clone();
+	names.refresh_in_background(Instant::now(),move || waker.send(()).unwrap());
+	assert!(names.is_running());
+	// While the lookup is running, another isn't started, nor waited for.
+	assert!(!names.is_due(Instant::now()));
+	assert_eq!(names.next_lookup(),None);
+	names.refresh_in_background(Instant::now(),move || done.send(()).unwrap());
+	assert!(names.finished().is_none());
+	drop(slow);
+	finished.recv_timeout(Duration::from_secs(5)).expect("lookup never finished");
+	let lookup:Lookup = names.finished().expect("finished lookup was not taken in");
+	assert!(lookup.changed);
+	assert_eq!(lookup.addresses,vec![address("192.0.2.2:3840")]);
+	assert!(!names.is_running());
+	assert!(names.next_lookup().is_some());
+	assert_eq!(resolver.lookups(),2);
+	// The second callback was never used.
+	assert!(finished.recv_timeout(Duration::from_millis(50)).is_err());
+}
+
+#[test]
+fn keeps_using_a_server_that_is_still_listed() {
+	let (a,b,c) = (address("192.0.2.1:3840"),address("192.0.2.2:3840"),address("192.0.2.3:3840"));
+	let mut failover:Failover = Failover::new(vec![a,b],policy()).unwrap();
+	failover.fail_over();
+	assert_eq!(failover.current(),b);
+	assert!(failover.update(vec![c,b]));
+	assert_eq!(failover.current(),b);
+	assert!(!failover.update(vec![c,b]));
+	assert!(failover.update(vec![c]));
+	assert_eq!(failover.current(),c);
+}
+
+// Asks a server for a subscription, and waits briefly for it to be granted.
+fn subscribe(socket:&UdpSocket,server:SocketAddr,pad:&Pad) -> bool {
+	sendbytes(socket,&server,&[],pad,&Clock::new()).unwrap();
+	let mut buf:[u8;500] = [0;500];
+	socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+	while let Ok((nrecv,srcaddr)) = socket.recv_from(&mut buf) {
+		if srcaddr == server {
+			return Packet::decode(&buf[..nrecv],pad).unwrap().status() == Some(StatusCode::Subscribed);
+		}
+	}
+	return false;
+}
+
+#[test]
+fn follows_a_server_that_moves() {
+	let pad:Pad = common::pad("resolve-move");
+	let old:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let new:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let resolver:StubResolver = StubResolver::default();
+	resolver.set(NAME,&[old.address]);
+	let mut names:ServerNames = ServerNames::new(vec![NAME.to_owned()],Box::new(resolver.clone()),None);
+	let mut failover:Failover = Failover::new(names.lookup().addresses,policy()).unwrap();
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	assert!(subscribe(&socket,failover.current(),&pad));
+	// The server moves, and its DNS record is updated; the old address stops answering.
+	old.answer(false);
+	resolver.set(NAME,&[new.address]);
+	// This is what the client does with unanswered subscription requests: once the server has had
+	// its chances, look its name up again before giving up on it.
+	let mut subscribed:bool = false;
+	for _ in 0..4 {
+		if subscribe(&socket,failover.current(),&pad) {
+			subscribed = true;
+			break;
+		}
+		if failover.unanswered() {
+			names.expire();
+			let lookup:Lookup = names.refresh(Instant::now()).unwrap();
+			assert!(lookup.changed);
+			assert!(failover.update(lookup.addresses));
+		}
+	}
+	assert!(subscribed,"never subscribed to the server's new address");
+	assert_eq!(failover.current(),new.address);
+	new.subscribed();
+}
@@ -0,0 +1,214 @@
+// Periodic tasks: parsing schedules, finding the next cron tick, and what happens to ticks that
+// were missed while a task was running late.
+
+extern crate teamech;
+
+mod common;
+
+use std::net::{SocketAddr,UdpSocket};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use teamech::clock::Clock;
+use teamech::logging::rfc3339;
+use teamech::net::Sender;
+use teamech::scheduler::{CronSpec,MissedTicks,Schedule,Scheduler,TaskResult,MAX_INTERVAL};
+use teamech::teacrypt::Pad;
+
+fn cron(spec:&str) -> CronSpec {
+	return spec.parse::<CronSpec>().unwrap();
+}
+
+// The cron tick after a unix time in milliseconds, as an RFC 3339 timestamp.
+fn next(spec:&str,unixms:u64) -> Option<String> {
+	return cron(spec).next_after(unixms).map(rfc3339);
+}
+
+#[test]
+fn intervals_are_parsed() {
+	assert_eq!("every 30s".parse::<Schedule>(),Ok(Schedule::Every(Duration::from_secs(30))));
+	assert_eq!("500ms".parse::<Schedule>(),Ok(Schedule::Every(Duration::from_millis(500))));
+	assert_eq!(" every 2h ".parse::<Schedule>(),Ok(Schedule::Every(Duration::from_secs(7_200))));
+	assert_eq!("1d".parse::<Schedule>(),Ok(Schedule::Every(Duration::from_secs(86_400))));
+	assert_eq!("*/5 * * * *".parse::<Schedule>(),Ok(Schedule::Cron(cron("*/5 * * * *"))));
+	for bad in ["every 0s","30","30y","s","every","every -5m"].iter() {
+		assert!(bad.parse::<Schedule>().is_err(),"{}",bad);
+	}
+	// Up to a year, and no further, however the interval is written.
+	assert_eq!("every 365d".parse::<Schedule>(),Ok(Schedule::Every(MAX_INTERVAL)));
+	assert_eq!("every 31536000000ms".parse::<Schedule>(),Ok(Schedule::Every(MAX_INTERVAL)));
+	for toolong in ["every 366d","every 31536000001ms","every 9999999999999999999s","every 18446744073709551615d"].iter() {
+		assert_eq!(toolong.parse::<Schedule>().unwrap_err().to_string(),
+			format!("invalid schedule: duration '{}' is longer than a year",&toolong[6..]));
+	}
+}
+
+#[test]
+fn bad_cron_specifications_are_rejected() {
+	for bad in [
+		"* * * *",
+		"* * * * * *",
+		"60 * * * *",
+		"* 24 * * *",
+		"* * 0 * *",
+		"* * 32 * *",
+		"* * * 13 *",
+		"* * * * 8",
+		"*/0 * * * *",
+		"5-1 * * * *",
+		"1-x * * * *",
+		"a * * * *",
+		"1,,2 * * * *",
+	].iter() {
+		assert!(bad.parse::<CronSpec>().is_err(),"{}",bad);
+	}
+	// Sunday can be written either way.
+	assert_eq!(cron("0 0 * * 7"),cron("0 0 * * 0"));
+}
+
+#[test]
+fn cron_ticks_cross_day_and_year_boundaries() {
+	// 2018-12-31T23:59:30Z
+	let newyear:u64 = 1_546_300_770_000;
+	assert_eq!(next("0 0 * * *",newyear),Some(String::from("2019-01-01T00:00:00.000Z")));
+	assert_eq!(next("*/15 * * * *",newyear),Some(String::from("2019-01-01T00:00:00.000Z")));
+	assert_eq!(next("59 23 * * *",newyear),Some(String::from("2019-01-01T23:59:00.000Z")));
+	assert_eq!(next("* * * * *",newyear),Some(String::from("2019-01-01T00:00:00.000Z")));
+}
+
+#[test]
+fn cron_ticks_cross_month_boundaries() {
+	// 2019-01-31T12:00:00Z, which is itself a tick, and so doesn't count.
+	assert_eq!(next("0 12 * * *",1_548_936_000_000),Some(String::from("2019-02-01T12:00:00.000Z")));
+	assert_eq!(next("0 12 1 * *",1_548_936_000_000),Some(String::from("2019-02-01T12:00:00.000Z")));
+	// Months without a 31st are passed over. 2019-03-01T00:00:00Z:
+	let march:u64 = 1_551_398_400_000;
+	let first:u64 = cron("30 6 31 * *").next_after(march).unwrap();
+	assert_eq!(rfc3339(first),"2019-03-31T06:30:00.000Z");
+	assert_eq!(next("30 6 31 * *",first),Some(String::from("2019-05-31T06:30:00.000Z")));
+	// So are years without a 29th of February, while the 31st never comes.
+	assert_eq!(next("0 0 29 2 *",march),Some(String::from("2020-02-29T00:00:00.000Z")));
+	assert_eq!(next("0 0 31 2 *",march),None);
+}
+
+#[test]
+fn cron_fields_combine() {
+	// 2018-10-01T12:00:00Z, a Monday.
+	let monday:u64 = 1_538_395_200_000;
+	let spec:CronSpec = cron("0-30/10,45 * * * *");
+	let mut ticks:Vec<String> = Vec::new();
+	let mut time:u64 = monday;
+	for _ in 0..5 {
+		time = spec.next_after(time).unwrap();
+		ticks.push(rfc3339(time));
+	}
+	assert_eq!(ticks,vec![
+		"2018-10-01T12:10:00.000Z",
+		"2018-10-01T12:20:00.000Z",
+		"2018-10-01T12:30:00.000Z",
+		"2018-10-01T12:45:00.000Z",
+		"2018-10-01T13:00:00.000Z",
+	]);
+	assert_eq!(next("0 0 * * 7",monday),Some(String::from("2018-10-07T00:00:00.000Z")));
+	assert_eq!(next("0 9 * * 1-5",monday),Some(String::from("2018-10-02T09:00:00.000Z")));
+	// With both the day of month and the day of week restricted, either will do: the 13th, or
+	// a Friday.
+	assert_eq!(next("0 9 13 * 5",monday),Some(String::from("2018-10-05T09:00:00.000Z")));
+	// 2018-10-05T10:07:00Z, that Friday, after the tick.
+	let friday:u64 = 1_538_734_020_000;
+	assert_eq!(next("0 9 13 * 5",friday),Some(String::from("2018-10-12T09:00:00.000Z")));
+	assert_eq!(next("0 9 13 * *",friday),Some(String::from("2018-10-13T09:00:00.000Z")));
+	// Restricting the month as well still applies to both.
+	assert_eq!(next("0 9 13 11 5",friday),Some(String::from("2018-11-02T09:00:00.000Z")));
+}
+
+#[test]
+#[should_panic(expected = "interval of zero")]
+fn zero_intervals_are_rejected() {
+	let mut scheduler:Scheduler = Scheduler::new();
+	scheduler.every("busy",Duration::from_millis(0),|_sender:&Sender| Ok(()));
+}
+
+// Intervals given in code aren't limited, but one too long for the clock just never comes due,
+// rather than bringing the client down.
+#[test]
+fn endless_intervals_never_come_due() {
+	withsender("endless",|sender:&Sender| {
+		let mut scheduler:Scheduler = Scheduler::new();
+		for policy in [MissedTicks::Skip,MissedTicks::Burst,MissedTicks::Delay].iter() {
+			scheduler.every("never",Duration::MAX,|_sender:&Sender| Ok(())).missed(*policy).jitter(Duration::MAX);
+		}
+		scheduler.run_due(sender);
+		scheduler.run_due(sender);
+		assert_eq!(scheduler.next_deadline(),None);
+		assert!(scheduler.tasks().iter().all(|task| task.runs() == 0));
+		// Nor does jitter too long for the clock.
+		scheduler.every("jittery",Duration::from_secs(1),|_sender:&Sender| Ok(())).jitter(Duration::MAX);
+		scheduler.run_due(sender);
+		assert!(scheduler.next_deadline().is_some());
+	});
+}
+
+// Runs a task whose first run takes 500 ms, on a 200 ms interval with the given missed-tick policy.
+// Returns the scheduler, and the time the slow run finished.
+fn fall_behind(policy:MissedTicks,sender:&Sender) -> (Scheduler,Instant) {
+	let mut scheduler:Scheduler = Scheduler::new();
+	let mut first:bool = true;
+	scheduler.every("slow",Duration::from_millis(200),move |_sender:&Sender| -> TaskResult {
+		if first {
+			first = false;
+			thread::sleep(Duration::from_millis(500));
+		}
+		return Ok(());
+	}).missed(policy);
+	// The first pass only schedules the task.
+	scheduler.run_due(sender);
+	thread::sleep(scheduler.next_deadline().unwrap() - Instant::now());
+	scheduler.run_due(sender);
+	assert_eq!(scheduler.tasks()[0].runs(),1);
+	return (scheduler,Instant::now());
+}
+
+fn withsender<F:FnOnce(&Sender)>(name:&str,test:F) {
+	let pad:Pad = common::pad(name);
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let server:SocketAddr = socket.local_addr().unwrap();
+	test(&Sender::new(&socket,server,&pad,&Clock::new()));
+}
+
+#[test]
+fn skipped_ticks_stay_on_the_grid() {
+	withsender("missedskip",|sender:&Sender| {
+		let (mut scheduler,finished) = fall_behind(MissedTicks::Skip,sender);
+		// The ticks at 400 ms and 600 ms were missed; the next one is at 800 ms, about 100 ms
+		// after the slow run finished at 700 ms.
+		let wait:Duration = scheduler.next_deadline().unwrap() - finished;
+		assert!(wait < Duration::from_millis(160),"{:?}",wait);
+		scheduler.run_due(sender);
+		assert_eq!(scheduler.tasks()[0].runs(),1);
+	});
+}
+
+#[test]
+fn burst_ticks_catch_up() {
+	withsender("missedburst",|sender:&Sender| {
+		let (mut scheduler,_finished) = fall_behind(MissedTicks::Burst,sender);
+		// The missed ticks at 400 ms and 600 ms are run straight away, one per pass.
+		scheduler.run_due(sender);
+		scheduler.run_due(sender);
+		scheduler.run_due(sender);
+		assert_eq!(scheduler.tasks()[0].runs(),3);
+		assert!(scheduler.next_deadline().unwrap() > Instant::now());
+	});
+}
+
+#[test]
+fn delayed_ticks_wait_a_whole_interval() {
+	withsender("misseddelay",|sender:&Sender| {
+		let (mut scheduler,finished) = fall_behind(MissedTicks::Delay,sender);
+		let wait:Duration = scheduler.next_deadline().unwrap() - finished;
+		assert!(wait > Duration::from_millis(180),"{:?}",wait);
+		scheduler.run_due(sender);
+		assert_eq!(scheduler.tasks()[0].runs(),1);
+	});
+}
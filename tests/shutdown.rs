@@ -0,0 +1,103 @@
+// Shutting down cleanly: signals, saying goodbye to the server, and shutdown hooks.
+
+extern crate teamech;
+
+mod common;
+
+use std::cell::RefCell;
+use std::net::{UdpSocket,SocketAddr};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration,Instant};
+
+use common::{Client,StandIn};
+use teamech::handler::{Message,MessageHandler,Router};
+use teamech::reactor;
+use teamech::reactor::{Event,Reactor};
+use teamech::shutdown::ShutdownRequest;
+use teamech::status::StatusCode;
+use teamech::teacrypt::Pad;
+
+#[test]
+#[cfg(unix)]
+fn says_goodbye_when_terminated() {
+	let pad:Pad = common::pad("goodbye");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",true);
+	let mut client:Client = Client::start("goodbye",&[String::from("--server"),server.address.to_string()]);
+	let address:SocketAddr = server.subscribed();
+	client.logged(&format!("Subscribed to server at {}",server.address));
+	client.signal("TERM");
+	client.logged("Received SIGTERM; shutting down.");
+	let goodbye = server.expect("a goodbye",|received| received.content == [StatusCode::Unsubscribe.to_byte()]);
+	assert_eq!(goodbye.from,address);
+	client.logged("Shut down.");
+	assert_eq!(client.exit_code(),Some(0));
+}
+
+#[test]
+#[cfg(unix)]
+fn stops_waiting_to_retry_when_interrupted() {
+	let pad:Pad = common::pad("interrupt");
+	let server:StandIn = StandIn::start(&pad,"127.0.0.1:0",false);
+	// Back off for a long time after every unanswered request.
+	let mut client:Client = Client::start_with("interrupt",&[String::from("--server"),server.address.to_string()],&[
+		("TEAMECH_ERROR_DELAY_MS","10000"),
+		("TEAMECH_BACKOFF_JITTER","0"),
+	]);
+	server.expect("a subscription request",|received| received.content.is_empty());
+	let interrupted:Instant = Instant::now();
+	client.signal("INT");
+	// Never having subscribed, the client has nothing to deliver and nobody to say goodbye to.
+	let output:Vec<String> = client.logged("Shut down.");
+	assert!(!output.iter().any(|line| line.contains("not delivered")),"{:?}",output);
+	assert_eq!(client.exit_code(),Some(0));
+	assert!(interrupted.elapsed() < Duration::from_secs(5),"took {:?} to shut down",interrupted.elapsed());
+	assert!(server.log.try_iter().all(|received| received.content.is_empty()));
+}
+
+#[test]
+fn requests_wake_the_client() {
+	let (outbox,mailbox) = reactor::mailbox();
+	let socket:UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let reactor:Reactor = Reactor::new(&socket,&mailbox).unwrap();
+	let stop:ShutdownRequest = ShutdownRequest::new(outbox);
+	let requester:ShutdownRequest = stop.clone();
+	thread::spawn(move || {
+		thread::sleep(Duration::from_millis(50));
+		requester.request();
+	});
+	let mut buf:[u8;500] = [0;500];
+	assert_eq!(reactor.wait(&mut buf,Some(Instant::now() + Duration::from_secs(5))).unwrap(),Event::Woken);
+	assert!(stop.is_requested());
+}
+
+// A handler that records when it's told about shutdown.
+struct Lamp {
+	log:Rc<RefCell<Vec<String>>>,
+}
+
+impl MessageHandler for Lamp {
+	fn handle(&mut self,_message:&Message) -> Option<Vec<u8>> {
+		return None;
+	}
+
+	fn shutdown(&mut self) {
+		self.log.borrow_mut().push(String::from("lamp off"));
+	}
+}
+
+#[test]
+fn handlers_and_hooks_are_told_about_shutdown() {
+	let log:Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+	let mut device:Router = Router::new();
+	device.exact("on",Lamp { log:log.clone() });
+	let hooklog:Rc<RefCell<Vec<String>>> = log.clone();
+	device.on_shutdown(move || hooklog.borrow_mut().push(String::from("device hook")));
+	let mut router:Router = Router::new();
+	router.exact("Hello world!",|_message:&Message| Some(b"Hello world!".to_vec()));
+	router.command("lamp",device);
+	let hooklog:Rc<RefCell<Vec<String>>> = log.clone();
+	router.on_shutdown(move || hooklog.borrow_mut().push(String::from("main hook")));
+	router.shutdown();
+	assert_eq!(*log.borrow(),vec!["lamp off","device hook","main hook"]);
+}
@@ -0,0 +1,51 @@
+// Status codes: their bytes on the wire, and codes this version doesn't know.
+
+extern crate teamech;
+
+use teamech::status::{StatusCode,StatusCounters};
+
+static KNOWN:[(u8,StatusCode);6] = [
+	(0x02,StatusCode::Subscribed),
+	(0x04,StatusCode::Unsubscribe),
+	(0x06,StatusCode::Ack),
+	(0x15,StatusCode::ValidationFailed),
+	(0x19,StatusCode::Rejected),
+	(0x1A,StatusCode::DecryptFailed),
+];
+
+#[test]
+fn codes_survive_the_round_trip() {
+	for (byte,code) in KNOWN.iter() {
+		assert_eq!(StatusCode::from_byte(*byte),*code);
+		assert_eq!(StatusCode::known(*byte),Some(*code));
+		assert_eq!(code.to_byte(),*byte);
+		assert_eq!(u8::from(StatusCode::from(*byte)),*byte);
+	}
+	assert_eq!(StatusCode::Unsubscribe.to_string(),"0x04 (unsubscribed)");
+}
+
+#[test]
+fn unknown_codes_are_rejected() {
+	for byte in 0..=255u8 {
+		if KNOWN.iter().any(|(known,_)| *known == byte) {
+			continue;
+		}
+		assert_eq!(StatusCode::known(byte),None,"0x{:02x}",byte);
+		// They're still kept, byte for byte, for counting and logging.
+		assert_eq!(StatusCode::from_byte(byte),StatusCode::Unknown(byte));
+		assert_eq!(StatusCode::from_byte(byte).to_byte(),byte);
+	}
+	assert_eq!(StatusCode::Unknown(0x07).to_string(),"0x07 (unknown status code)");
+}
+
+#[test]
+fn unknown_codes_are_counted_together() {
+	let mut statuses:StatusCounters = StatusCounters::new();
+	assert_eq!(statuses.record(StatusCode::from_byte(0x07)),1);
+	assert_eq!(statuses.record(StatusCode::from_byte(0x07)),2);
+	assert_eq!(statuses.record(StatusCode::from_byte(0x08)),1);
+	assert_eq!(statuses.record(StatusCode::Ack),1);
+	assert_eq!(statuses.count(StatusCode::Unknown(0x07)),2);
+	assert_eq!(statuses.count(StatusCode::Rejected),0);
+	assert_eq!(statuses.unknown(),3);
+}
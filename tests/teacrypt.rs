@@ -0,0 +1,139 @@
+// Encryption and decryption: the round trip, and every way it can fail.
+
+extern crate teamech;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use teamech::teacrypt;
+use teamech::teacrypt::{Error,Pad,PadMode,PadWindow,OVERHEAD};
+
+#[test]
+fn messages_survive_the_round_trip() {
+	let padpath:PathBuf = common::padfile("roundtrip");
+	for pad in [Pad::open(&padpath).unwrap(),Pad::load(&padpath).unwrap(),Pad::map(&padpath).unwrap()].iter() {
+		for message in [&b""[..],&b"x"[..],&b"are you there?"[..],&[0xff;1_000][..]].iter() {
+			let payload:Vec<u8> = teacrypt::encrypt(message,pad).unwrap();
+			assert_eq!(payload.len(),message.len() + OVERHEAD);
+			assert_eq!(teacrypt::decrypt(&payload,pad).unwrap(),*message);
+		}
+	}
+	// Each payload gets its own nonce, and so its own key.
+	let pad:Pad = Pad::load(&padpath).unwrap();
+	assert_ne!(teacrypt::encrypt(b"again",&pad).unwrap(),teacrypt::encrypt(b"again",&pad).unwrap());
+	let _ = fs::remove_file(&padpath);
+}
+
+#[test]
+fn empty_pads_are_rejected() {
+	let padpath:PathBuf = env::temp_dir().join(format!("teamech-test-emptypad-{}.pad",std::process::id()));
+	fs::write(&padpath,b"").unwrap();
+	for mode in [PadMode::File,PadMode::Memory,PadMode::Mapped].iter() {
+		match Pad::open_window(&padpath,*mode,PadWindow::default()) {
+			Err(Error::PadEmpty) => (),
+			other => panic!("{:?}: {:?}",mode,other),
+		};
+	}
+	let _ = fs::remove_file(&padpath);
+	// So are empty windows of pads that aren't.
+	let padpath:PathBuf = common::padfile("emptywindow");
+	match Pad::open_window(&padpath,PadMode::Memory,PadWindow { offset:100, size:Some(0) }) {
+		Err(Error::PadEmpty) => (),
+		other => panic!("{:?}",other),
+	};
+	let _ = fs::remove_file(&padpath);
+}
+
+#[test]
+fn windows_outside_the_pad_are_rejected() {
+	let padpath:PathBuf = common::padfile("outofrange");
+	match Pad::open_window(&padpath,PadMode::File,PadWindow { offset:65_000, size:Some(1_000) }) {
+		Err(Error::WindowOutOfRange { offset:65_000, size:1_000, filesize:65_536 }) => (),
+		other => panic!("{:?}",other),
+	};
+	let _ = fs::remove_file(&padpath);
+}
+
+#[test]
+fn truncated_pads_are_reported() {
+	let padpath:PathBuf = common::padfile("truncated");
+	let pad:Pad = Pad::open(&padpath).unwrap();
+	let payload:Vec<u8> = teacrypt::encrypt(b"before",&pad).unwrap();
+	// Cut the pad short underneath the open handle. Keys drawn from it now run off the end.
+	fs::write(&padpath,b"").unwrap();
+	match teacrypt::decrypt(&payload,&pad) {
+		Err(Error::PadTooSmall { size:65_536, .. }) => (),
+		other => panic!("{:?}",other),
+	};
+	match teacrypt::encrypt(b"after",&pad) {
+		Err(Error::PadTooSmall { size:65_536, .. }) => (),
+		other => panic!("{:?}",other),
+	};
+	let _ = fs::remove_file(&padpath);
+}
+
+// Directories can be opened like files, and on most Linux filesystems seeked, but never read.
+#[test]
+#[cfg(target_os = "linux")]
+fn unreadable_pads_are_reported() {
+	let directory:&Path = Path::new(env!("CARGO_MANIFEST_DIR"));
+	let pad:Pad = match Pad::open_window(directory,PadMode::File,PadWindow { offset:0, size:Some(1_000) }) {
+		Ok(pad) => pad,
+		// Some filesystems (e.g. tmpfs) refuse the seek instead, which is checked in tests/pad.rs.
+		Err(Error::Io(_)) => return,
+		Err(why) => panic!("{:?}",why),
+	};
+	match pad.byte(10) {
+		Err(Error::ReadFailed { offset:10, .. }) => (),
+		other => panic!("{:?}",other),
+	};
+	match teacrypt::encrypt(b"anything",&pad) {
+		Err(Error::ReadFailed { .. }) => (),
+		other => panic!("{:?}",other),
+	};
+}
+
+#[test]
+fn short_payloads_are_rejected_before_decryption() {
+	let pad:Pad = common::pad("shortpayload");
+	for length in [0,1,8,OVERHEAD-1].iter() {
+		match teacrypt::decrypt(&vec![0;*length],&pad) {
+			Err(Error::PayloadTooShort { length:reported }) => assert_eq!(reported,*length),
+			other => panic!("{}: {:?}",length,other),
+		};
+	}
+	// The shortest possible payload holds an empty message.
+	let payload:Vec<u8> = teacrypt::encrypt(b"",&pad).unwrap();
+	assert_eq!(payload.len(),OVERHEAD);
+	assert_eq!(teacrypt::decrypt(&payload,&pad).unwrap(),b"");
+}
+
+#[test]
+fn tampering_breaks_the_signature() {
+	let pad:Pad = common::pad("tampered");
+	let payload:Vec<u8> = teacrypt::encrypt(b"lamp on",&pad).unwrap();
+	// Flip a bit in the message, in the signature, and in the nonce.
+	for position in [0,7,10,payload.len()-1].iter() {
+		let mut tampered:Vec<u8> = payload.clone();
+		tampered[*position] ^= 0x01;
+		match teacrypt::decrypt(&tampered,&pad) {
+			Err(Error::SignatureInvalid) => (),
+			other => panic!("{}: {:?}",position,other),
+		};
+	}
+	match teacrypt::decrypt(&[0x55;40],&pad) {
+		Err(Error::SignatureInvalid) => (),
+		other => panic!("{:?}",other),
+	};
+	// A payload encrypted with a different pad is no better.
+	let otherpath:PathBuf = common::padfile("otherpad");
+	let other:Pad = Pad::open_window(&otherpath,PadMode::Memory,PadWindow { offset:1, size:None }).unwrap();
+	match teacrypt::decrypt(&payload,&other) {
+		Err(Error::SignatureInvalid) => (),
+		other => panic!("{:?}",other),
+	};
+	let _ = fs::remove_file(&otherpath);
+}